@@ -97,7 +97,7 @@ char read_mem_addr() {
     set_mem_read(false);
     set_mem_write(false);
 
-    set_shiftreg_value(((uint)bank << 8) || (uint)addr_high);
+    set_shiftreg_value(((uint)bank << 8) | (uint)addr_high);
     set_shiftreg_output_enabled(true);
 
     set_data_pins_dir(GPIO_IN);
@@ -130,7 +130,7 @@ void load_mem_addr(char bank, char addr_high, char addr_low) {
     set_mem_read(false);
     set_mem_write(false);
 
-    set_shiftreg_value(((uint)bank << 8) || (uint)addr_high);
+    set_shiftreg_value(((uint)bank << 8) | (uint)addr_high);
     set_shiftreg_output_enabled(true);
 
     set_data_pins_dir(GPIO_IN);
@@ -167,7 +167,7 @@ void write_256(char bank, char addr_high, char *data) {
     set_data_pins_dir(GPIO_OUT);
     set_addr_pins_dir(GPIO_OUT);
 
-    set_shiftreg_value(((uint)bank << 8) || (uint)addr_high);
+    set_shiftreg_value(((uint)bank << 8) | (uint)addr_high);
     set_shiftreg_output_enabled(true);
 
     for (int i=0; i<256; i++) {
@@ -187,7 +187,7 @@ void do_write_byte() {
 
     scanf("%c", &bank);
     scanf("%c", &addr_high);
-    scanf("%c", &addr_high);
+    scanf("%c", &addr_low);
     scanf("%c", &data);
 
     write_byte(bank, addr_high, addr_low, data);
@@ -200,10 +200,12 @@ void write_byte(char bank, char addr_high, char addr_low, char data) {
     set_mem_write(false);
 
     set_data_pins_dir(GPIO_OUT);
+    set_addr_pins_dir(GPIO_OUT);
 
-    set_shiftreg_value(((uint)addr_high << 8) || (uint)addr_low);
+    set_shiftreg_value(((uint)bank << 8) | (uint)addr_high);
     set_shiftreg_output_enabled(true);
 
+    write_addr_pins(addr_low);
     write_data_pins(data);
     sleep_ms(1);
     set_mem_write(true);
@@ -212,6 +214,7 @@ void write_byte(char bank, char addr_high, char addr_low, char data) {
     sleep_ms(1);
 
     set_data_pins_dir(GPIO_IN);
+    set_addr_pins_dir(GPIO_IN);
     set_shiftreg_output_enabled(false);
 }
 
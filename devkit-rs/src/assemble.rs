@@ -0,0 +1,294 @@
+use crate::devkit::DevkitResult;
+use crate::image::{Image, Segment};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use z80_assembler::{Compiler, DebugInfo, InMemorySourceProvider, Program, SourceHeader};
+
+const CAPACITY: usize = 64 * 1024;
+
+const HEX_EXTENSIONS: &[&str] = &["hex", "ihx"];
+const SREC_EXTENSIONS: &[&str] = &["srec", "s19", "s28", "s37", "mot"];
+
+/// `.z80` files are assembled, anything else is uploaded as it is.
+pub fn is_source(file: &str) -> bool {
+    has_extension(file, &["z80"])
+}
+
+fn has_extension(file: &str, extensions: &[&str]) -> bool {
+    let lower = file.to_lowercase();
+    extensions
+        .iter()
+        .any(|e| lower.ends_with(&format!(".{}", e)))
+}
+
+/// What `build` makes of the files.
+#[derive(Debug)]
+pub struct Build {
+    pub image: Image,
+    /// The files it was built from, included ones too, the ones `--watch`
+    /// looks at.
+    pub files: Vec<String>,
+    /// Where the bytes of the image come from, for the sources, or from the
+    /// `.dbg` file the assembler writes next to its output.
+    pub source_map: Option<SourceMap>,
+}
+
+/// Memory image for `files`: a single Intel HEX, S-record or binary file, or
+/// the program the sources assemble to, in the order given. Binaries and
+/// programs are placed at `base`, HEX and S-record files carry their own
+/// addresses.
+pub fn build(files: &[&str], base: u32) -> DevkitResult<Build> {
+    let read_error = |file: &str, e| format!("unable to read {}: {}", file, e);
+    match files {
+        [file] if !is_source(file) => {
+            let (image, source_map) =
+                if has_extension(file, HEX_EXTENSIONS) || has_extension(file, SREC_EXTENSIONS) {
+                    let text = std::fs::read_to_string(file).map_err(|e| read_error(file, e))?;
+                    let image = match has_extension(file, HEX_EXTENSIONS) {
+                        true => Image::parse_hex(&text),
+                        false => Image::parse_srec(&text),
+                    }
+                    .map_err(|e| format!("{}: {}", file, e))?;
+                    (image, SourceMap::load(file, base)?)
+                } else {
+                    let data = std::fs::read(file).map_err(|e| read_error(file, e))?;
+                    let image = Image::new(vec![Segment { addr: base, data }])
+                        .map_err(|e| format!("{}: {}", file, e))?;
+                    (image, SourceMap::load(file, base)?)
+                };
+            Ok(Build {
+                image,
+                files: vec![file.to_string()],
+                source_map,
+            })
+        }
+        _ if files.iter().all(|f| is_source(f)) => {
+            let mut sources = vec![];
+            for file in files {
+                let s = std::fs::read_to_string(file).map_err(|e| read_error(file, e))?;
+                sources.push((
+                    SourceHeader {
+                        filename: file.to_string(),
+                    },
+                    s,
+                ));
+            }
+            let program = assemble(sources)?;
+            let segments = program
+                .segments()
+                .into_iter()
+                .map(|s| Segment {
+                    addr: base + s.addr as u32,
+                    data: s.data,
+                })
+                .collect();
+            let mut files = program.debug_info.files.clone();
+            files.extend(program.includes);
+            Ok(Build {
+                image: Image::new(segments)?,
+                files,
+                source_map: Some(SourceMap::new(program.debug_info, base)),
+            })
+        }
+        _ => Err("only .z80 sources can be combined".into()),
+    }
+}
+
+/// Source lines of a program loaded at `base`.
+#[derive(Debug)]
+pub struct SourceMap {
+    info: DebugInfo,
+    base: u32,
+}
+
+impl SourceMap {
+    pub fn new(info: DebugInfo, base: u32) -> SourceMap {
+        SourceMap { info, base }
+    }
+
+    /// The `<file>.dbg` written next to `file`, or `file` itself if it is
+    /// one, for `file` uploaded with `base` as the upload address: HEX and
+    /// S-record files ignore it. `None` if there is no such file.
+    pub fn load(file: &str, base: u32) -> DevkitResult<Option<SourceMap>> {
+        let (path, program) = match file.len().checked_sub(4) {
+            Some(end) if has_extension(file, &["dbg"]) => (file.to_string(), &file[..end]),
+            _ => (format!("{}.dbg", file), file),
+        };
+        let base = match has_extension(program, HEX_EXTENSIONS)
+            || has_extension(program, SREC_EXTENSIONS)
+        {
+            true => 0,
+            false => base,
+        };
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("unable to read {}: {}", path, e).into()),
+        };
+        let info = DebugInfo::from_sidecar(&text).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(Some(SourceMap::new(info, base)))
+    }
+
+    /// `file:line:column` that produced the byte at `addr`, with the label
+    /// it follows.
+    pub fn locate(&self, addr: u32) -> Option<String> {
+        let entry = self.info.location(addr.checked_sub(self.base)? as usize)?;
+        let file = self.info.filename(entry.file_id).unwrap_or("?");
+        let mut out = format!("{}:{}:{}", file, entry.line, entry.column);
+        if let Some(label) = &entry.label {
+            out += &format!(" ({})", label);
+        }
+        Some(out)
+    }
+}
+
+fn assemble(sources: Vec<(SourceHeader, String)>) -> DevkitResult<Program> {
+    let names = sources
+        .iter()
+        .map(|(h, _)| h.filename.clone())
+        .collect::<Vec<_>>();
+    let compiler = Compiler::new(InMemorySourceProvider { files: sources }, CAPACITY);
+
+    compiler.assemble().map_err(|e| {
+        let file = match e.file_id() {
+            Some(id) => names.get(id),
+            None => names.first().filter(|_| names.len() == 1),
+        };
+        match file {
+            Some(file) => format!("{}: {}", file, e).into(),
+            None => e.to_string().into(),
+        }
+    })
+}
+
+/// Notices when any of the files is modified, created or removed.
+pub struct Watcher {
+    files: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+impl Watcher {
+    pub fn new(files: &[String]) -> Self {
+        Watcher {
+            files: files
+                .iter()
+                .map(|f| (PathBuf::from(f), modified(Path::new(f))))
+                .collect(),
+        }
+    }
+
+    /// True if something changed since the last call.
+    pub fn changed(&mut self) -> bool {
+        let mut changed = false;
+        for (path, time) in self.files.iter_mut() {
+            let now = modified(path);
+            if now != *time {
+                *time = now;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assemble::{build, SourceMap, Watcher};
+    use crate::image::Image;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn build_sources() {
+        let dir = std::env::temp_dir().join(format!("devkit-build-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let main = dir.join("main.z80");
+        let main = main.to_str().unwrap();
+        std::fs::write(main, "LD A, 12h\nJP &end\n.end:\nHALT\n").unwrap();
+
+        let built = build(&[main], 0x100).unwrap();
+        assert_eq!(
+            Image::binary(vec![0x3E, 0x12, 0xC3, 0x05, 0x00, 0x76], 0x100),
+            built.image
+        );
+        assert_eq!(vec![main.to_string()], built.files);
+        let source_map = built.source_map.unwrap();
+        assert_eq!(
+            Some(format!("{}:4:1 (end)", main)),
+            source_map.locate(0x105)
+        );
+        assert_eq!(None, source_map.locate(0xFF));
+
+        let mut watcher = Watcher::new(&built.files);
+        assert!(!watcher.changed());
+        std::fs::write(main, "LD A, 12h\nJP &missing\n").unwrap();
+        let later = SystemTime::now() + Duration::from_secs(5);
+        std::fs::File::options()
+            .write(true)
+            .open(main)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert!(watcher.changed());
+        assert!(!watcher.changed());
+
+        let err = build(&[main], 0).unwrap_err().to_string();
+        assert!(err.starts_with(main) && err.contains("l2 - "), "{}", err);
+        assert!(build(&[main, "data.bin"], 0).is_err());
+
+        // included files are watched too
+        let font = dir.join("font.txt");
+        std::fs::write(&font, "size 3 2\nchar A\n.#.\n#.#\n").unwrap();
+        std::fs::write(main, "#incfont \"font.txt\" font\n").unwrap();
+        let built = build(&[main], 0).unwrap();
+        let font = font.to_str().unwrap();
+        assert_eq!(vec![main.to_string(), font.to_string()], built.files);
+        let mut watcher = Watcher::new(&built.files);
+        std::fs::File::options()
+            .write(true)
+            .open(font)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert!(watcher.changed());
+
+        // HEX files keep their own addresses
+        let hex = dir.join("prog.HEX");
+        let hex = hex.to_str().unwrap();
+        std::fs::write(hex, ":0300300002337A1E\r\n:00000001FF\r\n").unwrap();
+        let built = build(&[hex], 0x100).unwrap();
+        assert_eq!(Image::binary(vec![0x02, 0x33, 0x7A], 0x30), built.image);
+        assert!(built.source_map.is_none());
+        std::fs::write(hex, ":0300300002337A1F\n").unwrap();
+        let err = build(&[hex], 0).unwrap_err().to_string();
+        assert!(err.ends_with("prog.HEX: line 1: bad checksum"), "{}", err);
+
+        // binaries bring the .dbg the assembler wrote next to them
+        let bin = dir.join("prog.bin");
+        let bin = bin.to_str().unwrap();
+        std::fs::write(bin, [0x00, 0x76]).unwrap();
+        let dbg = "z80dbg 1\nfile 0 prog.z80\ncode 0001h 1 0:4:5 loop\n";
+        std::fs::write(format!("{}.dbg", bin), dbg).unwrap();
+        let source_map = build(&[bin], 0x8000).unwrap().source_map.unwrap();
+        assert_eq!(
+            Some("prog.z80:4:5 (loop)".into()),
+            source_map.locate(0x8001)
+        );
+        assert_eq!(None, source_map.locate(0x8000));
+        let source_map = SourceMap::load(&format!("{}.dbg", bin), 0)
+            .unwrap()
+            .unwrap();
+        assert_eq!(Some("prog.z80:4:5 (loop)".into()), source_map.locate(1));
+        std::fs::write(format!("{}.dbg", bin), "z80dbg 2\n").unwrap();
+        let err = build(&[bin], 0).err().unwrap().to_string();
+        assert!(
+            err.ends_with("prog.bin.dbg: l1 - expected 'z80dbg 1'"),
+            "{}",
+            err
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
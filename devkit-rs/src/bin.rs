@@ -1,5 +1,8 @@
 use devkit::run;
+use std::env;
+use std::process::ExitCode;
 
-fn main() {
-    run()
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    run(&args)
 }
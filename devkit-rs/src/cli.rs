@@ -0,0 +1,544 @@
+use crate::assemble::{build, SourceMap, Watcher};
+use crate::config::{Config, DEFAULT_LISTEN};
+use crate::decode;
+use crate::devkit::{Devkit, DevkitResult};
+use crate::diagnostics;
+use crate::diagnostics::Fault;
+use crate::dump::hexdump;
+use crate::image::Image;
+use crate::protocol::Address;
+use crate::server;
+use crate::shadow::{Shadow, DEFAULT_SAMPLES};
+use crate::shell::shell;
+use crate::transport;
+use crate::transport::capture;
+use crate::transport::{CaptureTransport, Transport};
+use crate::{parse_number, upload_image, verify_image};
+use std::io::BufRead;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
+
+/// How often `--watch` looks at the sources.
+const WATCH_INTERVAL: Duration = Duration::from_millis(250);
+/// Shortest reply timeout over TCP, a server waits for the board before
+/// it answers.
+const REMOTE_REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+fn help() {
+    println!("usage: devkit [--config <file>] [--port <port>] [--baud <rate>]");
+    println!("              [--timeout <ms>] [--window <n>] [--compress]");
+    println!("              [--address <addr>] [--board <id>] [--no-cache]");
+    println!("              [--samples <n>] [--watch] [--capture <file>]");
+    println!("              [--listen <addr>] [--token <token>] [--debug <file>]");
+    println!("              <command>");
+    println!();
+    println!("commands:");
+    println!("  ports           list the available serial ports");
+    println!("  upload <file>...");
+    println!("                  write the file to the board memory. Intel HEX (.hex,");
+    println!("                  .ihx) and S-record (.srec, .s19, .s28, .s37, .mot)");
+    println!("                  files go to their own addresses, binaries to the");
+    println!("                  upload address (--address, 0 by default). .z80");
+    println!("                  sources are assembled first. With --watch keeps");
+    println!("                  running and uploads again when a source changes");
+    println!("  verify <file>...");
+    println!("                  check the board memory holds the file, differences");
+    println!("                  show the source line they are in");
+    println!("  read <addr> <len> [-o <file>]");
+    println!("                  hexdump the memory range, or save it to the file.");
+    println!("                  dump is the same command");
+    println!("  decode <capture>");
+    println!("                  list the requests in a --capture file with the");
+    println!("                  board's answers and how long they took");
+    println!("  shell           interactive session, asks for the port if it can't");
+    println!("                  pick one");
+    println!("  serve           share the board over TCP, on --listen (default");
+    println!(
+        "                  {}). Clients pass --port tcp://<host>:<port>",
+        DEFAULT_LISTEN
+    );
+    println!("                  and the same --token");
+    println!();
+    println!("bring-up diagnostics, the memory tests overwrite the range:");
+    println!("  diag data-bus <addr>");
+    println!("                  walking ones and zeros at the address");
+    println!("  diag addr-bus <start> <len>");
+    println!("                  checks every address line inside the range");
+    println!("  diag march <start> <len>");
+    println!("                  March C- RAM test, reports stuck and coupled bits");
+    println!("  diag shift      reads shift register values from stdin and sets them,");
+    println!("                  'l <addr>' strobes a read at the address");
+    println!("With --debug <file> faults show the source line of the program file");
+    println!("uploaded there, from the .dbg file the assembler writes next to it.");
+    println!();
+    println!("Settings are read from ~/.config/devkit/devkit.conf, then from the");
+    println!("nearest devkit.conf in the current directory or its parents, or from");
+    println!("--config, and the options override them. Without a port the only Pico");
+    println!("connected is used. Numbers are decimal, or hex with a 'h' suffix. Files");
+    println!("and addresses past FFFFh continue into the next 64 KiB banks, 10000h is");
+    println!("the start of bank 1.");
+    println!();
+    println!("upload and shell remember what was written to the board, per port or");
+    println!("--board id, in $DEVKIT_CACHE_DIR or ~/.cache/devkit, and only write the");
+    println!("pages that changed. --samples pages are read back first to check the");
+    println!(
+        "board still holds them (default {}), --no-cache",
+        DEFAULT_SAMPLES
+    );
+    println!("writes everything.");
+    println!();
+    println!("Writes are pipelined, --window of them wait for the board's answer");
+    println!("at once (4 by default). --compress sends the pages run-length encoded");
+    println!("when the firmware supports it.");
+}
+
+struct Args {
+    config: Option<String>,
+    port: Option<String>,
+    baud: Option<u32>,
+    timeout: Option<u64>,
+    window: Option<usize>,
+    compress: bool,
+    address: Option<u32>,
+    capture: Option<String>,
+    listen: Option<String>,
+    token: Option<String>,
+    board: Option<String>,
+    no_cache: bool,
+    samples: usize,
+    watch: bool,
+    out: Option<String>,
+    debug: Option<String>,
+    command: Vec<String>,
+}
+
+fn parse_args(args: &[String]) -> Option<Args> {
+    let mut parsed = Args {
+        config: None,
+        port: None,
+        baud: None,
+        timeout: None,
+        window: None,
+        compress: false,
+        address: None,
+        capture: None,
+        listen: None,
+        token: None,
+        board: None,
+        no_cache: false,
+        samples: DEFAULT_SAMPLES,
+        watch: false,
+        out: None,
+        debug: None,
+        command: vec![],
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--port" => parsed.port = Some(args.next()?.clone()),
+            "--config" => parsed.config = Some(args.next()?.clone()),
+            "--baud" => parsed.baud = Some(args.next()?.parse().ok()?),
+            "--timeout" => parsed.timeout = Some(args.next()?.parse().ok()?),
+            "--window" => parsed.window = Some(args.next()?.parse().ok().filter(|n| *n > 0)?),
+            "--compress" => parsed.compress = true,
+            "--address" => parsed.address = Some(parse_number(args.next()?)?),
+            "--capture" => parsed.capture = Some(args.next()?.clone()),
+            "--listen" => parsed.listen = Some(args.next()?.clone()),
+            "--token" => parsed.token = Some(args.next()?.clone()),
+            "--board" => parsed.board = Some(args.next()?.clone()),
+            "--no-cache" => parsed.no_cache = true,
+            "--samples" => parsed.samples = args.next()?.parse().ok()?,
+            "--watch" => parsed.watch = true,
+            "-o" => parsed.out = Some(args.next()?.clone()),
+            "--debug" => parsed.debug = Some(args.next()?.clone()),
+            "-h" | "--help" => return None,
+            _ => parsed.command.push(arg.clone()),
+        }
+    }
+    Some(parsed)
+}
+
+/// The configuration files with the command line options on top.
+fn load_config(args: &Args) -> DevkitResult<Config> {
+    let mut config = Config::load(args.config.as_deref().map(Path::new))?;
+    if let Some(port) = &args.port {
+        config.port = Some(port.clone());
+    }
+    if let Some(baud) = args.baud {
+        config.serial_rate = baud;
+    }
+    if let Some(timeout) = args.timeout {
+        config.link.reply = Duration::from_millis(timeout);
+    }
+    if let Some(window) = args.window {
+        config.link.window = window;
+    }
+    if args.compress {
+        config.link.compress = true;
+    }
+    if let Some(address) = args.address {
+        config.upload_address = address;
+    }
+    if let Some(capture) = &args.capture {
+        config.capture = Some(PathBuf::from(capture));
+    }
+    if let Some(listen) = &args.listen {
+        config.listen = listen.clone();
+    }
+    if let Some(token) = &args.token {
+        config.token = Some(token.clone());
+    }
+    Ok(config)
+}
+
+/// Runs the command line, `args` don't include the program name. Returns 2
+/// for usage errors and 1 if the command failed.
+pub fn run(args: &[String]) -> ExitCode {
+    let args = match parse_args(args) {
+        Some(a) => a,
+        None => {
+            help();
+            return ExitCode::from(2);
+        }
+    };
+
+    let config = match load_config(&args) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let command = args.command.iter().map(|a| a.as_str()).collect::<Vec<_>>();
+    let res = match command.as_slice() {
+        ["ports"] => ports(),
+        ["decode", file] => decode(file),
+        ["upload", files @ ..] if !files.is_empty() => upload(&args, &config, files),
+        ["verify", files @ ..] if !files.is_empty() => verify(&config, files),
+        ["read" | "dump", addr, len] => match (parse_number(addr), parse_number(len)) {
+            (Some(addr), Some(len)) if addr as usize + len as usize <= config.memory_size => {
+                read(&args, &config, addr, len as usize)
+            }
+            _ => {
+                help();
+                return ExitCode::from(2);
+            }
+        },
+        ["diag", test @ ..] => match diag_test(test, &config) {
+            Some(test) => diag(&args, &config, test),
+            None => {
+                help();
+                return ExitCode::from(2);
+            }
+        },
+        ["serve"] => serve(&config),
+        ["shell"] => find_port(&config)
+            .or_else(|_| choose_port())
+            .and_then(|p| shell(connect(&config, &p)?, shadow(&args, &p), &config)),
+        _ => {
+            help();
+            return ExitCode::from(2);
+        }
+    };
+
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+enum Diag {
+    DataBus(u32),
+    AddressBus(u32, u32),
+    March(u32, u32),
+    Shift,
+}
+
+fn diag_test(args: &[&str], config: &Config) -> Option<Diag> {
+    let in_range = |start: u32, len: u32| start as usize + len as usize <= config.memory_size;
+    match args {
+        ["data-bus", addr] => Some(Diag::DataBus(
+            parse_number(addr).filter(|a| in_range(*a, 1))?,
+        )),
+        ["addr-bus", start, len] | ["march", start, len] => {
+            let (start, len) = (parse_number(start)?, parse_number(len)?);
+            if !in_range(start, len) {
+                return None;
+            }
+            match args[0] {
+                "addr-bus" => Some(Diag::AddressBus(start, len)),
+                _ => Some(Diag::March(start, len)),
+            }
+        }
+        ["shift"] => Some(Diag::Shift),
+        _ => None,
+    }
+}
+
+fn diag(args: &Args, config: &Config, test: Diag) -> DevkitResult<()> {
+    let source_map = match &args.debug {
+        Some(file) => Some(
+            SourceMap::load(file, config.upload_address)?
+                .ok_or_else(|| format!("no debug information for {}", file))?,
+        ),
+        None => None,
+    };
+    let port = find_port(config)?;
+    let devkit = connect(config, &port)?;
+    // the memory tests leave the board holding test patterns
+    if let Some(shadow) = shadow(args, &port) {
+        shadow.invalidate()?;
+    }
+
+    let (faults, tested) = match test {
+        Diag::DataBus(addr) => (diagnostics::data_bus(&devkit, addr)?, 1),
+        Diag::AddressBus(start, len) => (diagnostics::address_bus(&devkit, start, len)?, 1),
+        Diag::March(start, len) => {
+            let faults = diagnostics::march(&devkit, start, len, |e| println!("{}", e))?;
+            (faults, len as usize)
+        }
+        Diag::Shift => {
+            shift_mode(&devkit, config)?;
+            return devkit.close();
+        }
+    };
+    devkit.close()?;
+
+    print!(
+        "{}",
+        diagnostics::report(&faults, tested, source_map.as_ref())
+    );
+    faults_result(&faults)
+}
+
+fn faults_result(faults: &[Fault]) -> DevkitResult<()> {
+    match faults.len() {
+        0 => Ok(()),
+        n => Err(format!("{} faults", n).into()),
+    }
+}
+
+/// Sets the shift register to each value read from stdin, so the outputs can
+/// be checked with a probe. `l <addr>` strobes a read at the address instead.
+fn shift_mode(devkit: &Devkit, config: &Config) -> DevkitResult<()> {
+    println!("shift register value, l <addr> to strobe a read, q to quit");
+    let stdin = std::io::stdin();
+    loop {
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [] => {}
+            ["q"] => return Ok(()),
+            ["l", addr] => {
+                match parse_number(addr).filter(|a| (*a as usize) < config.memory_size) {
+                    Some(addr) => {
+                        devkit.load(Address::from_linear(addr))?;
+                        println!("strobed {:06X}h", addr);
+                    }
+                    None => println!("invalid address {}", addr),
+                }
+            }
+            [val] => match parse_number(val).filter(|v| *v <= 0xFFFF) {
+                Some(val) => {
+                    devkit.set_shift_register(val as u16)?;
+                    println!("{:04X}h = {:016b}", val, val);
+                }
+                None => println!("expected a 16 bit value"),
+            },
+            _ => println!("expected a value, l <addr> or q"),
+        }
+    }
+}
+
+fn ports() -> DevkitResult<()> {
+    for p in serialport::available_ports()? {
+        println!("{}", p.port_name);
+    }
+    Ok(())
+}
+
+fn upload(args: &Args, config: &Config, files: &[&str]) -> DevkitResult<()> {
+    let (image, sources) = load_image(config, files)?;
+    let port = find_port(config)?;
+    let devkit = connect(config, &port)?;
+    let shadow = shadow(args, &port);
+    let actual = match &shadow {
+        Some(shadow) => shadow.load_validated(&devkit, args.samples)?,
+        None => Image::default(),
+    };
+    let mut current = upload_image(&image, actual, shadow.as_ref(), &devkit)?;
+
+    if args.watch {
+        let mut watcher = Watcher::new(&sources);
+        println!("watching {}", sources.join(", "));
+        loop {
+            std::thread::sleep(WATCH_INTERVAL);
+            if !watcher.changed() {
+                continue;
+            }
+            // build errors are only reported, the next save may fix them
+            match load_image(config, files) {
+                Ok((image, sources)) => {
+                    current = upload_image(&image, current, shadow.as_ref(), &devkit)?;
+                    watcher = Watcher::new(&sources);
+                    println!("uploaded, watching for changes");
+                }
+                Err(e) => eprintln!("error: {}", e),
+            }
+        }
+    }
+    devkit.close()
+}
+
+/// The image built from `files`, checked against the memory map.
+fn load_image(config: &Config, files: &[&str]) -> DevkitResult<(Image, Vec<String>)> {
+    let built = build(files, config.upload_address)?;
+    config.check_image(&built.image)?;
+    Ok((built.image, built.files))
+}
+
+/// The shadow image of the board on `port`, `None` with `--no-cache`.
+fn shadow(args: &Args, port: &str) -> Option<Shadow> {
+    if args.no_cache {
+        return None;
+    }
+    Shadow::for_board(args.board.as_deref().unwrap_or(port))
+}
+
+fn verify(config: &Config, files: &[&str]) -> DevkitResult<()> {
+    let built = build(files, config.upload_address)?;
+    let image = built.image;
+    let devkit = connect(config, &find_port(config)?)?;
+    let differing = verify_image(&image, built.source_map.as_ref(), &devkit)?;
+    devkit.close()?;
+
+    match differing {
+        0 => {
+            println!("ok, {} bytes match", image.len());
+            Ok(())
+        }
+        n => Err(format!("{} bytes differ", n).into()),
+    }
+}
+
+fn read(args: &Args, config: &Config, addr: u32, len: usize) -> DevkitResult<()> {
+    let devkit = connect(config, &find_port(config)?)?;
+    let data = devkit.read(Address::from_linear(addr), len)?;
+    devkit.close()?;
+
+    match &args.out {
+        Some(out) => {
+            std::fs::write(out, &data).map_err(|e| format!("unable to write {}: {}", out, e))?
+        }
+        None => print!("{}", hexdump(addr, &data)),
+    }
+    Ok(())
+}
+
+fn connect(config: &Config, port: &str) -> DevkitResult<Devkit> {
+    let transport = transport::open(port, config.serial_rate, config.token.as_deref())?;
+    let transport: Box<dyn Transport> = match &config.capture {
+        Some(file) => Box::new(CaptureTransport::create(file, transport)?),
+        None => transport,
+    };
+    let mut link = config.link;
+    if port.starts_with("tcp://") {
+        link.reply = link.reply.max(REMOTE_REPLY_TIMEOUT);
+    }
+    Devkit::open_with(transport, link)
+}
+
+/// Shares the connected board with the clients on `config.listen`.
+fn serve(config: &Config) -> DevkitResult<()> {
+    let token = config
+        .token
+        .as_deref()
+        .ok_or("serve needs a token, pass --token or set token in devkit.conf")?;
+    let port = find_port(config)?;
+    let devkit = connect(config, &port)?;
+    let listener = TcpListener::bind(&config.listen)
+        .map_err(|e| format!("unable to listen on {}: {}", config.listen, e))?;
+    println!("serving {} on {}", port, config.listen);
+    server::serve(&devkit, listener, token)
+}
+
+fn decode(file: &str) -> DevkitResult<()> {
+    for event in decode::decode(&capture::load(Path::new(file))?) {
+        println!("{}", event);
+    }
+    Ok(())
+}
+
+/// The configured port, or the only connected board.
+fn find_port(config: &Config) -> DevkitResult<String> {
+    config.select_port(&serialport::available_ports()?)
+}
+
+/// Lists the ports and reads the index of the one to use from stdin.
+fn choose_port() -> DevkitResult<String> {
+    let ports = serialport::available_ports()?;
+    if ports.is_empty() {
+        return Err("no serial ports found".into());
+    }
+    for (i, p) in ports.iter().enumerate() {
+        println!(" {}: {}", i, p.port_name);
+    }
+
+    let mut line = String::new();
+    let stdin = std::io::stdin();
+    stdin.lock().read_line(&mut line)?;
+
+    let idx: usize = line.trim().parse()?;
+    Ok(ports.get(idx).ok_or("port not found")?.port_name.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::run;
+    use crate::server::serve;
+    use crate::simulator::Simulator;
+    use crate::Devkit;
+    use std::net::TcpListener;
+    use std::process::ExitCode;
+
+    #[test]
+    fn read_command() {
+        let sim = Simulator::new(1);
+        sim.memory().lock().unwrap()[0x300..0x304].copy_from_slice(&[1, 2, 3, 4]);
+        let board = Devkit::open(Box::new(sim)).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = format!("tcp://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || serve(&board, listener, "bench-42"));
+
+        let dir = std::env::temp_dir().join(format!("devkit-cli-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = dir.join("devkit.conf");
+        std::fs::write(&config, format!("port = {}\n", port)).unwrap();
+        let out = dir.join("page.bin");
+        let run = |args: &str| {
+            let mut all = vec!["--config".to_string(), config.display().to_string()];
+            all.extend(args.split(' ').map(|a| a.to_string()));
+            run(&all)
+        };
+
+        let read = format!("--token bench-42 read 300h 4 -o {}", out.display());
+        assert_eq!(ExitCode::SUCCESS, run(&read));
+        assert_eq!(vec![1, 2, 3, 4], std::fs::read(&out).unwrap());
+        assert_eq!(ExitCode::SUCCESS, run("--token bench-42 dump 300h 4"));
+        assert_eq!(ExitCode::FAILURE, run("--token guess read 300h 4"));
+        assert_eq!(ExitCode::from(2), run("read 300h"));
+        assert_eq!(ExitCode::from(2), run("read 300h four"));
+        assert_eq!(ExitCode::from(2), run("read FFFFFFh 2"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
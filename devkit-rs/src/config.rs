@@ -1,11 +1,363 @@
+use crate::devkit::DevkitResult;
+use crate::image::Image;
+use crate::parse_number;
+use crate::protocol::{LinkOptions, BANKS, BANK_SIZE};
+use serialport::{SerialPortInfo, SerialPortType};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Name of the project and user configuration files.
+pub const FILE_NAME: &str = "devkit.conf";
+/// Raspberry Pi's USB vendor id, the boards are matched on it by default.
+pub const PICO_VID: u16 = 0x2E8A;
+/// Where `devkit serve` accepts clients by default.
+pub const DEFAULT_LISTEN: &str = "0.0.0.0:4000";
+
+/// Settings from the configuration files, overridden by the command line.
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Config {
+    /// Port to use, otherwise the only one matching the USB ids.
+    pub port: Option<String>,
+    pub usb_vid: Option<u16>,
+    pub usb_pid: Option<u16>,
+    pub usb_serial: Option<String>,
     pub serial_rate: u32,
+    pub link: LinkOptions,
+    /// Bytes of memory installed on the board, starting at 0.
+    pub memory_size: usize,
+    /// Inclusive ranges that uploads must not touch.
+    pub rom: Vec<(u32, u32)>,
+    /// Where `upload` and `verify` put binaries and assembled programs.
+    pub upload_address: u32,
+    /// File to log the serial traffic to.
+    pub capture: Option<PathBuf>,
+    /// Address `devkit serve` listens on.
+    pub listen: String,
+    /// Shared secret between `devkit serve` and its clients.
+    pub token: Option<String>,
 }
 
 impl Config {
     pub fn default() -> Self {
         Self {
+            port: None,
+            usb_vid: None,
+            usb_pid: None,
+            usb_serial: None,
             serial_rate: 115_200,
+            link: LinkOptions::default(),
+            memory_size: BANKS * BANK_SIZE,
+            rom: vec![],
+            upload_address: 0,
+            capture: None,
+            listen: DEFAULT_LISTEN.to_string(),
+            token: None,
+        }
+    }
+
+    /// The user configuration, then the project one on top of it. `file`
+    /// replaces the project configuration, which is otherwise the nearest
+    /// `devkit.conf` in the current directory or its parents. Missing files
+    /// are skipped, but not a missing `file`.
+    pub fn load(file: Option<&Path>) -> DevkitResult<Config> {
+        let mut config = Config::default();
+        if let Some(user) = user_file().filter(|f| f.is_file()) {
+            config.read(&user)?;
+        }
+        match file {
+            Some(file) => config.read(file)?,
+            None => {
+                if let Some(project) = project_file() {
+                    config.read(&project)?;
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    fn read(&mut self, file: &Path) -> DevkitResult<()> {
+        let text = std::fs::read_to_string(file)
+            .map_err(|e| format!("unable to read {}: {}", file.display(), e))?;
+        self.parse(&text)
+            .map_err(|e| format!("{}:{}", file.display(), e).into())
+    }
+
+    /// `key = value` lines, `#` outside quotes starts a comment. Strings can
+    /// be quoted and numbers are written as on the command line. Each
+    /// `rom = start end` line adds a ROM region.
+    pub fn parse(&mut self, text: &str) -> DevkitResult<()> {
+        for (i, line) in text.lines().enumerate() {
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let err = |msg: &str| format!("{}: {}", i + 1, msg);
+            let (key, val) = line
+                .split_once('=')
+                .ok_or_else(|| err("expected key = value"))?;
+            let (key, val) = (key.trim(), unquote(val.trim()));
+            let number = || parse_number(val).ok_or_else(|| err(&format!("invalid {}", key)));
+            let id = || {
+                number()?
+                    .try_into()
+                    .map_err(|_| err(&format!("{} must fit 16 bits", key)))
+            };
+
+            match key {
+                "port" => self.port = Some(val.to_string()),
+                "usb_vid" => self.usb_vid = Some(id()?),
+                "usb_pid" => self.usb_pid = Some(id()?),
+                "usb_serial" => self.usb_serial = Some(val.to_string()),
+                "baud" => self.serial_rate = number()?,
+                "reply_timeout_ms" => self.link.reply = Duration::from_millis(number()? as u64),
+                "retries" => self.link.retries = number()? as usize,
+                "window" => match number()? {
+                    0 => return Err(err("window must be at least 1").into()),
+                    n => self.link.window = n as usize,
+                },
+                "compress" => match val {
+                    "yes" | "true" | "on" => self.link.compress = true,
+                    "no" | "false" | "off" => self.link.compress = false,
+                    _ => return Err(err("compress must be yes or no").into()),
+                },
+                "memory_size" => {
+                    self.memory_size = number()? as usize;
+                    if self.memory_size > BANKS * BANK_SIZE {
+                        return Err(err(&format!("memory_size is past the {} banks", BANKS)).into());
+                    }
+                }
+                "rom" => match val.split_whitespace().collect::<Vec<_>>().as_slice() {
+                    [start, end] => match (parse_number(start), parse_number(end)) {
+                        (Some(start), Some(end)) if start <= end => self.rom.push((start, end)),
+                        _ => return Err(err("invalid rom range").into()),
+                    },
+                    _ => return Err(err("expected rom = start end").into()),
+                },
+                "upload_address" => self.upload_address = number()?,
+                "capture" => self.capture = Some(PathBuf::from(val)),
+                "listen" => self.listen = val.to_string(),
+                "token" => self.token = Some(val.to_string()),
+                _ => return Err(err(&format!("unknown key '{}'", key)).into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails if `image` doesn't fit in memory or writes to ROM.
+    pub fn check_image(&self, image: &Image) -> DevkitResult<()> {
+        if image.end() as usize > self.memory_size {
+            return Err(format!(
+                "image ends at {:06X}h, past the {:X}h bytes of memory",
+                image.end(),
+                self.memory_size
+            )
+            .into());
+        }
+        match self
+            .rom
+            .iter()
+            .find(|(start, end)| image.overlaps(*start, *end))
+        {
+            Some((start, end)) => {
+                Err(format!("image overlaps ROM at {:06X}h-{:06X}h", start, end).into())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// `port`, otherwise the only port whose USB ids match, by default any
+    /// Pico.
+    pub fn select_port(&self, ports: &[SerialPortInfo]) -> DevkitResult<String> {
+        if let Some(p) = &self.port {
+            return Ok(p.clone());
+        }
+        let matching = ports.iter().filter(|p| self.matches(p)).collect::<Vec<_>>();
+        match (matching.as_slice(), ports) {
+            ([p], _) => Ok(p.port_name.clone()),
+            ([], []) => Err("no serial ports found".into()),
+            ([], _) if self.usb_vid.is_none() && self.usb_pid.is_none() => {
+                Err("no Pico found, pass --port".into())
+            }
+            ([], _) => Err("no serial port matches the configured USB ids, pass --port".into()),
+            (many, _) => Err(format!(
+                "{} boards found ({}), pass --port or set usb_serial",
+                many.len(),
+                many.iter()
+                    .map(|p| p.port_name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+            .into()),
+        }
+    }
+
+    fn matches(&self, port: &SerialPortInfo) -> bool {
+        match &port.port_type {
+            SerialPortType::UsbPort(usb) => {
+                usb.vid == self.usb_vid.unwrap_or(PICO_VID)
+                    && self.usb_pid.is_none_or(|pid| usb.pid == pid)
+                    && self
+                        .usb_serial
+                        .as_ref()
+                        .is_none_or(|s| usb.serial_number.as_ref() == Some(s))
+            }
+            _ => false,
+        }
+    }
+}
+
+/// `line` up to the first `#` that isn't inside quotes.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn unquote(val: &str) -> &str {
+    val.strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(val)
+}
+
+/// `$XDG_CONFIG_HOME/devkit/devkit.conf` or `~/.config/devkit/devkit.conf`.
+fn user_file() -> Option<PathBuf> {
+    let env = |name| std::env::var_os(name).filter(|v| !v.is_empty());
+    let dir = match (env("XDG_CONFIG_HOME"), env("HOME")) {
+        (Some(config), _) => PathBuf::from(config),
+        (None, Some(home)) => Path::new(&home).join(".config"),
+        (None, None) => return None,
+    };
+    Some(dir.join("devkit").join(FILE_NAME))
+}
+
+fn project_file() -> Option<PathBuf> {
+    let cwd = std::env::current_dir().ok()?;
+    cwd.ancestors()
+        .map(|dir| dir.join(FILE_NAME))
+        .find(|f| f.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::image::Image;
+    use serialport::{SerialPortInfo, SerialPortType, UsbPortInfo};
+    use std::time::Duration;
+
+    fn usb(name: &str, vid: u16, pid: u16, serial: &str) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: name.to_string(),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid,
+                pid,
+                serial_number: Some(serial.to_string()),
+                manufacturer: None,
+                product: None,
+            }),
         }
     }
+
+    #[test]
+    fn parse_config() {
+        let mut config = Config::default();
+        config
+            .parse(
+                "# board on the bench\n\
+                 port = \"/dev/ttyACM1\"\n\
+                 baud=921600\n\
+                 usb_pid = 000Ah   # stdio over USB\n\
+                 reply_timeout_ms = 500\n\
+                 window = 16\n\
+                 compress = yes\n\
+                 memory_size = 0x20000\n\
+                 rom = 0 1FFFh\n\
+                 rom = 10000h 10FFFh   # boot ROM copy\n\
+                 upload_address = 8000h\n\
+                 token = \"bench 42\"\n",
+            )
+            .unwrap();
+        assert_eq!(Some("/dev/ttyACM1".to_string()), config.port);
+        assert_eq!(921_600, config.serial_rate);
+        assert_eq!(Some(0x000A), config.usb_pid);
+        assert_eq!(Duration::from_millis(500), config.link.reply);
+        assert_eq!(3, config.link.retries);
+        assert_eq!(16, config.link.window);
+        assert!(config.link.compress);
+        assert_eq!(0x20000, config.memory_size);
+        assert_eq!(vec![(0, 0x1FFF), (0x10000, 0x10FFF)], config.rom);
+        assert_eq!(0x8000, config.upload_address);
+        assert_eq!(Some("bench 42".to_string()), config.token);
+        assert_eq!("0.0.0.0:4000", config.listen);
+
+        config
+            .parse("token = \"ab#cd\"\nusb_serial = \"E6#1\" # the \"left\" one\n")
+            .unwrap();
+        assert_eq!(Some("ab#cd".to_string()), config.token);
+        assert_eq!(Some("E6#1".to_string()), config.usb_serial);
+
+        let err = |text: &str| Config::default().parse(text).unwrap_err().to_string();
+        assert_eq!("2: unknown key 'speed'", err("baud = 9600\nspeed = 1"));
+        assert_eq!("1: invalid baud", err("baud = fast"));
+        assert_eq!("1: usb_vid must fit 16 bits", err("usb_vid = 12345h"));
+        assert_eq!("1: expected key = value", err("port"));
+        assert_eq!("1: invalid rom range", err("rom = 2000h 1000h"));
+        assert_eq!("1: window must be at least 1", err("window = 0"));
+        assert_eq!("1: compress must be yes or no", err("compress = rle"));
+
+        let check = |addr, len| config.check_image(&Image::binary(vec![0; len], addr));
+        assert!(check(0x2000, 0x100).is_ok());
+        assert!(check(0x1F00, 0x101).is_err());
+        let err = check(0x1F000, 0x1001).unwrap_err().to_string();
+        assert!(err.contains("past the 20000h bytes"), "{}", err);
+        let err = check(0x10800, 1).unwrap_err().to_string();
+        assert_eq!("image overlaps ROM at 010000h-010FFFh", err);
+    }
+
+    #[test]
+    fn select_port() {
+        let mut config = Config::default();
+        let pico = usb("/dev/ttyACM0", 0x2E8A, 0x000A, "E661");
+        let other = usb("/dev/ttyUSB0", 0x0403, 0x6001, "FT1");
+        let unknown = SerialPortInfo {
+            port_name: "/dev/ttyS0".to_string(),
+            port_type: SerialPortType::Unknown,
+        };
+
+        assert_eq!(
+            "/dev/ttyACM0",
+            config.select_port(&[other.clone(), pico.clone()]).unwrap()
+        );
+        assert_eq!(
+            "no Pico found, pass --port",
+            config
+                .select_port(std::slice::from_ref(&unknown))
+                .unwrap_err()
+                .to_string()
+        );
+        assert!(config.select_port(&[]).is_err());
+        assert!(config.select_port(&[other.clone(), unknown]).is_err());
+
+        let second = usb("/dev/ttyACM1", 0x2E8A, 0x000A, "E662");
+        let err = config
+            .select_port(&[pico.clone(), second.clone()])
+            .unwrap_err();
+        assert!(err.to_string().starts_with("2 boards found"), "{}", err);
+        config.usb_serial = Some("E662".to_string());
+        assert_eq!(
+            "/dev/ttyACM1",
+            config.select_port(&[pico.clone(), second]).unwrap()
+        );
+
+        config.usb_serial = None;
+        config.usb_vid = Some(0x0403);
+        assert_eq!("/dev/ttyUSB0", config.select_port(&[pico, other]).unwrap());
+        config.port = Some("tcp://bench:2000".to_string());
+        assert_eq!("tcp://bench:2000", config.select_port(&[]).unwrap());
+    }
 }
@@ -0,0 +1,428 @@
+use crate::protocol::frame::{
+    parse, rle_decode, Frame, Parsed, CMD_HELLO, CMD_LOAD, CMD_READ, CMD_SHIFT_REGISTER, CMD_WRITE,
+    CMD_WRITE_RLE, NAK_AUTH, NAK_BOARD, NAK_CRC, NAK_INVALID_ARGUMENTS, NAK_UNKNOWN_COMMAND,
+    REPLY_ACK, REPLY_NAK,
+};
+use crate::protocol::Address;
+use crate::shadow::hash;
+use crate::transport::{Direction, Record};
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+/// One request in a capture and what the board answered.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Event {
+    /// When the request was sent.
+    pub time: Duration,
+    pub command: String,
+    /// Framed protocol only, a retry has the same one.
+    pub seq: Option<u8>,
+    pub addr: Option<Address>,
+    /// Bytes written or read.
+    pub len: usize,
+    /// FNV-1a hash of those bytes.
+    pub hash: Option<u32>,
+    pub response: String,
+    /// From the end of the request to the end of the reply.
+    pub latency: Option<Duration>,
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:10.6}", self.time.as_secs_f64())?;
+        match self.seq {
+            Some(seq) => write!(f, "  #{:02X}", seq)?,
+            None => write!(f, "     ")?,
+        }
+        write!(f, "  {:<14}", self.command)?;
+        match self.addr {
+            Some(a) if a.low == 0 => write!(f, "  bank {:02X} page {:02X}   ", a.bank, a.high)?,
+            Some(a) => write!(f, "  bank {:02X} page {:02X}+{:02X}", a.bank, a.high, a.low)?,
+            None => write!(f, "  {:18}", "")?,
+        }
+        match self.hash {
+            Some(h) => write!(f, "  {:4} bytes {:08X}", self.len, h)?,
+            None => write!(f, "  {:20}", "")?,
+        }
+        write!(f, "  {}", self.response)?;
+        if let Some(latency) = self.latency {
+            write!(f, " ({:.2} ms)", latency.as_secs_f64() * 1000.0)?;
+        }
+        Ok(())
+    }
+}
+
+/// Bytes of one direction, each with the time it was captured.
+type Stream = Vec<(u8, Duration)>;
+
+fn stream(records: &[Record], direction: Direction) -> Stream {
+    records
+        .iter()
+        .filter(|r| r.direction == direction)
+        .flat_map(|r| r.data.iter().map(move |b| (*b, r.time)))
+        .collect()
+}
+
+fn bytes(stream: &[(u8, Duration)]) -> Vec<u8> {
+    stream.iter().map(|(b, _)| *b).collect()
+}
+
+/// Frames in `stream` with the times of their first and last byte, the
+/// sequence number of a corrupt one instead. Garbage between frames is
+/// skipped.
+fn frames(stream: &[(u8, Duration)]) -> Vec<(Result<Frame, u8>, Duration, Duration)> {
+    let data = bytes(stream);
+    let mut frames = vec![];
+    let mut at = 0;
+    while at < data.len() {
+        match parse(&data[at..]) {
+            Parsed::Frame(f, len) => {
+                frames.push((Ok(f), stream[at].1, stream[at + len - 1].1));
+                at += len;
+            }
+            Parsed::Corrupt { seq, len } => {
+                frames.push((Err(seq), stream[at].1, stream[at + len - 1].1));
+                at += len;
+            }
+            Parsed::Garbage(len) => at += len,
+            Parsed::Incomplete => break,
+        }
+    }
+    frames
+}
+
+/// The requests in a capture, in the order they were sent. The capture is
+/// decoded as the framed protocol if the firmware answered the hello with a
+/// frame, as the raw protocol otherwise.
+pub fn decode(records: &[Record]) -> Vec<Event> {
+    let sent = stream(records, Direction::Sent);
+    let received = stream(records, Direction::Received);
+    let replies = frames(&received);
+    let framed = replies
+        .iter()
+        .any(|(f, _, _)| matches!(f, Ok(f) if f.seq == 0 && f.cmd == REPLY_ACK));
+    match framed {
+        true => decode_framed(&sent, replies),
+        false => decode_raw(&sent, &received),
+    }
+}
+
+fn decode_framed(
+    sent: &[(u8, Duration)],
+    replies: Vec<(Result<Frame, u8>, Duration, Duration)>,
+) -> Vec<Event> {
+    let mut used = vec![false; replies.len()];
+    let mut events = vec![];
+
+    for (request, start, end) in frames(sent) {
+        let request = match request {
+            Ok(f) => f,
+            Err(seq) => {
+                events.push(Event {
+                    time: start,
+                    command: "corrupt frame".to_string(),
+                    seq: Some(seq),
+                    addr: None,
+                    len: 0,
+                    hash: None,
+                    response: String::new(),
+                    latency: None,
+                });
+                continue;
+            }
+        };
+
+        let reply = replies.iter().enumerate().find(|(i, (r, _, t))| {
+            !used[*i] && *t >= end && matches!(r, Ok(r) if r.seq == request.seq)
+        });
+        let (reply, latency) = match reply {
+            Some((i, (Ok(r), _, t))) => {
+                used[i] = true;
+                (Some(r), Some(*t - end))
+            }
+            _ => (None, None),
+        };
+
+        let p = &request.payload;
+        let addr = (p.len() >= 3 && request.cmd != CMD_SHIFT_REGISTER && request.cmd != CMD_HELLO)
+            .then(|| Address {
+                bank: p[0],
+                high: p[1],
+                low: p[2],
+            });
+        let (command, data) = match request.cmd {
+            CMD_HELLO => ("hello".to_string(), None),
+            CMD_READ => (
+                "read".to_string(),
+                reply
+                    .filter(|r| r.cmd == REPLY_ACK)
+                    .map(|r| r.payload.clone()),
+            ),
+            CMD_WRITE => ("write".to_string(), p.get(3..).map(|d| d.to_vec())),
+            // the length and hash are of the unpacked data
+            CMD_WRITE_RLE => ("write rle".to_string(), p.get(3..).and_then(rle_decode)),
+            CMD_SHIFT_REGISTER if p.len() == 2 => (
+                format!("shift {:04X}h", u16::from_le_bytes([p[0], p[1]])),
+                None,
+            ),
+            CMD_SHIFT_REGISTER => ("shift".to_string(), None),
+            CMD_LOAD => ("load".to_string(), None),
+            c => (format!("command {:02X}h", c), None),
+        };
+        let response = match reply {
+            Some(r) if r.cmd == REPLY_ACK => "ACK".to_string(),
+            Some(r) if r.cmd == REPLY_NAK => match r.payload.first() {
+                Some(&NAK_CRC) => "NAK bad CRC".to_string(),
+                Some(&NAK_UNKNOWN_COMMAND) => "NAK unknown command".to_string(),
+                Some(&NAK_INVALID_ARGUMENTS) => "NAK invalid arguments".to_string(),
+                Some(&NAK_AUTH) => "NAK not authenticated".to_string(),
+                Some(&NAK_BOARD) => "NAK board failed".to_string(),
+                _ => "NAK".to_string(),
+            },
+            Some(r) => format!("reply {:02X}h", r.cmd),
+            None => "no reply".to_string(),
+        };
+
+        events.push(Event {
+            time: start,
+            command,
+            seq: Some(request.seq),
+            addr,
+            len: data.as_ref().map(|d| d.len()).unwrap_or(0),
+            hash: data.as_deref().map(hash),
+            response,
+            latency,
+        });
+    }
+    events
+}
+
+fn decode_raw(sent: &[(u8, Duration)], received: &[(u8, Duration)]) -> Vec<Event> {
+    let data = bytes(sent);
+    let replies = bytes(received);
+    let (mut at, mut r) = (0, 0);
+    let mut events = vec![];
+
+    while at < data.len() {
+        let args = &data[at + 1..];
+        let addr = |i: usize| Address {
+            bank: args[i],
+            high: args[i + 1],
+            low: args[i + 2],
+        };
+        let echo = |c: u8| [&b" - "[..], &[c], b"\n"].concat();
+
+        // (command, length, address, data, expected reply)
+        let (command, len, addr, written, expected) =
+            if let Parsed::Frame(f, len) = parse(&data[at..]) {
+                // the framed hello, old firmware echoes every byte of it
+                let expected = data[at..at + len].iter().flat_map(|c| echo(*c)).collect();
+                let command = match f.cmd {
+                    CMD_HELLO => "hello".to_string(),
+                    c => format!("command {:02X}h", c),
+                };
+                (command, len, None, None, expected)
+            } else {
+                match (data[at], args.len()) {
+                    (b'r', 3..) => (
+                        "r".to_string(),
+                        4,
+                        Some(addr(0)),
+                        None,
+                        b"r: '?'\n".to_vec(),
+                    ),
+                    (b'w', 4..) => (
+                        "w".to_string(),
+                        5,
+                        Some(addr(0)),
+                        Some(vec![args[3]]),
+                        b"a\n".to_vec(),
+                    ),
+                    (b'W', 258..) => (
+                        "W".to_string(),
+                        259,
+                        Some(Address {
+                            bank: args[0],
+                            high: args[1],
+                            low: 0,
+                        }),
+                        Some(args[2..258].to_vec()),
+                        b"a\n".to_vec(),
+                    ),
+                    (b'l', 3..) => ("l".to_string(), 4, Some(addr(0)), None, b"l\n".to_vec()),
+                    (b's', _) if args.contains(&b'\n') => {
+                        let line = &args[..args.iter().position(|b| *b == b'\n').unwrap()];
+                        let val = String::from_utf8_lossy(line).trim().to_string();
+                        let expected = format!("s: '{}'\n - \n\n", val).into_bytes();
+                        (format!("s {}", val), 2 + line.len(), None, None, expected)
+                    }
+                    (b'r' | b'w' | b'W' | b'l' | b's', _) => {
+                        events.push(Event {
+                            time: sent[at].1,
+                            command: format!("incomplete {}", data[at] as char),
+                            seq: None,
+                            addr: None,
+                            len: 0,
+                            hash: None,
+                            response: String::new(),
+                            latency: None,
+                        });
+                        break;
+                    }
+                    (c, _) => (format!("unknown {:02X}h", c), 1, None, None, echo(c)),
+                }
+            };
+
+        let end = sent[at + len - 1].1;
+        let reply = &replies[r.min(replies.len())..(r + expected.len()).min(replies.len())];
+        let matches = reply.len() == expected.len()
+            && reply
+                .iter()
+                .zip(&expected)
+                .all(|(a, e)| a == e || (*e == b'?' && command == "r"));
+        let response = if reply.len() < expected.len() {
+            "no reply".to_string()
+        } else if !matches {
+            format!("unexpected {:?}", String::from_utf8_lossy(reply))
+        } else if command == "r" {
+            format!("{:02X}h", reply[4])
+        } else {
+            "ok".to_string()
+        };
+        let read = (command == "r" && matches).then(|| vec![reply[4]]);
+        let latency = match reply.len() == expected.len() && !reply.is_empty() {
+            true => received[r + reply.len() - 1].1.checked_sub(end),
+            false => None,
+        };
+        r += reply.len();
+
+        let data = written.or(read);
+        events.push(Event {
+            time: sent[at].1,
+            command,
+            seq: None,
+            addr,
+            len: data.as_ref().map(|d| d.len()).unwrap_or(0),
+            hash: data.as_deref().map(hash),
+            response,
+            latency,
+        });
+        at += len;
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::decode::decode;
+    use crate::devkit::Devkit;
+    use crate::protocol::Address;
+    use crate::shadow::hash;
+    use crate::simulator::Simulator;
+    use crate::transport::capture::{load, parse, replay};
+    use crate::transport::{CaptureTransport, Direction};
+
+    /// Runs `session` against the simulated firmware and returns the
+    /// capture file.
+    fn capture(sim: Simulator, name: &str, session: impl FnOnce(&Devkit)) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("devkit-{}-{}.cap", name, std::process::id()));
+        let transport = CaptureTransport::create(&path, Box::new(sim)).unwrap();
+        let devkit = Devkit::open(Box::new(transport)).unwrap();
+        session(&devkit);
+        devkit.close().unwrap();
+        path
+    }
+
+    #[test]
+    fn decode_framed() {
+        let page = (0..=255).collect::<Vec<u8>>();
+        let path = capture(Simulator::framed(1), "framed", |devkit| {
+            devkit
+                .write(Address::from_linear(0x200), page.clone())
+                .unwrap();
+            devkit.read(Address::from_linear(0x200), 4).unwrap();
+            devkit.set_shift_register(0x1234).unwrap();
+        });
+        let records = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let events = decode(&records);
+        let summary = events
+            .iter()
+            .map(|e| (e.command.as_str(), e.seq, e.response.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                ("hello", Some(0), "ACK"),
+                ("write", Some(1), "ACK"),
+                ("read", Some(2), "ACK"),
+                ("shift 1234h", Some(3), "ACK"),
+            ],
+            summary
+        );
+        assert_eq!(Some(Address::from_linear(0x200)), events[1].addr);
+        assert_eq!((256, Some(hash(&page))), (events[1].len, events[1].hash));
+        assert_eq!(Some(hash(&[0, 1, 2, 3])), events[2].hash);
+        assert!(events.iter().all(|e| e.latency.is_some()));
+        let line = events[1].to_string();
+        assert!(
+            line.contains("write") && line.contains("bank 00 page 02"),
+            "{}",
+            line
+        );
+
+        // the same requests get the same answers from a fresh board
+        let captured = records
+            .iter()
+            .filter(|r| r.direction == Direction::Received)
+            .flat_map(|r| r.data.clone())
+            .collect::<Vec<_>>();
+        let mut sim = Simulator::framed(1);
+        assert_eq!(captured, replay(&records, &mut sim).unwrap());
+    }
+
+    #[test]
+    fn decode_raw() {
+        let path = capture(Simulator::new(1), "raw", |devkit| {
+            devkit
+                .write(Address::from_linear(0x100), vec![7; 256])
+                .unwrap();
+            devkit
+                .write(Address::from_linear(0x1234), vec![0xAB])
+                .unwrap();
+            devkit.read(Address::from_linear(0x1234), 1).unwrap();
+            devkit.set_shift_register(300).unwrap();
+        });
+        let records = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let events = decode(&records);
+        let summary = events
+            .iter()
+            .map(|e| (e.command.as_str(), e.response.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                ("hello", "ok"),
+                ("W", "ok"),
+                ("w", "ok"),
+                ("r", "ABh"),
+                ("s 300", "ok"),
+            ],
+            summary
+        );
+        assert_eq!(Some(Address::from_linear(0x1234)), events[2].addr);
+        assert_eq!(Some(hash(&[7; 256])), events[1].hash);
+    }
+
+    #[test]
+    fn damaged_capture() {
+        for time in ["-1", "inf", "NaN", "1e30"] {
+            let text = format!("# devkit capture\n0.5 > A5\n{} < 06\n", time);
+            assert_eq!(
+                "line 3: invalid record",
+                parse(&text).unwrap_err().to_string()
+            );
+        }
+    }
+}
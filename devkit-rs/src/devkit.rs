@@ -0,0 +1,354 @@
+use crate::protocol;
+use crate::protocol::{Address, LinkOptions, Protocol};
+use crate::transport;
+use crate::transport::Transport;
+use crate::worker::{worker, Job, Status};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+pub type DevkitResult<T> = Result<T, Box<dyn Error + Send + Sync + 'static>>;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Request {
+    Read(Address, usize),
+    /// Whole pages are written with one command each, the bytes before and
+    /// after them with byte writes.
+    Write(Address, Vec<u8>),
+    SetShiftRegister(u16),
+    /// Strobes a read at the address without reading the data.
+    Load(Address),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Response {
+    Done,
+    Data(Vec<u8>),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ConnectionState {
+    Connected,
+    /// The transport failed, every request from now on gets
+    /// `RequestError::Disconnected`.
+    Disconnected(String),
+    Closed,
+}
+
+#[derive(Debug)]
+pub enum RequestError {
+    Cancelled,
+    Disconnected(String),
+    /// The board answered wrongly or not at all, the connection is still
+    /// usable.
+    Failed(Box<dyn Error + Send + Sync + 'static>),
+}
+
+impl Display for RequestError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RequestError::Cancelled => write!(f, "request cancelled"),
+            RequestError::Disconnected(reason) => write!(f, "disconnected: {}", reason),
+            RequestError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for RequestError {}
+
+/// Stops a request between two pages, can be shared with other threads.
+#[derive(Clone, Default)]
+pub struct Cancel(Arc<AtomicBool>);
+
+impl Cancel {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A request that was sent to the board.
+pub struct Pending {
+    reply: Receiver<Result<Response, RequestError>>,
+    cancel: Cancel,
+}
+
+impl Pending {
+    pub fn wait(self) -> Result<Response, RequestError> {
+        self.reply
+            .recv()
+            .unwrap_or_else(|_| Err(RequestError::Disconnected("worker stopped".into())))
+    }
+
+    /// `None` if there is no response yet, the request keeps running.
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<Result<Response, RequestError>> {
+        match self.reply.recv_timeout(timeout) {
+            Ok(r) => Some(r),
+            Err(mpsc::RecvTimeoutError::Timeout) => None,
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                Some(Err(RequestError::Disconnected("worker stopped".into())))
+            }
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancel.cancel()
+    }
+
+    pub fn canceller(&self) -> Cancel {
+        self.cancel.clone()
+    }
+}
+
+/// Connection to the board. The transport is owned by a worker thread that
+/// runs the requests in order, each one is answered with its own result.
+pub struct Devkit {
+    handle: Option<JoinHandle<()>>,
+    jobs: Sender<Job>,
+    status: Arc<Mutex<Status>>,
+}
+
+impl Devkit {
+    pub fn connect(port: &str, baud: u32) -> DevkitResult<Devkit> {
+        Devkit::connect_with(port, baud, LinkOptions::default())
+    }
+
+    pub fn connect_with(port: &str, baud: u32, options: LinkOptions) -> DevkitResult<Devkit> {
+        Devkit::open_with(transport::open(port, baud, None)?, options)
+    }
+
+    /// Agrees on the protocol with the firmware and starts the worker.
+    pub fn open(transport: Box<dyn Transport>) -> DevkitResult<Devkit> {
+        Devkit::open_with(transport, LinkOptions::default())
+    }
+
+    pub fn open_with(transport: Box<dyn Transport>, options: LinkOptions) -> DevkitResult<Devkit> {
+        Ok(Devkit::start(protocol::negotiate(transport, options)?))
+    }
+
+    pub fn start(protocol: Box<dyn Protocol>) -> Devkit {
+        let (jobs, rx) = mpsc::channel();
+        let status = Arc::new(Mutex::new(Status {
+            state: ConnectionState::Connected,
+            listeners: vec![],
+        }));
+        let worker_status = status.clone();
+        let handle = thread::spawn(move || worker(protocol, rx, worker_status));
+
+        Devkit {
+            handle: Some(handle),
+            jobs,
+            status,
+        }
+    }
+
+    pub fn request(&self, request: Request) -> Pending {
+        let (reply, rx) = mpsc::channel();
+        let cancel = Cancel::default();
+        let job = Job {
+            request,
+            cancel: cancel.clone(),
+            reply,
+        };
+        if let Err(mpsc::SendError(job)) = self.jobs.send(job) {
+            let _ = job
+                .reply
+                .send(Err(RequestError::Disconnected("worker stopped".into())));
+        }
+        Pending { reply: rx, cancel }
+    }
+
+    pub fn read(&self, addr: Address, len: usize) -> Result<Vec<u8>, RequestError> {
+        match self.request(Request::Read(addr, len)).wait()? {
+            Response::Data(data) => Ok(data),
+            r => Err(RequestError::Failed(
+                format!("unexpected response {:?}", r).into(),
+            )),
+        }
+    }
+
+    pub fn write(&self, addr: Address, data: Vec<u8>) -> Result<(), RequestError> {
+        self.request(Request::Write(addr, data)).wait()?;
+        Ok(())
+    }
+
+    pub fn set_shift_register(&self, val: u16) -> Result<(), RequestError> {
+        self.request(Request::SetShiftRegister(val)).wait()?;
+        Ok(())
+    }
+
+    pub fn load(&self, addr: Address) -> Result<(), RequestError> {
+        self.request(Request::Load(addr)).wait()?;
+        Ok(())
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.status.lock().unwrap().state.clone()
+    }
+
+    /// Receives every state change from now on, starting with the current
+    /// state.
+    pub fn events(&self) -> Receiver<ConnectionState> {
+        let (tx, rx) = mpsc::channel();
+        let mut status = self.status.lock().unwrap();
+        let _ = tx.send(status.state.clone());
+        status.listeners.push(tx);
+        rx
+    }
+
+    /// Waits for the requests already sent and stops the worker.
+    pub fn close(mut self) -> DevkitResult<()> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> DevkitResult<()> {
+        // the worker ends when the channel is closed
+        let (jobs, _) = mpsc::channel();
+        drop(std::mem::replace(&mut self.jobs, jobs));
+        match self.handle.take() {
+            Some(h) => h.join().map_err(|_| "the devkit worker panicked".into()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Devkit {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::devkit::{ConnectionState, Devkit, Request, RequestError, Response};
+    use crate::protocol::{Address, Protocol};
+    use std::error::Error;
+    use std::io::ErrorKind;
+    use std::sync::mpsc;
+    use std::sync::mpsc::{Receiver, Sender};
+
+    /// Every read signals `started` and waits for `go`, which says whether
+    /// it fails and how.
+    struct Gated {
+        started: Sender<()>,
+        go: Receiver<Option<ErrorKind>>,
+    }
+
+    impl Protocol for Gated {
+        fn read(
+            &mut self,
+            _addr: Address,
+            len: usize,
+        ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync + 'static>> {
+            self.started.send(()).unwrap();
+            match self.go.recv().unwrap() {
+                Some(kind) => Err(std::io::Error::from(kind).into()),
+                None => Ok(vec![0xAA; len]),
+            }
+        }
+
+        fn write_page(
+            &mut self,
+            _page: Address,
+            _data: &[u8],
+        ) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+            Ok(())
+        }
+
+        fn write(
+            &mut self,
+            _addr: Address,
+            _data: &[u8],
+        ) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+            Err("no byte writes".into())
+        }
+
+        fn set_shift_register(
+            &mut self,
+            _val: u16,
+        ) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+            Ok(())
+        }
+
+        fn load(&mut self, _addr: Address) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+            Ok(())
+        }
+    }
+
+    fn gated() -> (Devkit, Receiver<()>, Sender<Option<ErrorKind>>) {
+        let (started, started_rx) = mpsc::channel();
+        let (go, go_rx) = mpsc::channel();
+        let devkit = Devkit::start(Box::new(Gated { started, go: go_rx }));
+        (devkit, started_rx, go)
+    }
+
+    #[test]
+    fn requests() {
+        let (devkit, started, go) = gated();
+        let events = devkit.events();
+        assert_eq!(ConnectionState::Connected, events.recv().unwrap());
+
+        go.send(None).unwrap();
+        assert_eq!(
+            vec![0xAA; 3],
+            devkit.read(Address::from_linear(0), 3).unwrap()
+        );
+        started.recv().unwrap();
+
+        // cancelled between the first and the second chunk
+        let pending = devkit.request(Request::Read(Address::from_linear(0), 1000));
+        started.recv().unwrap();
+        pending.cancel();
+        go.send(None).unwrap();
+        assert!(matches!(pending.wait(), Err(RequestError::Cancelled)));
+
+        // a timeout is only this request's problem
+        go.send(Some(ErrorKind::TimedOut)).unwrap();
+        let err = devkit.read(Address::from_linear(0), 1).unwrap_err();
+        assert!(matches!(err, RequestError::Failed(_)), "{}", err);
+        started.recv().unwrap();
+        assert_eq!(ConnectionState::Connected, devkit.state());
+
+        let partial = devkit.request(Request::Write(Address::from_linear(1), vec![0; 256]));
+        assert!(matches!(partial.wait(), Err(RequestError::Failed(_))));
+        assert_eq!(
+            Response::Done,
+            devkit
+                .request(Request::Write(Address::from_linear(256), vec![0; 512]))
+                .wait()
+                .unwrap()
+        );
+
+        // a broken port is the end of the connection
+        go.send(Some(ErrorKind::BrokenPipe)).unwrap();
+        devkit.read(Address::from_linear(0), 1).unwrap_err();
+        assert!(matches!(
+            events.recv().unwrap(),
+            ConnectionState::Disconnected(_)
+        ));
+        assert!(matches!(
+            devkit.read(Address::from_linear(0), 1),
+            Err(RequestError::Disconnected(_))
+        ));
+        devkit.close().unwrap();
+    }
+
+    #[test]
+    fn close() {
+        let (devkit, _started, _go) = gated();
+        let events = devkit.events();
+        devkit.close().unwrap();
+        assert_eq!(ConnectionState::Connected, events.recv().unwrap());
+        assert_eq!(ConnectionState::Closed, events.recv().unwrap());
+        assert!(events.recv().is_err());
+    }
+}
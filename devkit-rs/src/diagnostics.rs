@@ -0,0 +1,264 @@
+use crate::assemble::SourceMap;
+use crate::devkit::{Devkit, DevkitResult};
+use crate::protocol::Address;
+
+/// A byte that didn't read back as written.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Fault {
+    pub addr: u32,
+    pub expected: u8,
+    pub actual: u8,
+    /// Test step that found it.
+    pub step: String,
+}
+
+/// At most this many faulty addresses are listed by `report`.
+const LISTED: usize = 16;
+
+fn read_byte(devkit: &Devkit, addr: u32) -> DevkitResult<u8> {
+    Ok(devkit.read(Address::from_linear(addr), 1)?[0])
+}
+
+fn write_byte(devkit: &Devkit, addr: u32, val: u8) -> DevkitResult<()> {
+    Ok(devkit.write(Address::from_linear(addr), vec![val])?)
+}
+
+fn check(
+    faults: &mut Vec<Fault>,
+    devkit: &Devkit,
+    addr: u32,
+    expected: u8,
+    step: &str,
+) -> DevkitResult<()> {
+    let actual = read_byte(devkit, addr)?;
+    if actual != expected {
+        faults.push(Fault {
+            addr,
+            expected,
+            actual,
+            step: step.to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Walking ones then walking zeros at `addr`, finds data lines stuck or
+/// shorted together.
+pub fn data_bus(devkit: &Devkit, addr: u32) -> DevkitResult<Vec<Fault>> {
+    let mut faults = vec![];
+    for bit in 0..8 {
+        for (pattern, step) in [
+            (1u8 << bit, "walking ones"),
+            (!(1u8 << bit), "walking zeros"),
+        ] {
+            write_byte(devkit, addr, pattern)?;
+            check(&mut faults, devkit, addr, pattern, step)?;
+        }
+    }
+    Ok(faults)
+}
+
+/// Writes to `start` and to `start + 2^n` for every address line inside
+/// `len`, and checks none of them aliases another. A line that is stuck or
+/// open makes its address read what was written to `start`, and one shorted
+/// to another line makes a write show up at a second address. `start` should
+/// be aligned to the size of the range.
+pub fn address_bus(devkit: &Devkit, start: u32, len: u32) -> DevkitResult<Vec<Fault>> {
+    const PATTERN: u8 = 0xAA;
+    const ANTIPATTERN: u8 = 0x55;
+    let lines = (0..32).filter(|l| 1u32 << l < len).collect::<Vec<_>>();
+    let mut faults = vec![];
+
+    for l in &lines {
+        write_byte(devkit, start + (1 << l), PATTERN)?;
+    }
+    write_byte(devkit, start, ANTIPATTERN)?;
+    for l in &lines {
+        let step = format!("A{} stuck", l);
+        check(&mut faults, devkit, start + (1 << l), PATTERN, &step)?;
+    }
+    write_byte(devkit, start, PATTERN)?;
+
+    for l in &lines {
+        write_byte(devkit, start + (1 << l), ANTIPATTERN)?;
+        let step = format!("A{} stuck", l);
+        check(&mut faults, devkit, start, PATTERN, &step)?;
+        for other in lines.iter().filter(|o| *o != l) {
+            let step = format!("A{} shorted to A{}", l, other);
+            check(&mut faults, devkit, start + (1 << other), PATTERN, &step)?;
+        }
+        write_byte(devkit, start + (1 << l), PATTERN)?;
+    }
+    Ok(faults)
+}
+
+/// March C- over `len` bytes from `start`:
+///
+/// ```text
+/// ⇕(w0) ⇑(r0,w1) ⇑(r1,w0) ⇓(r0,w1) ⇓(r1,w0) ⇕(r0)
+/// ```
+///
+/// with 00h and FFh as the two values. Reading a byte at a time over the
+/// serial port would take hours, so each element runs on whole pages: a page
+/// is read and checked, then written. That still finds stuck bits, address
+/// decoder faults and coupling between pages, but not every coupling inside
+/// a page. `progress` gets the name of each element as it starts.
+pub fn march(
+    devkit: &Devkit,
+    start: u32,
+    len: u32,
+    mut progress: impl FnMut(&str),
+) -> DevkitResult<Vec<Fault>> {
+    // (name, ascending, value to read, value to write)
+    let elements: [(&str, bool, Option<u8>, Option<u8>); 6] = [
+        ("w0", true, None, Some(0x00)),
+        ("up r0,w1", true, Some(0x00), Some(0xFF)),
+        ("up r1,w0", true, Some(0xFF), Some(0x00)),
+        ("down r0,w1", false, Some(0x00), Some(0xFF)),
+        ("down r1,w0", false, Some(0xFF), Some(0x00)),
+        ("r0", true, Some(0x00), None),
+    ];
+
+    let mut blocks = vec![];
+    let mut at = start;
+    while at < start + len {
+        let next = ((at / 256 + 1) * 256).min(start + len);
+        blocks.push((at, (next - at) as usize));
+        at = next;
+    }
+
+    let mut faults = vec![];
+    for (name, ascending, read, write) in elements {
+        progress(name);
+        let order: Box<dyn Iterator<Item = &(u32, usize)>> = match ascending {
+            true => Box::new(blocks.iter()),
+            false => Box::new(blocks.iter().rev()),
+        };
+        for (addr, len) in order {
+            if let Some(expected) = read {
+                let data = devkit.read(Address::from_linear(*addr), *len)?;
+                for (i, actual) in data.into_iter().enumerate() {
+                    if actual != expected {
+                        faults.push(Fault {
+                            addr: addr + i as u32,
+                            expected,
+                            actual,
+                            step: name.to_string(),
+                        });
+                    }
+                }
+            }
+            if let Some(val) = write {
+                devkit.write(Address::from_linear(*addr), vec![val; *len])?;
+            }
+        }
+    }
+    Ok(faults)
+}
+
+/// Summary of the faults: a data bit that read the same wrong value at every
+/// one of the `tested` addresses is stuck, the rest is listed address by
+/// address, with the source line of the program there if there is a
+/// `source_map`.
+pub fn report(faults: &[Fault], tested: usize, source_map: Option<&SourceMap>) -> String {
+    if faults.is_empty() {
+        return "no faults found\n".to_string();
+    }
+
+    let mut out = String::new();
+    let mut stuck = 0u8;
+    for bit in 0..8 {
+        // addresses where the bit read as 0 and as 1 when it shouldn't have
+        let wrong = |val: u8| {
+            let mut addrs = faults
+                .iter()
+                .filter(|f| (f.actual >> bit) & 1 == val && (f.expected >> bit) & 1 != val)
+                .map(|f| f.addr)
+                .collect::<Vec<_>>();
+            addrs.sort();
+            addrs.dedup();
+            addrs.len()
+        };
+        let (low, high) = (wrong(0), wrong(1));
+        if tested > 1 && (low == 0 || high == 0) && low.max(high) >= tested {
+            out += &format!("D{} stuck at {}\n", bit, if high > 0 { 1 } else { 0 });
+            stuck |= 1 << bit;
+        }
+    }
+
+    let rest = faults
+        .iter()
+        .filter(|f| (f.expected ^ f.actual) & !stuck != 0)
+        .collect::<Vec<_>>();
+    for f in rest.iter().take(LISTED) {
+        out += &format!(
+            "{:06X}h: expected {:02X}h, read {:02X}h ({})",
+            f.addr, f.expected, f.actual, f.step
+        );
+        if let Some(location) = source_map.and_then(|m| m.locate(f.addr)) {
+            out += &format!(", {}", location);
+        }
+        out.push('\n');
+    }
+    if rest.len() > LISTED {
+        out += &format!("... {} more\n", rest.len() - LISTED);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assemble::SourceMap;
+    use crate::devkit::Devkit;
+    use crate::diagnostics::{address_bus, data_bus, march, report};
+    use crate::simulator::Simulator;
+    use z80_assembler::DebugInfo;
+
+    fn board(setup: impl FnOnce(&mut Simulator)) -> Devkit {
+        let mut sim = Simulator::framed(1);
+        setup(&mut sim);
+        Devkit::open(Box::new(sim)).unwrap()
+    }
+
+    #[test]
+    fn healthy_board() {
+        let devkit = board(|_| {});
+        assert!(data_bus(&devkit, 0x1234).unwrap().is_empty());
+        assert!(address_bus(&devkit, 0, 0x10000).unwrap().is_empty());
+        let mut elements = vec![];
+        let faults = march(&devkit, 0x80, 0x300, |e| elements.push(e.to_string())).unwrap();
+        assert!(faults.is_empty());
+        assert_eq!(6, elements.len());
+        assert_eq!("no faults found\n", report(&faults, 0x300, None));
+    }
+
+    #[test]
+    fn faulty_board() {
+        let devkit = board(|sim| sim.stuck_high = 0x08);
+        let faults = data_bus(&devkit, 0).unwrap();
+        assert!(faults.iter().all(|f| f.actual == f.expected | 0x08));
+        let faults = march(&devkit, 0, 0x200, |_| {}).unwrap();
+        assert_eq!("D3 stuck at 1\n", report(&faults, 0x200, None));
+
+        // A8 open: odd pages alias the even ones
+        let devkit = board(|sim| sim.open_address_lines = 0x100);
+        let faults = address_bus(&devkit, 0, 0x10000).unwrap();
+        assert!(!faults.is_empty());
+        assert!(faults.iter().all(|f| f.step.starts_with("A8")));
+        let faults = march(&devkit, 0, 0x200, |_| {}).unwrap();
+        let r = report(&faults, 0x200, None);
+        assert!(
+            r.starts_with("000100h: expected 00h, read FFh (up r0,w1)\n"),
+            "{}",
+            r
+        );
+        let info = DebugInfo::from_sidecar("z80dbg 1\nfile 0 main.z80\ncode 0100h 1 0:7:1 loop\n")
+            .unwrap();
+        let r = report(&faults, 0x200, Some(&SourceMap::new(info, 0)));
+        assert!(
+            r.starts_with("000100h: expected 00h, read FFh (up r0,w1), main.z80:7:1 (loop)\n"),
+            "{}",
+            r
+        );
+    }
+}
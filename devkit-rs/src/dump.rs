@@ -0,0 +1,126 @@
+use crate::assemble::SourceMap;
+use std::fmt::Write;
+
+/// 16 bytes per row, `8000h  12 34 ...  |ascii|`.
+pub fn hexdump(addr: u32, data: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, row) in data.chunks(16).enumerate() {
+        let hex = row
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let ascii = row
+            .iter()
+            .map(|b| {
+                if b.is_ascii_graphic() || *b == b' ' {
+                    *b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect::<String>();
+        writeln!(
+            out,
+            "{:04X}h  {:<47}  |{}|",
+            addr + i as u32 * 16,
+            hex,
+            ascii
+        )
+        .unwrap();
+    }
+    out
+}
+
+/// Inclusive address ranges where `actual`, read back from `addr`, differs
+/// from `expected`. Missing bytes count as different.
+pub fn differences(addr: u32, expected: &[u8], actual: &[u8]) -> Vec<(u32, u32)> {
+    let mut ranges: Vec<(u32, u32)> = vec![];
+    for (i, b) in expected.iter().enumerate() {
+        if actual.get(i) == Some(b) {
+            continue;
+        }
+        let a = addr + i as u32;
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == a => *end = a,
+            _ => ranges.push((a, a)),
+        }
+    }
+    ranges
+}
+
+/// Prints the differing ranges, with the source line each starts in when
+/// there is a `source_map`, returns the number of differing bytes.
+pub fn report_differences(
+    addr: u32,
+    expected: &[u8],
+    actual: &[u8],
+    source_map: Option<&SourceMap>,
+) -> usize {
+    let (report, count) = describe_differences(addr, expected, actual, source_map);
+    print!("{}", report);
+    count
+}
+
+fn describe_differences(
+    addr: u32,
+    expected: &[u8],
+    actual: &[u8],
+    source_map: Option<&SourceMap>,
+) -> (String, usize) {
+    let mut out = String::new();
+    let mut count = 0;
+    for (start, end) in differences(addr, expected, actual) {
+        let offset = (start - addr) as usize;
+        let len = (end - start) as usize + 1;
+        count += len;
+        if len == 1 {
+            write!(
+                out,
+                "{:04X}h: expected {:02X}h, read {}",
+                start,
+                expected[offset],
+                actual
+                    .get(offset)
+                    .map(|b| format!("{:02X}h", b))
+                    .unwrap_or_else(|| "nothing".to_string())
+            )
+            .unwrap();
+        } else {
+            write!(out, "{:04X}h-{:04X}h: {} bytes differ", start, end, len).unwrap();
+        }
+        match source_map.and_then(|m| m.locate(start)) {
+            Some(location) => writeln!(out, ", {}", location).unwrap(),
+            None => out.push('\n'),
+        }
+    }
+    (out, count)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assemble::SourceMap;
+    use crate::dump::describe_differences;
+    use z80_assembler::DebugInfo;
+
+    #[test]
+    fn source_locations() {
+        let info = DebugInfo::from_sidecar(
+            "z80dbg 1\nfile 0 main.z80\ncode 0000h 2 0:1:1 start\ndata 0002h 3 0:3:5 -\n",
+        )
+        .unwrap();
+        let source_map = SourceMap::new(info, 0x100);
+
+        let expected = [0x3E, 0x12, 0x41, 0x42, 0x43, 0x00];
+        let actual = [0x3E, 0x13, 0x41, 0x00, 0x00];
+        let (report, count) = describe_differences(0x100, &expected, &actual, Some(&source_map));
+        assert_eq!(4, count);
+        assert_eq!(
+            "0101h: expected 12h, read 13h, main.z80:1:1 (start)\n\
+             0103h-0105h: 3 bytes differ, main.z80:3:5\n",
+            report
+        );
+        let (report, _) = describe_differences(0x100, &expected, &actual, None);
+        assert!(report.starts_with("0101h: expected 12h, read 13h\n"));
+    }
+}
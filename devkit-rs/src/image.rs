@@ -0,0 +1,321 @@
+use crate::devkit::DevkitResult;
+
+/// Bytes to write starting at `addr`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Segment {
+    pub addr: u32,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    pub fn end(&self) -> u32 {
+        self.addr + self.data.len() as u32
+    }
+}
+
+/// What an upload writes: only the defined ranges, each at its address.
+/// Segments are kept in address order, adjacent ones are merged.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Image {
+    segments: Vec<Segment>,
+}
+
+impl Image {
+    /// Fails if two segments overlap or one runs past FFFFFFFFh.
+    pub fn new(mut segments: Vec<Segment>) -> DevkitResult<Image> {
+        segments.retain(|s| !s.data.is_empty());
+        if let Some(s) = segments.iter().find(|s| {
+            u32::try_from(s.data.len())
+                .ok()
+                .and_then(|len| s.addr.checked_add(len))
+                .is_none()
+        }) {
+            return Err(format!("data at {:06X}h runs past the end of memory", s.addr).into());
+        }
+        segments.sort_by_key(|s| s.addr);
+
+        let mut merged: Vec<Segment> = vec![];
+        for s in segments {
+            match merged.last_mut() {
+                Some(last) if s.addr < last.end() => {
+                    return Err(format!("data defined twice at {:06X}h", s.addr).into())
+                }
+                Some(last) if s.addr == last.end() => last.data.extend(s.data),
+                _ => merged.push(s),
+            }
+        }
+        Ok(Image { segments: merged })
+    }
+
+    /// A raw binary loaded at `addr`, it must end below FFFFFFFFh.
+    pub fn binary(data: Vec<u8>, addr: u32) -> Image {
+        Image::new(vec![Segment { addr, data }]).unwrap()
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// First address past the image.
+    pub fn end(&self) -> u32 {
+        self.segments.last().map(|s| s.end()).unwrap_or(0)
+    }
+
+    /// Bytes the image defines.
+    pub fn len(&self) -> usize {
+        self.segments.iter().map(|s| s.data.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// True if any byte in `start..=end` is defined.
+    pub fn overlaps(&self, start: u32, end: u32) -> bool {
+        self.segments
+            .iter()
+            .any(|s| s.addr <= end && start < s.end())
+    }
+
+    /// True if every byte in `start..=end` is defined.
+    pub fn covers(&self, start: u32, end: u32) -> bool {
+        self.segments
+            .iter()
+            .any(|s| s.addr <= start && end < s.end())
+    }
+
+    /// The `len` bytes from `start`, if the image defines all of them.
+    pub fn get(&self, start: u32, len: usize) -> Option<&[u8]> {
+        let s = self
+            .segments
+            .iter()
+            .find(|s| s.addr <= start && start as usize + len <= s.end() as usize)?;
+        let offset = (start - s.addr) as usize;
+        Some(&s.data[offset..offset + len])
+    }
+
+    /// Defines the bytes of `segment`, replacing what the image held there.
+    pub fn overwrite(&mut self, segment: Segment) {
+        let mut segments = vec![];
+        for s in std::mem::take(&mut self.segments) {
+            if s.addr < segment.addr {
+                let len = (s.end().min(segment.addr) - s.addr) as usize;
+                segments.push(Segment {
+                    addr: s.addr,
+                    data: s.data[..len].to_vec(),
+                });
+            }
+            if s.end() > segment.end() {
+                let start = s.addr.max(segment.end());
+                segments.push(Segment {
+                    addr: start,
+                    data: s.data[(start - s.addr) as usize..].to_vec(),
+                });
+            }
+        }
+        segments.push(segment);
+        *self = Image::new(segments).unwrap();
+    }
+
+    /// Intel HEX: data, end of file, extended segment and extended linear
+    /// address records. Start addresses are ignored.
+    pub fn parse_hex(text: &str) -> DevkitResult<Image> {
+        let mut segments = vec![];
+        let mut base = 0u32;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let err = |msg: &str| format!("line {}: {}", i + 1, msg);
+            let bytes = line
+                .strip_prefix(':')
+                .and_then(hex_bytes)
+                .filter(|b| b.len() >= 5 && b.len() == 5 + b[0] as usize)
+                .ok_or_else(|| err("invalid record"))?;
+            if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+                return Err(err("bad checksum").into());
+            }
+
+            let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+            let data = &bytes[4..bytes.len() - 1];
+            match (bytes[3], data) {
+                (0x00, _) => segments.push(Segment {
+                    addr: base + offset,
+                    data: data.to_vec(),
+                }),
+                (0x01, _) => break,
+                (0x02, [high, low]) => base = u16::from_be_bytes([*high, *low]) as u32 * 16,
+                (0x04, [high, low]) => base = (u16::from_be_bytes([*high, *low]) as u32) << 16,
+                (0x03, _) | (0x05, _) => {}
+                (t, _) => return Err(err(&format!("unsupported record type {:02X}h", t)).into()),
+            }
+        }
+        Image::new(segments)
+    }
+
+    /// Motorola S-records: S1, S2 and S3 data records. Headers, counts and
+    /// start addresses are ignored.
+    pub fn parse_srec(text: &str) -> DevkitResult<Image> {
+        let mut segments = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let err = |msg: &str| format!("line {}: {}", i + 1, msg);
+            let (kind, bytes) = match line.strip_prefix('S').filter(|l| l.is_ascii()) {
+                Some(l) if !l.is_empty() => (&l[..1], hex_bytes(&l[1..])),
+                _ => ("", None),
+            };
+            let bytes = bytes
+                .filter(|b| b.len() >= 2 && b.len() == 1 + b[0] as usize)
+                .ok_or_else(|| err("invalid record"))?;
+            if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0xFF {
+                return Err(err("bad checksum").into());
+            }
+
+            let addr_len = match kind {
+                "1" => 2,
+                "2" => 3,
+                "3" => 4,
+                "0" | "5" | "6" | "7" | "8" | "9" => continue,
+                _ => return Err(err(&format!("unsupported record S{}", kind)).into()),
+            };
+            if bytes.len() < 2 + addr_len {
+                return Err(err("invalid record").into());
+            }
+            let addr = bytes[1..1 + addr_len]
+                .iter()
+                .fold(0u32, |a, b| a << 8 | *b as u32);
+            segments.push(Segment {
+                addr,
+                data: bytes[1 + addr_len..bytes.len() - 1].to_vec(),
+            });
+        }
+        Image::new(segments)
+    }
+}
+
+fn hex_bytes(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::image::{Image, Segment};
+
+    #[test]
+    fn parse_hex() {
+        let image = Image::parse_hex(
+            ":0300300002337A1E\n\
+             :02003300ABCD53\n\
+             :020000040001F9\n\
+             :01800000552A\n\
+             :00000001FF\n\
+             :0100000012ED\n",
+        )
+        .unwrap();
+        assert_eq!(
+            &[
+                Segment {
+                    addr: 0x30,
+                    data: vec![0x02, 0x33, 0x7A, 0xAB, 0xCD]
+                },
+                Segment {
+                    addr: 0x18000,
+                    data: vec![0x55]
+                },
+            ],
+            image.segments()
+        );
+        assert_eq!(6, image.len());
+        assert_eq!(0x18001, image.end());
+        assert!(image.overlaps(0x34, 0x100));
+        assert!(!image.overlaps(0x35, 0x17FFF));
+        assert!(image.covers(0x30, 0x34));
+        assert!(!image.covers(0x30, 0x35));
+
+        let err = |text: &str| Image::parse_hex(text).unwrap_err().to_string();
+        assert_eq!("line 1: bad checksum", err(":0300300002337A1F"));
+        assert_eq!("line 2: invalid record", err(":0100000012ED\n0300"));
+        assert_eq!(
+            "data defined twice at 000031h",
+            err(":0300300002337A1E\n:01003100AA24")
+        );
+    }
+
+    #[test]
+    fn parse_srec() {
+        let image = Image::parse_srec(
+            "S00600004844521B\n\
+             S10601001234565C\n\
+             S20601FFFF0102F7\n\
+             S5030002FA\n\
+             S9030000FC\n",
+        )
+        .unwrap();
+        assert_eq!(
+            &[
+                Segment {
+                    addr: 0x100,
+                    data: vec![0x12, 0x34, 0x56]
+                },
+                Segment {
+                    addr: 0x1FFFF,
+                    data: vec![0x01, 0x02]
+                },
+            ],
+            image.segments()
+        );
+
+        let err = |text: &str| Image::parse_srec(text).unwrap_err().to_string();
+        assert_eq!("line 1: bad checksum", err("S1060100123456FC"));
+        assert_eq!("line 1: unsupported record S4", err("S4030000FC"));
+        assert_eq!("line 1: invalid record", err(":0300300002337A1E"));
+        assert_eq!(
+            "data at FFFFFFFEh runs past the end of memory",
+            err("S308FFFFFFFE010203F6")
+        );
+    }
+
+    #[test]
+    fn overwrite() {
+        let mut image = Image::binary(vec![1, 2, 3, 4], 0x10);
+        image.overwrite(Segment {
+            addr: 0x20,
+            data: vec![9],
+        });
+        image.overwrite(Segment {
+            addr: 0x11,
+            data: vec![5, 6],
+        });
+        image.overwrite(Segment {
+            addr: 0x0E,
+            data: vec![7, 8, 0],
+        });
+        assert_eq!(
+            &[
+                Segment {
+                    addr: 0x0E,
+                    data: vec![7, 8, 0, 5, 6, 4]
+                },
+                Segment {
+                    addr: 0x20,
+                    data: vec![9]
+                },
+            ],
+            image.segments()
+        );
+        assert_eq!(Some(&[0, 5][..]), image.get(0x10, 2));
+        assert_eq!(Some(&[][..]), image.get(0x20, 0));
+        assert_eq!(None, image.get(0x13, 2));
+        assert_eq!(None, image.get(0x0D, 1));
+    }
+}
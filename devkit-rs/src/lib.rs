@@ -1,154 +1,527 @@
+mod assemble;
+mod cli;
 mod config;
-mod devkit_thread;
+mod decode;
+mod devkit;
+mod diagnostics;
+mod dump;
+mod image;
+mod progress;
 mod protocol;
+mod server;
+mod shadow;
+mod shell;
+pub mod simulator;
+pub mod transport;
+mod worker;
 
-use crate::config::Config;
-use crate::devkit_thread::DevkitCommand::ConnectSerial;
-use crate::devkit_thread::{devkit_thread, DevkitCommand, DevkitResponse};
-use serialport;
-use std::error::Error;
-use std::fmt::{Display, Formatter};
-use std::io::BufRead;
-use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, Sender};
-use std::thread;
-use std::thread::JoinHandle;
-use std::time::Duration;
-
-pub fn run() {
-    let (tx, rx) = mpsc::channel();
-    let (res_tx, res_rx) = mpsc::channel();
-    let handle = thread::spawn(move || devkit_thread(rx, res_tx));
-    control_loop(handle, tx, res_rx).expect("something broke :(");
-}
+pub use crate::cli::run;
+pub use crate::devkit::{
+    Cancel, ConnectionState, Devkit, DevkitResult, Pending, Request, RequestError, Response,
+};
+pub use crate::image::{Image, Segment};
+pub use crate::protocol::{Address, LinkOptions, BANKS, BANK_SIZE};
+pub use crate::shadow::Shadow;
 
-fn control_loop(
-    handle: JoinHandle<Result<(), Box<dyn Error + Send + Sync + 'static>>>,
-    tx: Sender<DevkitCommand>,
-    res: Receiver<DevkitResponse>,
-) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
-    let config = Config::default();
-
-    connect_port(&tx, &config)?;
-
-    let mut current_mem = vec![];
-    let stdin = std::io::stdin();
-
-    loop {
-        let mut cmd = String::new();
-        stdin.lock().read_line(&mut cmd)?;
-        let args = cmd
-            .split(" ")
-            .into_iter()
-            .map(|a| a.trim())
-            .filter(|a| !a.is_empty())
-            .collect::<Vec<_>>();
+use crate::assemble::SourceMap;
+use crate::progress::Progress;
 
-        if args.len() == 0 {
-            println!("helpful message here :D");
-            continue
-        }
+/// Writes the 256 byte pages of `image` that differ from `actual`, what the
+/// board is believed to hold, and returns what it holds afterwards. Bytes
+/// the image doesn't define keep their value: pages it only partly covers
+/// are read back first if `actual` doesn't have them. The result also holds
+/// the whole pages written. The shadow is removed while the upload runs, an
+/// interrupted one leaves none behind.
+fn upload_image(
+    image: &Image,
+    mut actual: Image,
+    shadow: Option<&Shadow>,
+    devkit: &Devkit,
+) -> DevkitResult<Image> {
+    const PAGE: u32 = 256;
+    if image.end() as usize > BANKS * BANK_SIZE {
+        return Err(format!("image larger than {} banks", BANKS).into());
+    }
+    if let Some(shadow) = shadow {
+        shadow.invalidate()?;
+    }
 
-        match args[0] {
-            "u" => {
-                let filename = *args.get(1).unwrap_or(&"data");
-                println!("opening: {}", filename);
-                if let Ok(data) = std::fs::read(filename) {
-                    current_mem = update_memory(data, current_mem, &tx, &res)?;
-                } else {
-                    println!("unable to read file: {}", filename);
-                }
+    let mut pages: Vec<u32> = vec![];
+    for s in image.segments() {
+        for page in (s.addr / PAGE * PAGE..s.end()).step_by(PAGE as usize) {
+            if pages.last() != Some(&page) {
+                pages.push(page);
             }
-            "q" => {
-                break;
+        }
+    }
+
+    // runs of pages are read back at once
+    let unknown = |actual: &Image, page: u32| {
+        !image.covers(page, page + PAGE - 1) && actual.get(page, PAGE as usize).is_none()
+    };
+    let mut i = 0;
+    while i < pages.len() {
+        if !unknown(&actual, pages[i]) {
+            i += 1;
+            continue;
+        }
+        let mut end = i + 1;
+        while end < pages.len()
+            && pages[end] == pages[end - 1] + PAGE
+            && unknown(&actual, pages[end])
+        {
+            end += 1;
+        }
+        let len = (end - i) * PAGE as usize;
+        let data = devkit.read(Address::from_linear(pages[i]), len)?;
+        actual.overwrite(Segment {
+            addr: pages[i],
+            data,
+        });
+        i = end;
+    }
+
+    let mut writes = vec![];
+    for page in pages {
+        let old = actual.get(page, PAGE as usize);
+        let mut data = old.map(|d| d.to_vec()).unwrap_or(vec![0; PAGE as usize]);
+        for s in image.segments() {
+            let (start, end) = (s.addr.max(page), s.end().min(page + PAGE));
+            if start < end {
+                data[(start - page) as usize..(end - page) as usize]
+                    .copy_from_slice(&s.data[(start - s.addr) as usize..(end - s.addr) as usize]);
             }
-            &_ => {}
+        }
+        if old != Some(&data[..]) {
+            writes.push(Segment { addr: page, data });
         }
     }
 
-    tx.send(DevkitCommand::End)?;
-    handle.join().unwrap()
+    // all queued at once, the worker pipelines them
+    let mut progress = Progress::new(writes.len());
+    let mut pending = writes
+        .iter()
+        .map(|w| devkit.request(Request::Write(Address::from_linear(w.addr), w.data.clone())))
+        .collect::<Vec<_>>()
+        .into_iter();
+    while let Some(write) = pending.next() {
+        if let Err(e) = write.wait() {
+            pending.for_each(|p| p.cancel());
+            progress.finish();
+            return Err(e.into());
+        }
+        progress.advance(PAGE as usize);
+    }
+    if !writes.is_empty() {
+        println!("{}", progress.finish());
+    }
+
+    for w in writes {
+        actual.overwrite(w);
+    }
+    if let Some(shadow) = shadow {
+        shadow.store(&actual)?;
+    }
+    Ok(actual)
+}
+
+/// Reads back `expected.len()` bytes from `base` and prints where they
+/// differ, returns the number of differing bytes.
+fn verify_memory(
+    expected: &[u8],
+    base: u32,
+    source_map: Option<&SourceMap>,
+    devkit: &Devkit,
+) -> DevkitResult<usize> {
+    let actual = devkit.read(Address::from_linear(base), expected.len())?;
+    Ok(dump::report_differences(
+        base, expected, &actual, source_map,
+    ))
+}
+
+/// `verify_memory` for every segment of `image`.
+fn verify_image(
+    image: &Image,
+    source_map: Option<&SourceMap>,
+    devkit: &Devkit,
+) -> DevkitResult<usize> {
+    let mut differing = 0;
+    for s in image.segments() {
+        differing += verify_memory(&s.data, s.addr, source_map, devkit)?;
+    }
+    Ok(differing)
 }
 
-fn connect_port(
-    tx: &Sender<DevkitCommand>,
-    config: &Config,
-) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
-    let ports = serialport::available_ports().expect("No ports found!");
-    for (i, p) in ports.iter().enumerate() {
-        println!(" {}: {}", i, p.port_name);
+/// Decimal, or hex with a `h` suffix or `0x` prefix.
+fn parse_number(val: &str) -> Option<u32> {
+    let lower = val.to_lowercase();
+    if let Some(hex) = lower.strip_suffix('h') {
+        u32::from_str_radix(hex, 16).ok()
+    } else if let Some(hex) = lower.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16).ok()
+    } else {
+        lower.parse::<u32>().ok()
     }
+}
 
-    let mut line = String::new();
-    let stdin = std::io::stdin();
-    stdin.lock().read_line(&mut line)?;
+#[cfg(test)]
+mod tests {
+    use crate::protocol::frame::{Frame, CMD_HELLO, CMD_WRITE};
+    use crate::protocol::{FramedProtocol, Protocol, RawProtocol, PROTOCOL_VERSION};
+    use crate::simulator::Simulator;
+    use crate::transport::MemoryTransport;
+    use crate::{
+        upload_image, verify_image, Address, Devkit, Image, Request, Segment, Shadow, BANK_SIZE,
+    };
+    use std::time::Duration;
 
-    let idx: usize = line[..line.len() - 1].parse()?;
+    #[test]
+    fn upload_and_verify() {
+        let sim = Simulator::new(1);
+        let memory = sim.memory();
+        let devkit = Devkit::open(Box::new(sim)).unwrap();
 
-    tx.send(ConnectSerial(
-        ports.get(idx).ok_or("port not found")?.port_name.clone(),
-        config.serial_rate,
-    ))?;
+        let image = (0..200).map(|i| (i * 7) as u8).collect::<Vec<_>>();
+        let current = upload_image(
+            &Image::binary(image.clone(), 0),
+            Image::default(),
+            None,
+            &devkit,
+        )
+        .unwrap();
+        assert_eq!(256, current.len());
+        assert_eq!(image, memory.lock().unwrap()[..200].to_vec());
+        assert_eq!(0, verify_image(&current, None, &devkit).unwrap());
 
-    Ok(())
-}
+        memory.lock().unwrap()[10] ^= 0xFF;
+        memory.lock().unwrap()[11] ^= 0xFF;
+        assert_eq!(2, verify_image(&current, None, &devkit).unwrap());
+        assert_eq!(
+            vec![image[9], !image[10], !image[11]],
+            devkit.read(Address::from_linear(9), 3).unwrap()
+        );
 
-fn update_memory(
-    mut target: Vec<u8>,
-    actual: Vec<u8>,
-    tx: &Sender<DevkitCommand>,
-    res: &Receiver<DevkitResponse>,
-) -> Result<Vec<u8>, Box<dyn Error + Send + Sync + 'static>> {
-    // pad target mem to multiple of 256
-    loop {
-        if target.len() % 256 == 0 {
-            break;
-        }
-        target.push(0);
+        devkit.close().unwrap();
     }
 
-    let mut i = 0;
-    let mut blocks = vec![];
+    #[test]
+    fn upload_across_banks() {
+        let sim = Simulator::new(3);
+        let memory = sim.memory();
+        let devkit = Devkit::open(Box::new(sim)).unwrap();
 
-    loop {
-        if i >= target.len() {
-            break;
-        }
+        let image = (0..2 * BANK_SIZE + 300)
+            .map(|i| (i / 256 + i) as u8)
+            .collect::<Vec<_>>();
+        let current = upload_image(
+            &Image::binary(image.clone(), 0),
+            Image::default(),
+            None,
+            &devkit,
+        )
+        .unwrap();
+        assert_eq!(image, memory.lock().unwrap()[..image.len()].to_vec());
+        assert_eq!(0, verify_image(&current, None, &devkit).unwrap());
 
-        if i + 256 > actual.len() || actual[i..i + 256] != target[i..i + 256] {
-            blocks.push(((i / 256) as u16, target[i..i + 256].to_vec()));
-        }
+        // only the changed page of bank 1 is written again
+        let mut changed = current.segments()[0].data.clone();
+        changed[BANK_SIZE + 0x1234] ^= 0xFF;
+        memory.lock().unwrap()[BANK_SIZE] ^= 0xFF;
+        upload_image(&Image::binary(changed.clone(), 0), current, None, &devkit).unwrap();
+        assert_eq!(
+            changed[BANK_SIZE + 0x1234],
+            memory.lock().unwrap()[BANK_SIZE + 0x1234]
+        );
+        assert_ne!(changed[BANK_SIZE], memory.lock().unwrap()[BANK_SIZE]);
+
+        // nothing below an image high up is read back, only its page
+        let high = Image::binary(vec![0xAB; 16], 2 * BANK_SIZE as u32 + 0x8010);
+        let current = upload_image(&high, Image::default(), None, &devkit).unwrap();
+        let board = memory.lock().unwrap()[2 * BANK_SIZE + 0x8000..][..256].to_vec();
+        assert_eq!(vec![0xAB; 16], board[0x10..0x20].to_vec());
+        assert_eq!(
+            &[Segment {
+                addr: 2 * BANK_SIZE as u32 + 0x8000,
+                data: board
+            }],
+            current.segments()
+        );
 
-        i += 256;
+        let too_large = Image::binary(vec![0; 256 * BANK_SIZE + 1], 0);
+        assert!(upload_image(&too_large, Image::default(), None, &devkit).is_err());
+        devkit.close().unwrap();
     }
 
-    let blocks_to_update = blocks.len();
-    let pad_len = blocks_to_update.to_string().chars().count();
+    #[test]
+    fn upload_segments() {
+        let sim = Simulator::framed(1);
+        let memory = sim.memory();
+        memory.lock().unwrap()[0x10] = 0x77;
+        memory.lock().unwrap()[0x3F0] = 0x99;
+        let devkit = Devkit::open(Box::new(sim)).unwrap();
 
-    for (i, (addr, data)) in blocks.into_iter().enumerate() {
-        println!(
-            "{:0width$}/{:0width$}",
-            i + 1,
-            blocks_to_update,
-            width = pad_len
+        // bytes around the segments are read back, not overwritten
+        let code = (0..300).map(|i| i as u8).collect::<Vec<_>>();
+        let image = Image::new(vec![
+            Segment {
+                addr: 0x1000,
+                data: vec![1, 2, 3, 4],
+            },
+            Segment {
+                addr: 0x200,
+                data: code.clone(),
+            },
+        ])
+        .unwrap();
+        let current = upload_image(&image, Image::default(), None, &devkit).unwrap();
+        assert_eq!(
+            vec![(0x200, 0x200), (0x1000, 0x100)],
+            current
+                .segments()
+                .iter()
+                .map(|s| (s.addr, s.data.len()))
+                .collect::<Vec<_>>()
         );
+        assert_eq!(Some(&[0x99][..]), current.get(0x3F0, 1));
+        assert_eq!(None, current.get(0x10, 1));
+        let board = memory.lock().unwrap().clone();
+        assert_eq!(code, board[0x200..0x200 + 300].to_vec());
+        assert_eq!(
+            [0x99, 1, 2, 3, 4, 0],
+            [
+                board[0x3F0],
+                board[0x1000],
+                board[0x1001],
+                board[0x1002],
+                board[0x1003],
+                board[0x1004]
+            ]
+        );
+        assert_eq!(0, verify_image(&image, None, &devkit).unwrap());
 
-        tx.send(DevkitCommand::WriteBytes(addr, data))?;
+        // only the pages the image touches are written
+        memory.lock().unwrap()[0x10] = 0;
+        memory.lock().unwrap()[0x1004] = 0xEE;
+        let moved = Image::binary(code.clone(), 0x180);
+        upload_image(&moved, current, None, &devkit).unwrap();
+        let board = memory.lock().unwrap().clone();
+        assert_eq!(code, board[0x180..0x180 + 300].to_vec());
+        assert_eq!((0, 0xEE), (board[0x10], board[0x1004]));
+        devkit.close().unwrap();
+    }
 
-        let r = res.recv_timeout(Duration::from_millis(1000))?;
-        assert_eq!(r, DevkitResponse::Done);
+    #[test]
+    fn framed_protocol() {
+        // old firmware must see nothing but unknown commands in the hello
+        let hello = Frame {
+            seq: 0,
+            cmd: CMD_HELLO,
+            payload: vec![PROTOCOL_VERSION],
+        };
+        assert!(!hello.encode().iter().any(|b| b"lrswW".contains(b)));
+
+        let sim = Simulator::framed(2);
+        let memory = sim.memory();
+        let devkit = Devkit::open(Box::new(sim)).unwrap();
+        let image = (0..BANK_SIZE + 1000)
+            .map(|i| (i * 3) as u8)
+            .collect::<Vec<_>>();
+        let current = upload_image(
+            &Image::binary(image.clone(), 0),
+            Image::default(),
+            None,
+            &devkit,
+        )
+        .unwrap();
+        assert_eq!(image, memory.lock().unwrap()[..image.len()].to_vec());
+        assert_eq!(0, verify_image(&current, None, &devkit).unwrap());
+        devkit.close().unwrap();
     }
 
-    Ok(target)
-}
+    #[test]
+    fn framed_retries() {
+        let mut sim = Simulator::framed(1);
+        let memory = sim.memory();
+        // the NAK for the corrupt request and the ACK of the write get lost,
+        // the third attempt is answered from the simulator's last reply
+        sim.corrupt_requests = 1;
+        sim.lose_replies = 2;
+        sim.noise = b"garbage\xA5\x01".to_vec();
 
-#[derive(Debug)]
-struct ThreadError {}
+        let mut protocol = FramedProtocol::new(Box::new(sim));
+        protocol.reply_timeout = Duration::from_millis(20);
+        let page = (0..=255).collect::<Vec<u8>>();
+        protocol
+            .request(CMD_WRITE, [vec![0, 2, 0], page.clone()].concat())
+            .unwrap();
+        assert_eq!(page, memory.lock().unwrap()[0x200..0x300].to_vec());
 
-impl Display for ThreadError {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "error joining thread")
+        memory.lock().unwrap()[0x200] = 0xEE;
+        let devkit = Devkit::start(Box::new(protocol));
+        assert_eq!(
+            vec![0xEE, 1],
+            devkit.read(Address::from_linear(0x200), 2).unwrap()
+        );
+        devkit.close().unwrap();
     }
-}
 
-impl Error for ThreadError {}
+    #[test]
+    fn pipelined_writes() {
+        let mut sim = Simulator::framed(1);
+        let memory = sim.memory();
+        // the first write is NAKed and the ACKs of the next two get lost, the
+        // writes after them are already running
+        sim.corrupt_requests = 1;
+        sim.lose_replies = 2;
+        let mut protocol = FramedProtocol::new(Box::new(sim));
+        protocol.reply_timeout = Duration::from_millis(20);
+        protocol.window = 8;
+        protocol.compress = true;
+        let devkit = Devkit::start(Box::new(protocol));
+
+        let image = (0..40 * 256)
+            .map(|i| if i % 512 < 200 { (i * 5) as u8 } else { 0xFF })
+            .collect::<Vec<_>>();
+        let current = upload_image(
+            &Image::binary(image.clone(), 0),
+            Image::default(),
+            None,
+            &devkit,
+        )
+        .unwrap();
+        assert_eq!(image, memory.lock().unwrap()[..image.len()].to_vec());
+        assert_eq!(0, verify_image(&current, None, &devkit).unwrap());
+
+        // writes to the same bytes are never in flight together
+        let first = devkit.request(Request::Write(Address::from_linear(0x10), vec![1; 300]));
+        let second = devkit.request(Request::Write(Address::from_linear(0x80), vec![2; 16]));
+        first.wait().unwrap();
+        second.wait().unwrap();
+        assert_eq!(
+            [1, 2, 2, 1],
+            [0x7F, 0x80, 0x8F, 0x90].map(|a| memory.lock().unwrap()[a])
+        );
+        devkit.close().unwrap();
+
+        // firmware without compressed writes gets plain ones
+        let mut sim = Simulator::framed(1);
+        sim.rle = false;
+        let memory = sim.memory();
+        let mut protocol = FramedProtocol::new(Box::new(sim));
+        protocol.compress = true;
+        protocol
+            .write(Address::from_linear(0x100), &[0xAB; 256])
+            .unwrap();
+        assert!(!protocol.compress);
+        assert_eq!(
+            vec![0xAB; 256],
+            memory.lock().unwrap()[0x100..0x200].to_vec()
+        );
+    }
+
+    #[test]
+    fn legacy_fallback() {
+        let sim = Simulator::new(1);
+        let memory = sim.memory();
+        let devkit = Devkit::open(Box::new(sim)).unwrap();
+        let image = vec![0x55; 300];
+        upload_image(
+            &Image::binary(image.clone(), 0),
+            Image::default(),
+            None,
+            &devkit,
+        )
+        .unwrap();
+        assert_eq!(image, memory.lock().unwrap()[..300].to_vec());
+        devkit.close().unwrap();
+    }
+
+    #[test]
+    fn shadow_upload() {
+        let dir = std::env::temp_dir().join(format!("devkit-shadow-{}", std::process::id()));
+        let shadow = Shadow::new(&dir, "/dev/ttyACM0");
+        let image = (0..8 * 256).map(|i| (i / 3) as u8).collect::<Vec<_>>();
+
+        let sim = Simulator::framed(1);
+        let board = sim.memory();
+        let devkit = Devkit::open(Box::new(sim)).unwrap();
+        let actual = shadow.load_validated(&devkit, 4).unwrap();
+        assert!(actual.is_empty());
+        upload_image(
+            &Image::binary(image.clone(), 0),
+            actual,
+            Some(&shadow),
+            &devkit,
+        )
+        .unwrap();
+        devkit.close().unwrap();
+        assert_eq!(Image::binary(image.clone(), 0), shadow.load());
+
+        // a new session on the same board only writes the changed page: the
+        // byte changed behind the tool's back in page 5, which isn't
+        // sampled, stays as it is
+        let sim = Simulator::framed(1);
+        sim.memory().lock().unwrap()[..image.len()]
+            .copy_from_slice(&board.lock().unwrap()[..image.len()]);
+        sim.memory().lock().unwrap()[0x500] ^= 0xFF;
+        let board = sim.memory();
+        let devkit = Devkit::open(Box::new(sim)).unwrap();
+        let mut changed = image.clone();
+        changed[0x123] ^= 0xFF;
+        let actual = shadow.load_validated(&devkit, 4).unwrap();
+        assert_eq!(Image::binary(image.clone(), 0), actual);
+        upload_image(
+            &Image::binary(changed.clone(), 0),
+            actual,
+            Some(&shadow),
+            &devkit,
+        )
+        .unwrap();
+        assert_eq!(changed[0x123], board.lock().unwrap()[0x123]);
+        assert_ne!(changed[0x500], board.lock().unwrap()[0x500]);
+        assert_eq!(Image::binary(changed, 0), shadow.load());
+
+        // a sampled page that differs makes the shadow useless
+        board.lock().unwrap()[0] ^= 0xFF;
+        assert!(shadow.load_validated(&devkit, 4).unwrap().is_empty());
+        devkit.close().unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn partial_writes() {
+        for sim in [Simulator::new(1), Simulator::framed(1)] {
+            let memory = sim.memory();
+            let devkit = Devkit::open(Box::new(sim)).unwrap();
+            let data = (0..0x130).map(|i| i as u8 ^ 0x5A).collect::<Vec<_>>();
+            devkit
+                .write(Address::from_linear(0xF0), data.clone())
+                .unwrap();
+            devkit
+                .write(Address::from_linear(0x300), vec![0xAB])
+                .unwrap();
+
+            let memory = memory.lock().unwrap();
+            assert_eq!(data, memory[0xF0..0x220].to_vec());
+            assert_eq!([0, 0xAB, 0], memory[0x2FF..0x302]);
+        }
+    }
+
+    #[test]
+    fn thread_errors() {
+        // no answer from the board
+        let err = match Devkit::open(Box::new(MemoryTransport::default())) {
+            Err(e) => e,
+            Ok(_) => panic!("opened a session without a board"),
+        };
+        assert!(err.to_string().contains("no response"), "{}", err);
+
+        let devkit = Devkit::start(Box::new(RawProtocol {
+            transport: Box::new(MemoryTransport::default()),
+        }));
+        let err = devkit.read(Address::from_linear(0), 1).unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{}", err);
+        assert!(devkit.read(Address::from_linear(0), 1).is_err());
+    }
+}
@@ -0,0 +1,103 @@
+use std::io::{IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+/// Characters between the brackets of the bar.
+const WIDTH: usize = 30;
+
+/// Progress bar for the pages of an upload, drawn on stderr when it's a
+/// terminal.
+pub struct Progress {
+    total: usize,
+    done: usize,
+    bytes: usize,
+    start: Instant,
+    draw: bool,
+}
+
+impl Progress {
+    pub fn new(total: usize) -> Self {
+        Progress {
+            total,
+            done: 0,
+            bytes: 0,
+            start: Instant::now(),
+            draw: std::io::stderr().is_terminal(),
+        }
+    }
+
+    /// One more page of `bytes` is written.
+    pub fn advance(&mut self, bytes: usize) {
+        self.done += 1;
+        self.bytes += bytes;
+        if self.draw {
+            let mut stderr = std::io::stderr();
+            let _ = write!(stderr, "\r{}", self.line(self.start.elapsed()));
+            let _ = stderr.flush();
+        }
+    }
+
+    /// Clears the bar and returns the statistics.
+    pub fn finish(self) -> String {
+        if self.draw {
+            eprint!("\r{:1$}\r", "", WIDTH + 40);
+        }
+        let elapsed = self.start.elapsed();
+        format!(
+            "wrote {} pages, {} in {:.2} s ({}/s)",
+            self.done,
+            size(self.bytes as f64),
+            elapsed.as_secs_f64(),
+            size(rate(self.bytes, elapsed))
+        )
+    }
+
+    fn line(&self, elapsed: Duration) -> String {
+        let filled = WIDTH * self.done / self.total.max(1);
+        let width = self.total.to_string().len();
+        format!(
+            "[{}{}] {:>width$}/{} {}/s",
+            "#".repeat(filled),
+            ".".repeat(WIDTH - filled),
+            self.done,
+            self.total,
+            size(rate(self.bytes, elapsed)),
+        )
+    }
+}
+
+fn rate(bytes: usize, elapsed: Duration) -> f64 {
+    bytes as f64 / elapsed.as_secs_f64().max(0.001)
+}
+
+fn size(bytes: f64) -> String {
+    if bytes < 1024.0 {
+        format!("{:.0} B", bytes)
+    } else {
+        format!("{:.1} KiB", bytes / 1024.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::progress::Progress;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn progress_line() {
+        let mut progress = Progress {
+            total: 40,
+            done: 0,
+            bytes: 0,
+            start: Instant::now(),
+            draw: false,
+        };
+        for _ in 0..10 {
+            progress.advance(256);
+        }
+        assert_eq!(
+            "[#######.......................] 10/40 5.0 KiB/s",
+            progress.line(Duration::from_millis(500))
+        );
+        assert!(progress.finish().starts_with("wrote 10 pages, 2.5 KiB in "));
+    }
+}
@@ -0,0 +1,53 @@
+use crate::protocol::{read_byte, read_reply, Address, LOAD_ADDR, SET_SHIFT_REGISTER};
+use crate::transport::Transport;
+use std::error::Error;
+use std::io::ErrorKind;
+use std::time::{Duration, Instant};
+
+/// How long `load_addr` waits for the answer, the firmware holds the address
+/// for 50ms first.
+const LOAD_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Sets the shift register with `s <decimal>\n`. The firmware's `scanf`
+/// leaves the newline in the input and then echoes it as an unknown command,
+/// so the reply is `s: '<value>'\n - \n\n`.
+pub fn set_shift_register(
+    serial_port: &mut dyn Transport,
+    val: u16,
+) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    serial_port.write_all(&[SET_SHIFT_REGISTER])?;
+    serial_port.write_all(format!("{}\n", val).as_bytes())?;
+
+    let expected = format!("s: '{}'\n - \n\n", val).into_bytes();
+    if read_reply(serial_port, &expected)? {
+        Ok(())
+    } else {
+        Err("unexpected response setting the shift register".into())
+    }
+}
+
+/// Strobes a read at `addr` with `l bank high low`, the firmware holds the
+/// address for 50ms and answers `l\n`.
+pub fn load_addr(
+    serial_port: &mut dyn Transport,
+    addr: Address,
+) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    serial_port.write_all(&[LOAD_ADDR, addr.bank, addr.high, addr.low])?;
+
+    // longer than a single read waits
+    let deadline = Instant::now() + LOAD_TIMEOUT;
+    let first = loop {
+        match read_byte(serial_port) {
+            Ok(b) => break b,
+            Err(e)
+                if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock)
+                    && Instant::now() < deadline => {}
+            Err(e) => return Err(e.into()),
+        }
+    };
+    if first == b'l' && read_reply(serial_port, b"\n")? {
+        Ok(())
+    } else {
+        Err(format!("unexpected response loading {:06X}h", addr.linear()).into())
+    }
+}
@@ -0,0 +1,259 @@
+/// Framed protocol, both directions use the same layout:
+///
+/// ```text
+/// A5h seq cmd len_lo len_hi <len bytes of payload> crc_lo crc_hi
+/// ```
+///
+/// the CRC (CRC-16/CCITT-FALSE) covers everything between the sync byte and
+/// the CRC. Replies carry the sequence number of the request they answer.
+pub const SYNC: u8 = 0xA5;
+pub const MAX_PAYLOAD: usize = 1024;
+const HEADER_LEN: usize = 5;
+
+/// `[version]`, answered with `ACK [version]`.
+pub const CMD_HELLO: u8 = 0x01;
+/// `[bank, high, low, len_lo, len_hi]`, answered with `ACK <data>`.
+pub const CMD_READ: u8 = 0x02;
+/// `[bank, high, low, <data>]`, answered with an empty `ACK`.
+pub const CMD_WRITE: u8 = 0x03;
+/// `[value_lo, value_hi]`, answered with an empty `ACK`.
+pub const CMD_SHIFT_REGISTER: u8 = 0x04;
+/// `[bank, high, low]`, strobes a read at the address, answered with an
+/// empty `ACK`.
+pub const CMD_LOAD: u8 = 0x05;
+/// `[bank, high, low, <packets>]`, like `CMD_WRITE` with the data run-length
+/// encoded, see `rle_decode`. Answered with an empty `ACK`, or a NAK for
+/// invalid arguments if the packets are truncated or the decoded data
+/// doesn't fit in memory. Firmware without it answers the NAK for unknown
+/// commands.
+pub const CMD_WRITE_RLE: u8 = 0x06;
+/// `[token]`, the client's answer to `SERVER_GREETING`. Answered with an
+/// empty `ACK`, or `NAK_AUTH` before the server closes the connection.
+pub const CMD_AUTH: u8 = 0x07;
+
+pub const REPLY_ACK: u8 = 0x80;
+/// `[reason]`, one of the `NAK_` codes.
+pub const REPLY_NAK: u8 = 0x81;
+/// `[version]` with sequence number 0, sent by `devkit serve` as soon as a
+/// client connects. Boards never send it.
+pub const SERVER_GREETING: u8 = 0x82;
+
+pub const NAK_CRC: u8 = 1;
+pub const NAK_UNKNOWN_COMMAND: u8 = 2;
+pub const NAK_INVALID_ARGUMENTS: u8 = 3;
+/// From a devkit server: the token is wrong or the client didn't send one.
+pub const NAK_AUTH: u8 = 4;
+/// From a devkit server: the board didn't carry out the request.
+pub const NAK_BOARD: u8 = 5;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Frame {
+    pub seq: u8,
+    pub cmd: u8,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum Parsed {
+    /// A valid frame, `len` bytes long.
+    Frame(Frame, usize),
+    /// A complete frame with a bad CRC, `len` bytes long.
+    Corrupt {
+        seq: u8,
+        len: usize,
+    },
+    /// `len` bytes before the next possible frame start.
+    Garbage(usize),
+    Incomplete,
+}
+
+impl Frame {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![
+            SYNC,
+            self.seq,
+            self.cmd,
+            self.payload.len() as u8,
+            (self.payload.len() >> 8) as u8,
+        ];
+        out.extend(self.payload.iter());
+        let crc = crc16(&out[1..]);
+        out.extend(crc.to_le_bytes());
+        out
+    }
+}
+
+/// Looks for a frame at the start of `buf`.
+pub fn parse(buf: &[u8]) -> Parsed {
+    match buf.first() {
+        None => return Parsed::Incomplete,
+        Some(&SYNC) => {}
+        Some(_) => {
+            return Parsed::Garbage(buf.iter().position(|b| *b == SYNC).unwrap_or(buf.len()))
+        }
+    }
+    if buf.len() < HEADER_LEN {
+        return Parsed::Incomplete;
+    }
+
+    let len = buf[3] as usize | (buf[4] as usize) << 8;
+    if len > MAX_PAYLOAD {
+        // not a real header, look for the next sync byte
+        return Parsed::Garbage(1);
+    }
+    let total = HEADER_LEN + len + 2;
+    if buf.len() < total {
+        return Parsed::Incomplete;
+    }
+
+    let crc = u16::from_le_bytes([buf[total - 2], buf[total - 1]]);
+    if crc != crc16(&buf[1..total - 2]) {
+        return Parsed::Corrupt {
+            seq: buf[1],
+            len: total,
+        };
+    }
+    Parsed::Frame(
+        Frame {
+            seq: buf[1],
+            cmd: buf[2],
+            payload: buf[HEADER_LEN..total - 2].to_vec(),
+        },
+        total,
+    )
+}
+
+/// Longest literal and repeat in one run-length packet.
+const MAX_LITERAL: usize = 128;
+const MIN_REPEAT: usize = 3;
+const MAX_REPEAT: usize = 130;
+
+/// Packs `data` for `CMD_WRITE_RLE`, runs shorter than 3 bytes are left as
+/// literals.
+pub fn rle_encode(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut literal_start = 0;
+    let mut at = 0;
+    while at <= data.len() {
+        let run = data[at..]
+            .iter()
+            .take(MAX_REPEAT)
+            .take_while(|b| Some(*b) == data.get(at))
+            .count();
+        let end = at == data.len();
+        if end || run >= MIN_REPEAT || at - literal_start == MAX_LITERAL {
+            for chunk in data[literal_start..at].chunks(MAX_LITERAL) {
+                out.push((chunk.len() - 1) as u8);
+                out.extend(chunk);
+            }
+            literal_start = at;
+        }
+        if end {
+            break;
+        }
+        if run >= MIN_REPEAT {
+            out.push(0x80 + (run - MIN_REPEAT) as u8);
+            out.push(data[at]);
+            at += run;
+            literal_start = at;
+        } else {
+            at += 1;
+        }
+    }
+    out
+}
+
+/// Unpacks `CMD_WRITE_RLE` data, a sequence of packets starting with a
+/// control byte `n`:
+///
+/// - `00h..7Fh`: `n + 1` literal bytes follow
+/// - `80h..FFh`: one byte follows, repeated `n - 80h + 3` times
+///
+/// `None` if the last packet is cut short.
+pub fn rle_decode(packed: &[u8]) -> Option<Vec<u8>> {
+    let mut out = vec![];
+    let mut at = 0;
+    while let Some(&n) = packed.get(at) {
+        if n < 0x80 {
+            let len = n as usize + 1;
+            out.extend(packed.get(at + 1..at + 1 + len)?);
+            at += 1 + len;
+        } else {
+            let val = *packed.get(at + 1)?;
+            out.extend(std::iter::repeat_n(val, (n - 0x80) as usize + MIN_REPEAT));
+            at += 2;
+        }
+    }
+    Some(out)
+}
+
+/// CRC-16/CCITT-FALSE: polynomial 1021h, initial value FFFFh.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for b in data {
+        crc ^= (*b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::frame::{crc16, parse, rle_decode, rle_encode, Frame, Parsed, CMD_WRITE};
+
+    #[test]
+    fn framing() {
+        assert_eq!(0x29B1, crc16(b"123456789"));
+
+        let frame = Frame {
+            seq: 7,
+            cmd: CMD_WRITE,
+            payload: vec![0xA5, 1, 2],
+        };
+        let data = frame.encode();
+        assert_eq!(Parsed::Frame(frame.clone(), data.len()), parse(&data));
+        assert_eq!(Parsed::Incomplete, parse(&data[..data.len() - 1]));
+
+        let mut noisy = b"xx".to_vec();
+        noisy.extend(&data);
+        assert_eq!(Parsed::Garbage(2), parse(&noisy));
+
+        let mut corrupt = data.clone();
+        corrupt[6] ^= 1;
+        assert_eq!(
+            Parsed::Corrupt {
+                seq: 7,
+                len: data.len()
+            },
+            parse(&corrupt)
+        );
+    }
+
+    #[test]
+    fn run_length() {
+        assert_eq!(
+            vec![0x00, 1, 0x80, 2, 0x01, 3, 4],
+            rle_encode(&[1, 2, 2, 2, 3, 4])
+        );
+        assert_eq!(vec![0xFF, 0, 0xFB, 0], rle_encode(&[0; 256]));
+        assert!(rle_encode(&[]).is_empty());
+
+        let page = (0..256)
+            .map(|i| if i < 100 { i as u8 } else { 0xFF })
+            .collect::<Vec<_>>();
+        assert_eq!(1 + 100 + 2 + 2, rle_encode(&page).len());
+        let noise = (0..300).map(|i| (i * 7 + i / 3) as u8).collect::<Vec<_>>();
+        for data in [&page, &noise, &vec![5; 1000]] {
+            assert_eq!(Some(data.clone()), rle_decode(&rle_encode(data)));
+        }
+
+        assert_eq!(None, rle_decode(&[0x02, 1, 2]));
+        assert_eq!(None, rle_decode(&[0x80]));
+    }
+}
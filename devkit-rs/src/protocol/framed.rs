@@ -0,0 +1,429 @@
+use crate::protocol::frame::{
+    parse, rle_encode, Frame, Parsed, CMD_HELLO, CMD_LOAD, CMD_READ, CMD_SHIFT_REGISTER, CMD_WRITE,
+    CMD_WRITE_RLE, MAX_PAYLOAD, NAK_AUTH, NAK_BOARD, NAK_CRC, NAK_INVALID_ARGUMENTS,
+    NAK_UNKNOWN_COMMAND, REPLY_ACK, REPLY_NAK,
+};
+use crate::protocol::{Address, Protocol};
+use crate::transport::Transport;
+use std::error::Error;
+use std::io::ErrorKind;
+use std::time::{Duration, Instant};
+
+pub const PROTOCOL_VERSION: u8 = 1;
+const DEFAULT_REPLY_TIMEOUT: Duration = Duration::from_millis(200);
+const DEFAULT_RETRIES: usize = 3;
+const DEFAULT_WINDOW: usize = 4;
+
+type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync + 'static>>;
+
+pub enum Handshake {
+    Framed,
+    /// The firmware echoed the bytes back as unknown commands, it only
+    /// speaks the raw protocol.
+    Legacy,
+}
+
+/// How long to wait for a reply, how many times a request is sent again,
+/// how many writes can wait for their reply at once and whether they are
+/// compressed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LinkOptions {
+    pub reply: Duration,
+    pub retries: usize,
+    pub window: usize,
+    pub compress: bool,
+}
+
+impl Default for LinkOptions {
+    fn default() -> Self {
+        LinkOptions {
+            reply: DEFAULT_REPLY_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            window: DEFAULT_WINDOW,
+            compress: false,
+        }
+    }
+}
+
+/// A write frame waiting for its reply.
+struct InFlight {
+    index: usize,
+    seq: u8,
+    frame: Vec<u8>,
+    compressed: bool,
+    attempts: usize,
+}
+
+/// Sends every command as a frame and waits for the reply with the same
+/// sequence number. Requests are sent again after a timeout or a NAK for a
+/// bad CRC, the firmware answers a repeated sequence number with its last
+/// reply instead of running the command twice.
+///
+/// Writes are pipelined: up to `window` of them are sent before waiting for
+/// the replies. One sent again after the firmware already ran later ones
+/// runs twice, which is harmless as long as no later write in the window
+/// touches the same bytes, so those wait. With `compress` the data goes
+/// run-length encoded when that's shorter, until the firmware says it
+/// doesn't know `CMD_WRITE_RLE`.
+pub struct FramedProtocol {
+    pub transport: Box<dyn Transport>,
+    pub reply_timeout: Duration,
+    pub retries: usize,
+    pub window: usize,
+    pub compress: bool,
+    seq: u8,
+    received: Vec<u8>,
+}
+
+impl FramedProtocol {
+    pub fn new(transport: Box<dyn Transport>) -> Self {
+        FramedProtocol {
+            transport,
+            reply_timeout: DEFAULT_REPLY_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            window: DEFAULT_WINDOW,
+            compress: false,
+            seq: 0,
+            received: vec![],
+        }
+    }
+
+    /// The hello frame uses sequence number 0 and none of its bytes is a
+    /// raw protocol command, so old firmware just echoes it back.
+    pub fn handshake(&mut self) -> Result<Handshake> {
+        let hello = Frame {
+            seq: 0,
+            cmd: CMD_HELLO,
+            payload: vec![PROTOCOL_VERSION],
+        }
+        .encode();
+
+        for _ in 0..=self.retries {
+            self.transport.write_all(&hello)?;
+            let deadline = Instant::now() + self.reply_timeout;
+
+            loop {
+                if self.received.starts_with(b" - ") {
+                    // let the rest of the echo arrive and throw it away
+                    while self.fill(Instant::now() + self.reply_timeout)? {}
+                    self.received.clear();
+                    return Ok(Handshake::Legacy);
+                }
+                let partial_echo = !self.received.is_empty() && b" - ".starts_with(&self.received);
+                if !partial_echo {
+                    match self.next_frame() {
+                        Some(f) if f.seq == 0 && f.cmd == REPLY_ACK => {
+                            return match f.payload.as_slice() {
+                                [PROTOCOL_VERSION] => Ok(Handshake::Framed),
+                                [v] => Err(format!(
+                                    "the firmware speaks protocol version {}, expected {}",
+                                    v, PROTOCOL_VERSION
+                                )
+                                .into()),
+                                _ => Err("invalid hello reply".into()),
+                            };
+                        }
+                        Some(_) => continue,
+                        None => {}
+                    }
+                }
+                if !self.fill(deadline)? {
+                    break;
+                }
+            }
+        }
+        Err("no response from the devkit".into())
+    }
+
+    /// Sends a request and returns the payload of its `ACK`.
+    pub fn request(&mut self, cmd: u8, payload: Vec<u8>) -> Result<Vec<u8>> {
+        self.seq = self.seq.wrapping_add(1);
+        let seq = self.seq;
+        let frame = Frame { seq, cmd, payload }.encode();
+
+        for _ in 0..=self.retries {
+            self.transport.write_all(&frame)?;
+            let deadline = Instant::now() + self.reply_timeout;
+
+            let reply = loop {
+                match self.next_frame() {
+                    // stale replies to earlier attempts are skipped
+                    Some(f) if f.seq == seq => break Some(f),
+                    Some(_) => continue,
+                    None => {
+                        if !self.fill(deadline)? {
+                            break None;
+                        }
+                    }
+                }
+            };
+
+            match reply {
+                Some(f) if f.cmd == REPLY_ACK => return Ok(f.payload),
+                Some(f) if f.cmd == REPLY_NAK && f.payload == [NAK_CRC] => continue,
+                Some(f) if f.cmd == REPLY_NAK => {
+                    return Err(format!(
+                        "the devkit rejected command {:02X}h: {}",
+                        cmd,
+                        nak_reason(f.payload.first().copied())
+                    )
+                    .into())
+                }
+                Some(f) => return Err(format!("unexpected reply {:02X}h", f.cmd).into()),
+                None => continue,
+            }
+        }
+        Err(format!("no valid reply after {} attempts", self.retries + 1).into())
+    }
+
+    /// Encodes a write of `data` at `addr` with the next sequence number.
+    fn write_frame(&mut self, index: usize, addr: Address, data: &[u8]) -> InFlight {
+        let packed = match self.compress {
+            true => Some(rle_encode(data)).filter(|p| p.len() < data.len()),
+            false => None,
+        };
+        let (cmd, data) = match &packed {
+            Some(p) => (CMD_WRITE_RLE, p.as_slice()),
+            None => (CMD_WRITE, data),
+        };
+        let mut payload = vec![addr.bank, addr.high, addr.low];
+        payload.extend(data);
+
+        self.seq = self.seq.wrapping_add(1);
+        InFlight {
+            index,
+            seq: self.seq,
+            frame: Frame {
+                seq: self.seq,
+                cmd,
+                payload,
+            }
+            .encode(),
+            compressed: packed.is_some(),
+            attempts: 0,
+        }
+    }
+
+    /// Sends `write` again, or reports it failed once it ran out of
+    /// attempts.
+    fn resend(
+        &mut self,
+        mut write: InFlight,
+        in_flight: &mut Vec<InFlight>,
+        done: &mut dyn FnMut(usize, Result<()>),
+    ) -> Result<()> {
+        if write.attempts > self.retries {
+            done(
+                write.index,
+                Err(format!("no valid reply after {} attempts", write.attempts).into()),
+            );
+            return Ok(());
+        }
+        write.attempts += 1;
+        in_flight.push(write);
+        self.transport
+            .write_all(&in_flight[in_flight.len() - 1].frame)?;
+        Ok(())
+    }
+
+    fn pipeline(
+        &mut self,
+        blocks: &[(Address, &[u8])],
+        skip: &dyn Fn(usize) -> bool,
+        done: &mut dyn FnMut(usize, Result<()>),
+        in_flight: &mut Vec<InFlight>,
+        next: &mut usize,
+    ) -> Result<()> {
+        let span = |i: usize| {
+            let start = blocks[i].0.linear();
+            start..start + blocks[i].1.len() as u32
+        };
+        let mut deadline = Instant::now() + self.reply_timeout;
+        loop {
+            while *next < blocks.len() && in_flight.len() < self.window.max(1) {
+                let i = *next;
+                if skip(i) {
+                    *next += 1;
+                    continue;
+                }
+                let range = span(i);
+                if in_flight.iter().any(|w| {
+                    let other = span(w.index);
+                    other.start < range.end && range.start < other.end
+                }) {
+                    break;
+                }
+                *next += 1;
+                let write = self.write_frame(i, blocks[i].0, blocks[i].1);
+                self.resend(write, in_flight, done)?;
+                deadline = Instant::now() + self.reply_timeout;
+            }
+            if in_flight.is_empty() {
+                return Ok(());
+            }
+
+            let reply = match self.next_frame() {
+                Some(f) => f,
+                None => {
+                    if !self.fill(deadline)? {
+                        // the lost ones go again, in the order they were sent
+                        let mut lost = std::mem::take(in_flight).into_iter();
+                        while let Some(write) = lost.next() {
+                            if let Err(e) = self.resend(write, in_flight, done) {
+                                in_flight.extend(lost);
+                                return Err(e);
+                            }
+                        }
+                        deadline = Instant::now() + self.reply_timeout;
+                    }
+                    continue;
+                }
+            };
+            // stale replies to earlier attempts are skipped
+            let Some(pos) = in_flight.iter().position(|w| w.seq == reply.seq) else {
+                continue;
+            };
+            let write = in_flight.remove(pos);
+            deadline = Instant::now() + self.reply_timeout;
+            match (reply.cmd, reply.payload.as_slice()) {
+                (REPLY_ACK, _) => done(write.index, Ok(())),
+                (REPLY_NAK, [NAK_CRC]) => self.resend(write, in_flight, done)?,
+                (REPLY_NAK, [NAK_UNKNOWN_COMMAND]) if write.compressed => {
+                    self.compress = false;
+                    let (addr, data) = blocks[write.index];
+                    let mut plain = self.write_frame(write.index, addr, data);
+                    plain.attempts = write.attempts - 1;
+                    self.resend(plain, in_flight, done)?;
+                }
+                (REPLY_NAK, reason) => done(
+                    write.index,
+                    Err(format!(
+                        "the devkit rejected command {:02X}h: {}",
+                        if write.compressed {
+                            CMD_WRITE_RLE
+                        } else {
+                            CMD_WRITE
+                        },
+                        nak_reason(reason.first().copied())
+                    )
+                    .into()),
+                ),
+                (cmd, _) => done(
+                    write.index,
+                    Err(format!("unexpected reply {:02X}h", cmd).into()),
+                ),
+            }
+        }
+    }
+
+    /// Takes the next valid frame out of the received bytes, skipping
+    /// garbage and corrupt frames.
+    fn next_frame(&mut self) -> Option<Frame> {
+        loop {
+            match parse(&self.received) {
+                Parsed::Frame(f, len) => {
+                    self.received.drain(..len);
+                    return Some(f);
+                }
+                Parsed::Corrupt { len, .. } | Parsed::Garbage(len) => {
+                    self.received.drain(..len);
+                }
+                Parsed::Incomplete => return None,
+            }
+        }
+    }
+
+    /// Waits for more bytes, false if none arrived before `deadline`.
+    fn fill(&mut self, deadline: Instant) -> Result<bool> {
+        let mut buf = [0u8; 512];
+        loop {
+            match self.transport.read(&mut buf) {
+                Ok(0) => {}
+                Ok(n) => {
+                    self.received.extend(&buf[..n]);
+                    return Ok(true);
+                }
+                Err(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) => {}
+                Err(e) => return Err(e.into()),
+            }
+            if Instant::now() >= deadline {
+                return Ok(false);
+            }
+        }
+    }
+}
+
+/// The same error for another block, I/O errors keep their kind.
+fn copy_error(e: &(dyn Error + Send + Sync + 'static)) -> Box<dyn Error + Send + Sync + 'static> {
+    match e.downcast_ref::<std::io::Error>() {
+        Some(io) => std::io::Error::new(io.kind(), io.to_string()).into(),
+        None => e.to_string().into(),
+    }
+}
+
+fn nak_reason(code: Option<u8>) -> &'static str {
+    match code {
+        Some(NAK_UNKNOWN_COMMAND) => "unknown command",
+        Some(NAK_INVALID_ARGUMENTS) => "invalid arguments",
+        Some(NAK_AUTH) => "not authenticated",
+        Some(NAK_BOARD) => "the board failed, see the server's log",
+        _ => "unknown reason",
+    }
+}
+
+impl Protocol for FramedProtocol {
+    fn read(&mut self, addr: Address, len: usize) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            let a = Address::from_linear(addr.linear() + data.len() as u32);
+            let chunk = (len - data.len()).min(MAX_PAYLOAD);
+            let reply = self.request(
+                CMD_READ,
+                vec![a.bank, a.high, a.low, chunk as u8, (chunk >> 8) as u8],
+            )?;
+            if reply.len() != chunk {
+                return Err(format!("expected {} bytes, got {}", chunk, reply.len()).into());
+            }
+            data.extend(reply);
+        }
+        Ok(data)
+    }
+
+    fn write_page(&mut self, page: Address, data: &[u8]) -> Result<()> {
+        self.write(page, data)
+    }
+
+    fn write(&mut self, addr: Address, data: &[u8]) -> Result<()> {
+        let mut result = Ok(());
+        self.write_blocks(&[(addr, data)], &|_| false, &mut |_, r| result = r);
+        result
+    }
+
+    fn write_blocks(
+        &mut self,
+        blocks: &[(Address, &[u8])],
+        skip: &dyn Fn(usize) -> bool,
+        done: &mut dyn FnMut(usize, Result<()>),
+    ) {
+        let mut in_flight = vec![];
+        let mut next = 0;
+        if let Err(e) = self.pipeline(blocks, skip, done, &mut in_flight, &mut next) {
+            // the transport failed, nothing left gets written
+            let unfinished = in_flight.iter().map(|w| w.index);
+            let unsent = (next..blocks.len()).filter(|i| !skip(*i));
+            for i in unfinished.chain(unsent).collect::<Vec<_>>() {
+                done(i, Err(copy_error(e.as_ref())));
+            }
+        }
+    }
+
+    fn set_shift_register(&mut self, val: u16) -> Result<()> {
+        self.request(CMD_SHIFT_REGISTER, val.to_le_bytes().to_vec())?;
+        Ok(())
+    }
+
+    fn load(&mut self, addr: Address) -> Result<()> {
+        self.request(CMD_LOAD, vec![addr.bank, addr.high, addr.low])?;
+        Ok(())
+    }
+}
@@ -1,4 +1,275 @@
+mod bus;
+pub mod frame;
+mod framed;
+mod read_bytes;
 mod write_bytes;
-pub use write_bytes::write_bytes_to_addr;
+pub use bus::{load_addr, set_shift_register};
+pub use framed::{FramedProtocol, Handshake, LinkOptions, PROTOCOL_VERSION};
+pub use read_bytes::read_bytes_from_addr;
+pub use write_bytes::{write_byte_to_addr, write_bytes_to_addr};
 
-const WRITE_BYTES: u8 = 'W' as u8;
+use crate::transport::Transport;
+use std::error::Error;
+
+const LOAD_ADDR: u8 = b'l';
+const READ_BYTE: u8 = b'r';
+const SET_SHIFT_REGISTER: u8 = b's';
+const WRITE_BYTE: u8 = b'w';
+const WRITE_BYTES: u8 = b'W';
+
+/// Banks the firmware can select, each one is 64 KiB.
+pub const BANKS: usize = 256;
+pub const BANK_SIZE: usize = 64 * 1024;
+
+/// Board address as the firmware takes it: the bank and the high byte go to
+/// the shift register, the low byte to the address pins.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Address {
+    pub bank: u8,
+    pub high: u8,
+    pub low: u8,
+}
+
+impl Address {
+    /// `addr` counts across banks, bank `n` starts at `n * BANK_SIZE`.
+    pub fn from_linear(addr: u32) -> Address {
+        Address {
+            bank: (addr >> 16) as u8,
+            high: (addr >> 8) as u8,
+            low: addr as u8,
+        }
+    }
+
+    pub fn linear(&self) -> u32 {
+        (self.bank as u32) << 16 | (self.high as u32) << 8 | self.low as u32
+    }
+}
+
+/// Commands the board understands, over either protocol.
+pub trait Protocol: Send {
+    fn read(
+        &mut self,
+        addr: Address,
+        len: usize,
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync + 'static>>;
+
+    /// Writes the 256 bytes page starting at `page`.
+    fn write_page(
+        &mut self,
+        page: Address,
+        data: &[u8],
+    ) -> Result<(), Box<dyn Error + Send + Sync + 'static>>;
+
+    /// Writes part of a page, `data` must not cross into the next one.
+    fn write(
+        &mut self,
+        addr: Address,
+        data: &[u8],
+    ) -> Result<(), Box<dyn Error + Send + Sync + 'static>>;
+
+    /// Writes each block, whole pages or parts of one, and calls `done`
+    /// with its index once it's written or has failed. Blocks `skip` returns
+    /// true for just before they're sent are left out and get no call. A
+    /// protocol that can pipelines them, so the calls can come in any
+    /// order.
+    fn write_blocks(
+        &mut self,
+        blocks: &[(Address, &[u8])],
+        skip: &dyn Fn(usize) -> bool,
+        done: &mut dyn FnMut(usize, Result<(), Box<dyn Error + Send + Sync + 'static>>),
+    ) {
+        for (i, (addr, data)) in blocks.iter().enumerate() {
+            if skip(i) {
+                continue;
+            }
+            let result = match (addr.low, data.len()) {
+                (0, 256) => self.write_page(*addr, data),
+                _ => self.write(*addr, data),
+            };
+            done(i, result);
+        }
+    }
+
+    /// Sets the 16 bits of the shift register, bank and high address byte.
+    fn set_shift_register(
+        &mut self,
+        val: u16,
+    ) -> Result<(), Box<dyn Error + Send + Sync + 'static>>;
+
+    /// Puts `addr` on the bus and strobes a read, for probing the bus.
+    fn load(&mut self, addr: Address) -> Result<(), Box<dyn Error + Send + Sync + 'static>>;
+}
+
+/// The firmware's original single character commands, there is no error
+/// detection so a lost byte desynchronises it.
+pub struct RawProtocol {
+    pub transport: Box<dyn Transport>,
+}
+
+impl Protocol for RawProtocol {
+    fn read(
+        &mut self,
+        addr: Address,
+        len: usize,
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync + 'static>> {
+        read_bytes_from_addr(self.transport.as_mut(), addr, len)
+    }
+
+    fn write_page(
+        &mut self,
+        page: Address,
+        data: &[u8],
+    ) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+        write_bytes_to_addr(self.transport.as_mut(), page, data)
+    }
+
+    fn write(
+        &mut self,
+        addr: Address,
+        data: &[u8],
+    ) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+        for (i, b) in data.iter().enumerate() {
+            let a = Address::from_linear(addr.linear() + i as u32);
+            write_byte_to_addr(self.transport.as_mut(), a, *b)?;
+        }
+        Ok(())
+    }
+
+    fn set_shift_register(
+        &mut self,
+        val: u16,
+    ) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+        set_shift_register(self.transport.as_mut(), val)
+    }
+
+    fn load(&mut self, addr: Address) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+        load_addr(self.transport.as_mut(), addr)
+    }
+}
+
+fn read_byte(serial_port: &mut dyn Transport) -> std::io::Result<u8> {
+    let mut buf = [0u8; 1];
+    serial_port.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+/// Reads a reply of the raw firmware and checks it is `expected`. The Pico
+/// SDK can print each `\n` as `\r\n`, either is accepted.
+fn read_reply(serial_port: &mut dyn Transport, expected: &[u8]) -> std::io::Result<bool> {
+    for e in expected {
+        let mut b = read_byte(serial_port)?;
+        if *e == b'\n' && b == b'\r' {
+            b = read_byte(serial_port)?;
+        }
+        if b != *e {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Uses the framed protocol, or the raw one if the firmware doesn't know it.
+pub fn negotiate(
+    transport: Box<dyn Transport>,
+    options: LinkOptions,
+) -> Result<Box<dyn Protocol>, Box<dyn Error + Send + Sync + 'static>> {
+    let mut framed = FramedProtocol::new(transport);
+    framed.reply_timeout = options.reply;
+    framed.retries = options.retries;
+    framed.window = options.window;
+    framed.compress = options.compress;
+    match framed.handshake()? {
+        Handshake::Framed => Ok(Box::new(framed)),
+        Handshake::Legacy => Ok(Box::new(RawProtocol {
+            transport: framed.transport,
+        })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::{
+        load_addr, read_bytes_from_addr, set_shift_register, write_byte_to_addr,
+        write_bytes_to_addr, Address,
+    };
+    use crate::simulator::Simulator;
+    use crate::transport::MemoryTransport;
+    use std::time::Duration;
+
+    #[test]
+    fn commands() {
+        let mut t = MemoryTransport::new(b"r: '\x12'\nr: '\x34'\n");
+        assert_eq!(
+            vec![0x12, 0x34],
+            read_bytes_from_addr(&mut t, Address::from_linear(0x1FFFF), 2).unwrap()
+        );
+        assert_eq!(b"r\x01\xFF\xFFr\x02\x00\x00".to_vec(), t.outgoing);
+
+        let mut t = MemoryTransport::new(b"r: 'x-\n");
+        assert_eq!(
+            "unexpected response reading 000000h",
+            read_bytes_from_addr(&mut t, Address::from_linear(0), 1)
+                .unwrap_err()
+                .to_string()
+        );
+
+        let mut t = MemoryTransport::new(b"a\n");
+        let page = Address {
+            bank: 2,
+            high: 0x80,
+            low: 0,
+        };
+        write_bytes_to_addr(&mut t, page, &[0; 256]).unwrap();
+        assert_eq!(b"W\x02\x80".to_vec(), t.outgoing[..3].to_vec());
+        assert_eq!(3 + 256, t.outgoing.len());
+
+        let unaligned = Address::from_linear(0x8001);
+        assert!(write_bytes_to_addr(&mut t, unaligned, &[0; 256]).is_err());
+
+        let mut t = MemoryTransport::new(b"a\n");
+        write_byte_to_addr(&mut t, Address::from_linear(0x12345), 0xAB).unwrap();
+        assert_eq!(b"w\x01\x23\x45\xAB".to_vec(), t.outgoing);
+
+        let mut t = MemoryTransport::new(b"s: '4660'\n - \n\nl\n");
+        set_shift_register(&mut t, 0x1234).unwrap();
+        load_addr(&mut t, Address::from_linear(0x10203)).unwrap();
+        assert_eq!(b"s4660\nl\x01\x02\x03".to_vec(), t.outgoing);
+    }
+
+    #[test]
+    fn crlf_replies() {
+        // the data byte is printed as it is, a 0Ah one becomes \r\n too
+        let mut t =
+            MemoryTransport::new(b"r: '\x12'\r\nr: '\r\n'\r\nr: '\r'\r\nr: '\n'\nr: '\r'\n");
+        assert_eq!(
+            vec![0x12, 0x0A, 0x0D, 0x0A, 0x0D],
+            read_bytes_from_addr(&mut t, Address::from_linear(0), 5).unwrap()
+        );
+
+        let mut t = MemoryTransport::new(b"a\r\na\r\ns: '4660'\r\n - \r\n\r\nl\r\n");
+        write_bytes_to_addr(&mut t, Address::from_linear(0x100), &[0; 256]).unwrap();
+        write_byte_to_addr(&mut t, Address::from_linear(0), 1).unwrap();
+        set_shift_register(&mut t, 0x1234).unwrap();
+        load_addr(&mut t, Address::from_linear(0)).unwrap();
+
+        let mut t = MemoryTransport::new(b"a\r\r\n");
+        assert!(write_byte_to_addr(&mut t, Address::from_linear(0), 1).is_err());
+    }
+
+    #[test]
+    fn slow_load() {
+        // the firmware holds the address for 50ms, a single read gives up
+        // after 10ms
+        let mut sim = Simulator::new(2);
+        sim.load_delay = Duration::from_millis(50);
+        load_addr(&mut sim, Address::from_linear(0x10203)).unwrap();
+        assert_eq!(Some(0x10203), sim.loaded);
+        assert_eq!(
+            vec![0],
+            read_bytes_from_addr(&mut sim, Address::from_linear(0), 1).unwrap()
+        );
+
+        sim.load_delay = Duration::from_millis(500);
+        assert!(load_addr(&mut sim, Address::from_linear(0)).is_err());
+    }
+}
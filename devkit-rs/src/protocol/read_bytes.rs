@@ -0,0 +1,39 @@
+use crate::protocol::{read_byte, read_reply, Address, READ_BYTE};
+use crate::transport::Transport;
+use std::error::Error;
+
+/// Reads `len` bytes starting at `addr`, continuing into the next banks,
+/// one `r` command per byte. The firmware answers each one with
+/// `r: '<byte>'\n`, or `\r\n` at the end with CRLF output.
+pub fn read_bytes_from_addr(
+    serial_port: &mut dyn Transport,
+    addr: Address,
+    len: usize,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync + 'static>> {
+    let mut data = Vec::with_capacity(len);
+
+    for i in 0..len {
+        let a = Address::from_linear(addr.linear() + i as u32);
+        serial_port.write_all(&[READ_BYTE, a.bank, a.high, a.low])?;
+
+        let unexpected = || format!("unexpected response reading {:06X}h", a.linear());
+        if !read_reply(serial_port, b"r: '")? {
+            return Err(unexpected().into());
+        }
+        // with CRLF output a 0Ah byte arrives as \r\n
+        let (val, rest) = match read_byte(serial_port)? {
+            b'\r' => match read_byte(serial_port)? {
+                b'\n' => (b'\n', &b"'\n"[..]),
+                b'\'' => (b'\r', &b"\n"[..]),
+                _ => return Err(unexpected().into()),
+            },
+            b => (b, &b"'\n"[..]),
+        };
+        if !read_reply(serial_port, rest)? {
+            return Err(unexpected().into());
+        }
+        data.push(val);
+    }
+
+    Ok(data)
+}
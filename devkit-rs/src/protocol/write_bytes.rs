@@ -1,25 +1,43 @@
-use crate::protocol::WRITE_BYTES;
-use serialport::SerialPort;
+use crate::protocol::{read_reply, Address, WRITE_BYTE, WRITE_BYTES};
+use crate::transport::Transport;
 use std::error::Error;
-use std::io::{Read, Write};
 
+/// Writes the 256 bytes page starting at `page`, the firmware takes
+/// `W bank addr_high <data>` so `page.low` must be 0.
 pub fn write_bytes_to_addr(
-    serial_port: &mut Box<dyn SerialPort>,
-    addr_high: u16,
+    serial_port: &mut dyn Transport,
+    page: Address,
     data: &[u8],
 ) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
-    let addr_cmd = [
-        WRITE_BYTES,
-        (addr_high % 256) as u8,
-        (addr_high / 256) as u8,
-    ];
-    serial_port.write(&addr_cmd)?;
-    serial_port.write(data)?;
+    if page.low != 0 || data.len() != 256 {
+        return Err(format!(
+            "writes must be 256 bytes at the start of a page, got {} at {:06X}h",
+            data.len(),
+            page.linear()
+        )
+        .into());
+    }
+
+    let addr_cmd = [WRITE_BYTES, page.bank, page.high];
+    serial_port.write_all(&addr_cmd)?;
+    serial_port.write_all(data)?;
+
+    read_ack(serial_port)
+}
 
-    let mut buf = [0u8; 1];
-    serial_port.read_exact(&mut buf)?;
+/// Writes a single byte with `w bank addr_high addr_low <byte>`.
+pub fn write_byte_to_addr(
+    serial_port: &mut dyn Transport,
+    addr: Address,
+    val: u8,
+) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    serial_port.write_all(&[WRITE_BYTE, addr.bank, addr.high, addr.low, val])?;
+    read_ack(serial_port)
+}
 
-    if buf[0] == 'a' as u8 {
+/// The firmware answers writes with `a\n`.
+fn read_ack(serial_port: &mut dyn Transport) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    if read_reply(serial_port, b"a\n")? {
         Ok(())
     } else {
         Err("unexpected response".into())
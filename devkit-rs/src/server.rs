@@ -0,0 +1,338 @@
+use crate::devkit::{ConnectionState, Devkit, DevkitResult, Pending, Request, RequestError};
+use crate::protocol::frame::{
+    parse, rle_decode, Frame, Parsed, CMD_AUTH, CMD_HELLO, CMD_LOAD, CMD_READ, CMD_SHIFT_REGISTER,
+    CMD_WRITE, CMD_WRITE_RLE, MAX_PAYLOAD, NAK_AUTH, NAK_BOARD, NAK_CRC, NAK_INVALID_ARGUMENTS,
+    NAK_UNKNOWN_COMMAND, REPLY_ACK, REPLY_NAK, SERVER_GREETING,
+};
+use crate::protocol::{Address, BANKS, BANK_SIZE, PROTOCOL_VERSION};
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the listener checks the board is still there between clients.
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+/// How long a client has to send the token.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Shares `devkit` with the clients connecting to `listener`. Each one is
+/// greeted with `SERVER_GREETING` and must answer with `CMD_AUTH` and
+/// `token`, then it talks to the server as to a board with the framed
+/// protocol. Requests from several clients are run in the order they
+/// arrive. Returns when the connection to the board is lost.
+pub fn serve(devkit: &Devkit, listener: TcpListener, token: &str) -> DevkitResult<()> {
+    listener.set_nonblocking(true)?;
+    let clients = Mutex::new(vec![]);
+
+    thread::scope(|scope| {
+        let result = loop {
+            if let ConnectionState::Disconnected(reason) = devkit.state() {
+                break Err(format!("lost the board: {}", reason).into());
+            }
+            let (stream, peer) = match listener.accept() {
+                Ok(client) => client,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(ACCEPT_INTERVAL);
+                    continue;
+                }
+                Err(e) => break Err(e.into()),
+            };
+            let socket = stream.try_clone().ok();
+            if let Some(s) = socket.as_ref().and_then(|s| s.try_clone().ok()) {
+                clients.lock().unwrap().push(s);
+            }
+            scope.spawn(move || {
+                println!("{} connected", peer);
+                match session(devkit, stream, token) {
+                    Ok(()) => println!("{} disconnected", peer),
+                    Err(e) => eprintln!("{}: {}", peer, e),
+                }
+                // the clients list still holds the socket open
+                if let Some(s) = socket {
+                    let _ = s.shutdown(Shutdown::Both);
+                }
+            });
+        };
+        // the sessions end once their sockets are closed
+        for client in clients.lock().unwrap().iter() {
+            let _ = client.shutdown(Shutdown::Both);
+        }
+        result
+    })
+}
+
+/// Answer to a request, the writes are still running.
+enum Reply {
+    Pending(Pending),
+    Ready(u8, Vec<u8>),
+}
+
+/// Serves one client until it disconnects.
+fn session(devkit: &Devkit, mut stream: TcpStream, token: &str) -> DevkitResult<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_nodelay(true)?;
+    let greeting = Frame {
+        seq: 0,
+        cmd: SERVER_GREETING,
+        payload: vec![PROTOCOL_VERSION],
+    };
+    stream.write_all(&greeting.encode())?;
+
+    let mut authenticated = false;
+    let deadline = Instant::now() + AUTH_TIMEOUT;
+    let mut received = vec![];
+    let mut queue = VecDeque::new();
+    // sequence number and encoded frame of the last reply, for repeated
+    // requests like the firmware
+    let mut last_reply: Option<(u8, Vec<u8>)> = None;
+    let mut buf = [0u8; 4096];
+
+    loop {
+        if !authenticated {
+            match deadline.checked_duration_since(Instant::now()) {
+                Some(left) if !left.is_zero() => stream.set_read_timeout(Some(left))?,
+                _ => return Err("no token in time".into()),
+            }
+        }
+        let len = match stream.read(&mut buf) {
+            Err(e)
+                if !authenticated
+                    && matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+            {
+                return Err("no token in time".into())
+            }
+            len => len?,
+        };
+        if len == 0 {
+            return Ok(());
+        }
+        received.extend(&buf[..len]);
+
+        loop {
+            let frame = match parse(&received) {
+                Parsed::Incomplete => break,
+                Parsed::Garbage(len) => {
+                    received.drain(..len);
+                    continue;
+                }
+                Parsed::Corrupt { seq, len } => {
+                    received.drain(..len);
+                    queue.push_back((seq, Reply::Ready(REPLY_NAK, vec![NAK_CRC])));
+                    continue;
+                }
+                Parsed::Frame(frame, len) => {
+                    received.drain(..len);
+                    frame
+                }
+            };
+
+            if !authenticated {
+                if frame.cmd != CMD_AUTH || !same_token(&frame.payload, token.as_bytes()) {
+                    let nak = Frame {
+                        seq: frame.seq,
+                        cmd: REPLY_NAK,
+                        payload: vec![NAK_AUTH],
+                    };
+                    stream.write_all(&nak.encode())?;
+                    return Err("wrong token".into());
+                }
+                authenticated = true;
+                stream.set_read_timeout(None)?;
+                let ack = Frame {
+                    seq: frame.seq,
+                    cmd: REPLY_ACK,
+                    payload: vec![],
+                };
+                stream.write_all(&ack.encode())?;
+                continue;
+            }
+
+            // a repeated request means the reply got lost, don't run it twice
+            if queue.iter().any(|(seq, _)| *seq == frame.seq) {
+                continue;
+            }
+            if let Some((seq, reply)) = &last_reply {
+                if queue.is_empty() && *seq == frame.seq {
+                    stream.write_all(reply)?;
+                    continue;
+                }
+            }
+
+            let reply = match frame.cmd {
+                CMD_WRITE | CMD_WRITE_RLE => write(devkit, &frame),
+                _ => {
+                    // the writes before it run first
+                    flush(&mut stream, &mut queue, &mut last_reply)?;
+                    let (cmd, payload) = run(devkit, &frame);
+                    Reply::Ready(cmd, payload)
+                }
+            };
+            queue.push_back((frame.seq, reply));
+        }
+        flush(&mut stream, &mut queue, &mut last_reply)?;
+    }
+}
+
+/// Compares the tokens in a time that doesn't depend on where they differ.
+fn same_token(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Sends the replies in the order the requests came.
+fn flush(
+    stream: &mut TcpStream,
+    queue: &mut VecDeque<(u8, Reply)>,
+    last_reply: &mut Option<(u8, Vec<u8>)>,
+) -> DevkitResult<()> {
+    while let Some((seq, reply)) = queue.pop_front() {
+        let (cmd, payload) = match reply {
+            Reply::Pending(pending) => answer(pending.wait().map(|_| vec![])),
+            Reply::Ready(cmd, payload) => (cmd, payload),
+        };
+        // NAKs for bad frames are not answers, the request will come again
+        let bad_frame = cmd == REPLY_NAK && payload == [NAK_CRC];
+        let reply = Frame { seq, cmd, payload }.encode();
+        stream.write_all(&reply)?;
+        if !bad_frame {
+            *last_reply = Some((seq, reply));
+        }
+    }
+    Ok(())
+}
+
+/// Queues a write on the board without waiting for it.
+fn write(devkit: &Devkit, frame: &Frame) -> Reply {
+    let data = match (frame.cmd, frame.payload.get(3..)) {
+        (CMD_WRITE, Some(data)) => Some(data.to_vec()),
+        (CMD_WRITE_RLE, Some(packed)) => rle_decode(packed),
+        _ => None,
+    };
+    match (address(&frame.payload), data) {
+        (Some(addr), Some(data)) if in_memory(addr, data.len()) => {
+            Reply::Pending(devkit.request(Request::Write(addr, data)))
+        }
+        _ => Reply::Ready(REPLY_NAK, vec![NAK_INVALID_ARGUMENTS]),
+    }
+}
+
+fn run(devkit: &Devkit, frame: &Frame) -> (u8, Vec<u8>) {
+    let p = frame.payload.as_slice();
+    match (frame.cmd, p) {
+        (CMD_HELLO, [_]) => (REPLY_ACK, vec![PROTOCOL_VERSION]),
+        (CMD_READ, [_, _, _, lo, hi]) => {
+            let addr = address(p).unwrap();
+            let len = *lo as usize | (*hi as usize) << 8;
+            if len > MAX_PAYLOAD || !in_memory(addr, len) {
+                return (REPLY_NAK, vec![NAK_INVALID_ARGUMENTS]);
+            }
+            answer(devkit.read(addr, len))
+        }
+        (CMD_SHIFT_REGISTER, [lo, hi]) => answer(
+            devkit
+                .set_shift_register(u16::from_le_bytes([*lo, *hi]))
+                .map(|_| vec![]),
+        ),
+        (CMD_LOAD, [_, _, _]) => answer(devkit.load(address(p).unwrap()).map(|_| vec![])),
+        (CMD_HELLO | CMD_READ | CMD_SHIFT_REGISTER | CMD_LOAD, _) => {
+            (REPLY_NAK, vec![NAK_INVALID_ARGUMENTS])
+        }
+        _ => (REPLY_NAK, vec![NAK_UNKNOWN_COMMAND]),
+    }
+}
+
+fn answer(result: Result<Vec<u8>, RequestError>) -> (u8, Vec<u8>) {
+    match result {
+        Ok(data) => (REPLY_ACK, data),
+        Err(e) => {
+            eprintln!("board: {}", e);
+            (REPLY_NAK, vec![NAK_BOARD])
+        }
+    }
+}
+
+fn address(payload: &[u8]) -> Option<Address> {
+    match payload {
+        [bank, high, low, ..] => Some(Address {
+            bank: *bank,
+            high: *high,
+            low: *low,
+        }),
+        _ => None,
+    }
+}
+
+fn in_memory(addr: Address, len: usize) -> bool {
+    addr.linear() as usize + len <= BANKS * BANK_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::frame::SERVER_GREETING;
+    use crate::protocol::LinkOptions;
+    use crate::server::serve;
+    use crate::simulator::Simulator;
+    use crate::transport;
+    use crate::{upload_image, Address, Devkit, Image};
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    #[test]
+    fn remote_devkit() {
+        let sim = Simulator::new(1);
+        let memory = sim.memory();
+        let board = Devkit::open(Box::new(sim)).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = format!("tcp://{}", listener.local_addr().unwrap());
+        thread::spawn(move || serve(&board, listener, "bench-42"));
+
+        let err = |token| transport::open(&port, 0, token).err().unwrap().to_string();
+        assert!(err(None).contains("needs a token"));
+        assert!(err(Some("guess")).contains("refused the token"));
+
+        // the client speaks the framed protocol to the server, which
+        // forwards to a raw firmware board
+        let options = LinkOptions {
+            window: 8,
+            compress: true,
+            ..LinkOptions::default()
+        };
+        let open = || {
+            let transport = transport::open(&port, 0, Some("bench-42")).unwrap();
+            Devkit::open_with(transport, options).unwrap()
+        };
+        let (first, second) = (open(), open());
+        let image = (0..1000).map(|i| (i / 100) as u8).collect::<Vec<_>>();
+        upload_image(
+            &Image::binary(image.clone(), 0x300),
+            Image::default(),
+            None,
+            &first,
+        )
+        .unwrap();
+        assert_eq!(image, memory.lock().unwrap()[0x300..0x300 + 1000].to_vec());
+        assert_eq!(
+            image[..10].to_vec(),
+            second.read(Address::from_linear(0x300), 10).unwrap()
+        );
+        second.set_shift_register(0x0102).unwrap();
+        first.close().unwrap();
+        second.close().unwrap();
+    }
+
+    #[test]
+    fn silent_client() {
+        let board = Devkit::open(Box::new(Simulator::new(1))).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve(&board, listener, "bench-42"));
+
+        // after the greeting the server hangs up on a client without a token
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut received = vec![];
+        stream.read_to_end(&mut received).unwrap();
+        assert_eq!(SERVER_GREETING, received[2]);
+    }
+}
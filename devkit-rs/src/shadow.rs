@@ -0,0 +1,215 @@
+use crate::devkit::{Devkit, DevkitResult};
+use crate::image::{Image, Segment};
+use crate::protocol::Address;
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 4] = b"DKSH";
+const VERSION: u8 = 2;
+const PAGE: usize = 256;
+/// Pages read back to check the board still holds the shadow image.
+pub const DEFAULT_SAMPLES: usize = 4;
+
+/// What the board is believed to hold, kept on disk between sessions so an
+/// upload only writes the pages that changed. The file is
+///
+/// ```text
+/// "DKSH" version count:u32 <segment>...
+/// segment: addr:u32 len:u32 <FNV-1a hash:u32 of every 256 bytes> <data>
+/// ```
+///
+/// with little endian numbers. A file whose hashes don't match is ignored,
+/// and so is one from an older version.
+pub struct Shadow {
+    path: PathBuf,
+}
+
+impl Shadow {
+    /// `key` names the board, usually its port or the `--board` id.
+    pub fn new(dir: &Path, key: &str) -> Shadow {
+        let name = key
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect::<String>();
+        Shadow {
+            path: dir.join(format!("{}.shadow", name)),
+        }
+    }
+
+    /// The shadow in the cache directory: `$DEVKIT_CACHE_DIR`,
+    /// `$XDG_CACHE_HOME/devkit` or `~/.cache/devkit`.
+    pub fn for_board(key: &str) -> Option<Shadow> {
+        let env = |name| std::env::var_os(name).filter(|v| !v.is_empty());
+        let dir = match (env("DEVKIT_CACHE_DIR"), env("XDG_CACHE_HOME"), env("HOME")) {
+            (Some(dir), _, _) => PathBuf::from(dir),
+            (None, Some(cache), _) => Path::new(&cache).join("devkit"),
+            (None, None, Some(home)) => Path::new(&home).join(".cache").join("devkit"),
+            (None, None, None) => return None,
+        };
+        Some(Shadow::new(&dir, key))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The stored image, empty if there is none or it is damaged.
+    pub fn load(&self) -> Image {
+        match std::fs::read(&self.path) {
+            Ok(data) => decode(&data).unwrap_or_else(|| {
+                eprintln!("ignoring damaged shadow image {}", self.path.display());
+                Image::default()
+            }),
+            Err(_) => Image::default(),
+        }
+    }
+
+    /// `load`, checked against `samples` pages read back from the board.
+    /// Returns an empty image if any of them differ.
+    pub fn load_validated(&self, devkit: &Devkit, samples: usize) -> DevkitResult<Image> {
+        let image = self.load();
+        if image.is_empty() || validate(&image, devkit, samples)? {
+            Ok(image)
+        } else {
+            println!("board memory changed since the last upload, writing everything");
+            Ok(Image::default())
+        }
+    }
+
+    /// Writes to a temporary file first so a crash never leaves half an
+    /// image behind.
+    pub fn store(&self, image: &Image) -> DevkitResult<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, encode(image))?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    /// Forgets the image, done before an upload so an interrupted one
+    /// doesn't leave a shadow the board doesn't match.
+    pub fn invalidate(&self) -> DevkitResult<()> {
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// FNV-1a.
+pub fn hash(data: &[u8]) -> u32 {
+    data.iter().fold(0x811C9DC5u32, |h, b| {
+        (h ^ *b as u32).wrapping_mul(0x01000193)
+    })
+}
+
+pub fn encode(image: &Image) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+    out.extend((image.segments().len() as u32).to_le_bytes());
+    for s in image.segments() {
+        out.extend(s.addr.to_le_bytes());
+        out.extend((s.data.len() as u32).to_le_bytes());
+        for page in s.data.chunks(PAGE) {
+            out.extend(hash(page).to_le_bytes());
+        }
+        out.extend(&s.data);
+    }
+    out
+}
+
+pub fn decode(data: &[u8]) -> Option<Image> {
+    let mut rest = data.strip_prefix(MAGIC)?.strip_prefix(&[VERSION][..])?;
+    let mut take = |len: usize| {
+        let (taken, left) = rest.split_at_checked(len)?;
+        rest = left;
+        Some(taken)
+    };
+    let number = |b: &[u8]| u32::from_le_bytes(b.try_into().unwrap());
+
+    let count = number(take(4)?);
+    let mut segments = vec![];
+    for _ in 0..count {
+        let addr = number(take(4)?);
+        let len = number(take(4)?) as usize;
+        let hashes = take(len.div_ceil(PAGE) * 4)?;
+        let data = take(len)?;
+        let intact = data
+            .chunks(PAGE)
+            .zip(hashes.chunks(4))
+            .all(|(page, h)| hash(page).to_le_bytes() == h);
+        if !intact {
+            return None;
+        }
+        segments.push(Segment {
+            addr,
+            data: data.to_vec(),
+        });
+    }
+    if !rest.is_empty() {
+        return None;
+    }
+    Image::new(segments).ok()
+}
+
+/// Reads back `samples` pages spread over the image, true if they all match.
+pub fn validate(image: &Image, devkit: &Devkit, samples: usize) -> DevkitResult<bool> {
+    let pages = image
+        .segments()
+        .iter()
+        .flat_map(|s| {
+            s.data
+                .chunks(PAGE)
+                .enumerate()
+                .map(|(i, data)| (s.addr + (i * PAGE) as u32, data))
+        })
+        .collect::<Vec<_>>();
+    let mut checked = (0..samples.min(pages.len()))
+        .map(|k| k * pages.len() / samples.min(pages.len()))
+        .collect::<Vec<_>>();
+    if samples > 0 && !pages.is_empty() {
+        checked.push(pages.len() - 1);
+    }
+    checked.dedup();
+
+    for page in checked {
+        let (addr, expected) = pages[page];
+        let actual = devkit.read(Address::from_linear(addr), expected.len())?;
+        if actual != expected {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::image::{Image, Segment};
+    use crate::shadow::{decode, encode};
+
+    #[test]
+    fn shadow_format() {
+        let image = Image::new(vec![
+            Segment {
+                addr: 0x100,
+                data: (0..600).map(|i| (i * 5) as u8).collect(),
+            },
+            Segment {
+                addr: 0x30000,
+                data: vec![1, 2, 3],
+            },
+        ])
+        .unwrap();
+        let data = encode(&image);
+        assert_eq!(Some(image), decode(&data));
+        assert_eq!(Some(Image::default()), decode(&encode(&Image::default())));
+
+        let mut damaged = data.clone();
+        *damaged.last_mut().unwrap() ^= 1;
+        assert_eq!(None, decode(&damaged));
+        assert_eq!(None, decode(&data[..data.len() - 1]));
+        assert_eq!(None, decode(b"DKSH"));
+        assert_eq!(None, decode(b"DKSH\x01\x00\x00\x00\x00"));
+    }
+}
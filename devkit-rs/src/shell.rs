@@ -0,0 +1,168 @@
+use crate::assemble::build;
+use crate::config::Config;
+use crate::devkit::{Devkit, DevkitResult};
+use crate::dump::hexdump;
+use crate::image::{Image, Segment};
+use crate::protocol::Address;
+use crate::shadow::{Shadow, DEFAULT_SAMPLES};
+use crate::{parse_number, upload_image, verify_image};
+use std::io::BufRead;
+
+/// Interactive session: `u [file...]` uploads the file, or assembles and
+/// uploads the `.z80` sources, at the configured upload address (only the
+/// blocks that changed since the last upload, or since the one stored in
+/// `shadow`), `v` checks the board still holds the last upload, `d addr len`
+/// dumps memory, `q` quits. `peek`, `poke`, `fill` and `copy` patch single
+/// bytes and ranges without uploading a whole image.
+pub fn shell(devkit: Devkit, shadow: Option<Shadow>, config: &Config) -> DevkitResult<()> {
+    let mut current_mem = match &shadow {
+        Some(shadow) => shadow.load_validated(&devkit, DEFAULT_SAMPLES)?,
+        None => Image::default(),
+    };
+    let stdin = std::io::stdin();
+
+    loop {
+        let mut cmd = String::new();
+        if stdin.lock().read_line(&mut cmd)? == 0 {
+            break;
+        }
+        let args = cmd
+            .split(" ")
+            .map(|a| a.trim())
+            .filter(|a| !a.is_empty())
+            .collect::<Vec<_>>();
+
+        if args.is_empty() {
+            println!("u [file...]: upload file or sources, v: verify, d addr len: dump, q: quit");
+            println!("peek addr [len], poke addr byte..., fill start end byte, copy src dst len");
+            continue;
+        }
+
+        match args[0] {
+            "u" => {
+                let files = match &args[1..] {
+                    [] => vec!["data"],
+                    files => files.to_vec(),
+                };
+                println!("opening: {}", files.join(" "));
+                let image = build(&files, config.upload_address)
+                    .and_then(|b| config.check_image(&b.image).map(|_| b.image));
+                match image {
+                    Ok(image) => {
+                        current_mem = upload_image(&image, current_mem, shadow.as_ref(), &devkit)?;
+                    }
+                    Err(e) => println!("{}", e),
+                }
+            }
+            "v" => match verify_image(&current_mem, None, &devkit)? {
+                0 => println!("ok, {} bytes match", current_mem.len()),
+                n => println!("{} bytes differ", n),
+            },
+            "d" => match (
+                args.get(1).and_then(|a| parse_number(a)),
+                args.get(2).and_then(|l| parse_number(l)),
+            ) {
+                (Some(addr), Some(len)) => {
+                    let data = devkit.read(Address::from_linear(addr), len as usize)?;
+                    print!("{}", hexdump(addr, &data));
+                }
+                _ => println!("usage: d addr len"),
+            },
+            "peek" => match numbers(&args[1..]).as_deref() {
+                Some([addr]) => print!("{}", hexdump(*addr, &read(&devkit, config, *addr, 1)?)),
+                Some([addr, len]) => {
+                    print!("{}", hexdump(*addr, &read(&devkit, config, *addr, *len)?))
+                }
+                _ => println!("usage: peek addr [len]"),
+            },
+            "poke" => match numbers(&args[1..]).as_deref() {
+                Some([addr, data @ ..]) if !data.is_empty() && data.iter().all(|b| *b < 256) => {
+                    let data = data.iter().map(|b| *b as u8).collect();
+                    patch(
+                        &devkit,
+                        config,
+                        *addr,
+                        data,
+                        &mut current_mem,
+                        shadow.as_ref(),
+                    )?;
+                }
+                _ => println!("usage: poke addr byte..."),
+            },
+            "fill" => match numbers(&args[1..]).as_deref() {
+                Some([start, end, val]) if start <= end && *val < 256 => {
+                    let data = vec![*val as u8; (end - start + 1) as usize];
+                    patch(
+                        &devkit,
+                        config,
+                        *start,
+                        data,
+                        &mut current_mem,
+                        shadow.as_ref(),
+                    )?;
+                }
+                _ => println!("usage: fill start end byte, end is included"),
+            },
+            "copy" => match numbers(&args[1..]).as_deref() {
+                Some([src, dst, len]) => {
+                    let data = read(&devkit, config, *src, *len)?;
+                    patch(
+                        &devkit,
+                        config,
+                        *dst,
+                        data,
+                        &mut current_mem,
+                        shadow.as_ref(),
+                    )?;
+                }
+                _ => println!("usage: copy src dst len"),
+            },
+            "q" => {
+                break;
+            }
+            &_ => {}
+        }
+    }
+
+    devkit.close()
+}
+
+/// All the arguments as numbers, `None` if one isn't.
+fn numbers(args: &[&str]) -> Option<Vec<u32>> {
+    args.iter().map(|a| parse_number(a)).collect()
+}
+
+fn in_range(config: &Config, addr: u32, len: usize) -> DevkitResult<()> {
+    if addr as usize + len > config.memory_size {
+        return Err(format!("{:X}h + {:X}h is past the end of memory", addr, len).into());
+    }
+    Ok(())
+}
+
+fn read(devkit: &Devkit, config: &Config, addr: u32, len: u32) -> DevkitResult<Vec<u8>> {
+    in_range(config, addr, len as usize)?;
+    Ok(devkit.read(Address::from_linear(addr), len as usize)?)
+}
+
+/// Writes `data` at `addr` and adds it to what the board is known to hold,
+/// and to the shadow.
+fn patch(
+    devkit: &Devkit,
+    config: &Config,
+    addr: u32,
+    data: Vec<u8>,
+    current_mem: &mut Image,
+    shadow: Option<&Shadow>,
+) -> DevkitResult<()> {
+    in_range(config, addr, data.len())?;
+    if let Some(shadow) = shadow {
+        shadow.invalidate()?;
+    }
+    devkit.write(Address::from_linear(addr), data.clone())?;
+
+    current_mem.overwrite(Segment { addr, data });
+    if let Some(shadow) = shadow {
+        shadow.store(current_mem)?;
+    }
+    Ok(())
+}
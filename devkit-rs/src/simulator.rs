@@ -0,0 +1,434 @@
+use crate::protocol::frame::{
+    parse, rle_decode, Frame, Parsed, CMD_HELLO, CMD_LOAD, CMD_READ, CMD_SHIFT_REGISTER, CMD_WRITE,
+    CMD_WRITE_RLE, MAX_PAYLOAD, NAK_CRC, NAK_INVALID_ARGUMENTS, NAK_UNKNOWN_COMMAND, REPLY_ACK,
+    REPLY_NAK,
+};
+use crate::protocol::{BANK_SIZE, PROTOCOL_VERSION};
+use crate::transport::{Transport, READ_TIMEOUT};
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Model of the Pico firmware's command loop (`devkit-fw/main.c`) backed by
+/// a memory array, addresses are `bank * 64 KiB + addr_high * 256 +
+/// addr_low`:
+///
+/// - `l bank high low` loads the address on the bus, answers `l\n`
+/// - `r bank high low` answers `r: '<byte>'\n`
+/// - `s <decimal>` sets the shift register, answers `s: '<value>'\n`
+/// - `w bank high low byte` answers `a\n`
+/// - `W bank high <256 bytes>` answers `a\n`
+///
+/// anything else is echoed back as ` - <char>\n`. With `Firmware::Framed` it
+/// speaks the framed protocol instead, see `protocol::frame`.
+pub struct Simulator {
+    memory: Arc<Mutex<Vec<u8>>>,
+    pub firmware: Firmware,
+    /// The framed firmware understands `CMD_WRITE_RLE`.
+    pub rle: bool,
+    pub shift_register: u32,
+    /// The next replies are lost on the way back.
+    pub lose_replies: usize,
+    /// The next frames are received with a bad CRC.
+    pub corrupt_requests: usize,
+    /// Sent before the next reply.
+    pub noise: Vec<u8>,
+    /// Data bits that always read as 1.
+    pub stuck_high: u8,
+    /// Data bits that always read as 0.
+    pub stuck_low: u8,
+    /// Address bits, of the linear address, that are never driven and read
+    /// as 0.
+    pub open_address_lines: usize,
+    /// Last address strobed with `l`.
+    pub loaded: Option<usize>,
+    /// How long the address is held before the load is answered, 50ms on
+    /// the board.
+    pub load_delay: Duration,
+    received: Vec<u8>,
+    responses: VecDeque<u8>,
+    /// Sequence number and encoded frame of the last framed reply.
+    last_reply: Option<(u8, Vec<u8>)>,
+    /// Nothing is answered before then.
+    busy_until: Option<Instant>,
+    timeout: Duration,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Firmware {
+    Raw,
+    Framed,
+}
+
+impl Simulator {
+    pub fn new(banks: usize) -> Self {
+        Simulator {
+            memory: Arc::new(Mutex::new(vec![0; banks * BANK_SIZE])),
+            firmware: Firmware::Raw,
+            rle: true,
+            shift_register: 0,
+            lose_replies: 0,
+            corrupt_requests: 0,
+            noise: vec![],
+            stuck_high: 0,
+            stuck_low: 0,
+            open_address_lines: 0,
+            loaded: None,
+            load_delay: Duration::ZERO,
+            received: vec![],
+            responses: VecDeque::new(),
+            last_reply: None,
+            busy_until: None,
+            timeout: READ_TIMEOUT,
+        }
+    }
+
+    pub fn framed(banks: usize) -> Self {
+        Simulator {
+            firmware: Firmware::Framed,
+            ..Simulator::new(banks)
+        }
+    }
+
+    /// Handle to the board memory, stays valid after the simulator is moved
+    /// into a `Devkit`.
+    pub fn memory(&self) -> Arc<Mutex<Vec<u8>>> {
+        self.memory.clone()
+    }
+
+    fn addr(bank: u8, high: u8, low: u8) -> usize {
+        bank as usize * BANK_SIZE + high as usize * 256 + low as usize
+    }
+
+    /// Byte at `addr` as the faulty bus reads it, `None` past the end.
+    fn load_byte(&self, memory: &[u8], addr: usize) -> Option<u8> {
+        let val = memory.get(addr & !self.open_address_lines)?;
+        Some((val | self.stuck_high) & !self.stuck_low)
+    }
+
+    fn store_byte(&self, memory: &mut [u8], addr: usize, val: u8) -> bool {
+        match memory.get_mut(addr & !self.open_address_lines) {
+            Some(b) => {
+                *b = val;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Runs every complete command in `received`.
+    fn process(&mut self) {
+        loop {
+            let len = match self.firmware {
+                Firmware::Raw => self.execute(),
+                Firmware::Framed => self.execute_frame(),
+            };
+            match len {
+                Some(len) => {
+                    self.received.drain(..len);
+                }
+                None => return,
+            }
+        }
+    }
+
+    fn send(&mut self, reply: Vec<u8>) {
+        if self.lose_replies > 0 {
+            self.lose_replies -= 1;
+            return;
+        }
+        self.responses.extend(self.noise.drain(..));
+        self.responses.extend(reply);
+    }
+
+    /// Executes the command at the start of `received`, returns its length or
+    /// `None` if it's still incomplete.
+    fn execute(&mut self) -> Option<usize> {
+        let cmd = *self.received.first()?;
+        let args = &self.received[1..];
+        let mut memory = self.memory.lock().unwrap();
+
+        let (len, response) = match cmd {
+            b'l' if args.len() >= 3 => {
+                self.loaded = Some(Self::addr(args[0], args[1], args[2]));
+                self.busy_until = Some(Instant::now() + self.load_delay);
+                (4, "l\n".as_bytes().to_vec())
+            }
+            b'r' if args.len() >= 3 => {
+                let val = self
+                    .load_byte(&memory, Self::addr(args[0], args[1], args[2]))
+                    .unwrap_or(0xFF);
+                let mut r = b"r: '".to_vec();
+                r.push(val);
+                r.extend(b"'\n");
+                (4, r)
+            }
+            b's' => {
+                // like `scanf("%d")`: leading whitespace, then digits up to
+                // the first other character, which is left in the input
+                let start = args.iter().position(|b| !b.is_ascii_whitespace())?;
+                let digits = args[start..].iter().position(|b| !b.is_ascii_digit())?;
+                let text = std::str::from_utf8(&args[start..start + digits]).unwrap();
+                self.shift_register = text.parse().unwrap_or(0);
+                (
+                    1 + start + digits,
+                    format!("s: '{}'\n", self.shift_register).into_bytes(),
+                )
+            }
+            b'w' if args.len() >= 4 => {
+                self.store_byte(&mut memory, Self::addr(args[0], args[1], args[2]), args[3]);
+                (5, b"a\n".to_vec())
+            }
+            b'W' if args.len() >= 2 + 256 => {
+                let start = Self::addr(args[0], args[1], 0);
+                for (i, b) in args[2..2 + 256].iter().enumerate() {
+                    self.store_byte(&mut memory, start + i, *b);
+                }
+                (3 + 256, b"a\n".to_vec())
+            }
+            b'l' | b'r' | b'w' | b'W' => return None,
+            c => (1, [&b" - "[..], &[c], b"\n"].concat()),
+        };
+
+        drop(memory);
+        self.send(response);
+        Some(len)
+    }
+
+    /// Like `execute` for the framed protocol.
+    fn execute_frame(&mut self) -> Option<usize> {
+        let (frame, len) = match parse(&self.received) {
+            Parsed::Incomplete => return None,
+            Parsed::Garbage(len) => return Some(len),
+            Parsed::Corrupt { seq, len } => {
+                self.reply(seq, REPLY_NAK, vec![NAK_CRC]);
+                return Some(len);
+            }
+            Parsed::Frame(frame, len) => (frame, len),
+        };
+
+        if self.corrupt_requests > 0 {
+            self.corrupt_requests -= 1;
+            self.reply(frame.seq, REPLY_NAK, vec![NAK_CRC]);
+            return Some(len);
+        }
+        // a repeated request means the reply got lost, don't run it twice
+        if let Some((seq, reply)) = &self.last_reply {
+            if *seq == frame.seq {
+                let reply = reply.clone();
+                self.send(reply);
+                return Some(len);
+            }
+        }
+
+        let mut memory = self.memory.lock().unwrap();
+        let linear = |p: &[u8]| p[0] as usize * BANK_SIZE + p[1] as usize * 256 + p[2] as usize;
+        let (cmd, payload) = match (frame.cmd, frame.payload.as_slice()) {
+            (CMD_HELLO, [_]) => (REPLY_ACK, vec![PROTOCOL_VERSION]),
+            (CMD_READ, [_, _, _, lo, hi]) => {
+                let start = linear(&frame.payload);
+                let count = *lo as usize | (*hi as usize) << 8;
+                let data = (start..start + count)
+                    .map(|a| self.load_byte(&memory, a))
+                    .collect::<Option<Vec<_>>>();
+                match data {
+                    Some(data) if count <= MAX_PAYLOAD => (REPLY_ACK, data),
+                    _ => (REPLY_NAK, vec![NAK_INVALID_ARGUMENTS]),
+                }
+            }
+            (CMD_WRITE, [_, _, _, data @ ..]) => {
+                self.store(&mut memory, &frame.payload, Some(data))
+            }
+            (CMD_WRITE_RLE, [_, _, _, packed @ ..]) if self.rle => {
+                self.store(&mut memory, &frame.payload, rle_decode(packed).as_deref())
+            }
+            (CMD_SHIFT_REGISTER, [lo, hi]) => {
+                self.shift_register = u16::from_le_bytes([*lo, *hi]) as u32;
+                (REPLY_ACK, vec![])
+            }
+            (CMD_LOAD, [_, _, _]) => {
+                self.loaded = Some(linear(&frame.payload));
+                self.busy_until = Some(Instant::now() + self.load_delay);
+                (REPLY_ACK, vec![])
+            }
+            (CMD_WRITE_RLE, _) if self.rle => (REPLY_NAK, vec![NAK_INVALID_ARGUMENTS]),
+            (CMD_HELLO | CMD_READ | CMD_WRITE | CMD_SHIFT_REGISTER | CMD_LOAD, _) => {
+                (REPLY_NAK, vec![NAK_INVALID_ARGUMENTS])
+            }
+            _ => (REPLY_NAK, vec![NAK_UNKNOWN_COMMAND]),
+        };
+        drop(memory);
+
+        self.reply(frame.seq, cmd, payload);
+        Some(len)
+    }
+
+    /// Writes `data` at the address at the start of `payload`, the reply to
+    /// a write command.
+    fn store(&self, memory: &mut [u8], payload: &[u8], data: Option<&[u8]>) -> (u8, Vec<u8>) {
+        let start = Self::addr(payload[0], payload[1], payload[2]);
+        match data {
+            Some(data) if start + data.len() <= memory.len() => {
+                for (i, b) in data.iter().enumerate() {
+                    self.store_byte(memory, start + i, *b);
+                }
+                (REPLY_ACK, vec![])
+            }
+            _ => (REPLY_NAK, vec![NAK_INVALID_ARGUMENTS]),
+        }
+    }
+
+    fn reply(&mut self, seq: u8, cmd: u8, payload: Vec<u8>) {
+        let reply = Frame { seq, cmd, payload }.encode();
+        // NAKs for bad frames are not answers, the request will come again
+        if cmd != REPLY_NAK {
+            self.last_reply = Some((seq, reply.clone()));
+        }
+        self.send(reply);
+    }
+}
+
+impl Read for Simulator {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if let Some(until) = self.busy_until {
+            // like a serial port, waits at most the timeout for the answer
+            let now = Instant::now();
+            if now < until {
+                std::thread::sleep(self.timeout.min(until - now));
+                if Instant::now() < until {
+                    return Err(ErrorKind::TimedOut.into());
+                }
+            }
+            self.busy_until = None;
+        }
+        if self.responses.is_empty() && !buf.is_empty() {
+            return Err(ErrorKind::TimedOut.into());
+        }
+        let len = buf.len().min(self.responses.len());
+        for (b, v) in buf.iter_mut().zip(self.responses.drain(..len)) {
+            *b = v;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for Simulator {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.received.extend_from_slice(buf);
+        self.process();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for Simulator {
+    fn set_timeout(&mut self, timeout: Duration) -> std::io::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::frame::{
+        parse, Frame, Parsed, CMD_READ, CMD_WRITE, NAK_CRC, NAK_UNKNOWN_COMMAND, REPLY_ACK,
+        REPLY_NAK,
+    };
+    use crate::protocol::BANK_SIZE;
+    use crate::simulator::Simulator;
+    use std::io::{Read, Write};
+
+    fn response(sim: &mut Simulator) -> Vec<u8> {
+        let mut out = vec![];
+        let mut buf = [0u8; 64];
+        while let Ok(n) = sim.read(&mut buf) {
+            out.extend(&buf[..n]);
+        }
+        out
+    }
+
+    #[test]
+    fn firmware_commands() {
+        let mut sim = Simulator::new(2);
+
+        sim.write_all(b"w\x01\x12\x34\xAB").unwrap();
+        assert_eq!(b"a\n".to_vec(), response(&mut sim));
+        assert_eq!(0xAB, sim.memory().lock().unwrap()[BANK_SIZE + 0x1234]);
+
+        // commands can arrive split over several writes
+        sim.write_all(b"r\x01\x12").unwrap();
+        assert!(response(&mut sim).is_empty());
+        sim.write_all(b"\x34").unwrap();
+        assert_eq!(b"r: '\xAB'\n".to_vec(), response(&mut sim));
+
+        let mut page = vec![b'W', 0, 3];
+        page.extend((0..=255).collect::<Vec<u8>>());
+        sim.write_all(&page).unwrap();
+        assert_eq!(b"a\n".to_vec(), response(&mut sim));
+        assert_eq!(
+            (0..=255).collect::<Vec<u8>>(),
+            sim.memory().lock().unwrap()[0x300..0x400].to_vec()
+        );
+
+        sim.write_all(b"s 1234\nl\x00\x00\x00x").unwrap();
+        assert_eq!(b"s: '1234'\n - \n\nl\n - x\n".to_vec(), response(&mut sim));
+        assert_eq!(1234, sim.shift_register);
+    }
+
+    fn reply(sim: &mut Simulator) -> Frame {
+        match parse(&response(sim)) {
+            Parsed::Frame(f, _) => f,
+            p => panic!("{:?}", p),
+        }
+    }
+
+    #[test]
+    fn framed_commands() {
+        let mut sim = Simulator::framed(1);
+        let write = Frame {
+            seq: 1,
+            cmd: CMD_WRITE,
+            payload: vec![0, 0x12, 0x34, 0xAB, 0xCD],
+        };
+
+        sim.corrupt_requests = 1;
+        sim.write_all(&write.encode()).unwrap();
+        assert_eq!(vec![NAK_CRC], reply(&mut sim).payload);
+        assert_eq!(0, sim.memory().lock().unwrap()[0x1234]);
+
+        sim.write_all(&write.encode()).unwrap();
+        let f = reply(&mut sim);
+        assert_eq!((1, REPLY_ACK), (f.seq, f.cmd));
+        assert_eq!(
+            vec![0xAB, 0xCD],
+            sim.memory().lock().unwrap()[0x1234..0x1236].to_vec()
+        );
+
+        // a repeated request only gets the last reply again
+        sim.memory().lock().unwrap()[0x1234] = 0;
+        sim.write_all(&write.encode()).unwrap();
+        assert_eq!(REPLY_ACK, reply(&mut sim).cmd);
+        assert_eq!(0, sim.memory().lock().unwrap()[0x1234]);
+
+        let read = Frame {
+            seq: 2,
+            cmd: CMD_READ,
+            payload: vec![0, 0x12, 0x35, 1, 0],
+        };
+        sim.write_all(&read.encode()).unwrap();
+        assert_eq!(vec![0xCD], reply(&mut sim).payload);
+
+        let unknown = Frame {
+            seq: 3,
+            cmd: 0x7F,
+            payload: vec![],
+        };
+        sim.write_all(&unknown.encode()).unwrap();
+        let f = reply(&mut sim);
+        assert_eq!((REPLY_NAK, vec![NAK_UNKNOWN_COMMAND]), (f.cmd, f.payload));
+    }
+}
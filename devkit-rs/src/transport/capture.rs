@@ -0,0 +1,174 @@
+use crate::transport::Transport;
+use std::error::Error;
+use std::fs::File;
+use std::io::{ErrorKind, LineWriter, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const HEADER: &str = "# devkit capture";
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// Bytes that went over the link at `time` since the capture started.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Record {
+    pub time: Duration,
+    pub direction: Direction,
+    pub data: Vec<u8>,
+}
+
+/// Logs everything written to and read from `inner`. The capture is a text
+/// file with one record per line:
+///
+/// ```text
+/// # devkit capture
+/// 0.000012 > A5000101000142B1
+/// 0.000430 < A50080010001...
+/// ```
+///
+/// seconds since the start, `>` for bytes sent to the board and `<` for
+/// bytes received, then the bytes in hex. Reads that time out aren't
+/// logged.
+pub struct CaptureTransport {
+    inner: Box<dyn Transport>,
+    log: Box<dyn Write + Send>,
+    start: Instant,
+}
+
+impl CaptureTransport {
+    pub fn new(
+        inner: Box<dyn Transport>,
+        mut log: Box<dyn Write + Send>,
+    ) -> std::io::Result<CaptureTransport> {
+        writeln!(log, "{}", HEADER)?;
+        Ok(CaptureTransport {
+            inner,
+            log,
+            start: Instant::now(),
+        })
+    }
+
+    pub fn create(
+        path: &Path,
+        inner: Box<dyn Transport>,
+    ) -> Result<CaptureTransport, Box<dyn Error + Send + Sync + 'static>> {
+        let file = File::create(path)
+            .map_err(|e| format!("unable to create {}: {}", path.display(), e))?;
+        Ok(CaptureTransport::new(
+            inner,
+            Box::new(LineWriter::new(file)),
+        )?)
+    }
+
+    fn record(&mut self, direction: Direction, data: &[u8]) -> std::io::Result<()> {
+        let hex = data
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<String>();
+        let dir = match direction {
+            Direction::Sent => '>',
+            Direction::Received => '<',
+        };
+        let time = self.start.elapsed().as_secs_f64();
+        writeln!(self.log, "{:.6} {} {}", time, dir, hex)
+    }
+}
+
+impl Read for CaptureTransport {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        if len > 0 {
+            self.record(Direction::Received, &buf[..len])?;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for CaptureTransport {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.record(Direction::Sent, &buf[..len])?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.log.flush()?;
+        self.inner.flush()
+    }
+}
+
+impl Transport for CaptureTransport {
+    fn set_timeout(&mut self, timeout: Duration) -> std::io::Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+}
+
+/// Reads a capture written by `CaptureTransport`.
+pub fn parse(text: &str) -> Result<Vec<Record>, Box<dyn Error + Send + Sync + 'static>> {
+    let mut lines = text.lines().enumerate();
+    if lines.next().map(|(_, l)| l.trim()) != Some(HEADER) {
+        return Err("not a devkit capture".into());
+    }
+
+    let mut records = vec![];
+    for (i, line) in lines {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid = || format!("line {}: invalid record", i + 1);
+        let (time, direction, hex) = match line.split_whitespace().collect::<Vec<_>>()[..] {
+            [time, ">", hex] => (time, Direction::Sent, hex),
+            [time, "<", hex] => (time, Direction::Received, hex),
+            _ => return Err(invalid().into()),
+        };
+        let time = time
+            .parse::<f64>()
+            .ok()
+            .and_then(|t| Duration::try_from_secs_f64(t).ok())
+            .ok_or_else(invalid)?;
+        let data = (0..hex.len())
+            .step_by(2)
+            .map(|i| {
+                hex.get(i..i + 2)
+                    .and_then(|b| u8::from_str_radix(b, 16).ok())
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid)?;
+        records.push(Record {
+            time,
+            direction,
+            data,
+        });
+    }
+    Ok(records)
+}
+
+pub fn load(path: &Path) -> Result<Vec<Record>, Box<dyn Error + Send + Sync + 'static>> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("unable to read {}: {}", path.display(), e))?;
+    parse(&text).map_err(|e| format!("{}: {}", path.display(), e).into())
+}
+
+/// Sends the captured requests to `transport` in the same order, returns
+/// everything it answered. After each write the replies are read until the
+/// transport times out.
+pub fn replay(records: &[Record], transport: &mut dyn Transport) -> std::io::Result<Vec<u8>> {
+    let mut received = vec![];
+    let mut buf = [0u8; 512];
+    for r in records.iter().filter(|r| r.direction == Direction::Sent) {
+        transport.write_all(&r.data)?;
+        loop {
+            match transport.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => received.extend(&buf[..n]),
+                Err(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) => break,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(received)
+}
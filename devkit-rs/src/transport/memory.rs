@@ -0,0 +1,51 @@
+use crate::transport::Transport;
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::time::Duration;
+
+/// Canned responses for tests: reads come from `incoming` and time out when
+/// it is empty, writes are collected in `outgoing`.
+#[derive(Default)]
+pub struct MemoryTransport {
+    pub incoming: VecDeque<u8>,
+    pub outgoing: Vec<u8>,
+}
+
+impl MemoryTransport {
+    pub fn new(incoming: &[u8]) -> Self {
+        MemoryTransport {
+            incoming: incoming.iter().copied().collect(),
+            outgoing: vec![],
+        }
+    }
+}
+
+impl Read for MemoryTransport {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.incoming.is_empty() && !buf.is_empty() {
+            return Err(ErrorKind::TimedOut.into());
+        }
+        let len = buf.len().min(self.incoming.len());
+        for (b, v) in buf.iter_mut().zip(self.incoming.drain(..len)) {
+            *b = v;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for MemoryTransport {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.outgoing.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn set_timeout(&mut self, _timeout: Duration) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,53 @@
+use serialport::SerialPort;
+use std::error::Error;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+pub use crate::transport::capture::{CaptureTransport, Direction, Record};
+pub use crate::transport::memory::MemoryTransport;
+
+pub mod capture;
+mod memory;
+mod remote;
+
+/// Timeout of a single read, the firmware answers every command quickly.
+pub const READ_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// Byte stream to the board, reads fail with `TimedOut` or `WouldBlock`
+/// once the timeout expires.
+pub trait Transport: Read + Write + Send {
+    fn set_timeout(&mut self, timeout: Duration) -> std::io::Result<()>;
+}
+
+impl Transport for Box<dyn SerialPort> {
+    fn set_timeout(&mut self, timeout: Duration) -> std::io::Result<()> {
+        self.as_mut().set_timeout(timeout)?;
+        Ok(())
+    }
+}
+
+impl Transport for TcpStream {
+    fn set_timeout(&mut self, timeout: Duration) -> std::io::Result<()> {
+        self.set_read_timeout(Some(timeout))
+    }
+}
+
+/// Opens a serial port, or a TCP connection for `tcp://host:port`: either a
+/// `devkit serve`, which gets `token`, or a serial to network bridge.
+pub fn open(
+    port: &str,
+    baud: u32,
+    token: Option<&str>,
+) -> Result<Box<dyn Transport>, Box<dyn Error + Send + Sync + 'static>> {
+    let mut transport: Box<dyn Transport> = match port.strip_prefix("tcp://") {
+        Some(addr) => Box::new(remote::connect(addr, token)?),
+        None => Box::new(
+            serialport::new(port, baud)
+                .open()
+                .map_err(|e| format!("unable to open {}: {}", port, e))?,
+        ),
+    };
+    transport.set_timeout(READ_TIMEOUT)?;
+    Ok(transport)
+}
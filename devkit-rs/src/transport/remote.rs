@@ -0,0 +1,75 @@
+use crate::protocol::frame::{
+    parse, Frame, Parsed, CMD_AUTH, NAK_AUTH, REPLY_ACK, REPLY_NAK, SERVER_GREETING,
+};
+use crate::protocol::PROTOCOL_VERSION;
+use std::error::Error;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// How long a server takes at most to greet, and to check the token.
+const GREETING_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Connects to `addr`. A `devkit serve` greets the client first and gets
+/// `token` back, a serial to network bridge stays silent and the stream
+/// goes straight to the board.
+pub fn connect(
+    addr: &str,
+    token: Option<&str>,
+) -> Result<TcpStream, Box<dyn Error + Send + Sync + 'static>> {
+    let mut stream =
+        TcpStream::connect(addr).map_err(|e| format!("unable to connect to {}: {}", addr, e))?;
+    stream.set_read_timeout(Some(GREETING_TIMEOUT))?;
+
+    let greeting = match next_frame(&mut stream)? {
+        Some(f) if f.seq == 0 && f.cmd == SERVER_GREETING => f,
+        _ => return Ok(stream),
+    };
+    if greeting.payload != [PROTOCOL_VERSION] {
+        return Err(format!("{} speaks another protocol version", addr).into());
+    }
+    let token = token.ok_or_else(|| {
+        format!(
+            "{} is a devkit server, it needs a token (--token or token in devkit.conf)",
+            addr
+        )
+    })?;
+
+    let auth = Frame {
+        seq: 0,
+        cmd: CMD_AUTH,
+        payload: token.as_bytes().to_vec(),
+    };
+    stream.write_all(&auth.encode())?;
+    match next_frame(&mut stream)? {
+        Some(f) if f.cmd == REPLY_ACK => Ok(stream),
+        Some(f) if f.cmd == REPLY_NAK && f.payload == [NAK_AUTH] => {
+            Err(format!("the devkit server at {} refused the token", addr).into())
+        }
+        _ => Err(format!("no answer from the devkit server at {}", addr).into()),
+    }
+}
+
+/// The first frame from `stream`, `None` if anything else or nothing
+/// arrives.
+fn next_frame(
+    stream: &mut TcpStream,
+) -> Result<Option<Frame>, Box<dyn Error + Send + Sync + 'static>> {
+    let mut received = vec![];
+    let mut buf = [0u8; 256];
+    loop {
+        match parse(&received) {
+            Parsed::Frame(f, _) => return Ok(Some(f)),
+            Parsed::Incomplete => {}
+            Parsed::Corrupt { .. } | Parsed::Garbage(_) => return Ok(None),
+        }
+        match stream.read(&mut buf) {
+            Ok(0) => return Err("the connection was closed".into()),
+            Ok(n) => received.extend(&buf[..n]),
+            Err(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
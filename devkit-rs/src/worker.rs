@@ -0,0 +1,217 @@
+use crate::devkit::{Cancel, ConnectionState, Request, RequestError, Response};
+use crate::protocol::{Address, Protocol};
+use std::cell::RefCell;
+use std::error::Error;
+use std::io::ErrorKind;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+/// Reads are split in chunks this long so they can be cancelled.
+const READ_CHUNK: usize = 256;
+
+pub struct Job {
+    pub request: Request,
+    pub cancel: Cancel,
+    pub reply: Sender<Result<Response, RequestError>>,
+}
+
+/// Connection state shared with `Devkit`.
+pub struct Status {
+    pub state: ConnectionState,
+    pub listeners: Vec<Sender<ConnectionState>>,
+}
+
+impl Status {
+    fn set(&mut self, state: ConnectionState) {
+        self.listeners.retain(|l| l.send(state.clone()).is_ok());
+        self.state = state;
+    }
+}
+
+/// Answers the jobs, notices when the connection is lost.
+struct Replies {
+    status: Arc<Mutex<Status>>,
+    lost: Option<String>,
+}
+
+impl Replies {
+    fn send(&mut self, job: &Job, result: Result<Response, RequestError>) {
+        if let (None, Err(RequestError::Failed(e))) = (&self.lost, &result) {
+            if is_fatal(e.as_ref()) {
+                self.lost = Some(e.to_string());
+                self.status
+                    .lock()
+                    .unwrap()
+                    .set(ConnectionState::Disconnected(e.to_string()));
+            }
+        }
+        // nobody waiting for the answer is fine
+        let _ = job.reply.send(result);
+    }
+}
+
+/// Runs the jobs until the `Devkit` is closed. After a transport error the
+/// connection is considered lost and the remaining jobs fail. Writes that
+/// are already queued behind each other go to the protocol together, so it
+/// can pipeline them.
+pub fn worker(mut protocol: Box<dyn Protocol>, jobs: Receiver<Job>, status: Arc<Mutex<Status>>) {
+    let mut replies = Replies { status, lost: None };
+    let mut queued = None;
+
+    while let Some(job) = queued.take().or_else(|| jobs.recv().ok()) {
+        let result = match &replies.lost {
+            Some(reason) => Err(RequestError::Disconnected(reason.clone())),
+            None if job.cancel.is_cancelled() => Err(RequestError::Cancelled),
+            None if matches!(job.request, Request::Write(..)) => {
+                let mut batch = vec![job];
+                for next in jobs.try_iter() {
+                    if !matches!(next.request, Request::Write(..)) {
+                        queued = Some(next);
+                        break;
+                    }
+                    batch.push(next);
+                }
+                write(protocol.as_mut(), &batch, &mut replies);
+                continue;
+            }
+            None => run(protocol.as_mut(), job.request.clone(), &job.cancel),
+        };
+        replies.send(&job, result);
+    }
+
+    if replies.lost.is_none() {
+        replies.status.lock().unwrap().set(ConnectionState::Closed);
+    }
+}
+
+/// Runs a batch of write jobs, answering each one as soon as all its blocks
+/// are written. A job stops at its first failure or when it's cancelled.
+fn write(protocol: &mut dyn Protocol, jobs: &[Job], replies: &mut Replies) {
+    let mut blocks = vec![];
+    let mut owner = vec![];
+    for (j, job) in jobs.iter().enumerate() {
+        if let Request::Write(addr, data) = &job.request {
+            for (start, len) in segments(addr.linear(), data.len()) {
+                let offset = (start - addr.linear()) as usize;
+                blocks.push((Address::from_linear(start), &data[offset..offset + len]));
+                owner.push(j);
+            }
+        }
+    }
+
+    let mut remaining = vec![0; jobs.len()];
+    for j in &owner {
+        remaining[*j] += 1;
+    }
+    let failed = RefCell::new(vec![false; jobs.len()]);
+    let replies = RefCell::new(replies);
+    for (j, job) in jobs.iter().enumerate() {
+        if remaining[j] == 0 {
+            replies.borrow_mut().send(job, Ok(Response::Done));
+        }
+    }
+
+    let skip = |i: usize| {
+        let job = &jobs[owner[i]];
+        job.cancel.is_cancelled() || failed.borrow()[owner[i]] || replies.borrow().lost.is_some()
+    };
+    let mut done = |i: usize, result: Result<(), Box<dyn Error + Send + Sync + 'static>>| {
+        let j = owner[i];
+        remaining[j] -= 1;
+        match result {
+            Err(e) if !failed.borrow()[j] => {
+                failed.borrow_mut()[j] = true;
+                replies
+                    .borrow_mut()
+                    .send(&jobs[j], Err(RequestError::Failed(e)));
+            }
+            Ok(()) if remaining[j] == 0 && !failed.borrow()[j] => {
+                replies.borrow_mut().send(&jobs[j], Ok(Response::Done));
+            }
+            _ => {}
+        }
+    };
+    protocol.write_blocks(&blocks, &skip, &mut done);
+
+    // jobs with blocks that were skipped
+    let replies = replies.into_inner();
+    for (j, job) in jobs.iter().enumerate() {
+        if remaining[j] > 0 && !failed.borrow()[j] {
+            let result = match &replies.lost {
+                Some(reason) => Err(RequestError::Disconnected(reason.clone())),
+                None => Err(RequestError::Cancelled),
+            };
+            replies.send(job, result);
+        }
+    }
+}
+
+fn run(
+    protocol: &mut dyn Protocol,
+    request: Request,
+    cancel: &Cancel,
+) -> Result<Response, RequestError> {
+    match request {
+        Request::Read(addr, len) => {
+            let mut data = Vec::with_capacity(len);
+            while data.len() < len {
+                if cancel.is_cancelled() {
+                    return Err(RequestError::Cancelled);
+                }
+                let a = Address::from_linear(addr.linear() + data.len() as u32);
+                let chunk = (len - data.len()).min(READ_CHUNK);
+                data.extend(protocol.read(a, chunk).map_err(RequestError::Failed)?);
+            }
+            Ok(Response::Data(data))
+        }
+        Request::Write(..) => unreachable!("writes are run in batches"),
+        Request::SetShiftRegister(val) => protocol
+            .set_shift_register(val)
+            .map(|_| Response::Done)
+            .map_err(RequestError::Failed),
+        Request::Load(addr) => protocol
+            .load(addr)
+            .map(|_| Response::Done)
+            .map_err(RequestError::Failed),
+    }
+}
+
+/// Splits a write at page boundaries, whole pages go with one command.
+fn segments(start: u32, len: usize) -> Vec<(u32, usize)> {
+    let end = start as usize + len;
+    let mut segments = vec![];
+    let mut at = start as usize;
+    while at < end {
+        let next = ((at / 256 + 1) * 256).min(end);
+        segments.push((at as u32, next - at));
+        at = next;
+    }
+    segments
+}
+
+/// I/O errors other than timeouts mean the port is gone.
+fn is_fatal(e: &(dyn Error + 'static)) -> bool {
+    match e.downcast_ref::<std::io::Error>() {
+        Some(e) => !matches!(
+            e.kind(),
+            ErrorKind::TimedOut | ErrorKind::WouldBlock | ErrorKind::Interrupted
+        ),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::worker::segments;
+
+    #[test]
+    fn write_segments() {
+        assert_eq!(
+            vec![(0xF0, 0x10), (0x100, 0x100), (0x200, 0x20)],
+            segments(0xF0, 0x130)
+        );
+        assert_eq!(vec![(0x100, 0x100)], segments(0x100, 0x100));
+        assert_eq!(vec![(0x105, 1)], segments(0x105, 1));
+        assert!(segments(0x105, 0).is_empty());
+    }
+}
@@ -1,3 +1,4 @@
+use z80_assembler::linker::{summary, Script};
 use z80_assembler::{Compiler, InMemorySourceProvider, SourceHeader};
 
 use std::env;
@@ -6,30 +7,54 @@ fn help() {
     println!();
 }
 
+fn source_provider(source: &str) -> InMemorySourceProvider {
+    let s = std::fs::read_to_string(source).unwrap();
+    InMemorySourceProvider {
+        files: vec![(
+            SourceHeader {
+                filename: source.to_string(),
+            },
+            s,
+        )],
+    }
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
 
     match args.len() {
-        3 => {
+        // with a linker script placing the sections
+        3 | 5 if args.len() == 3 || args[3] == "-T" => {
             let source = &args[1];
             let dest = &args[2];
+            let script = args
+                .get(4)
+                .map(|f| Script::parse(&std::fs::read_to_string(f).unwrap()).unwrap());
+
+            let mut compiler = Compiler::new(source_provider(source), 64 * 1024);
+            if let Some(script) = script.clone() {
+                compiler = compiler.with_script(script);
+            }
+            let res = compiler.assemble().unwrap();
+
+            std::fs::write(dest, &res.data).unwrap();
+            // source locations for the debugger and the devkit tool
+            std::fs::write(format!("{}.dbg", dest), res.debug_info.to_sidecar()).unwrap();
+
+            let script = script.unwrap_or_else(|| {
+                Script::default_for(res.sections.iter().map(|p| p.section.as_str()))
+            });
+            print!("{}", summary(&script, &res.sections));
+        }
+        // object file for z80ld
+        4 if args[1] == "-c" => {
+            let source = &args[2];
+            let dest = &args[3];
 
-            let s = std::fs::read_to_string(source).unwrap();
-            let res = Compiler::new(
-                InMemorySourceProvider {
-                    files: vec![(
-                        SourceHeader {
-                            filename: source.to_string(),
-                        },
-                        s,
-                    )],
-                },
-                64 * 1024,
-            )
-            .compile()
-            .unwrap();
-
-            std::fs::write(dest, res).unwrap();
+            let object = Compiler::new(source_provider(source), 64 * 1024)
+                .assemble_object()
+                .unwrap();
+            std::fs::write(dest, object.to_text()).unwrap();
         }
         _ => {
             help();
@@ -0,0 +1,98 @@
+use std::env;
+use std::process::ExitCode;
+use z80_assembler::font::{decode, Range};
+
+fn help() {
+    println!("usage: z80font <font> <table.bin> [options]");
+    println!();
+    println!("Converts a BDF, PSF or text font to a glyph table for the text routines");
+    println!("of the standard library.");
+    println!();
+    println!("  --range <first>-<last>    hex characters to include, like 20-7E, can be");
+    println!("                            repeated, all the font up to FF by default");
+}
+
+struct Args {
+    font: String,
+    output: String,
+    ranges: Vec<Range>,
+}
+
+fn parse_args(args: &[String]) -> Result<Args, String> {
+    let mut positional = vec![];
+    let mut ranges = vec![];
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--range" => {
+                let v = args
+                    .next()
+                    .ok_or_else(|| format!("{} needs a value", arg))?;
+                let invalid = || format!("expected <first>-<last> for --range, got '{}'", v);
+                let (first, last) = v.split_once('-').ok_or_else(invalid)?;
+                ranges.push(Range {
+                    first: u8::from_str_radix(first, 16).map_err(|_| invalid())?,
+                    last: u8::from_str_radix(last, 16).map_err(|_| invalid())?,
+                });
+            }
+            a if a.starts_with("--") => return Err(format!("unknown option {}", a)),
+            _ => positional.push(arg.clone()),
+        }
+    }
+
+    match positional.as_slice() {
+        [font, output] => Ok(Args {
+            font: font.clone(),
+            output: output.clone(),
+            ranges,
+        }),
+        _ => Err("expected a font and an output file".to_string()),
+    }
+}
+
+fn run(args: Args) -> Result<(), String> {
+    let data =
+        std::fs::read(&args.font).map_err(|e| format!("unable to read {}: {}", args.font, e))?;
+    let font = decode(&data).map_err(|e| format!("{}: {}", args.font, e))?;
+    let ranges = if args.ranges.is_empty() {
+        font.ranges()
+    } else {
+        args.ranges
+    };
+    let table = font.table(&ranges).map_err(|e| e.to_string())?;
+    std::fs::write(&args.output, &table.data)
+        .map_err(|e| format!("unable to write {}: {}", args.output, e))?;
+
+    println!(
+        "{}: {}x{}, {} bytes per glyph, {} bytes",
+        args.font,
+        font.width,
+        font.height,
+        font.glyph_size(),
+        table.data.len()
+    );
+    for (range, offset) in table.ranges {
+        println!(
+            "  {:02X}h-{:02X}h at {:04X}h",
+            range.first, range.last, offset
+        );
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.is_empty() || args.iter().any(|a| a == "--help") {
+        help();
+        return ExitCode::from(2);
+    }
+
+    match parse_args(&args).and_then(run) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::from(2)
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use std::env;
+use std::process::ExitCode;
+use z80_assembler::image::{decode, quantize, Options, FRAME_HEIGHT, FRAME_WIDTH};
+
+fn help() {
+    println!("usage: z80img <image> <pixels.bin> [options]");
+    println!();
+    println!("Converts a PNG, BMP or PPM image to palette indexes for the VGA board.");
+    println!();
+    println!("  --palette <file>     also write the 512 byte palette table");
+    println!("  --tiles <w>x<h>      cut the pixels in tiles, for sprites and tile maps");
+    println!("  --transparent <i>    palette index of the transparent pixels");
+    println!("  --colors <n>         palette entries to use, 256 by default");
+}
+
+struct Args {
+    image: String,
+    output: String,
+    palette: Option<String>,
+    tiles: Option<(usize, usize)>,
+    options: Options,
+}
+
+fn parse_args(args: &[String]) -> Result<Args, String> {
+    let mut positional = vec![];
+    let mut parsed = Args {
+        image: String::new(),
+        output: String::new(),
+        palette: None,
+        tiles: None,
+        options: Options::default(),
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("{} needs a value", arg))
+                .cloned()
+        };
+        let number = |v: String| {
+            v.parse::<usize>()
+                .map_err(|_| format!("invalid number '{}' for {}", v, arg))
+        };
+        match arg.as_str() {
+            "--palette" => parsed.palette = Some(value()?),
+            "--tiles" => {
+                let v = value()?;
+                let (w, h) = v
+                    .split_once('x')
+                    .ok_or_else(|| format!("expected <w>x<h> for --tiles, got '{}'", v))?;
+                parsed.tiles = Some((number(w.to_string())?, number(h.to_string())?));
+            }
+            "--transparent" => {
+                let i = number(value()?)?;
+                if i > 255 {
+                    return Err("--transparent must be a palette index".to_string());
+                }
+                parsed.options.transparent = Some(i as u8);
+            }
+            "--colors" => parsed.options.colors = number(value()?)?,
+            a if a.starts_with("--") => return Err(format!("unknown option {}", a)),
+            _ => positional.push(arg.clone()),
+        }
+    }
+
+    match positional.as_slice() {
+        [image, output] => {
+            parsed.image = image.clone();
+            parsed.output = output.clone();
+            Ok(parsed)
+        }
+        _ => Err("expected an image and an output file".to_string()),
+    }
+}
+
+fn run(args: Args) -> Result<(), String> {
+    let data =
+        std::fs::read(&args.image).map_err(|e| format!("unable to read {}: {}", args.image, e))?;
+    let image = decode(&data).map_err(|e| format!("{}: {}", args.image, e))?;
+    let indexed = quantize(&image, &args.options).map_err(|e| e.to_string())?;
+
+    let pixels = match args.tiles {
+        Some((w, h)) => indexed.tiles(w, h).map_err(|e| e.to_string())?,
+        None => indexed.pixels.clone(),
+    };
+    std::fs::write(&args.output, &pixels)
+        .map_err(|e| format!("unable to write {}: {}", args.output, e))?;
+    if let Some(palette) = &args.palette {
+        std::fs::write(palette, indexed.palette_table())
+            .map_err(|e| format!("unable to write {}: {}", palette, e))?;
+    }
+
+    print!(
+        "{}: {}x{}, {} palette entries",
+        args.image, image.width, image.height, indexed.colors
+    );
+    match args.tiles {
+        Some((w, h)) => println!(", {} tiles", pixels.len() / (w * h)),
+        None if (image.width, image.height) != (FRAME_WIDTH, FRAME_HEIGHT) => {
+            println!(" (a frame is {}x{})", FRAME_WIDTH, FRAME_HEIGHT)
+        }
+        None => println!(),
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.is_empty() || args.iter().any(|a| a == "--help") {
+        help();
+        return ExitCode::from(2);
+    }
+
+    match parse_args(&args).and_then(run) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::from(2)
+        }
+    }
+}
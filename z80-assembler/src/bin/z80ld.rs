@@ -0,0 +1,110 @@
+use std::env;
+use std::process::ExitCode;
+use z80_assembler::linker::{link, section_names, summary, Script};
+use z80_assembler::object::Object;
+
+fn help() {
+    println!("usage: z80ld <object>... -o <output.bin> [options]");
+    println!();
+    println!("Links the object files written by `z80_assembler -c` into a memory image");
+    println!("from address 0, bank n from n * 10000h. bss sections are not written.");
+    println!();
+    println!("  -T <script>    linker script placing the sections, like:");
+    println!("                   memory ROM 0000h-7FFFh");
+    println!("                   memory RAM 8000h-FFFFh");
+    println!("                   memory VRAM 0000h-FFFFh bank 1");
+    println!("                   section code in ROM");
+    println!("                   section vectors at 0038h in ROM");
+    println!("                   section bss in RAM");
+    println!("                 by default code from 0, then data and the other");
+    println!("                 sections, and bss from 8000h");
+    println!("  --map          prints where the sections and symbols are");
+}
+
+struct Args {
+    objects: Vec<String>,
+    output: String,
+    script: Option<String>,
+    map: bool,
+}
+
+fn parse_args(args: &[String]) -> Result<Args, String> {
+    let mut objects = vec![];
+    let mut output = None;
+    let mut script = None;
+    let mut map = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "-T" => {
+                let v = args
+                    .next()
+                    .ok_or_else(|| format!("{} needs a value", arg))?
+                    .clone();
+                if arg == "-o" {
+                    output = Some(v);
+                } else {
+                    script = Some(v);
+                }
+            }
+            "--map" => map = true,
+            a if a.starts_with('-') => return Err(format!("unknown option {}", a)),
+            _ => objects.push(arg.clone()),
+        }
+    }
+
+    if objects.is_empty() {
+        return Err("expected at least one object file".to_string());
+    }
+    Ok(Args {
+        objects,
+        output: output.ok_or("expected an output file, -o <output.bin>")?,
+        script,
+        map,
+    })
+}
+
+fn run(args: Args) -> Result<(), String> {
+    let mut objects = vec![];
+    for file in args.objects {
+        let text = std::fs::read_to_string(&file)
+            .map_err(|e| format!("unable to read {}: {}", file, e))?;
+        let object = Object::from_text(&text).map_err(|e| format!("{}: {}", file, e))?;
+        objects.push((file, object));
+    }
+    let script = match &args.script {
+        Some(file) => {
+            let text = std::fs::read_to_string(file)
+                .map_err(|e| format!("unable to read {}: {}", file, e))?;
+            Script::parse(&text).map_err(|e| format!("{}: {}", file, e))?
+        }
+        None => Script::default_for(section_names(&objects)),
+    };
+
+    let linked = link(&objects, &script).map_err(|e| e.to_string())?;
+    std::fs::write(&args.output, &linked.data)
+        .map_err(|e| format!("unable to write {}: {}", args.output, e))?;
+    print!("{}", summary(&script, &linked.sections));
+    if args.map {
+        println!();
+        print!("{}", linked.map());
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.is_empty() || args.iter().any(|a| a == "--help") {
+        help();
+        return ExitCode::from(2);
+    }
+
+    match parse_args(&args).and_then(run) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::from(2)
+        }
+    }
+}
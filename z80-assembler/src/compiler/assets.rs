@@ -0,0 +1,133 @@
+use crate::compiler::instructions::{CompileError, CompileErrorType};
+use crate::font;
+use crate::font::{GlyphTable, Range};
+use crate::image;
+use crate::image::Options;
+use crate::parser::{Token, TokenValue};
+
+/// What `#incimage` puts in the program.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ImagePart {
+    /// One palette index per pixel, row by row.
+    Pixels,
+    /// The 512 byte palette table.
+    Palette,
+    /// Pixels cut in tiles of this width and height.
+    Tiles(usize, usize),
+}
+
+/// Arguments of `#incimage "file" [pixels|palette|tiles w h]
+/// [transparent i] [colors n]`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IncImage {
+    pub file: String,
+    pub part: ImagePart,
+    pub options: Options,
+}
+
+pub fn parse_incimage(tokens: &[Token], line: usize) -> Result<IncImage, CompileError> {
+    let invalid = || CompileError {
+        error: CompileErrorType::InvalidDirective("#incimage".to_string(), line),
+        instr: None,
+    };
+    let file = match tokens.first().map(|t| &t.token) {
+        Some(TokenValue::Str(file)) => file.clone(),
+        _ => return Err(invalid()),
+    };
+    let value = |i: usize, max: usize| match tokens.get(i).map(|t| &t.token) {
+        Some(TokenValue::Value(v, _)) if (*v as usize) <= max => Ok(*v as usize),
+        _ => Err(invalid()),
+    };
+
+    let mut inc = IncImage {
+        file,
+        part: ImagePart::Pixels,
+        options: Options::default(),
+    };
+    let mut i = 1;
+    while let Some(t) = tokens.get(i) {
+        i += match &t.token {
+            TokenValue::Identifier(w) if w == "pixels" => {
+                inc.part = ImagePart::Pixels;
+                1
+            }
+            TokenValue::Identifier(w) if w == "palette" => {
+                inc.part = ImagePart::Palette;
+                1
+            }
+            TokenValue::Identifier(w) if w == "tiles" => {
+                inc.part = ImagePart::Tiles(value(i + 1, 0xFFFF)?, value(i + 2, 0xFFFF)?);
+                3
+            }
+            TokenValue::Identifier(w) if w == "transparent" => {
+                inc.options.transparent = Some(value(i + 1, 0xFF)? as u8);
+                2
+            }
+            TokenValue::Identifier(w) if w == "colors" => {
+                inc.options.colors = value(i + 1, image::PALETTE_SIZE)?;
+                2
+            }
+            _ => return Err(invalid()),
+        };
+    }
+    Ok(inc)
+}
+
+/// The bytes `inc` includes from the image file `data`.
+pub fn convert_image(inc: &IncImage, data: &[u8]) -> Result<Vec<u8>, image::ImageError> {
+    let indexed = image::quantize(&image::decode(data)?, &inc.options)?;
+    match inc.part {
+        ImagePart::Pixels => Ok(indexed.pixels),
+        ImagePart::Palette => Ok(indexed.palette_table()),
+        ImagePart::Tiles(width, height) => indexed.tiles(width, height),
+    }
+}
+
+/// Arguments of `#incfont "file" name [first-last ...]`, all the characters
+/// of the font up to FFh without ranges.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IncFont {
+    pub file: String,
+    pub name: String,
+    pub ranges: Vec<Range>,
+}
+
+pub fn parse_incfont(tokens: &[Token], line: usize) -> Result<IncFont, CompileError> {
+    let invalid = || CompileError {
+        error: CompileErrorType::InvalidDirective("#incfont".to_string(), line),
+        instr: None,
+    };
+    let (file, name) = match (tokens.first(), tokens.get(1)) {
+        (Some(file), Some(name)) => match (&file.token, &name.token) {
+            (TokenValue::Str(file), TokenValue::Identifier(name)) => (file.clone(), name.clone()),
+            _ => return Err(invalid()),
+        },
+        _ => return Err(invalid()),
+    };
+    let value = |t: &Token| match t.token {
+        TokenValue::Value(v, _) if v <= 0xFF => Ok(v as u8),
+        _ => Err(invalid()),
+    };
+
+    let mut ranges = vec![];
+    for range in tokens[2..].chunks(3) {
+        match range {
+            [first, minus, last] if minus.token == TokenValue::Minus => ranges.push(Range {
+                first: value(first)?,
+                last: value(last)?,
+            }),
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(IncFont { file, name, ranges })
+}
+
+/// The glyph table of `inc` from the font file `data`.
+pub fn convert_font(inc: &IncFont, data: &[u8]) -> Result<GlyphTable, font::FontError> {
+    let font = font::decode(data)?;
+    if inc.ranges.is_empty() {
+        font.table(&font.ranges())
+    } else {
+        font.table(&inc.ranges)
+    }
+}
@@ -0,0 +1,279 @@
+use std::fmt::{Display, Formatter};
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DebugInfo {
+    pub files: Vec<String>,
+    pub lines: Vec<LineEntry>,
+}
+
+/// Output bytes `addr..addr + len` were produced by `line:column` of
+/// `files[file_id]`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LineEntry {
+    pub addr: usize,
+    pub len: usize,
+    pub kind: EntryKind,
+    pub file_id: usize,
+    pub line: usize,
+    pub column: usize,
+    /// Last label defined before the entry.
+    pub label: Option<String>,
+    /// Macro invocations the entry was expanded from, outermost first.
+    pub macro_stack: Vec<MacroCall>,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EntryKind {
+    Code,
+    Data,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MacroCall {
+    pub name: String,
+    pub file_id: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct SidecarError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for SidecarError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "l{} - {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for SidecarError {}
+
+const SIDECAR_HEADER: &str = "z80dbg 1";
+
+impl DebugInfo {
+    pub fn location(&self, addr: usize) -> Option<&LineEntry> {
+        self.lines
+            .iter()
+            .find(|l| addr >= l.addr && addr < l.addr + l.len)
+    }
+
+    pub fn filename(&self, file_id: usize) -> Option<&str> {
+        self.files.get(file_id).map(|f| f.as_str())
+    }
+
+    /// Start addresses of the code generated by `file:line`, `file` can also
+    /// be just the last component of the path.
+    pub fn addresses(&self, file: &str, line: usize) -> Vec<usize> {
+        self.lines
+            .iter()
+            .filter(|l| l.line == line && self.file_matches(l.file_id, file))
+            .map(|l| l.addr)
+            .collect()
+    }
+
+    fn file_matches(&self, file_id: usize, file: &str) -> bool {
+        match self.filename(file_id) {
+            Some(f) => {
+                f == file
+                    || std::path::Path::new(f)
+                        .file_name()
+                        .map(|n| n == file)
+                        .unwrap_or(false)
+            }
+            None => false,
+        }
+    }
+
+    /// Text form written next to the binary, one entry per line:
+    ///
+    /// ```text
+    /// z80dbg 1
+    /// file 0 src/main.z80
+    /// code 0010h 3 0:12:5 loop print@0:30:1
+    /// data 0013h 2 0:14:13 -
+    /// ```
+    ///
+    /// entries are `kind addr len file:line:column label macro_stack...`,
+    /// `-` when there is no enclosing label.
+    pub fn to_sidecar(&self) -> String {
+        let mut out = format!("{}\n", SIDECAR_HEADER);
+        for (id, f) in self.files.iter().enumerate() {
+            out.push_str(&format!("file {} {}\n", id, f));
+        }
+        for l in self.lines.iter() {
+            out.push_str(&format!(
+                "{} {:04X}h {} {}:{}:{} {}",
+                match l.kind {
+                    EntryKind::Code => "code",
+                    EntryKind::Data => "data",
+                },
+                l.addr,
+                l.len,
+                l.file_id,
+                l.line,
+                l.column,
+                l.label.as_deref().unwrap_or("-")
+            ));
+            for m in l.macro_stack.iter() {
+                out.push_str(&format!(
+                    " {}@{}:{}:{}",
+                    m.name, m.file_id, m.line, m.column
+                ));
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    pub fn from_sidecar(text: &str) -> Result<DebugInfo, SidecarError> {
+        let mut info = DebugInfo::default();
+        let mut lines = text.lines().enumerate();
+
+        match lines.next() {
+            Some((_, SIDECAR_HEADER)) => {}
+            _ => {
+                return Err(SidecarError {
+                    line: 1,
+                    message: format!("expected '{}'", SIDECAR_HEADER),
+                })
+            }
+        }
+
+        for (i, line) in lines {
+            let err = |message: &str| SidecarError {
+                line: i + 1,
+                message: message.to_string(),
+            };
+            let mut fields = line.split(' ');
+
+            match fields.next() {
+                Some("file") => {
+                    let id = fields
+                        .next()
+                        .and_then(|id| id.parse::<usize>().ok())
+                        .ok_or_else(|| err("invalid file id"))?;
+                    if id != info.files.len() {
+                        return Err(err("file ids must be sequential"));
+                    }
+                    // the rest of the line, paths can contain spaces
+                    info.files.push(fields.collect::<Vec<_>>().join(" "));
+                }
+                Some(kind @ ("code" | "data")) => {
+                    let addr = fields
+                        .next()
+                        .and_then(|a| a.strip_suffix('h'))
+                        .and_then(|a| usize::from_str_radix(a, 16).ok())
+                        .ok_or_else(|| err("invalid address"))?;
+                    let len = fields
+                        .next()
+                        .and_then(|l| l.parse::<usize>().ok())
+                        .ok_or_else(|| err("invalid length"))?;
+                    let (file_id, line, column) = fields
+                        .next()
+                        .and_then(parse_position)
+                        .ok_or_else(|| err("invalid position"))?;
+                    let label = match fields.next() {
+                        Some("-") => None,
+                        Some(l) => Some(l.to_string()),
+                        None => return Err(err("missing label")),
+                    };
+                    let macro_stack = fields
+                        .map(|m| {
+                            let (name, pos) = m.split_once('@')?;
+                            let (file_id, line, column) = parse_position(pos)?;
+                            Some(MacroCall {
+                                name: name.to_string(),
+                                file_id,
+                                line,
+                                column,
+                            })
+                        })
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or_else(|| err("invalid macro call"))?;
+
+                    info.lines.push(LineEntry {
+                        addr,
+                        len,
+                        kind: if kind == "code" {
+                            EntryKind::Code
+                        } else {
+                            EntryKind::Data
+                        },
+                        file_id,
+                        line,
+                        column,
+                        label,
+                        macro_stack,
+                    });
+                }
+                Some("") | None => {}
+                Some(other) => return Err(err(&format!("unknown entry '{}'", other))),
+            }
+        }
+
+        Ok(info)
+    }
+}
+
+fn parse_position(s: &str) -> Option<(usize, usize, usize)> {
+    let mut parts = s.split(':').map(|p| p.parse::<usize>().ok());
+    let pos = (parts.next()??, parts.next()??, parts.next()??);
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(pos)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::debug_info::{DebugInfo, EntryKind, LineEntry, MacroCall};
+
+    #[test]
+    fn sidecar_round_trip() {
+        let info = DebugInfo {
+            files: vec!["src/my file.z80".to_string()],
+            lines: vec![
+                LineEntry {
+                    addr: 0x10,
+                    len: 3,
+                    kind: EntryKind::Code,
+                    file_id: 0,
+                    line: 12,
+                    column: 5,
+                    label: Some("loop".to_string()),
+                    macro_stack: vec![MacroCall {
+                        name: "print".to_string(),
+                        file_id: 0,
+                        line: 30,
+                        column: 7,
+                    }],
+                },
+                LineEntry {
+                    addr: 0x13,
+                    len: 2,
+                    kind: EntryKind::Data,
+                    file_id: 0,
+                    line: 14,
+                    column: 13,
+                    label: None,
+                    macro_stack: vec![],
+                },
+            ],
+        };
+
+        let text = info.to_sidecar();
+        assert_eq!(
+            "z80dbg 1\nfile 0 src/my file.z80\ncode 0010h 3 0:12:5 loop print@0:30:7\ndata 0013h 2 0:14:13 -\n",
+            text
+        );
+        assert_eq!(info, DebugInfo::from_sidecar(&text).unwrap());
+        assert_eq!(
+            "l2 - invalid position",
+            DebugInfo::from_sidecar("z80dbg 1\ncode 0h 1 0:1 -")
+                .unwrap_err()
+                .to_string()
+        );
+    }
+}
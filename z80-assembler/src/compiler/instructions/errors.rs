@@ -1,6 +1,8 @@
 use crate::compiler::instructions::{CompileData, Placeholder};
 use crate::domain::{Argument, Instruction};
+use crate::linker::LinkError;
 use crate::parser::ParseError;
+use std::fmt::{Display, Formatter};
 
 #[derive(Debug, Eq, PartialEq)]
 pub struct CompileError {
@@ -17,6 +19,19 @@ pub enum CompileErrorType {
     UnexpectedArgument(Argument),
     ConstantNotFound(String),
     UnableToCalculateRelativeJump(Placeholder),
+    /// `*label` reads past the end of the section the label is in.
+    ValuePastSection(Placeholder),
+    /// `*label` of a label in bss, whose bytes are not output.
+    BssValue(Placeholder),
+    /// A label the instruction has no operand bytes for.
+    UnresolvedOperand(Placeholder),
+    InvalidDirective(String, usize),
+    UnterminatedBlock(String, usize),
+    /// File, reason and line of an include that failed, line 0 for a file
+    /// of the `SourceProvider` list.
+    IncludeFailed(String, String, usize),
+    /// The sections don't fit where the linker script puts them.
+    Placement(LinkError),
 }
 
 impl From<ParseError> for CompileError {
@@ -28,6 +43,95 @@ impl From<ParseError> for CompileError {
     }
 }
 
+impl CompileError {
+    /// Line the error was found on, starting from 1.
+    pub fn line(&self) -> Option<usize> {
+        match &self.error {
+            CompileErrorType::IncludeFailed(_, _, 0) => None,
+            CompileErrorType::ParseError(ParseError::UnexpectedChar(_, line))
+            | CompileErrorType::ParseError(ParseError::UnexpectedEOF(line))
+            | CompileErrorType::ParseError(ParseError::UnterminatedString(_, line))
+            | CompileErrorType::LabelNotFound(_, line)
+            | CompileErrorType::InvalidDirective(_, line)
+            | CompileErrorType::UnterminatedBlock(_, line)
+            | CompileErrorType::IncludeFailed(_, _, line) => Some(*line),
+            CompileErrorType::ParseError(ParseError::UnexpectedToken(t)) => Some(t.line),
+            CompileErrorType::UnableToCalculateRelativeJump(ph)
+            | CompileErrorType::ValuePastSection(ph)
+            | CompileErrorType::BssValue(ph)
+            | CompileErrorType::UnresolvedOperand(ph) => Some(ph.line),
+            _ => self.instr.as_ref().map(|i| i.line),
+        }
+    }
+
+    /// Index of the source file in the `SourceProvider` list, when known.
+    pub fn file_id(&self) -> Option<usize> {
+        match &self.error {
+            CompileErrorType::ParseError(ParseError::UnexpectedToken(t)) => Some(t.file_id),
+            _ => self.instr.as_ref().map(|i| i.file_id),
+        }
+    }
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(line) = self.line() {
+            write!(f, "l{} - ", line)?;
+        }
+        match &self.error {
+            CompileErrorType::ParseError(ParseError::UnexpectedChar(c, _)) => {
+                write!(f, "unexpected character '{}'", c)
+            }
+            CompileErrorType::ParseError(ParseError::UnexpectedEOF(_)) => {
+                write!(f, "unexpected end of file")
+            }
+            CompileErrorType::ParseError(ParseError::UnterminatedString(c, _)) => {
+                write!(f, "missing closing '{}'", c)
+            }
+            CompileErrorType::ParseError(ParseError::UnexpectedToken(t)) => {
+                write!(f, "expected {:?}, found {:?}", t.expected, t.actual)
+            }
+            CompileErrorType::ExpectedShortArgument(arg, val) => {
+                write!(f, "argument {} must fit in a byte, found {:X}h", arg, val)
+            }
+            CompileErrorType::ExpectedBitArgument(arg, val) => {
+                write!(f, "argument {} must be a bit number, found {:X}h", arg, val)
+            }
+            CompileErrorType::LabelNotFound(label, _) => write!(f, "label '{}' not found", label),
+            CompileErrorType::UnexpectedArgument(arg) => write!(f, "unexpected argument {:?}", arg),
+            CompileErrorType::ConstantNotFound(name) => write!(f, "constant '{}' not found", name),
+            CompileErrorType::UnableToCalculateRelativeJump(ph) => {
+                write!(f, "'{}' is too far for a relative jump", ph.label)
+            }
+            CompileErrorType::ValuePastSection(ph) => {
+                write!(f, "'*{}' reads past the end of its section", ph.label)
+            }
+            CompileErrorType::BssValue(ph) => {
+                write!(f, "'*{}' reads bss, which has no data", ph.label)
+            }
+            CompileErrorType::UnresolvedOperand(ph) => {
+                write!(f, "label '{}' can't be used as an operand here", ph.label)
+            }
+            CompileErrorType::InvalidDirective(name, _) => {
+                write!(f, "invalid directive '{}'", name)
+            }
+            CompileErrorType::UnterminatedBlock(name, _) => {
+                write!(f, "block '{}' is never closed", name)
+            }
+            CompileErrorType::IncludeFailed(file, reason, _) => {
+                write!(f, "unable to include '{}': {}", file, reason)
+            }
+            CompileErrorType::Placement(e) => write!(f, "{}", e),
+        }?;
+        if let Some(instr) = &self.instr {
+            write!(f, " in '{}'", instr.opcode.to_uppercase())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CompileError {}
+
 pub fn unimplemented_instr(instr: &Instruction) -> ! {
     unimplemented!(
         "l{} - unimplemented instruction '{}' arg0: {:?} arg1: {:?}",
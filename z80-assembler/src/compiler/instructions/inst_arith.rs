@@ -165,7 +165,7 @@ fn offset(
 ) -> Result<CompileData, CompileError> {
     match arg {
         Argument::ShortReg(sr) => {
-            let opcode = codes.r | to_3bit_code(*sr)?;
+            let opcode = codes.r | (to_3bit_code(*sr)? << 3);
             compile_data_1(opcode)
         }
         Argument::WideRegAddress(WideReg::HL) => compile_data_1(codes.hl),
@@ -41,6 +41,8 @@ pub struct CompileData {
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Placeholder {
     pub idx: usize,
+    /// Index of the section `idx` is in.
+    pub section: usize,
     pub label: String,
     pub ph_type: PlaceholderType,
     pub line: usize,
@@ -167,7 +169,11 @@ fn compile_call(
             update_ph(p0, 1, PlaceholderType::AbsAddress, phs);
             compile_data_3(0xCD, low_byte(*val), high_byte(*val))
         }
-        (Argument::Condition(c), Argument::Value(val)) => {
+        (Argument::DirectAddress(val), Argument::None) => {
+            update_ph(p0, 1, PlaceholderType::AbsAddress, phs);
+            compile_data_3(0xCD, low_byte(*val), high_byte(*val))
+        }
+        (Argument::Condition(c), Argument::Value(val) | Argument::DirectAddress(val)) => {
             update_ph(p1, 1, PlaceholderType::AbsAddress, phs);
             compile_data_3(
                 0b11000100 | (to_cond_code(*c)? << 3),
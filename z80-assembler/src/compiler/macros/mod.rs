@@ -36,9 +36,10 @@ pub fn compile_macro(
             }
         }
         "#exec" => {
+            let call = tokens.first().cloned();
             let (name, args) = get_macro_name_and_args(tokens)?;
 
-            if let Some(m) = macros.get(name.as_str()) {
+            if let (Some(m), Some(call)) = (macros.get(name.as_str()), call) {
                 let mut out = vec![];
 
                 for t in &m.tokens {
@@ -61,7 +62,7 @@ pub fn compile_macro(
                     }
                 }
 
-                tokenizer.push_front(&out)
+                tokenizer.push_expansion(call, &out)
             } else {
                 panic!("macro: '{:?}' not found", args)
             }
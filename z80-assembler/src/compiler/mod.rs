@@ -1,33 +1,67 @@
+use crate::compiler::assets::{convert_font, convert_image, parse_incfont, parse_incimage};
+pub use crate::compiler::debug_info::{DebugInfo, EntryKind, LineEntry, MacroCall, SidecarError};
 use crate::compiler::instructions::{
-    compile_instruction, label_not_found, CompileError, CompileErrorType, Placeholder,
-    PlaceholderType,
+    compile_instruction, label_not_found, Placeholder, PlaceholderType,
 };
+pub use crate::compiler::instructions::{CompileError, CompileErrorType};
 use crate::compiler::macros::compile_macro;
+pub use crate::compiler::program::{Program, Segment};
 use crate::compiler::r#macro::Macro;
 pub use crate::compiler::source_provider::{InMemorySourceProvider, SourceHeader, SourceProvider};
+use crate::compiler::test_blocks::read_test_block;
+pub use crate::compiler::test_blocks::TestBlock;
 use crate::compiler::utilities::relative_delta;
 use crate::domain::{Argument, Instruction, ParseItem};
-use crate::parser::tokenizer::BufferedTokenizer;
-use crate::parser::Parser;
-use std::collections::HashMap;
+use crate::font::GlyphTable;
+use crate::linker::{load, place, strip_bss, Script, BSS_SECTION};
+use crate::object::{Object, Relocation, RelocationKind, Section, Symbol};
+use crate::parser::tokenizer::{BufferedTokenizer, Tokenizer};
+use crate::parser::{Parser, Token, TokenValue};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
 
+mod assets;
+mod debug_info;
 mod instructions;
 mod r#macro;
 mod macros;
+mod program;
 mod source_provider;
-mod utilities;
+mod test_blocks;
+pub(crate) mod utilities;
 
 pub struct Compiler<T>
 where
     T: SourceProvider,
 {
     source_provider: T,
+    capacity: usize,
+    /// Bytes and location counter of the current section.
     out: Vec<u8>,
     idx: usize,
-    label_map: HashMap<String, usize>,
+    /// Sections of `#section` with their location counter, the bytes of the
+    /// current one are in `out`.
+    sections: Vec<(Section, usize)>,
+    section: usize,
+    /// Section and offset of each label.
+    label_map: HashMap<String, (usize, usize)>,
     placeholders: Vec<Placeholder>,
     constants: HashMap<String, u16>,
     macros: HashMap<String, Macro>,
+    test_blocks: Vec<TestBlock>,
+    debug_info: DebugInfo,
+    /// Section of each of `debug_info.lines`.
+    line_sections: Vec<usize>,
+    item_start: Option<Token>,
+    item_macros: Vec<Token>,
+    current_label: Option<String>,
+    /// Library files already included, each is assembled once.
+    libraries: HashSet<String>,
+    /// Depth of the library being assembled, 0 in the program's own files.
+    library_depth: usize,
+    /// Files read through `SourceProvider::binary`.
+    includes: Vec<String>,
+    script: Option<Script>,
 }
 
 impl<T> Compiler<T>
@@ -37,60 +71,254 @@ where
     pub fn new(source_provider: T, capacity: usize) -> Self {
         Compiler {
             source_provider,
+            capacity,
             out: vec![0u8; capacity],
             idx: 0,
+            sections: vec![(
+                Section {
+                    name: "code".to_string(),
+                    data: vec![],
+                },
+                0,
+            )],
+            section: 0,
             label_map: HashMap::new(),
             placeholders: vec![],
             constants: HashMap::new(),
             macros: HashMap::new(),
+            test_blocks: vec![],
+            debug_info: DebugInfo::default(),
+            line_sections: vec![],
+            item_start: None,
+            item_macros: vec![],
+            current_label: None,
+            libraries: HashSet::new(),
+            library_depth: 0,
+            includes: vec![],
+            script: None,
         }
     }
 
-    pub fn compile(mut self) -> Result<Vec<u8>, CompileError> {
-        for file in self.source_provider.file_list() {
-            self.constants.clear();
-            let source = self.source_provider.source(&file.filename);
-            let mut tokenizer = BufferedTokenizer::new(&source, 0);
-            let mut parser = Parser::new();
-
-            loop {
-                if let Some(pi) = parser.parse_next(&mut tokenizer)? {
-                    self.process_item(pi, &mut tokenizer)?;
-                } else {
-                    break;
-                }
-            }
-        }
+    /// Places the sections with `script` instead of `Script::default_for`.
+    pub fn with_script(mut self, script: Script) -> Self {
+        self.script = Some(script);
+        self
+    }
+
+    pub fn compile(self) -> Result<Vec<u8>, CompileError> {
+        self.assemble().map(|p| p.data)
+    }
+
+    pub fn assemble(mut self) -> Result<Program, CompileError> {
+        self.assemble_sources()?;
+
+        let sections = self.take_sections();
+        let script = self
+            .script
+            .take()
+            .unwrap_or_else(|| Script::default_for(sections.iter().map(|s| s.name.as_str())));
+        let name = self.debug_info.files.first().cloned().unwrap_or_default();
+        let placed = place(&script, &[(&name, &sections)]).map_err(|e| CompileError {
+            error: CompileErrorType::Placement(e),
+            instr: None,
+        })?;
+        let bases = sections
+            .iter()
+            .map(|s| {
+                placed
+                    .iter()
+                    .find(|(_, p)| p.section == s.name)
+                    .map_or(0, |(_, p)| p.addr)
+            })
+            .collect::<Vec<_>>();
+        let address = |(section, offset): (usize, usize)| bases[section] + offset;
+        let mut out = load(&placed, &[&sections], self.capacity);
 
-        for ph in self.placeholders.into_iter() {
-            let addr = self
+        for ph in std::mem::take(&mut self.placeholders).into_iter() {
+            let label = *self
                 .label_map
                 .get(ph.label.as_str())
                 .ok_or(label_not_found(&ph))?;
+            let addr = address(label);
+            let at = address((ph.section, ph.idx));
 
             match ph.ph_type {
-                PlaceholderType::ShortValue => {
-                    self.out[ph.idx] = self.out[*addr];
-                }
-                PlaceholderType::WideValue => {
-                    self.out[ph.idx] = self.out[*addr];
-                    self.out[ph.idx + 1] = self.out[*addr + 1];
+                PlaceholderType::ShortValue | PlaceholderType::WideValue => {
+                    let size = if ph.ph_type == PlaceholderType::ShortValue {
+                        1
+                    } else {
+                        2
+                    };
+                    let (section, offset) = label;
+                    let error = if sections[section].name == BSS_SECTION {
+                        Some(CompileErrorType::BssValue(ph.clone()))
+                    } else if offset + size > sections[section].data.len() {
+                        Some(CompileErrorType::ValuePastSection(ph.clone()))
+                    } else {
+                        None
+                    };
+                    if let Some(error) = error {
+                        return Err(CompileError { error, instr: None });
+                    }
+                    out.copy_within(addr..addr + size, at);
                 }
                 PlaceholderType::AbsAddress => {
-                    self.out[ph.idx] = (*addr % 256) as u8;
-                    self.out[ph.idx + 1] = (*addr / 256) as u8
+                    out[at] = (addr % 256) as u8;
+                    out[at + 1] = (addr / 256) as u8
                 }
                 PlaceholderType::RelAddress => {
-                    self.out[ph.idx] = relative_delta(ph.idx + 1, *addr).ok_or(CompileError {
+                    out[at] = relative_delta(at + 1, addr).ok_or(CompileError {
                         error: CompileErrorType::UnableToCalculateRelativeJump(ph.clone()),
                         instr: None,
                     })?;
                 }
-                t => panic!("Invalid placeholder type: {:?}", t),
+                PlaceholderType::Undefined => {
+                    return Err(CompileError {
+                        error: CompileErrorType::UnresolvedOperand(ph.clone()),
+                        instr: None,
+                    })
+                }
             }
         }
+        strip_bss(&mut out, &placed, self.capacity);
+
+        // bss is not loaded, there is nothing to debug in it
+        let lines = std::mem::take(&mut self.debug_info.lines);
+        self.debug_info.lines = lines
+            .into_iter()
+            .zip(self.line_sections.iter())
+            .filter(|(_, s)| sections[**s].name != BSS_SECTION)
+            .map(|(mut l, s)| {
+                l.addr += bases[*s];
+                l
+            })
+            .collect();
+
+        Ok(Program {
+            data: out,
+            labels: self
+                .label_map
+                .into_iter()
+                .map(|(name, l)| (name, address(l)))
+                .collect(),
+            test_blocks: self.test_blocks,
+            debug_info: self.debug_info,
+            sections: placed.into_iter().map(|(_, p)| p).collect(),
+            includes: self.includes,
+        })
+    }
+
+    /// Assembles the sources without resolving labels, for `z80ld`. Every
+    /// label use becomes a relocation.
+    pub fn assemble_object(mut self) -> Result<Object, CompileError> {
+        self.assemble_sources()?;
+
+        let sections = self.take_sections();
+        let mut symbols = self
+            .label_map
+            .into_iter()
+            .map(|(name, (section, offset))| Symbol {
+                name,
+                section: sections[section].name.clone(),
+                offset,
+            })
+            .collect::<Vec<_>>();
+        symbols.sort_by_key(|s| (s.section.clone(), s.offset, s.name.clone()));
+        let mut relocations = vec![];
+        for ph in self.placeholders {
+            let kind = match ph.ph_type {
+                PlaceholderType::AbsAddress => RelocationKind::Absolute,
+                PlaceholderType::RelAddress => RelocationKind::Relative,
+                PlaceholderType::ShortValue => RelocationKind::Byte,
+                PlaceholderType::WideValue => RelocationKind::Word,
+                PlaceholderType::Undefined => {
+                    return Err(CompileError {
+                        error: CompileErrorType::UnresolvedOperand(ph),
+                        instr: None,
+                    })
+                }
+            };
+            relocations.push(Relocation {
+                section: sections[ph.section].name.clone(),
+                offset: ph.idx,
+                symbol: ph.label,
+                kind,
+                line: ph.line,
+            });
+        }
 
-        Ok(self.out)
+        Ok(Object {
+            sections,
+            symbols,
+            relocations,
+        })
+    }
+
+    fn assemble_sources(&mut self) -> Result<(), CompileError> {
+        for file in self.source_provider.file_list() {
+            self.constants.clear();
+            self.switch_section("code");
+            let source = self
+                .source_provider
+                .source(&file.filename)
+                .map_err(|e| CompileError {
+                    error: CompileErrorType::IncludeFailed(file.filename.clone(), e.to_string(), 0),
+                    instr: None,
+                })?;
+            self.assemble_file(file.filename, &source)?;
+        }
+        Ok(())
+    }
+
+    /// Makes `name` the section the next items go to.
+    fn switch_section(&mut self, name: &str) {
+        let (current, idx) = &mut self.sections[self.section];
+        std::mem::swap(&mut current.data, &mut self.out);
+        *idx = self.idx;
+
+        self.section = match self.sections.iter().position(|(s, _)| s.name == name) {
+            Some(i) => i,
+            None => {
+                let data = vec![0u8; self.capacity];
+                let name = name.to_string();
+                self.sections.push((Section { name, data }, 0));
+                self.sections.len() - 1
+            }
+        };
+        let (next, idx) = &mut self.sections[self.section];
+        std::mem::swap(&mut next.data, &mut self.out);
+        self.idx = *idx;
+    }
+
+    /// The sections, cut at their location counter.
+    fn take_sections(&mut self) -> Vec<Section> {
+        let (current, idx) = &mut self.sections[self.section];
+        std::mem::swap(&mut current.data, &mut self.out);
+        *idx = self.idx;
+
+        std::mem::take(&mut self.sections)
+            .into_iter()
+            .map(|(mut s, idx)| {
+                s.data.truncate(idx);
+                s
+            })
+            .collect()
+    }
+
+    fn assemble_file(&mut self, filename: String, source: &str) -> Result<(), CompileError> {
+        let file_id = self.debug_info.files.len();
+        self.debug_info.files.push(filename);
+        let mut tokenizer = BufferedTokenizer::new(source, file_id);
+        let mut parser = Parser::new();
+
+        loop {
+            self.start_item(&mut tokenizer)?;
+            if let Some(pi) = parser.parse_next(&mut tokenizer)? {
+                self.process_item(pi, &mut tokenizer)?;
+            } else {
+                return Ok(());
+            }
+        }
     }
 
     fn process_item(
@@ -100,9 +328,11 @@ where
     ) -> Result<(), CompileError> {
         Ok(match item {
             ParseItem::Label(l) => {
-                self.label_map.insert(l.name, self.idx);
+                self.current_label = Some(l.name.clone());
+                self.label_map.insert(l.name, (self.section, self.idx));
             }
             ParseItem::Instruction(inst) => {
+                let labels = [&inst.arg0, &inst.arg1].map(|a| matches!(a, Argument::Label(_)));
                 let (inst, p0, p1) = self.extract_placeholders(inst);
                 let inst = self.replace_constants(inst)?;
                 let data = compile_instruction(&inst, p0, p1, &mut self.placeholders).map_err(
@@ -111,12 +341,19 @@ where
                         err
                     },
                 )?;
+                for (p, label) in [p0, p1].into_iter().zip(labels) {
+                    if label {
+                        self.label_address(p, &inst)?;
+                    }
+                }
+                self.record_location(data.len as usize, EntryKind::Code);
                 for i in 0..data.len {
                     self.out[self.idx] = data.data[i as usize];
                     self.idx += 1;
                 }
             }
             ParseItem::Data(data) => {
+                self.record_location(data.len(), EntryKind::Data);
                 for b in data.iter() {
                     self.out[self.idx] = *b;
                     self.idx += 1;
@@ -125,12 +362,206 @@ where
             ParseItem::Constant(cons) => {
                 self.constants.insert(cons.name, cons.value);
             }
-            ParseItem::Directive(cmd, tokens) => {
-                compile_macro(cmd, tokens, tokenizer, &mut self.macros)?
-            }
+            ParseItem::Directive(cmd, tokens) => match cmd.as_str() {
+                "#test" => {
+                    let block = read_test_block(tokens, tokenizer)?;
+                    // the tests of a library run with the library
+                    if self.library_depth == 0 {
+                        self.test_blocks.push(block);
+                    }
+                }
+                "#include" => self.include_library(&tokens)?,
+                "#section" => {
+                    let name = self.section_name(&tokens)?;
+                    self.switch_section(&name);
+                }
+                "#incimage" => {
+                    let data = self.include_image(&tokens)?;
+                    self.record_location(data.len(), EntryKind::Data);
+                    for b in data {
+                        self.out[self.idx] = b;
+                        self.idx += 1;
+                    }
+                }
+                "#incfont" => {
+                    let (name, table) = self.include_font(&tokens)?;
+                    self.label_map
+                        .insert(name.clone(), (self.section, self.idx));
+                    for (range, offset) in table.ranges {
+                        self.label_map.insert(
+                            format!("{}_{:02X}", name, range.first),
+                            (self.section, self.idx + offset),
+                        );
+                    }
+                    self.record_location(table.data.len(), EntryKind::Data);
+                    for b in table.data {
+                        self.out[self.idx] = b;
+                        self.idx += 1;
+                    }
+                }
+                _ => compile_macro(cmd, tokens, tokenizer, &mut self.macros)?,
+            },
         })
     }
 
+    /// Assembles the library file of an `#include <name>` in place, unless
+    /// it was already included. Its constants stay in the library.
+    fn include_library(&mut self, tokens: &[Token]) -> Result<(), CompileError> {
+        let (line, _) = self.directive_start();
+        let name = match tokens {
+            [Token {
+                token: TokenValue::Library(name),
+                ..
+            }] => name.clone(),
+            _ => {
+                return Err(CompileError {
+                    error: CompileErrorType::InvalidDirective("#include".to_string(), line),
+                    instr: None,
+                })
+            }
+        };
+        if !self.libraries.insert(name.clone()) {
+            return Ok(());
+        }
+        let source = self.source_provider.library(&name).ok_or(CompileError {
+            error: CompileErrorType::IncludeFailed(
+                name.clone(),
+                "no such library".to_string(),
+                line,
+            ),
+            instr: None,
+        })?;
+
+        let constants = std::mem::take(&mut self.constants);
+        let label = self.current_label.take();
+        let section = self.sections[self.section].0.name.clone();
+        self.switch_section("code");
+        self.library_depth += 1;
+        let res = self.assemble_file(format!("<{}>", name), &source);
+        self.library_depth -= 1;
+        self.switch_section(&section);
+        self.constants = constants;
+        self.current_label = label;
+        res
+    }
+
+    /// Name of the section of a `#section` directive.
+    fn section_name(&self, tokens: &[Token]) -> Result<String, CompileError> {
+        match tokens {
+            [Token {
+                token: TokenValue::Identifier(name),
+                ..
+            }] => Ok(name.clone()),
+            _ => Err(CompileError {
+                error: CompileErrorType::InvalidDirective(
+                    "#section".to_string(),
+                    self.directive_start().0,
+                ),
+                instr: None,
+            }),
+        }
+    }
+
+    /// Reads and converts the image of an `#incimage` directive.
+    fn include_image(&mut self, tokens: &[Token]) -> Result<Vec<u8>, CompileError> {
+        let (line, file_id) = self.directive_start();
+        let inc = parse_incimage(tokens, line)?;
+        let failed = |reason: String| CompileError {
+            error: CompileErrorType::IncludeFailed(inc.file.clone(), reason, line),
+            instr: None,
+        };
+        let data = self
+            .binary(file_id, &inc.file)
+            .map_err(|e| failed(e.to_string()))?;
+        convert_image(&inc, &data).map_err(|e| failed(e.to_string()))
+    }
+
+    /// Reads the font of an `#incfont` directive, returns the name of its
+    /// labels and the glyph table.
+    fn include_font(&mut self, tokens: &[Token]) -> Result<(String, GlyphTable), CompileError> {
+        let (line, file_id) = self.directive_start();
+        let inc = parse_incfont(tokens, line)?;
+        let failed = |reason: String| CompileError {
+            error: CompileErrorType::IncludeFailed(inc.file.clone(), reason, line),
+            instr: None,
+        };
+        let data = self
+            .binary(file_id, &inc.file)
+            .map_err(|e| failed(e.to_string()))?;
+        let table = convert_font(&inc, &data).map_err(|e| failed(e.to_string()))?;
+        Ok((inc.name, table))
+    }
+
+    /// Contents of the file `name` an include in `file_id` refers to.
+    fn binary(&mut self, file_id: usize, name: &str) -> std::io::Result<Vec<u8>> {
+        let path = self.include_path(file_id, name);
+        if !self.includes.contains(&path) {
+            self.includes.push(path.clone());
+        }
+        self.source_provider.binary(&path)
+    }
+
+    /// Line and file of the directive being processed.
+    fn directive_start(&self) -> (usize, usize) {
+        match &self.item_start {
+            Some(t) => (t.line, t.file_id),
+            None => (0, 0),
+        }
+    }
+
+    /// `name` relative to the directory of the source file `file_id`.
+    fn include_path(&self, file_id: usize, name: &str) -> String {
+        match self
+            .debug_info
+            .filename(file_id)
+            .and_then(|f| Path::new(f).parent())
+        {
+            Some(dir) => dir.join(name).to_string_lossy().into_owned(),
+            None => name.to_string(),
+        }
+    }
+
+    /// Remembers where the next item starts, and the macros it comes from,
+    /// for its debug info.
+    fn start_item(&mut self, tokenizer: &mut BufferedTokenizer) -> Result<(), CompileError> {
+        while tokenizer.peek()?.token == TokenValue::NewLine {
+            tokenizer.next()?;
+        }
+        self.item_start = Some(tokenizer.peek()?);
+        self.item_macros = tokenizer.expansion_stack();
+        Ok(())
+    }
+
+    fn record_location(&mut self, len: usize, kind: EntryKind) {
+        let start = match &self.item_start {
+            Some(t) if len > 0 => t,
+            _ => return,
+        };
+        self.line_sections.push(self.section);
+        self.debug_info.lines.push(LineEntry {
+            addr: self.idx,
+            len,
+            kind,
+            file_id: start.file_id,
+            line: start.line,
+            column: start.column,
+            label: self.current_label.clone(),
+            macro_stack: self
+                .item_macros
+                .iter()
+                .map(|t| MacroCall {
+                    name: match &t.token {
+                        TokenValue::Identifier(name) => name.clone(),
+                        _ => String::new(),
+                    },
+                    file_id: t.file_id,
+                    line: t.line,
+                    column: t.column,
+                })
+                .collect(),
+        });
+    }
+
     fn replace_constants(&self, inst: Instruction) -> Result<Instruction, CompileError> {
         let arg0 = self.try_parse_constant(&inst.arg0, &inst)?;
         let arg1 = self.try_parse_constant(&inst.arg1, &inst)?;
@@ -176,6 +607,22 @@ where
         )
     }
 
+    /// Placeholder `p` of a label used as a value gets the label's address,
+    /// which needs 16 bits.
+    fn label_address(&mut self, p: isize, inst: &Instruction) -> Result<(), CompileError> {
+        let ph = &mut self.placeholders[p as usize];
+        match ph.ph_type {
+            PlaceholderType::WideValue | PlaceholderType::AbsAddress => {
+                ph.ph_type = PlaceholderType::AbsAddress;
+                Ok(())
+            }
+            _ => Err(CompileError {
+                error: CompileErrorType::UnexpectedArgument(Argument::Label(ph.label.clone())),
+                instr: Some(inst.clone()),
+            }),
+        }
+    }
+
     fn try_extract_placeholder(
         &mut self,
         arg: &Argument,
@@ -185,6 +632,7 @@ where
             Argument::LabelAddress(s) => {
                 self.placeholders.push(Placeholder {
                     idx: self.idx,
+                    section: self.section,
                     label: s.clone(),
                     ph_type: PlaceholderType::Undefined,
                     line,
@@ -194,9 +642,10 @@ where
                     isize::try_from(self.placeholders.len()).unwrap() - 1,
                 )
             }
-            Argument::LabelValue(s) => {
+            Argument::LabelValue(s) | Argument::Label(s) => {
                 self.placeholders.push(Placeholder {
                     idx: self.idx,
+                    section: self.section,
                     label: s.clone(),
                     ph_type: PlaceholderType::Undefined,
                     line,
@@ -214,8 +663,12 @@ where
 #[cfg(test)]
 mod tests {
     use crate::compiler::instructions::{CompileError, CompileErrorType};
-    use crate::compiler::source_provider::{InMemorySourceProvider, SourceHeader};
+    use crate::compiler::source_provider::{InMemorySourceProvider, SourceHeader, SourceProvider};
+    use crate::compiler::{EntryKind, LineEntry, MacroCall, Segment};
+    use crate::linker::Script;
+    use crate::object::RelocationKind;
     use crate::Compiler;
+    use std::io::ErrorKind;
 
     #[test]
     #[rustfmt::skip]
@@ -291,6 +744,220 @@ ld l, (IY + a3h)
         );
     }
 
+    #[test]
+    #[rustfmt::skip]
+    fn test_compile_stack_and_ports() {
+        let compiler = Compiler::new(InMemorySourceProvider {
+            files: vec![(
+                SourceHeader { filename: "main.z80".to_string(), },
+                r#"
+push af
+pop af
+in a, (c)
+out (c), e
+"#.to_string(),
+            )],
+        }, 6);
+
+        compare_memory(
+            vec![
+                0xF5,       // push af
+                0xF1,       // pop af
+                0xED, 0x78, // in a, (c)
+                0xED, 0x59, // out (c), e
+            ],
+            compiler.compile().unwrap(),
+        );
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_compile_inc_dec() {
+        let compiler = Compiler::new(InMemorySourceProvider {
+            files: vec![(
+                SourceHeader { filename: "main.z80".to_string(), },
+                r#"
+inc b
+inc d
+dec a
+dec l
+"#.to_string(),
+            )],
+        }, 4);
+
+        compare_memory(
+            vec![
+                0x04, // inc b
+                0x14, // inc d
+                0x3D, // dec a
+                0x2D, // dec l
+            ],
+            compiler.compile().unwrap(),
+        );
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn label_addresses() {
+        let compiler = Compiler::new(InMemorySourceProvider {
+            files: vec![(
+                SourceHeader { filename: "main.z80".to_string(), },
+                r#"
+.start: ld ix, table
+ld hl, table
+call &start
+.table: 12h
+"#.to_string(),
+            )],
+        }, 16);
+
+        compare_memory(
+            vec![
+                0xDD, 0x21, 0x0A, 0x00, // ld ix, table
+                0x21, 0x0A, 0x00,       // ld hl, table
+                0xCD, 0x00, 0x00,       // call &start
+                0x12,
+            ],
+            compiler.compile().unwrap(),
+        );
+
+        let short = Compiler::new(InMemorySourceProvider {
+            files: vec![(
+                SourceHeader { filename: "main.z80".to_string(), },
+                "ld a, table\n.table: 12h".to_string(),
+            )],
+        }, 16);
+        assert!(short.compile().is_err());
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn assemble_object() {
+        let compiler = Compiler::new(InMemorySourceProvider {
+            files: vec![(
+                SourceHeader { filename: "main.z80".to_string(), },
+                r#"
+.start: call &print
+ld b, *count
+JR NC, &start
+.count: 12h
+"#.to_string(),
+            )],
+        }, 16);
+
+        let object = compiler.assemble_object().unwrap();
+        assert_eq!(
+            vec![
+                0xCD, 0x00, 0x00, // call &print
+                0x06, 0x00,       // ld b, *count
+                0x30, 0x00,       // JR NC, &start
+                0x12,
+            ],
+            object.sections[0].data
+        );
+        assert_eq!(
+            vec![("start", 0), ("count", 7)],
+            object.symbols.iter().map(|s| (s.name.as_str(), s.offset)).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![
+                ("print", 1, RelocationKind::Absolute, 2),
+                ("count", 4, RelocationKind::Byte, 3),
+                ("start", 6, RelocationKind::Relative, 4),
+            ],
+            object.relocations.iter()
+                .map(|r| (r.symbol.as_str(), r.offset, r.kind, r.line))
+                .collect::<Vec<_>>()
+        );
+
+        let compiler = || Compiler::new(InMemorySourceProvider {
+            files: vec![(
+                SourceHeader { filename: "main.z80".to_string(), },
+                "nop\nrst *vector\n.vector: 08h\n".to_string(),
+            )],
+        }, 16);
+        let err = compiler().assemble_object().unwrap_err();
+        assert_eq!("l2 - label 'vector' can't be used as an operand here", err.to_string());
+        let err = compiler().assemble().unwrap_err();
+        assert_eq!("l2 - label 'vector' can't be used as an operand here", err.to_string());
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn sections() {
+        let source = r#"
+.start: ld hl, counter
+ld a, *message
+#section data
+.message: 41h 42h
+#section bss
+.counter: 00h 00h
+#section code
+ret
+"#;
+        let compiler = |script: Option<&str>| {
+            let compiler = Compiler::new(InMemorySourceProvider {
+                files: vec![(
+                    SourceHeader { filename: "main.z80".to_string(), },
+                    source.to_string(),
+                )],
+            }, 16);
+            match script {
+                Some(s) => compiler.with_script(Script::parse(s).unwrap()),
+                None => compiler,
+            }
+        };
+
+        let program = compiler(None).assemble().unwrap();
+        compare_memory(
+            vec![
+                0x21, 0x00, 0x80, // ld hl, counter
+                0x3E, 0x41,       // ld a, *message
+                0xC9,             // ret
+                0x41, 0x42,       // data
+                0, 0, 0, 0, 0, 0, 0, 0,
+            ],
+            program.data.clone(),
+        );
+        assert_eq!(Some(0x8000), program.label("counter"));
+        assert_eq!(Some(6), program.label("message"));
+        assert_eq!(
+            vec![0, 3, 6, 7, 5],
+            program.debug_info.lines.iter().map(|l| l.addr).collect::<Vec<_>>()
+        );
+
+        let script = "memory ROM 0000h-0005h
+memory RAM 8000h-FFFFh
+section code in ROM
+section data in ROM
+section bss in RAM";
+        let err = compiler(Some(script)).assemble().unwrap_err();
+        assert_eq!("section 'data' overflows ROM by 2 bytes", err.to_string());
+        let err = compiler(Some("section code")).assemble().unwrap_err();
+        assert_eq!("main.z80: section 'data' is not in the linker script", err.to_string());
+
+        let err = |source: &str| {
+            Compiler::new(InMemorySourceProvider {
+                files: vec![(
+                    SourceHeader { filename: "main.z80".to_string(), },
+                    source.to_string(),
+                )],
+            }, 16).assemble().unwrap_err().to_string()
+        };
+        assert_eq!(
+            "l1 - '*buf' reads bss, which has no data",
+            err(".main: ld hl, *buf\n#section bss\n.buf: 00h\n")
+        );
+        assert_eq!(
+            "l1 - '*end' reads past the end of its section",
+            err(".main: ld b, *end\n.end:\n")
+        );
+        assert_eq!(
+            "l1 - '*buf' reads past the end of its section",
+            err("ld hl, *buf\n#section data\n.buf: 01h\n")
+        );
+    }
+
     #[test]
     fn label_not_found_error() {
         let compiler = Compiler::new(
@@ -309,13 +976,15 @@ ld a, *missing_label
             1024,
         );
 
+        let err = compiler.compile().unwrap_err();
         assert_eq!(
             CompileError {
                 error: CompileErrorType::LabelNotFound("missing_label".to_string(), 3),
                 instr: None,
             },
-            compiler.compile().unwrap_err()
-        )
+            err
+        );
+        assert_eq!("l3 - label 'missing_label' not found", err.to_string());
     }
 
     #[test]
@@ -587,6 +1256,213 @@ ld arg1, arg2
         );
     }
 
+    #[test]
+    #[rustfmt::skip]
+    fn test_assemble_labels_and_test_blocks() {
+        let compiler = Compiler::new(InMemorySourceProvider {
+            files: vec![(
+                SourceHeader { filename: "main.z80".to_string(), },
+                r#"
+.start:  ld a, 1h
+#test start_sets_a
+call start
+expect A, 1h
+#endt
+.end:    ret
+"#.to_string(),
+            )],
+        }, 1024);
+
+        let program = compiler.assemble().unwrap();
+
+        assert_eq!(Some(0), program.label("start"));
+        assert_eq!(Some(2), program.label("end"));
+        assert_eq!(1, program.test_blocks.len());
+        assert_eq!("start_sets_a", program.test_blocks[0].name);
+        assert_eq!(3, program.test_blocks[0].line);
+        assert_eq!(
+            vec![Segment { addr: 0, data: vec![0x3E, 0x01, 0xC9] }],
+            program.segments()
+        );
+        compare_memory(vec![0x3E, 0x01, 0xC9], program.data);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_debug_info() {
+        let compiler = Compiler::new(InMemorySourceProvider {
+            files: vec![(
+                SourceHeader { filename: "main.z80".to_string(), },
+                r#"
+#defm inner arg1
+    inc arg1
+#endm
+#defm outer arg1
+  #exec inner arg1
+#endm
+
+.start:  ld a, 1h
+.table:  12h 34h
+         #exec outer B
+"#.to_string(),
+            )],
+        }, 1024);
+
+        let info = compiler.assemble().unwrap().debug_info;
+        let call = |name: &str, line, column| MacroCall {
+            name: name.to_string(),
+            file_id: 0,
+            line,
+            column,
+        };
+
+        assert_eq!(vec!["main.z80".to_string()], info.files);
+        assert_eq!(
+            vec![
+                LineEntry {
+                    addr: 0, len: 2, kind: EntryKind::Code, file_id: 0, line: 9, column: 10,
+                    label: Some("start".to_string()), macro_stack: vec![],
+                },
+                LineEntry {
+                    addr: 2, len: 1, kind: EntryKind::Data, file_id: 0, line: 10, column: 10,
+                    label: Some("table".to_string()), macro_stack: vec![],
+                },
+                LineEntry {
+                    addr: 3, len: 1, kind: EntryKind::Data, file_id: 0, line: 10, column: 14,
+                    label: Some("table".to_string()), macro_stack: vec![],
+                },
+                LineEntry {
+                    addr: 4, len: 1, kind: EntryKind::Code, file_id: 0, line: 3, column: 5,
+                    label: Some("table".to_string()),
+                    macro_stack: vec![call("outer", 11, 16), call("inner", 6, 9)],
+                },
+            ],
+            info.lines
+        );
+        assert_eq!(Some(&info.lines[2]), info.location(3));
+        assert_eq!(vec![4], info.addresses("main.z80", 3));
+    }
+
+    #[test]
+    fn unterminated_test_block_error() {
+        let compiler = Compiler::new(
+            InMemorySourceProvider {
+                files: vec![(
+                    SourceHeader {
+                        filename: "main.z80".to_string(),
+                    },
+                    "#test never_ends\ncall start\n".to_string(),
+                )],
+            },
+            1024,
+        );
+
+        assert_eq!(
+            CompileError {
+                error: CompileErrorType::UnterminatedBlock("never_ends".to_string(), 1),
+                instr: None,
+            },
+            compiler.assemble().unwrap_err()
+        )
+    }
+
+    /// One source file and the files it includes.
+    struct WithIncludes {
+        source: &'static str,
+        includes: Vec<(&'static str, Vec<u8>)>,
+    }
+
+    impl SourceProvider for WithIncludes {
+        fn file_list(&self) -> Vec<SourceHeader> {
+            vec![SourceHeader {
+                filename: "src/main.z80".to_string(),
+            }]
+        }
+
+        fn source(&self, _filename: &str) -> std::io::Result<String> {
+            Ok(self.source.to_string())
+        }
+
+        fn binary(&self, filename: &str) -> std::io::Result<Vec<u8>> {
+            self.includes
+                .iter()
+                .find(|(name, _)| *name == filename)
+                .map(|(_, data)| data.clone())
+                .ok_or(ErrorKind::NotFound.into())
+        }
+    }
+
+    #[test]
+    fn missing_source() {
+        struct Missing;
+        impl SourceProvider for Missing {
+            fn file_list(&self) -> Vec<SourceHeader> {
+                vec![SourceHeader {
+                    filename: "main.z80".to_string(),
+                }]
+            }
+
+            fn source(&self, _filename: &str) -> std::io::Result<String> {
+                Err(ErrorKind::NotFound.into())
+            }
+        }
+
+        let err = Compiler::new(Missing, 16).assemble().unwrap_err();
+        assert_eq!(None, err.line());
+        assert_eq!(
+            "unable to include 'main.z80': entity not found",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn include_image() {
+        // red and blue
+        let mut ship = b"P6 2 1 255\n".to_vec();
+        ship.extend([255, 0, 0, 0, 0, 255]);
+        let compiler = Compiler::new(
+            WithIncludes {
+                source: r#"
+.palette: #incimage "art/ship.ppm" palette transparent 0h
+.ship:    #incimage "art/ship.ppm" tiles 1h 1h transparent 0h
+"#,
+                includes: vec![("src/art/ship.ppm", ship)],
+            },
+            1024,
+        );
+
+        let program = compiler.assemble().unwrap();
+        assert_eq!(Some(512), program.label("ship"));
+        assert_eq!(vec![0, 0, 0xF8, 0, 0, 0x3E], program.data[..6].to_vec());
+        assert_eq!(vec![1, 2], program.data[512..514].to_vec());
+        assert_eq!(EntryKind::Data, program.debug_info.lines[1].kind);
+        assert_eq!(3, program.debug_info.lines[1].line);
+        assert_eq!(vec!["src/art/ship.ppm"], program.includes);
+
+        let missing = Compiler::new(
+            WithIncludes {
+                source: "#incimage \"logo.png\"",
+                includes: vec![],
+            },
+            1024,
+        );
+        assert_eq!(
+            "l1 - unable to include 'logo.png': entity not found",
+            missing.assemble().unwrap_err().to_string()
+        );
+        let invalid = Compiler::new(
+            WithIncludes {
+                source: "#incimage \"logo.png\" tiles 8h",
+                includes: vec![],
+            },
+            1024,
+        );
+        assert_eq!(
+            "l1 - invalid directive '#incimage'",
+            invalid.assemble().unwrap_err().to_string()
+        );
+    }
+
     fn compare_memory(expected: Vec<u8>, actual: Vec<u8>) {
         if actual.len() < expected.len() {
             eprintln!("expected: {:?}, actual {:?}", expected.len(), actual.len());
@@ -610,4 +1486,94 @@ ld arg1, arg2
             }
         }
     }
+
+    #[test]
+    fn include_font() {
+        let font = b"size 3 2\nchar A\n.#.\n#.#\nchar B\n##.\n###\nchar a\n#..\n..#\n";
+        let compiler = Compiler::new(
+            WithIncludes {
+                source: r#"
+ld ix, font
+#incfont "font.txt" font
+#incfont "font.txt" upper 41h-41h 40h-40h
+"#,
+                includes: vec![("src/font.txt", font.to_vec())],
+            },
+            1024,
+        );
+
+        let program = compiler.assemble().unwrap();
+        assert_eq!(Some(4), program.label("font"));
+        assert_eq!(Some(9), program.label("font_41"));
+        assert_eq!(Some(15), program.label("font_61"));
+        assert_eq!(Some(19), program.label("upper"));
+        assert_eq!(Some(24), program.label("upper_41"));
+        assert_eq!(Some(28), program.label("upper_40"));
+        assert_eq!(
+            vec![3, 2, 2, 0x41, 0x42, 0x40, 0xA0],
+            program.data[4..11].to_vec()
+        );
+        assert_eq!(
+            vec![0x40, 0xA0, 0x40, 0x40, 0, 0, 0xFF, 0],
+            program.data[24..32].to_vec()
+        );
+
+        let invalid = Compiler::new(
+            WithIncludes {
+                source: "#incfont \"font.txt\" font 20h",
+                includes: vec![],
+            },
+            1024,
+        );
+        assert_eq!(
+            "l1 - invalid directive '#incfont'",
+            invalid.assemble().unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn include_library() {
+        let compiler = Compiler::new(
+            InMemorySourceProvider {
+                files: vec![(
+                    SourceHeader {
+                        filename: "main.z80".to_string(),
+                    },
+                    r#"
+@size: 10h
+call &mult16
+#include <std/math.z80>
+#include <std/math.z80>
+ld b, @size
+"#
+                    .to_string(),
+                )],
+            },
+            1024,
+        );
+
+        let program = compiler.assemble().unwrap();
+        let mult16 = program.label("mult16").unwrap();
+        assert_eq!(vec![0xCD, mult16 as u8, 0], program.data[..3].to_vec());
+        assert!(program.label("div16").is_some());
+        // included once, its tests stay in the library
+        assert_eq!(vec!["main.z80", "<std/math.z80>"], program.debug_info.files);
+        assert!(program.test_blocks.is_empty());
+
+        let missing = Compiler::new(
+            InMemorySourceProvider {
+                files: vec![(
+                    SourceHeader {
+                        filename: "main.z80".to_string(),
+                    },
+                    "\n#include <std/nope.z80>".to_string(),
+                )],
+            },
+            1024,
+        );
+        assert_eq!(
+            "l2 - unable to include 'std/nope.z80': no such library",
+            missing.assemble().unwrap_err().to_string()
+        );
+    }
 }
@@ -0,0 +1,56 @@
+use crate::compiler::debug_info::DebugInfo;
+use crate::compiler::test_blocks::TestBlock;
+use crate::linker::Placed;
+use std::collections::HashMap;
+
+#[derive(Debug)]
+pub struct Program {
+    pub data: Vec<u8>,
+    pub labels: HashMap<String, usize>,
+    pub test_blocks: Vec<TestBlock>,
+    pub debug_info: DebugInfo,
+    /// Where the linker script put the sections.
+    pub sections: Vec<Placed>,
+    /// Files `#incimage` and `#incfont` read, the program depends on them too.
+    pub includes: Vec<String>,
+}
+
+/// Bytes the program defines starting at `addr`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Segment {
+    pub addr: usize,
+    pub data: Vec<u8>,
+}
+
+impl Program {
+    pub fn label(&self, name: &str) -> Option<u16> {
+        self.labels.get(name).map(|addr| *addr as u16)
+    }
+
+    /// The ranges of `data` that instructions and data statements produced,
+    /// in address order. The rest of `data` is padding.
+    pub fn segments(&self) -> Vec<Segment> {
+        let mut ranges = self
+            .debug_info
+            .lines
+            .iter()
+            .map(|l| (l.addr, l.addr + l.len))
+            .collect::<Vec<_>>();
+        ranges.sort();
+
+        let mut merged: Vec<(usize, usize)> = vec![];
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        merged
+            .into_iter()
+            .map(|(start, end)| Segment {
+                addr: start,
+                data: self.data[start..end].to_vec(),
+            })
+            .collect()
+    }
+}
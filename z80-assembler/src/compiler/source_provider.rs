@@ -1,3 +1,5 @@
+use crate::stdlib;
+
 #[derive(Clone)]
 pub struct SourceHeader {
     pub filename: String,
@@ -5,7 +7,19 @@ pub struct SourceHeader {
 
 pub trait SourceProvider {
     fn file_list(&self) -> Vec<SourceHeader>;
-    fn source(&self, filename: &str) -> String;
+    fn source(&self, filename: &str) -> std::io::Result<String>;
+
+    /// Contents of a file a directive like `#incimage` refers to, the name
+    /// is relative to the working directory. Read from disk by default.
+    fn binary(&self, filename: &str) -> std::io::Result<Vec<u8>> {
+        std::fs::read(filename)
+    }
+
+    /// Source of the library file of an `#include <name>`, the standard
+    /// library bundled with the assembler by default.
+    fn library(&self, name: &str) -> Option<String> {
+        stdlib::source(name).map(|s| s.to_string())
+    }
 }
 
 pub struct InMemorySourceProvider {
@@ -20,11 +34,11 @@ impl SourceProvider for InMemorySourceProvider {
             .collect::<Vec<_>>()
     }
 
-    fn source(&self, filename: &str) -> String {
+    fn source(&self, filename: &str) -> std::io::Result<String> {
         self.files
             .iter()
             .find(|(h, _)| h.filename == filename)
             .map(|(_, c)| c.clone())
-            .unwrap()
+            .ok_or(std::io::ErrorKind::NotFound.into())
     }
 }
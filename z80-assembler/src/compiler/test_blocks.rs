@@ -0,0 +1,57 @@
+use crate::compiler::instructions::{CompileError, CompileErrorType};
+use crate::parser::tokenizer::{BufferedTokenizer, Tokenizer};
+use crate::parser::{Token, TokenValue};
+
+/// Raw body of a `#test <name>` ... `#endt` block. The assembler does not
+/// interpret these, they are kept for test runners.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TestBlock {
+    pub name: String,
+    pub line: usize,
+    pub file_id: usize,
+    pub tokens: Vec<Token>,
+}
+
+pub fn read_test_block(
+    header: Vec<Token>,
+    tokenizer: &mut BufferedTokenizer,
+) -> Result<TestBlock, CompileError> {
+    let (name, line, file_id) = match header.first() {
+        Some(Token {
+            token: TokenValue::Identifier(name),
+            line,
+            file_id,
+            ..
+        }) if header.len() == 1 => (name.clone(), *line, *file_id),
+        t => {
+            return Err(CompileError {
+                error: CompileErrorType::InvalidDirective(
+                    "#test".to_string(),
+                    t.map(|t| t.line).unwrap_or(0),
+                ),
+                instr: None,
+            })
+        }
+    };
+
+    let mut block = TestBlock {
+        name,
+        line,
+        file_id,
+        tokens: vec![],
+    };
+
+    loop {
+        let t = tokenizer.next()?;
+        match &t.token {
+            TokenValue::Directive(d) if d.starts_with("#endt") => return Ok(block),
+            TokenValue::EOF => {
+                return Err(CompileError {
+                    error: CompileErrorType::UnterminatedBlock(block.name, line),
+                    instr: None,
+                })
+            }
+            _ => block.tokens.push(t),
+        }
+    }
+}
@@ -32,6 +32,8 @@ pub enum Argument {
     LabelValue(String),
     DirectAddress(u16),
     LabelAddress(String),
+    /// Address of a label as a 16 bit value, like `ld hl, table`.
+    Label(String),
     ShortRegAddress(ShortReg),
     WideRegAddress(WideReg),
     RegOffsetAddress(WideReg, u16),
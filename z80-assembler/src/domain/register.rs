@@ -25,6 +25,6 @@ pub fn parse_register(identifier: &str) -> ParsedRegister {
         "sp" => ParsedRegister::WideReg(WideReg::SP),
         "ix" => ParsedRegister::WideReg(WideReg::IX),
         "iy" => ParsedRegister::WideReg(WideReg::IY),
-        _ => unimplemented!("unimplemented identifier handler: {:?}", identifier),
+        _ => ParsedRegister::Error(identifier.to_string()),
     }
 }
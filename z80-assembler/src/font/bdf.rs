@@ -0,0 +1,126 @@
+use crate::font::{Font, FontError, GlyphBuilder};
+use std::collections::BTreeMap;
+
+/// Decodes a BDF font. Glyphs are placed in the cell of `FONTBOUNDINGBOX`
+/// by their own bounding box, characters without an encoding are skipped.
+pub fn decode(data: &[u8]) -> Result<Font, FontError> {
+    let text = String::from_utf8_lossy(data);
+    let mut lines = text.lines().enumerate();
+    // width, height and offsets of the font's bounding box
+    let mut cell: Option<[isize; 4]> = None;
+    let mut font = Font {
+        width: 0,
+        height: 0,
+        glyphs: BTreeMap::new(),
+    };
+
+    let numbers = |n: usize, words: &[&str], count: usize| -> Result<Vec<isize>, FontError> {
+        let values = words
+            .iter()
+            .skip(1)
+            .take(count)
+            .map(|w| w.parse::<isize>())
+            .collect::<Result<Vec<_>, _>>()
+            .ok()
+            .filter(|v| v.len() == count);
+        values.ok_or_else(|| FontError(format!("l{} - invalid {}", n + 1, words[0])))
+    };
+
+    while let Some((n, line)) = lines.next() {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        match words.first() {
+            Some(&"FONTBOUNDINGBOX") => {
+                let v = numbers(n, &words, 4)?;
+                if v[0] <= 0 || v[1] <= 0 {
+                    return Err(FontError(format!("l{} - invalid FONTBOUNDINGBOX", n + 1)));
+                }
+                cell = Some([v[0], v[1], v[2], v[3]]);
+                font.width = v[0] as usize;
+                font.height = v[1] as usize;
+            }
+            Some(&"STARTCHAR") => {
+                let [_, cell_height, cell_x, cell_y] = cell.ok_or_else(|| {
+                    FontError(format!("l{} - STARTCHAR before FONTBOUNDINGBOX", n + 1))
+                })?;
+                let mut encoding = None;
+                let mut bbx = None;
+                let mut glyph = GlyphBuilder::new(font.width, font.height);
+
+                while let Some((n, line)) = lines.next() {
+                    let words = line.split_whitespace().collect::<Vec<_>>();
+                    match words.first() {
+                        Some(&"ENCODING") => encoding = Some(numbers(n, &words, 1)?[0]),
+                        Some(&"BBX") => bbx = Some(numbers(n, &words, 4)?),
+                        Some(&"BITMAP") => {
+                            let b = bbx.clone().ok_or_else(|| {
+                                FontError(format!("l{} - BITMAP before BBX", n + 1))
+                            })?;
+                            // rows from the top of the cell
+                            let top = (cell_height + cell_y) - (b[1] + b[3]);
+                            for row in 0..b[1] {
+                                let (n, hex) = lines.next().ok_or_else(|| {
+                                    FontError("unexpected end of the BDF font".to_string())
+                                })?;
+                                let bits = u64::from_str_radix(hex.trim(), 16).map_err(|_| {
+                                    FontError(format!("l{} - invalid bitmap row", n + 1))
+                                })?;
+                                let len = hex.trim().len() as isize * 4;
+                                for x in 0..b[0].min(len) {
+                                    if bits >> (len - 1 - x) & 1 == 1 {
+                                        glyph.set(b[2] - cell_x + x, top + row);
+                                    }
+                                }
+                            }
+                        }
+                        Some(&"ENDCHAR") => break,
+                        _ => {}
+                    }
+                }
+                if let Some(code) = encoding.filter(|e| *e >= 0) {
+                    font.glyphs.insert(code as u32, glyph.data);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if cell.is_none() {
+        return Err(FontError("BDF font without FONTBOUNDINGBOX".to_string()));
+    }
+    Ok(font)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::font::bdf::decode;
+
+    #[test]
+    fn decode_bdf() {
+        let bdf = b"STARTFONT 2.1
+FONT test
+FONTBOUNDINGBOX 4 4 0 -1
+CHARS 2
+STARTCHAR A
+ENCODING 65
+BBX 3 3 0 0
+BITMAP
+40
+A0
+E0
+ENDCHAR
+STARTCHAR comma
+ENCODING 44
+BBX 1 2 1 -1
+BITMAP
+80
+80
+ENDCHAR
+ENDFONT
+";
+        let font = decode(bdf).unwrap();
+        assert_eq!((4, 4), (font.width, font.height));
+        // the baseline is one row above the bottom of the cell
+        assert_eq!(Some(&vec![0x40, 0xA0, 0xE0, 0x00]), font.glyphs.get(&65));
+        assert_eq!(Some(&vec![0x00, 0x00, 0x40, 0x40]), font.glyphs.get(&44));
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+
+mod bdf;
+mod psf;
+mod text;
+
+/// Marks the end of the ranges in a glyph table, a first character after the
+/// last one.
+pub const END_OF_RANGES: [u8; 2] = [0xFF, 0x00];
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FontError(pub String);
+
+impl Display for FontError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for FontError {}
+
+/// Fixed size bitmap font. Glyphs are stored row by row, each row in
+/// `(width + 7) / 8` bytes with the leftmost pixel in the high bit.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Font {
+    pub width: usize,
+    pub height: usize,
+    pub glyphs: BTreeMap<u32, Vec<u8>>,
+}
+
+/// Decodes a BDF or PSF font, or the text format of `text::parse`.
+pub fn decode(data: &[u8]) -> Result<Font, FontError> {
+    if data.starts_with(&psf::PSF1_MAGIC) || data.starts_with(&psf::PSF2_MAGIC) {
+        psf::decode(data)
+    } else if data.starts_with(b"STARTFONT") {
+        bdf::decode(data)
+    } else {
+        text::parse(data)
+    }
+}
+
+/// Characters `first` to `last` of a glyph table, `last` included.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Range {
+    pub first: u8,
+    pub last: u8,
+}
+
+/// Glyph table for the text routines of the standard library, with the
+/// offset of the glyphs of every range.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GlyphTable {
+    pub data: Vec<u8>,
+    pub ranges: Vec<(Range, usize)>,
+}
+
+impl Font {
+    pub fn row_bytes(&self) -> usize {
+        self.width.div_ceil(8)
+    }
+
+    pub fn glyph_size(&self) -> usize {
+        self.row_bytes() * self.height
+    }
+
+    /// Ranges of consecutive characters the font has, up to 255.
+    pub fn ranges(&self) -> Vec<Range> {
+        let mut ranges: Vec<Range> = vec![];
+        for c in self.glyphs.keys().filter(|c| **c <= 0xFF).map(|c| *c as u8) {
+            match ranges.last_mut() {
+                Some(r) if c > 0 && r.last == c - 1 => r.last = c,
+                _ => ranges.push(Range { first: c, last: c }),
+            }
+        }
+        ranges
+    }
+
+    /// Packs `ranges` of the font, the characters it doesn't have are blank.
+    ///
+    /// ```text
+    /// width, height, bytes per glyph
+    /// first, last, glyphs of first to last    ; for every range
+    /// FFh, 00h
+    /// ```
+    pub fn table(&self, ranges: &[Range]) -> Result<GlyphTable, FontError> {
+        if self.width > 0xFF || self.glyph_size() > 0xFF {
+            return Err(FontError(format!(
+                "{}x{} glyphs don't fit a glyph table",
+                self.width, self.height
+            )));
+        }
+        let mut data = vec![self.width as u8, self.height as u8, self.glyph_size() as u8];
+        let mut offsets = vec![];
+        for r in ranges {
+            if r.first > r.last {
+                return Err(FontError(format!(
+                    "invalid range {:02X}h-{:02X}h",
+                    r.first, r.last
+                )));
+            }
+            data.extend([r.first, r.last]);
+            offsets.push((*r, data.len()));
+            for c in r.first..=r.last {
+                match self.glyphs.get(&(c as u32)) {
+                    Some(glyph) => data.extend(glyph),
+                    None => data.extend(vec![0; self.glyph_size()]),
+                }
+            }
+        }
+        data.extend(END_OF_RANGES);
+        Ok(GlyphTable {
+            data,
+            ranges: offsets,
+        })
+    }
+}
+
+/// Builds glyphs pixel by pixel.
+struct GlyphBuilder {
+    width: usize,
+    height: usize,
+    data: Vec<u8>,
+}
+
+impl GlyphBuilder {
+    fn new(width: usize, height: usize) -> Self {
+        GlyphBuilder {
+            width,
+            height,
+            data: vec![0; width.div_ceil(8) * height],
+        }
+    }
+
+    /// Pixels outside the glyph are ignored.
+    fn set(&mut self, x: isize, y: isize) {
+        if x < 0 || y < 0 || x as usize >= self.width || y as usize >= self.height {
+            return;
+        }
+        let (x, y) = (x as usize, y as usize);
+        self.data[y * self.width.div_ceil(8) + x / 8] |= 0x80 >> (x % 8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::font::{decode, Range};
+
+    #[test]
+    fn glyph_table() {
+        let font = decode(
+            b"size 3 2\n\
+              char 41h\n\
+              .#.\n\
+              #.#\n\
+              char 42h\n\
+              ##.\n\
+              ###\n\
+              char 61h\n\
+              #..\n\
+              ..#\n",
+        )
+        .unwrap();
+
+        let ranges = font.ranges();
+        assert_eq!(
+            vec![
+                Range {
+                    first: 0x41,
+                    last: 0x42
+                },
+                Range {
+                    first: 0x61,
+                    last: 0x61
+                }
+            ],
+            ranges
+        );
+        let table = font.table(&ranges).unwrap();
+        assert_eq!(
+            vec![
+                3, 2, 2, // width, height, bytes per glyph
+                0x41, 0x42, 0x40, 0xA0, 0xC0, 0xE0, // A and B
+                0x61, 0x61, 0x80, 0x20, // a
+                0xFF, 0x00,
+            ],
+            table.data
+        );
+        assert_eq!(
+            vec![5, 11],
+            table.ranges.iter().map(|r| r.1).collect::<Vec<_>>()
+        );
+
+        // missing characters are blank
+        let table = font
+            .table(&[Range {
+                first: 0x40,
+                last: 0x41,
+            }])
+            .unwrap();
+        assert_eq!(
+            vec![0x40, 0x41, 0, 0, 0x40, 0xA0],
+            table.data[3..9].to_vec()
+        );
+    }
+}
@@ -0,0 +1,72 @@
+use crate::font::{Font, FontError};
+use std::collections::BTreeMap;
+
+pub const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+pub const PSF2_MAGIC: [u8; 4] = [0x72, 0xB5, 0x4A, 0x86];
+
+/// Decodes a PSF1 or PSF2 console font, glyph `n` is character `n`. The
+/// unicode tables are ignored.
+pub fn decode(data: &[u8]) -> Result<Font, FontError> {
+    let truncated = || FontError("truncated PSF font".to_string());
+    let u32_at = |pos: usize| -> Result<usize, FontError> {
+        let b = data.get(pos..pos + 4).ok_or_else(truncated)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+    };
+
+    let (start, count, size, width, height) = if data.starts_with(&PSF2_MAGIC) {
+        (
+            u32_at(8)?,
+            u32_at(16)?,
+            u32_at(20)?,
+            u32_at(28)?,
+            u32_at(24)?,
+        )
+    } else {
+        let mode = *data.get(2).ok_or_else(truncated)?;
+        let height = *data.get(3).ok_or_else(truncated)? as usize;
+        let count = if mode & 0x01 != 0 { 512 } else { 256 };
+        (4, count, height, 8, height)
+    };
+    if width == 0 || height == 0 || size != width.div_ceil(8) * height {
+        return Err(FontError("invalid PSF glyph size".to_string()));
+    }
+
+    let glyphs = data
+        .get(start..start + count * size)
+        .ok_or_else(truncated)?
+        .chunks_exact(size)
+        .enumerate()
+        .map(|(i, g)| (i as u32, g.to_vec()))
+        .collect::<BTreeMap<_, _>>();
+    Ok(Font {
+        width,
+        height,
+        glyphs,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::font::psf::{decode, PSF1_MAGIC, PSF2_MAGIC};
+
+    #[test]
+    fn decode_psf() {
+        let mut psf1 = PSF1_MAGIC.to_vec();
+        psf1.extend([0, 2]);
+        psf1.extend((0..512).map(|i| (i / 2) as u8));
+        let font = decode(&psf1).unwrap();
+        assert_eq!((8, 2, 256), (font.width, font.height, font.glyphs.len()));
+        assert_eq!(Some(&vec![0x41, 0x41]), font.glyphs.get(&0x41));
+
+        // 10 pixels wide, two bytes a row
+        let mut psf2 = PSF2_MAGIC.to_vec();
+        for v in [0u32, 32, 0, 2, 2, 1, 10] {
+            psf2.extend(v.to_le_bytes());
+        }
+        psf2.extend([0xFF, 0xC0, 0x80, 0x40]);
+        let font = decode(&psf2).unwrap();
+        assert_eq!((10, 1), (font.width, font.height));
+        assert_eq!(Some(&vec![0x80, 0x40]), font.glyphs.get(&1));
+        assert!(decode(&psf2[..34]).is_err());
+    }
+}
@@ -0,0 +1,126 @@
+use crate::font::{Font, FontError, GlyphBuilder};
+use std::collections::BTreeMap;
+
+/// Parses a font drawn in text, one `char` line followed by the rows of
+/// each glyph. `#` or `X` is a pixel that is on, `.` or a space one that is
+/// off. Without a `size` line the first glyph gives the size.
+///
+/// ```text
+/// ; comments start with a semicolon
+/// size 5 7
+/// char A          ; or 41h, or 65
+/// .###.
+/// #...#
+/// ...
+/// ```
+pub fn parse(data: &[u8]) -> Result<Font, FontError> {
+    let text =
+        std::str::from_utf8(data).map_err(|_| FontError("unknown font format".to_string()))?;
+    let mut size: Option<(usize, usize)> = None;
+    // code, first line and rows of every glyph
+    let mut glyphs: Vec<(u32, usize, Vec<&str>)> = vec![];
+
+    for (n, line) in text.lines().enumerate() {
+        let n = n + 1;
+        let err = |message: String| FontError(format!("l{} - {}", n, message));
+        let line = line.split(';').next().unwrap().trim_end();
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("size") => {
+                let mut number = || {
+                    words
+                        .next()
+                        .and_then(|w| w.parse::<usize>().ok())
+                        .filter(|v| *v > 0)
+                        .ok_or_else(|| err("expected size <width> <height>".to_string()))
+                };
+                size = Some((number()?, number()?));
+            }
+            Some("char") => {
+                let code = match words.next() {
+                    Some(c) => code(c).ok_or_else(|| err(format!("invalid character '{}'", c)))?,
+                    None => return Err(err("expected a character after 'char'".to_string())),
+                };
+                glyphs.push((code, n, vec![]));
+            }
+            _ if line.trim().is_empty() => {}
+            _ => match glyphs.last_mut() {
+                Some((_, _, rows)) => rows.push(line),
+                None => return Err(err("expected 'char' before the glyph rows".to_string())),
+            },
+        }
+    }
+
+    let (width, height) = match (size, glyphs.first()) {
+        (Some(size), _) => size,
+        (None, Some((_, _, rows))) => (
+            rows.iter().map(|r| r.chars().count()).max().unwrap_or(0),
+            rows.len(),
+        ),
+        (None, None) => return Err(FontError("the font has no glyphs".to_string())),
+    };
+
+    let mut font = Font {
+        width,
+        height,
+        glyphs: BTreeMap::new(),
+    };
+    for (code, line, rows) in glyphs {
+        let err = |message: &str| FontError(format!("l{} - {}", line, message));
+        if rows.len() != height {
+            return Err(err(&format!("expected {} rows", height)));
+        }
+        let mut glyph = GlyphBuilder::new(width, height);
+        for (y, row) in rows.iter().enumerate() {
+            if row.chars().count() > width {
+                return Err(err(&format!("rows are {} pixels wide", width)));
+            }
+            for (x, c) in row.chars().enumerate() {
+                match c {
+                    '#' | 'X' => glyph.set(x as isize, y as isize),
+                    '.' | ' ' => {}
+                    _ => return Err(err(&format!("unexpected '{}' in a glyph", c))),
+                }
+            }
+        }
+        if font.glyphs.insert(code, glyph.data).is_some() {
+            return Err(err(&format!("character {:02X}h is defined twice", code)));
+        }
+    }
+    Ok(font)
+}
+
+/// A single character stands for itself, numbers are decimal or hex with
+/// an `h` suffix like in the assembler.
+fn code(word: &str) -> Option<u32> {
+    let mut chars = word.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Some(c as u32),
+        _ => match word.strip_suffix('h') {
+            Some(hex) => u32::from_str_radix(hex, 16).ok(),
+            None => word.parse().ok(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::font::text::parse;
+
+    #[test]
+    fn text_font() {
+        let font = parse(b"; 2x2\nchar A\n#.\n.#\nchar 66\nX \n X\n").unwrap();
+        assert_eq!((2, 2), (font.width, font.height));
+        assert_eq!(Some(&vec![0x80, 0x40]), font.glyphs.get(&0x41));
+        assert_eq!(Some(&vec![0x80, 0x40]), font.glyphs.get(&66));
+
+        assert_eq!(
+            "l2 - expected 2 rows",
+            parse(b"size 2 2\nchar 41h\n#.\n").unwrap_err().to_string()
+        );
+        assert_eq!(
+            "l1 - expected 'char' before the glyph rows",
+            parse(b"#.\n").unwrap_err().to_string()
+        );
+    }
+}
@@ -0,0 +1,124 @@
+use crate::image::{Image, ImageError};
+
+/// Decodes an uncompressed BMP with 1, 4, 8, 24 or 32 bits per pixel.
+pub fn decode(data: &[u8]) -> Result<Image, ImageError> {
+    let truncated = || ImageError("truncated BMP file".to_string());
+    let u16_at = |pos: usize| -> Result<u16, ImageError> {
+        let b = data.get(pos..pos + 2).ok_or_else(truncated)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    };
+    let u32_at = |pos: usize| -> Result<u32, ImageError> {
+        let b = data.get(pos..pos + 4).ok_or_else(truncated)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    };
+
+    if !data.starts_with(b"BM") {
+        return Err(ImageError("not a BMP file".to_string()));
+    }
+    let offset = u32_at(10)? as usize;
+    let header_size = u32_at(14)? as usize;
+    if header_size < 40 {
+        return Err(ImageError("unsupported BMP header".to_string()));
+    }
+    let width = u32_at(18)? as i32;
+    let height = u32_at(22)? as i32;
+    let bits = u16_at(28)?;
+    let compression = u32_at(30)?;
+    // BI_BITFIELDS is accepted for the usual BGRA layout of 32 bit images
+    if compression != 0 && !(compression == 3 && bits == 32) {
+        return Err(ImageError("compressed BMPs are not supported".to_string()));
+    }
+    if width <= 0 || height == 0 {
+        return Err(ImageError("invalid BMP size".to_string()));
+    }
+
+    let palette = match bits {
+        1 | 4 | 8 => {
+            let count = match u32_at(46)? {
+                0 => 1 << bits,
+                n => n as usize,
+            };
+            let start = 14 + header_size;
+            data.get(start..start + count * 4)
+                .ok_or_else(truncated)?
+                .chunks_exact(4)
+                .map(|c| [c[2], c[1], c[0], 255])
+                .collect::<Vec<_>>()
+        }
+        24 | 32 => vec![],
+        _ => return Err(ImageError(format!("unsupported BMP depth {}", bits))),
+    };
+
+    let (width, rows) = (width as usize, height.unsigned_abs() as usize);
+    let stride = (width * bits as usize).div_ceil(32) * 4;
+    let mut pixels = Vec::with_capacity(width * rows);
+    for y in 0..rows {
+        // rows are stored bottom-up unless the height is negative
+        let row = if height > 0 { rows - 1 - y } else { y };
+        let start = offset + row * stride;
+        let line = data.get(start..start + stride).ok_or_else(truncated)?;
+        for x in 0..width {
+            pixels.push(match bits {
+                24 => [line[x * 3 + 2], line[x * 3 + 1], line[x * 3], 255],
+                32 => [
+                    line[x * 4 + 2],
+                    line[x * 4 + 1],
+                    line[x * 4],
+                    line[x * 4 + 3],
+                ],
+                _ => {
+                    let bit = x * bits as usize;
+                    let index =
+                        (line[bit / 8] >> (8 - bits as usize - bit % 8)) & ((1 << bits) - 1);
+                    *palette.get(index as usize).ok_or_else(|| {
+                        ImageError(format!("BMP colour index {} outside the palette", index))
+                    })?
+                }
+            });
+        }
+    }
+
+    // 32 bit images without any alpha are opaque
+    if bits == 32 && pixels.iter().all(|p| p[3] == 0) {
+        pixels.iter_mut().for_each(|p| p[3] = 255);
+    }
+    Ok(Image {
+        width,
+        height: rows,
+        pixels,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::image::bmp::decode;
+
+    #[test]
+    fn decode_bmp() {
+        let mut bmp = b"BM".to_vec();
+        bmp.extend(70u32.to_le_bytes()); // file size
+        bmp.extend([0; 4]);
+        bmp.extend(54u32.to_le_bytes()); // pixel data offset
+        bmp.extend(40u32.to_le_bytes());
+        bmp.extend(2u32.to_le_bytes()); // width
+        bmp.extend(2u32.to_le_bytes()); // height, bottom-up
+        bmp.extend(1u16.to_le_bytes());
+        bmp.extend(24u16.to_le_bytes());
+        bmp.extend([0; 24]);
+        // rows of 6 bytes padded to 8, bottom row first
+        bmp.extend([0xFF, 0, 0, 0, 0, 0, 0, 0]);
+        bmp.extend([0, 0, 0xFF, 0, 0xFF, 0, 0, 0]);
+
+        let image = decode(&bmp).unwrap();
+        assert_eq!((2, 2), (image.width, image.height));
+        assert_eq!(
+            vec![
+                [255, 0, 0, 255],
+                [0, 255, 0, 255],
+                [0, 0, 255, 255],
+                [0, 0, 0, 255]
+            ],
+            image.pixels
+        );
+    }
+}
@@ -0,0 +1,281 @@
+use crate::image::ImageError;
+
+/// Order the code lengths of the code length alphabet are stored in.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Unpacks a zlib stream (RFC 1950/1951), the checksum is not verified.
+pub fn zlib_decompress(data: &[u8]) -> Result<Vec<u8>, ImageError> {
+    match data {
+        [cmf, flg, ..]
+            if cmf & 0x0F == 8 && (*cmf as u16 * 256 + *flg as u16).is_multiple_of(31) =>
+        {
+            if flg & 0x20 != 0 {
+                return Err(error("preset dictionaries are not supported"));
+            }
+            inflate(&data[2..])
+        }
+        _ => Err(error("not a zlib stream")),
+    }
+}
+
+pub fn inflate(data: &[u8]) -> Result<Vec<u8>, ImageError> {
+    let mut bits = Bits {
+        data,
+        pos: 0,
+        bit: 0,
+    };
+    let mut out = vec![];
+
+    loop {
+        let last = bits.read(1)? == 1;
+        match bits.read(2)? {
+            0 => stored(&mut bits, &mut out)?,
+            1 => {
+                let (lengths, distances) = fixed_codes();
+                codes(&mut bits, &mut out, &lengths, &distances)?
+            }
+            2 => {
+                let (lengths, distances) = dynamic_codes(&mut bits)?;
+                codes(&mut bits, &mut out, &lengths, &distances)?
+            }
+            _ => return Err(error("invalid block type")),
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+fn error(message: &str) -> ImageError {
+    ImageError(format!("corrupt compressed data, {}", message))
+}
+
+/// Reads the stream least significant bit first.
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit: u8,
+}
+
+impl<'a> Bits<'a> {
+    fn read(&mut self, count: u8) -> Result<u16, ImageError> {
+        let mut value = 0u16;
+        for i in 0..count {
+            let byte = self
+                .data
+                .get(self.pos)
+                .ok_or_else(|| error("unexpected end"))?;
+            value |= ((byte >> self.bit) as u16 & 1) << i;
+            self.bit += 1;
+            if self.bit == 8 {
+                self.bit = 0;
+                self.pos += 1;
+            }
+        }
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        if self.bit != 0 {
+            self.bit = 0;
+            self.pos += 1;
+        }
+    }
+}
+
+/// Canonical Huffman code, by code length.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0u16; 16];
+        for l in lengths {
+            counts[*l as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut offsets = [0u16; 16];
+        for i in 1..16 {
+            offsets[i] = offsets[i - 1] + counts[i - 1];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, l) in lengths.iter().enumerate() {
+            if *l != 0 {
+                symbols[offsets[*l as usize] as usize] = symbol as u16;
+                offsets[*l as usize] += 1;
+            }
+        }
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Result<u16, ImageError> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..16 {
+            code |= bits.read(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(error("invalid code"))
+    }
+}
+
+fn stored(bits: &mut Bits, out: &mut Vec<u8>) -> Result<(), ImageError> {
+    bits.align();
+    let header = bits
+        .data
+        .get(bits.pos..bits.pos + 4)
+        .ok_or_else(|| error("unexpected end"))?;
+    let len = u16::from_le_bytes([header[0], header[1]]);
+    if len != !u16::from_le_bytes([header[2], header[3]]) {
+        return Err(error("stored block length mismatch"));
+    }
+    let start = bits.pos + 4;
+    let data = bits
+        .data
+        .get(start..start + len as usize)
+        .ok_or_else(|| error("unexpected end"))?;
+    out.extend(data);
+    bits.pos = start + len as usize;
+    Ok(())
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    for (i, l) in lengths.iter_mut().enumerate() {
+        *l = match i {
+            0..=143 => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _ => 8,
+        };
+    }
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+fn dynamic_codes(bits: &mut Bits) -> Result<(Huffman, Huffman), ImageError> {
+    let literals = bits.read(5)? as usize + 257;
+    let distances = bits.read(5)? as usize + 1;
+    let code_lengths = bits.read(4)? as usize + 4;
+
+    let mut lengths = [0u8; 19];
+    for i in CODE_LENGTH_ORDER.iter().take(code_lengths) {
+        lengths[*i] = bits.read(3)? as u8;
+    }
+    let code = Huffman::new(&lengths);
+
+    let mut lengths = vec![];
+    while lengths.len() < literals + distances {
+        let (value, repeat) = match code.decode(bits)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths.last().ok_or_else(|| error("nothing to repeat"))?;
+                (previous, 3 + bits.read(2)?)
+            }
+            17 => (0, 3 + bits.read(3)?),
+            _ => (0, 11 + bits.read(7)?),
+        };
+        lengths.extend(std::iter::repeat_n(value, repeat as usize));
+    }
+    if lengths.len() > literals + distances {
+        return Err(error("too many code lengths"));
+    }
+    Ok((
+        Huffman::new(&lengths[..literals]),
+        Huffman::new(&lengths[literals..]),
+    ))
+}
+
+fn codes(
+    bits: &mut Bits,
+    out: &mut Vec<u8>,
+    lengths: &Huffman,
+    distances: &Huffman,
+) -> Result<(), ImageError> {
+    loop {
+        let symbol = lengths.decode(bits)? as usize;
+        match symbol {
+            0..=255 => out.push(symbol as u8),
+            256 => return Ok(()),
+            _ => {
+                let i = symbol - 257;
+                if i >= LENGTH_BASE.len() {
+                    return Err(error("invalid length"));
+                }
+                let len = LENGTH_BASE[i] as usize + bits.read(LENGTH_EXTRA[i])? as usize;
+                let i = distances.decode(bits)? as usize;
+                if i >= DISTANCE_BASE.len() {
+                    return Err(error("invalid distance"));
+                }
+                let distance = DISTANCE_BASE[i] as usize + bits.read(DISTANCE_EXTRA[i])? as usize;
+                if distance > out.len() {
+                    return Err(error("distance too far back"));
+                }
+                let start = out.len() - distance;
+                for k in 0..len {
+                    out.push(out[start + k]);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::image::inflate::zlib_decompress;
+
+    #[test]
+    fn inflate_blocks() {
+        // stored block
+        assert_eq!(
+            b"abc".to_vec(),
+            zlib_decompress(&[0x78, 0x01, 0x01, 0x03, 0x00, 0xFC, 0xFF, b'a', b'b', b'c']).unwrap()
+        );
+        // fixed codes with a back reference, from zlib
+        assert_eq!(
+            b"abcabcabcabc".to_vec(),
+            zlib_decompress(&[
+                0x78, 0x9C, 0x4B, 0x4C, 0x4A, 0x4E, 0x84, 0x21, 0x00, 0x1D, 0xE0, 0x04, 0x99
+            ])
+            .unwrap()
+        );
+        // dynamic codes
+        let packed = [
+            0x78, 0xDA, 0xED, 0xC8, 0xC9, 0x09, 0x00, 0x30, 0x08, 0x00, 0xC1, 0x5A, 0xBD, 0x10,
+            0x03, 0x11, 0xC4, 0xFE, 0x21, 0xDF, 0x14, 0xB1, 0xF3, 0x1C, 0xD1, 0x38, 0x63, 0xED,
+            0x7B, 0x2B, 0xB3, 0xEE, 0x7A, 0xDB, 0x9C, 0x50, 0xA1, 0x69, 0x9A, 0xA6, 0x69, 0x9A,
+            0xFE, 0xFB, 0x01, 0x2F, 0x72, 0x5D, 0x77,
+        ];
+        assert_eq!(
+            (0..2100)
+                .map(|i| (i * i % 23 + 97) as u8)
+                .collect::<Vec<_>>(),
+            zlib_decompress(&packed).unwrap()
+        );
+        assert!(zlib_decompress(&[0x78, 0x9C, 0x4B]).is_err());
+    }
+}
@@ -0,0 +1,339 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+
+mod bmp;
+mod inflate;
+mod png;
+mod ppm;
+
+/// Entries in the palette of the VGA board.
+pub const PALETTE_SIZE: usize = 256;
+/// Size of a frame of the VGA board, one palette index per pixel.
+pub const FRAME_WIDTH: usize = 200;
+pub const FRAME_HEIGHT: usize = 150;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ImageError(pub String);
+
+impl Display for ImageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+/// Decoded image, RGBA pixels row by row.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<[u8; 4]>,
+}
+
+/// Decodes a PNG, BMP or PPM file, told apart by their signature.
+pub fn decode(data: &[u8]) -> Result<Image, ImageError> {
+    if data.starts_with(&png::SIGNATURE) {
+        png::decode(data)
+    } else if data.starts_with(b"BM") {
+        bmp::decode(data)
+    } else if data.starts_with(b"P") {
+        ppm::decode(data)
+    } else {
+        Err(ImageError("unknown image format".to_string()))
+    }
+}
+
+/// Palette entry of the VGA board, 5 bits per channel packed as
+/// `RRRRRGGG GGBBBBB0`.
+pub fn color(r: u8, g: u8, b: u8) -> [u8; 2] {
+    [
+        (r & 0b1111_1000) | ((g & 0b1110_0000) >> 5),
+        ((g & 0b0001_1000) << 3) | ((b & 0b1111_1000) >> 2),
+    ]
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Options {
+    /// Palette entries the image may use, the transparent one included.
+    pub colors: usize,
+    /// Index the pixels with an alpha under 50% get, it is not used for any
+    /// colour. Without it alpha is ignored.
+    pub transparent: Option<u8>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            colors: PALETTE_SIZE,
+            transparent: None,
+        }
+    }
+}
+
+/// Image made of palette indexes.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IndexedImage {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+    /// RGB of every palette entry, unused ones are black.
+    pub palette: Vec<[u8; 3]>,
+    /// Entries used by the image.
+    pub colors: usize,
+}
+
+impl IndexedImage {
+    /// The palette as the 512 bytes the board expects.
+    pub fn palette_table(&self) -> Vec<u8> {
+        self.palette
+            .iter()
+            .flat_map(|[r, g, b]| color(*r, *g, *b))
+            .collect()
+    }
+
+    /// Cuts the image in `width` x `height` tiles, left to right then top to
+    /// bottom, each stored row by row.
+    pub fn tiles(&self, width: usize, height: usize) -> Result<Vec<u8>, ImageError> {
+        if width == 0
+            || height == 0
+            || !self.width.is_multiple_of(width)
+            || !self.height.is_multiple_of(height)
+        {
+            return Err(ImageError(format!(
+                "a {}x{} image can't be cut in {}x{} tiles",
+                self.width, self.height, width, height
+            )));
+        }
+        let mut out = Vec::with_capacity(self.pixels.len());
+        for ty in 0..self.height / height {
+            for tx in 0..self.width / width {
+                for y in 0..height {
+                    let start = (ty * height + y) * self.width + tx * width;
+                    out.extend(&self.pixels[start..start + width]);
+                }
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// Maps `image` to the palette of the board. Colours are reduced to 5 bits
+/// per channel, when there are still more of them than `options.colors`
+/// allows they are merged with median cut.
+pub fn quantize(image: &Image, options: &Options) -> Result<IndexedImage, ImageError> {
+    let reserved = match options.transparent {
+        Some(t) if (t as usize) < options.colors => 1,
+        _ => 0,
+    };
+    if options.colors > PALETTE_SIZE || options.colors <= reserved {
+        return Err(ImageError(format!(
+            "can't use {} palette entries",
+            options.colors
+        )));
+    }
+    let transparent = |p: &[u8; 4]| options.transparent.is_some() && p[3] < 128;
+
+    // distinct colours, in the order they first appear
+    let mut counts: HashMap<u16, usize> = HashMap::new();
+    let mut colors = vec![];
+    for p in image.pixels.iter().filter(|p| !transparent(p)) {
+        let key = reduce(p);
+        *counts.entry(key).or_insert_with(|| {
+            colors.push(key);
+            0
+        }) += 1;
+    }
+
+    let available = options.colors - reserved;
+    let chosen = if colors.len() <= available {
+        colors.iter().map(|k| expand(*k)).collect::<Vec<_>>()
+    } else {
+        let weighted = colors.iter().map(|k| (*k, counts[k])).collect();
+        median_cut(weighted, available)
+    };
+
+    // entries go in the free slots in order
+    let slots = (0..PALETTE_SIZE)
+        .filter(|i| options.transparent != Some(*i as u8))
+        .take(chosen.len())
+        .collect::<Vec<_>>();
+    let mut palette = vec![[0u8; 3]; PALETTE_SIZE];
+    for (slot, c) in slots.iter().zip(&chosen) {
+        palette[*slot] = *c;
+    }
+
+    let mut index: HashMap<u16, u8> = HashMap::new();
+    for key in &colors {
+        let rgb = expand(*key);
+        let nearest = (0..chosen.len())
+            .min_by_key(|i| distance(&rgb, &chosen[*i]))
+            .unwrap();
+        index.insert(*key, slots[nearest] as u8);
+    }
+
+    let pixels = image
+        .pixels
+        .iter()
+        .map(|p| match options.transparent {
+            Some(t) if transparent(p) => t,
+            _ => index[&reduce(p)],
+        })
+        .collect();
+    Ok(IndexedImage {
+        width: image.width,
+        height: image.height,
+        pixels,
+        palette,
+        colors: chosen.len() + reserved,
+    })
+}
+
+/// 5 bits per channel, as `0RRRRRGGGGGBBBBB`.
+fn reduce(p: &[u8; 4]) -> u16 {
+    (p[0] as u16 >> 3) << 10 | (p[1] as u16 >> 3) << 5 | p[2] as u16 >> 3
+}
+
+fn expand(key: u16) -> [u8; 3] {
+    let channel = |shift: u16| {
+        let v = ((key >> shift) & 0x1F) as u8;
+        v << 3 | v >> 2
+    };
+    [channel(10), channel(5), channel(0)]
+}
+
+fn distance(a: &[u8; 3], b: &[u8; 3]) -> u32 {
+    a.iter()
+        .zip(b)
+        .map(|(x, y)| (*x as i32 - *y as i32).pow(2) as u32)
+        .sum()
+}
+
+/// Splits the colours in `count` boxes, each time the box with the widest
+/// channel at the weighted median of that channel, and returns the average
+/// colour of every box.
+fn median_cut(colors: Vec<(u16, usize)>, count: usize) -> Vec<[u8; 3]> {
+    let channel = |key: u16, c: usize| (key >> (10 - 5 * c)) & 0x1F;
+    let widest = |b: &Vec<(u16, usize)>| {
+        (0..3)
+            .map(|c| {
+                let values = b.iter().map(|(k, _)| channel(*k, c));
+                let range = values.clone().max().unwrap() - values.min().unwrap();
+                (range, c)
+            })
+            .max()
+            .unwrap()
+    };
+
+    let mut boxes = vec![colors];
+    while boxes.len() < count {
+        let Some((i, (_, c))) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.len() > 1)
+            .map(|(i, b)| (i, widest(b)))
+            .max_by_key(|(_, (range, _))| *range)
+        else {
+            break;
+        };
+        let mut b = boxes.swap_remove(i);
+        b.sort_by_key(|(k, _)| channel(*k, c));
+        let total = b.iter().map(|(_, n)| n).sum::<usize>();
+        let mut seen = 0;
+        let mut split = 1;
+        for (j, (_, n)) in b.iter().enumerate() {
+            seen += n;
+            if seen * 2 >= total {
+                split = (j + 1).clamp(1, b.len() - 1);
+                break;
+            }
+        }
+        let upper = b.split_off(split);
+        boxes.push(b);
+        boxes.push(upper);
+    }
+
+    boxes
+        .iter()
+        .map(|b| {
+            let total = b.iter().map(|(_, n)| n).sum::<usize>();
+            let mut sum = [0usize; 3];
+            for (k, n) in b {
+                for (c, s) in sum.iter_mut().enumerate() {
+                    *s += expand(*k)[c] as usize * n;
+                }
+            }
+            sum.map(|s| ((s + total / 2) / total) as u8)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::image::{color, quantize, Image, Options};
+
+    fn image(width: usize, height: usize, pixels: Vec<[u8; 4]>) -> Image {
+        Image {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    #[test]
+    fn palette_format() {
+        // same packing as WriteColor in the Go renderer
+        assert_eq!([0xF8, 0x00], color(255, 0, 0));
+        assert_eq!([0x07, 0xC0], color(0, 255, 0));
+        assert_eq!([0x00, 0x3E], color(0, 0, 255));
+        assert_eq!([0x08, 0x82], color(8, 16, 8));
+    }
+
+    #[test]
+    fn quantize_and_tiles() {
+        let red = [255, 0, 0, 255];
+        let blue = [0, 0, 255, 255];
+        let clear = [0, 0, 0, 0];
+        let sprite = image(4, 2, vec![clear, red, red, blue, clear, blue, red, clear]);
+
+        let options = Options {
+            transparent: Some(0),
+            ..Options::default()
+        };
+        let indexed = quantize(&sprite, &options).unwrap();
+        assert_eq!(vec![0, 1, 1, 2, 0, 2, 1, 0], indexed.pixels);
+        assert_eq!(3, indexed.colors);
+        assert_eq!(512, indexed.palette_table().len());
+        assert_eq!(
+            vec![0, 0, 0xF8, 0, 0, 0x3E, 0, 0],
+            indexed.palette_table()[..8].to_vec()
+        );
+        assert_eq!(vec![0, 1, 0, 2, 1, 2, 1, 0], indexed.tiles(2, 2).unwrap());
+        assert!(indexed.tiles(3, 2).is_err());
+
+        // without a transparent index alpha is ignored
+        let opaque = quantize(&sprite, &Options::default()).unwrap();
+        assert_eq!(vec![0, 1, 1, 2, 0, 2, 1, 0], opaque.pixels);
+        assert_eq!([0, 0, 0], opaque.palette[0]);
+    }
+
+    #[test]
+    fn median_cut() {
+        // a gradient of 32 reds and 32 greens in 4 entries
+        let pixels = (0..32u8)
+            .map(|i| [i * 8, 0, 0, 255])
+            .chain((0..32u8).map(|i| [0, i * 8, 0, 255]))
+            .collect::<Vec<_>>();
+        let options = Options {
+            colors: 4,
+            ..Options::default()
+        };
+        let indexed = quantize(&image(64, 1, pixels), &options).unwrap();
+        assert_eq!(4, indexed.colors);
+        assert!(indexed.pixels.iter().all(|i| *i < 4));
+        // dark and bright ends of a ramp land in different entries
+        assert_ne!(indexed.pixels[1], indexed.pixels[31]);
+        assert_ne!(indexed.pixels[33], indexed.pixels[63]);
+        assert!(indexed.palette[4..].iter().all(|c| *c == [0, 0, 0]));
+    }
+}
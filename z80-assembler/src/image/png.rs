@@ -0,0 +1,255 @@
+use crate::image::inflate::zlib_decompress;
+use crate::image::{Image, ImageError};
+
+pub const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+struct Header {
+    width: usize,
+    height: usize,
+    depth: u8,
+    color_type: u8,
+}
+
+impl Header {
+    fn channels(&self) -> usize {
+        match self.color_type {
+            2 => 3,
+            4 => 2,
+            6 => 4,
+            _ => 1,
+        }
+    }
+}
+
+/// Decodes a non-interlaced PNG of any colour type and bit depth, the
+/// chunk checksums are not verified.
+pub fn decode(data: &[u8]) -> Result<Image, ImageError> {
+    if !data.starts_with(&SIGNATURE) {
+        return Err(ImageError("not a PNG file".to_string()));
+    }
+    let mut header = None;
+    let mut palette: Vec<[u8; 4]> = vec![];
+    let mut transparency = vec![];
+    let mut compressed = vec![];
+
+    let mut pos = SIGNATURE.len();
+    while pos + 8 <= data.len() {
+        let len = u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+        let kind = &data[pos + 4..pos + 8];
+        let chunk = data
+            .get(pos + 8..pos + 8 + len)
+            .ok_or_else(|| ImageError("truncated PNG chunk".to_string()))?;
+        pos += 12 + len;
+
+        match kind {
+            b"IHDR" if len == 13 => {
+                if chunk[12] != 0 {
+                    return Err(ImageError("interlaced PNGs are not supported".to_string()));
+                }
+                header = Some(Header {
+                    width: u32::from_be_bytes(chunk[0..4].try_into().unwrap()) as usize,
+                    height: u32::from_be_bytes(chunk[4..8].try_into().unwrap()) as usize,
+                    depth: chunk[8],
+                    color_type: chunk[9],
+                });
+            }
+            b"PLTE" => {
+                palette = chunk
+                    .chunks_exact(3)
+                    .map(|c| [c[0], c[1], c[2], 255])
+                    .collect()
+            }
+            b"tRNS" => transparency = chunk.to_vec(),
+            b"IDAT" => compressed.extend(chunk),
+            b"IEND" => break,
+            _ => {}
+        }
+    }
+
+    let header = header.ok_or_else(|| ImageError("PNG without a header".to_string()))?;
+    let valid = match header.color_type {
+        0 => matches!(header.depth, 1 | 2 | 4 | 8 | 16),
+        3 => matches!(header.depth, 1 | 2 | 4 | 8),
+        2 | 4 | 6 => matches!(header.depth, 8 | 16),
+        _ => false,
+    };
+    if !valid {
+        return Err(ImageError(format!(
+            "unsupported PNG colour type {} with depth {}",
+            header.color_type, header.depth
+        )));
+    }
+    for (entry, alpha) in palette.iter_mut().zip(&transparency) {
+        entry[3] = *alpha;
+    }
+
+    let raw = unfilter(&header, &zlib_decompress(&compressed)?)?;
+    let mut pixels = Vec::with_capacity(header.width * header.height);
+    for row in raw.chunks_exact(stride(&header)) {
+        for x in 0..header.width {
+            pixels.push(pixel(&header, row, x, &palette, &transparency)?);
+        }
+    }
+    Ok(Image {
+        width: header.width,
+        height: header.height,
+        pixels,
+    })
+}
+
+fn stride(header: &Header) -> usize {
+    (header.width * header.channels() * header.depth as usize).div_ceil(8)
+}
+
+/// Undoes the per row filters, returns the rows without their filter byte.
+fn unfilter(header: &Header, data: &[u8]) -> Result<Vec<u8>, ImageError> {
+    let stride = stride(header);
+    // distance to the same channel of the previous pixel
+    let bpp = (header.channels() * header.depth as usize).div_ceil(8);
+    if data.len() < (stride + 1) * header.height {
+        return Err(ImageError("truncated PNG image data".to_string()));
+    }
+
+    let mut out = vec![0u8; stride * header.height];
+    for y in 0..header.height {
+        let filter = data[y * (stride + 1)];
+        let line = &data[y * (stride + 1) + 1..(y + 1) * (stride + 1)];
+        for x in 0..stride {
+            let a = if x >= bpp {
+                out[y * stride + x - bpp]
+            } else {
+                0
+            };
+            let b = if y > 0 { out[(y - 1) * stride + x] } else { 0 };
+            let c = if x >= bpp && y > 0 {
+                out[(y - 1) * stride + x - bpp]
+            } else {
+                0
+            };
+            let predicted = match filter {
+                0 => 0,
+                1 => a,
+                2 => b,
+                3 => ((a as u16 + b as u16) / 2) as u8,
+                4 => paeth(a, b, c),
+                f => return Err(ImageError(format!("invalid PNG filter {}", f))),
+            };
+            out[y * stride + x] = line[x].wrapping_add(predicted);
+        }
+    }
+    Ok(out)
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = (
+        (p - a as i16).abs(),
+        (p - b as i16).abs(),
+        (p - c as i16).abs(),
+    );
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+fn pixel(
+    header: &Header,
+    row: &[u8],
+    x: usize,
+    palette: &[[u8; 4]],
+    transparency: &[u8],
+) -> Result<[u8; 4], ImageError> {
+    let depth = header.depth as usize;
+    // channel `i` of the pixel, scaled to 8 bits
+    let channel = |i: usize| -> u8 {
+        let index = x * header.channels() + i;
+        match depth {
+            16 => row[index * 2],
+            8 => row[index],
+            _ => {
+                let bit = index * depth;
+                let value = (row[bit / 8] >> (8 - depth - bit % 8)) & ((1 << depth) - 1);
+                if header.color_type == 3 {
+                    value
+                } else {
+                    (value as u16 * 255 / ((1 << depth) - 1)) as u8
+                }
+            }
+        }
+    };
+    // raw sample `i`, what tRNS refers to
+    let sample = |i: usize| -> u16 {
+        let index = x * header.channels() + i;
+        match depth {
+            16 => u16::from_be_bytes([row[index * 2], row[index * 2 + 1]]),
+            8 => row[index] as u16,
+            _ => {
+                let bit = index * depth;
+                ((row[bit / 8] >> (8 - depth - bit % 8)) & ((1 << depth) - 1)) as u16
+            }
+        }
+    };
+    let key = |i: usize| -> Option<u16> {
+        transparency
+            .get(i * 2..i * 2 + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+    };
+
+    Ok(match header.color_type {
+        0 => {
+            let v = channel(0);
+            let alpha = if key(0) == Some(sample(0)) { 0 } else { 255 };
+            [v, v, v, alpha]
+        }
+        2 => {
+            let opaque = (0..3).any(|i| key(i) != Some(sample(i)));
+            [
+                channel(0),
+                channel(1),
+                channel(2),
+                if opaque { 255 } else { 0 },
+            ]
+        }
+        3 => *palette.get(channel(0) as usize).ok_or_else(|| {
+            ImageError(format!(
+                "PNG colour index {} outside the palette",
+                channel(0)
+            ))
+        })?,
+        4 => [channel(0), channel(0), channel(0), channel(1)],
+        _ => [channel(0), channel(1), channel(2), channel(3)],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::image::png::decode;
+
+    #[test]
+    fn decode_png() {
+        // 2x2 RGBA, the rows filtered with Sub and Up
+        let png = [
+            0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A, 0x00, 0x00, 0x00, 0x0D, 0x49, 0x48,
+            0x44, 0x52, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x02, 0x08, 0x06, 0x00, 0x00,
+            0x00, 0x72, 0xB6, 0x0D, 0x24, 0x00, 0x00, 0x00, 0x17, 0x49, 0x44, 0x41, 0x54, 0x78,
+            0xDA, 0x63, 0xFC, 0xCF, 0xC0, 0xF0, 0x9F, 0x11, 0x48, 0x30, 0x31, 0x32, 0x00, 0x49,
+            0x46, 0x06, 0x46, 0x00, 0x31, 0x1E, 0x04, 0x04, 0x01, 0xC1, 0xF1, 0x35, 0x00, 0x00,
+            0x00, 0x00, 0x49, 0x45, 0x4E, 0x44, 0xAE, 0x42, 0x60, 0x82,
+        ];
+        let image = decode(&png).unwrap();
+        assert_eq!((2, 2), (image.width, image.height));
+        assert_eq!(
+            vec![
+                [255, 0, 0, 255],
+                [0, 255, 0, 255],
+                [0, 0, 255, 255],
+                [0, 0, 0, 0]
+            ],
+            image.pixels
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use crate::image::{Image, ImageError};
+
+/// Decodes a binary (P5, P6) or plain (P2, P3) grey or colour PNM file.
+pub fn decode(data: &[u8]) -> Result<Image, ImageError> {
+    let (plain, channels) = match data.get(..2) {
+        Some(b"P2") => (true, 1),
+        Some(b"P3") => (true, 3),
+        Some(b"P5") => (false, 1),
+        Some(b"P6") => (false, 3),
+        _ => return Err(ImageError("not a PPM or PGM file".to_string())),
+    };
+    let mut pos = 2;
+    let width = number(data, &mut pos)?;
+    let height = number(data, &mut pos)?;
+    let max = number(data, &mut pos)?;
+    if max == 0 || max > 255 {
+        return Err(ImageError(format!("unsupported PPM maximum value {}", max)));
+    }
+
+    let count = width * height * channels;
+    let samples = if plain {
+        (0..count)
+            .map(|_| number(data, &mut pos))
+            .collect::<Result<Vec<_>, _>>()?
+    } else {
+        // a single whitespace character separates the header from the data
+        pos += 1;
+        data.get(pos..pos + count)
+            .ok_or_else(|| ImageError("truncated PPM file".to_string()))?
+            .iter()
+            .map(|s| *s as usize)
+            .collect()
+    };
+
+    let scale = |s: usize| (s.min(max) * 255 / max) as u8;
+    let pixels = samples
+        .chunks_exact(channels)
+        .map(|c| match c {
+            [v] => [scale(*v), scale(*v), scale(*v), 255],
+            _ => [scale(c[0]), scale(c[1]), scale(c[2]), 255],
+        })
+        .collect();
+    Ok(Image {
+        width,
+        height,
+        pixels,
+    })
+}
+
+/// Reads the next decimal number, skipping whitespace and comments.
+fn number(data: &[u8], pos: &mut usize) -> Result<usize, ImageError> {
+    loop {
+        match data.get(*pos) {
+            Some(b'#') => {
+                while !matches!(data.get(*pos), Some(b'\n') | None) {
+                    *pos += 1;
+                }
+            }
+            Some(c) if c.is_ascii_whitespace() => *pos += 1,
+            _ => break,
+        }
+    }
+    let start = *pos;
+    while data.get(*pos).is_some_and(|c| c.is_ascii_digit()) {
+        *pos += 1;
+    }
+    std::str::from_utf8(&data[start..*pos])
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| ImageError("invalid PPM header".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::image::ppm::decode;
+
+    #[test]
+    fn decode_ppm() {
+        let plain = decode(b"P3\n# test\n2 1\n15\n15 0 0  0 0 15\n").unwrap();
+        assert_eq!(vec![[255, 0, 0, 255], [0, 0, 255, 255]], plain.pixels);
+
+        let mut binary = b"P6 1 1 255\n".to_vec();
+        binary.extend([10, 20, 30]);
+        assert_eq!(vec![[10, 20, 30, 255]], decode(&binary).unwrap().pixels);
+        assert!(decode(b"P6 2 2 255\n\x00").is_err());
+    }
+}
@@ -1,5 +1,13 @@
 pub mod compiler;
 pub mod domain;
+pub mod font;
+pub mod image;
+pub mod linker;
+pub mod object;
 pub mod parser;
+pub mod stdlib;
 
-pub use compiler::{Compiler, InMemorySourceProvider, SourceHeader, SourceProvider};
+pub use compiler::{
+    CompileError, CompileErrorType, Compiler, DebugInfo, EntryKind, InMemorySourceProvider,
+    LineEntry, MacroCall, Program, Segment, SidecarError, SourceHeader, SourceProvider, TestBlock,
+};
@@ -0,0 +1,811 @@
+use crate::compiler::utilities::relative_delta;
+use crate::object::{Object, RelocationKind, Section};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+
+const BANK_SIZE: usize = 0x10000;
+
+/// Section that reserves memory: it gets an address but its bytes are not
+/// in the output.
+pub const BSS_SECTION: &str = "bss";
+
+/// Where the linker puts the sections:
+///
+/// ```text
+/// memory ROM 0000h-7FFFh
+/// memory RAM 8000h-FFFFh
+/// memory VRAM 0000h-FFFFh bank 1
+/// section vectors at 0000h in ROM
+/// section code in ROM
+/// section bss in RAM
+/// ```
+///
+/// `;` starts a comment. A section follows the previous one of its memory,
+/// or of the sections without a memory, unless it has an address.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Script {
+    pub memory: Vec<Memory>,
+    pub sections: Vec<Placement>,
+}
+
+/// Range of addresses of a bank, `end` included.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Memory {
+    pub name: String,
+    pub bank: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Placement {
+    pub section: String,
+    pub memory: Option<String>,
+    pub addr: Option<usize>,
+}
+
+impl Script {
+    pub fn parse(text: &str) -> Result<Script, LinkError> {
+        let mut script = Script::default();
+        for (i, line) in text.lines().enumerate() {
+            let err = |message: String| LinkError::Script {
+                line: i + 1,
+                message,
+            };
+            let line = line.split(';').next().unwrap_or("");
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            let address = |s: &str| {
+                parse_number(s)
+                    .filter(|a| *a < BANK_SIZE)
+                    .ok_or_else(|| err(format!("invalid address '{}'", s)))
+            };
+
+            match words.as_slice() {
+                [] => {}
+                ["memory", name, range, rest @ ..] => {
+                    let (start, end) = range
+                        .split_once('-')
+                        .ok_or_else(|| err(format!("invalid range '{}'", range)))?;
+                    let (start, end) = (address(start)?, address(end)?);
+                    if start > end {
+                        return Err(err(format!("invalid range '{}'", range)));
+                    }
+                    let bank = match rest {
+                        [] => 0,
+                        ["bank", n] => parse_number(n)
+                            .filter(|n| *n < 256)
+                            .ok_or_else(|| err(format!("invalid bank '{}'", n)))?,
+                        _ => return Err(err("expected 'bank <n>'".to_string())),
+                    };
+                    if script.memory.iter().any(|m| m.name == *name) {
+                        return Err(err(format!("memory '{}' is defined twice", name)));
+                    }
+                    script.memory.push(Memory {
+                        name: name.to_string(),
+                        bank,
+                        start,
+                        end,
+                    });
+                }
+                ["section", name, rest @ ..] => {
+                    let mut placement = Placement {
+                        section: name.to_string(),
+                        memory: None,
+                        addr: None,
+                    };
+                    for pair in rest.chunks(2) {
+                        match pair {
+                            ["at", addr] => placement.addr = Some(address(addr)?),
+                            ["in", memory] => placement.memory = Some(memory.to_string()),
+                            _ => {
+                                return Err(err(
+                                    "expected 'section <name> [at <address>] [in <memory>]'"
+                                        .to_string(),
+                                ))
+                            }
+                        }
+                    }
+                    if let Some(name) = &placement.memory {
+                        let memory = script
+                            .memory(name)
+                            .ok_or_else(|| err(format!("unknown memory '{}'", name)))?;
+                        if let Some(addr) = placement.addr.filter(|a| !memory.contains(*a)) {
+                            return Err(err(format!("{:04X}h is outside {}", addr, name)));
+                        }
+                    }
+                    if script.sections.iter().any(|p| p.section == *name) {
+                        return Err(err(format!("section '{}' is placed twice", name)));
+                    }
+                    script.sections.push(placement);
+                }
+                _ => {
+                    return Err(err(
+                        "expected 'memory <name> <start>-<end> [bank <n>]' or 'section <name>'"
+                            .to_string(),
+                    ))
+                }
+            }
+        }
+        Ok(script)
+    }
+
+    /// Layout used without a script: `code` from 0, `data` and the other
+    /// sections after it, in the order of `sections`, and `bss` at the
+    /// start of RAM.
+    pub fn default_for<'a>(sections: impl IntoIterator<Item = &'a str>) -> Script {
+        let mut script = Script {
+            memory: vec![Memory {
+                name: "RAM".to_string(),
+                bank: 0,
+                start: 0x8000,
+                end: 0xFFFF,
+            }],
+            sections: vec![],
+        };
+        let names = ["code", "data"].into_iter().chain(sections);
+        for name in names {
+            if name != BSS_SECTION && !script.sections.iter().any(|p| p.section == name) {
+                script.sections.push(Placement {
+                    section: name.to_string(),
+                    memory: None,
+                    addr: None,
+                });
+            }
+        }
+        script.sections[0].addr = Some(0);
+        script.sections.push(Placement {
+            section: BSS_SECTION.to_string(),
+            memory: Some("RAM".to_string()),
+            addr: None,
+        });
+        script
+    }
+
+    pub fn memory(&self, name: &str) -> Option<&Memory> {
+        self.memory.iter().find(|m| m.name == name)
+    }
+}
+
+impl Memory {
+    fn contains(&self, addr: usize) -> bool {
+        (self.start..=self.end).contains(&addr)
+    }
+
+    fn size(&self) -> usize {
+        self.end - self.start + 1
+    }
+}
+
+/// `1Fh` or `31`.
+fn parse_number(s: &str) -> Option<usize> {
+    match s.strip_suffix('h') {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Section of an object at its place in memory. `addr` counts across banks,
+/// bank `n` starts at `n * 10000h`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Placed {
+    pub section: String,
+    pub object: String,
+    pub memory: Option<String>,
+    pub addr: usize,
+    pub len: usize,
+}
+
+/// Places the sections of the objects, given by name, as `script` says.
+/// Sections of the same name are put one after the other in the order of
+/// the objects. Returns each placed section with the index of its object.
+pub(crate) fn place(
+    script: &Script,
+    objects: &[(&str, &[Section])],
+) -> Result<Vec<(usize, Placed)>, LinkError> {
+    for (name, sections) in objects.iter() {
+        if let Some(s) = sections
+            .iter()
+            .find(|s| !script.sections.iter().any(|p| p.section == s.name))
+        {
+            return Err(LinkError::UnplacedSection {
+                object: name.to_string(),
+                section: s.name.clone(),
+            });
+        }
+    }
+
+    let mut placed = vec![];
+    // next address of each memory, "" for the sections outside of one
+    let mut next: HashMap<&str, usize> = HashMap::new();
+    for p in script.sections.iter() {
+        let memory = p.memory.as_ref().and_then(|m| script.memory(m));
+        let (key, bank, start, end) = match memory {
+            Some(m) => (m.name.as_str(), m.bank, m.start, m.end + 1),
+            None => ("", 0, 0, BANK_SIZE),
+        };
+        let mut addr = p.addr.unwrap_or(*next.get(key).unwrap_or(&start));
+        for (i, (name, sections)) in objects.iter().enumerate() {
+            if let Some(s) = sections.iter().find(|s| s.name == p.section) {
+                placed.push((
+                    i,
+                    Placed {
+                        section: s.name.clone(),
+                        object: name.to_string(),
+                        memory: p.memory.clone(),
+                        addr: bank * BANK_SIZE + addr,
+                        len: s.data.len(),
+                    },
+                ));
+                addr += s.data.len();
+            }
+        }
+        if addr > end {
+            return Err(LinkError::Overflow {
+                section: p.section.clone(),
+                memory: key.to_string(),
+                by: addr - end,
+            });
+        }
+        next.insert(key, addr);
+    }
+
+    let mut ranges = placed
+        .iter()
+        .map(|(_, p)| p)
+        .filter(|p| p.len > 0)
+        .collect::<Vec<_>>();
+    ranges.sort_by_key(|p| p.addr);
+    for pair in ranges.windows(2) {
+        if pair[0].addr + pair[0].len > pair[1].addr {
+            return Err(LinkError::Overlap(
+                pair[0].section.clone(),
+                pair[1].section.clone(),
+            ));
+        }
+    }
+    Ok(placed)
+}
+
+/// Memory holding every placed section, bss included, at least `len` long.
+pub(crate) fn load(placed: &[(usize, Placed)], sections: &[&[Section]], len: usize) -> Vec<u8> {
+    let end = placed
+        .iter()
+        .map(|(_, p)| p.addr + p.len)
+        .max()
+        .unwrap_or(0);
+    let mut memory = vec![0u8; end.max(len)];
+    for (i, p) in placed.iter() {
+        if let Some(s) = sections[*i].iter().find(|s| s.name == p.section) {
+            memory[p.addr..p.addr + p.len].copy_from_slice(&s.data);
+        }
+    }
+    memory
+}
+
+/// Drops the bss sections from `memory`: they are zeroed, and cut off when
+/// nothing after them is output. `memory` stays at least `len` long.
+pub(crate) fn strip_bss(memory: &mut Vec<u8>, placed: &[(usize, Placed)], len: usize) {
+    let mut end = len;
+    for (_, p) in placed.iter() {
+        if p.section == BSS_SECTION {
+            memory[p.addr..p.addr + p.len].fill(0);
+        } else {
+            end = end.max(p.addr + p.len);
+        }
+    }
+    memory.truncate(end);
+}
+
+#[derive(Debug)]
+pub struct Linked {
+    /// Memory from address 0 to the end of the last section that is output.
+    pub data: Vec<u8>,
+    pub symbols: HashMap<String, usize>,
+    pub sections: Vec<Placed>,
+}
+
+impl Linked {
+    /// Placed sections and symbols by address, written by `z80ld --map`.
+    pub fn map(&self) -> String {
+        let mut out = String::new();
+        for p in self.sections.iter() {
+            out.push_str(&format!(
+                "{:04X}h {:5} {} ({})\n",
+                p.addr, p.len, p.section, p.object
+            ));
+        }
+        let mut symbols = self.symbols.iter().collect::<Vec<_>>();
+        symbols.sort_by_key(|(name, addr)| (**addr, name.as_str()));
+        for (name, addr) in symbols {
+            out.push_str(&format!("{:04X}h {}\n", addr, name));
+        }
+        out
+    }
+}
+
+/// Size of each section and how much of each memory they use:
+///
+/// ```text
+/// code      0000h    1234 bytes
+/// bss       8000h      16 bytes  RAM
+///
+/// RAM          16 of 32768 bytes
+/// ```
+pub fn summary(script: &Script, placed: &[Placed]) -> String {
+    let mut out = String::new();
+    for p in script.sections.iter() {
+        let sections = placed.iter().filter(|s| s.section == p.section);
+        let (addr, len) = sections.fold((None, 0), |(addr, len), s| {
+            (addr.or(Some(s.addr)), len + s.len)
+        });
+        if let Some(addr) = addr {
+            let line = format!(
+                "{:10}{:04X}h {:7} bytes  {}",
+                p.section,
+                addr,
+                len,
+                p.memory.as_deref().unwrap_or("")
+            );
+            out.push_str(line.trim_end());
+            out.push('\n');
+        }
+    }
+    if !script.memory.is_empty() {
+        out.push('\n');
+    }
+    for m in script.memory.iter() {
+        let used = placed
+            .iter()
+            .filter(|p| p.memory.as_ref() == Some(&m.name))
+            .map(|p| p.len)
+            .sum::<usize>();
+        out.push_str(&format!("{:10}{:7} of {} bytes\n", m.name, used, m.size()));
+    }
+    out
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum SymbolError {
+    Duplicate {
+        name: String,
+        objects: Vec<String>,
+    },
+    Unresolved {
+        name: String,
+        object: String,
+        line: usize,
+    },
+}
+
+impl Display for SymbolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SymbolError::Duplicate { name, objects } => {
+                write!(f, "'{}' is defined in {}", name, objects.join(", "))
+            }
+            SymbolError::Unresolved { name, object, line } => {
+                write!(f, "{}: l{} - unresolved symbol '{}'", object, line, name)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum LinkError {
+    Script {
+        line: usize,
+        message: String,
+    },
+    UnplacedSection {
+        object: String,
+        section: String,
+    },
+    /// Section that doesn't fit in its memory, empty for the sections
+    /// outside of one, by how many bytes.
+    Overflow {
+        section: String,
+        memory: String,
+        by: usize,
+    },
+    Overlap(String, String),
+    Symbols(Vec<SymbolError>),
+    RelativeJump {
+        object: String,
+        line: usize,
+        symbol: String,
+    },
+    /// `*symbol` reads past the end of the section `symbol` is in.
+    ValuePastSection {
+        object: String,
+        line: usize,
+        symbol: String,
+    },
+    /// `*symbol` of a symbol in bss, whose bytes are not output.
+    BssValue {
+        object: String,
+        line: usize,
+        symbol: String,
+    },
+}
+
+impl Display for LinkError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LinkError::Script { line, message } => write!(f, "script l{} - {}", line, message),
+            LinkError::UnplacedSection { object, section } => write!(
+                f,
+                "{}: section '{}' is not in the linker script",
+                object, section
+            ),
+            LinkError::Overflow {
+                section,
+                memory,
+                by,
+            } if memory.is_empty() => write!(
+                f,
+                "section '{}' goes {} bytes past the end of memory",
+                section, by
+            ),
+            LinkError::Overflow {
+                section,
+                memory,
+                by,
+            } => write!(
+                f,
+                "section '{}' overflows {} by {} bytes",
+                section, memory, by
+            ),
+            LinkError::Overlap(a, b) => write!(f, "sections '{}' and '{}' overlap", a, b),
+            LinkError::Symbols(errors) => {
+                let errors = errors.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+                write!(f, "{}", errors.join("\n"))
+            }
+            LinkError::RelativeJump {
+                object,
+                line,
+                symbol,
+            } => write!(
+                f,
+                "{}: l{} - '{}' is too far for a jr",
+                object, line, symbol
+            ),
+            LinkError::ValuePastSection {
+                object,
+                line,
+                symbol,
+            } => write!(
+                f,
+                "{}: l{} - '*{}' reads past the end of its section",
+                object, line, symbol
+            ),
+            LinkError::BssValue {
+                object,
+                line,
+                symbol,
+            } => write!(
+                f,
+                "{}: l{} - '*{}' reads bss, which has no data",
+                object, line, symbol
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LinkError {}
+
+/// Names of the sections of `objects`, for `Script::default_for`.
+pub fn section_names(objects: &[(String, Object)]) -> impl Iterator<Item = &str> {
+    objects
+        .iter()
+        .flat_map(|(_, o)| o.sections.iter().map(|s| s.name.as_str()))
+}
+
+/// Places the sections of `objects`, named by their file, as `script` says
+/// and resolves their relocations.
+pub fn link(objects: &[(String, Object)], script: &Script) -> Result<Linked, LinkError> {
+    let sections = objects
+        .iter()
+        .map(|(name, o)| (name.as_str(), o.sections.as_slice()))
+        .collect::<Vec<_>>();
+    let placed = place(script, &sections)?;
+    let mut data = load(
+        &placed,
+        &sections.iter().map(|(_, s)| *s).collect::<Vec<_>>(),
+        0,
+    );
+    // address of each (object, section)
+    let bases = placed
+        .iter()
+        .map(|(i, p)| ((*i, p.section.as_str()), p.addr))
+        .collect::<HashMap<_, _>>();
+    // end of the section each symbol is in, `*symbol` reads stay inside it
+    let mut section_ends = HashMap::new();
+    let mut bss_symbols = HashSet::new();
+
+    let mut errors = vec![];
+    let mut symbols = HashMap::new();
+    let mut defined_in: HashMap<&str, Vec<String>> = HashMap::new();
+    for (i, (name, object)) in objects.iter().enumerate() {
+        for s in object.symbols.iter() {
+            let base = bases[&(i, s.section.as_str())];
+            symbols.insert(s.name.clone(), base + s.offset);
+            if let Some(section) = object.sections.iter().find(|x| x.name == s.section) {
+                section_ends.insert(s.name.clone(), base + section.data.len());
+            }
+            if s.section == BSS_SECTION {
+                bss_symbols.insert(s.name.as_str());
+            }
+            let objects = defined_in.entry(&s.name).or_default();
+            if !objects.contains(name) {
+                objects.push(name.clone());
+            }
+        }
+    }
+    let mut duplicates = defined_in
+        .into_iter()
+        .filter(|(_, objects)| objects.len() > 1)
+        .collect::<Vec<_>>();
+    duplicates.sort();
+    for (name, objects) in duplicates {
+        errors.push(SymbolError::Duplicate {
+            name: name.to_string(),
+            objects,
+        });
+    }
+    for (name, object) in objects.iter() {
+        for r in object.relocations.iter() {
+            if !symbols.contains_key(&r.symbol) {
+                errors.push(SymbolError::Unresolved {
+                    name: r.symbol.clone(),
+                    object: name.clone(),
+                    line: r.line,
+                });
+            }
+        }
+    }
+    if !errors.is_empty() {
+        return Err(LinkError::Symbols(errors));
+    }
+
+    for (i, (name, object)) in objects.iter().enumerate() {
+        for r in object.relocations.iter() {
+            let at = bases[&(i, r.section.as_str())] + r.offset;
+            let target = symbols[&r.symbol];
+            match r.kind {
+                RelocationKind::Absolute => {
+                    data[at] = (target % 256) as u8;
+                    data[at + 1] = (target / 256) as u8;
+                }
+                RelocationKind::Relative => {
+                    data[at] =
+                        relative_delta(at + 1, target).ok_or_else(|| LinkError::RelativeJump {
+                            object: name.clone(),
+                            line: r.line,
+                            symbol: r.symbol.clone(),
+                        })?;
+                }
+                RelocationKind::Byte | RelocationKind::Word => {
+                    let size = r.kind.size();
+                    if bss_symbols.contains(r.symbol.as_str()) {
+                        return Err(LinkError::BssValue {
+                            object: name.clone(),
+                            line: r.line,
+                            symbol: r.symbol.clone(),
+                        });
+                    }
+                    if section_ends
+                        .get(&r.symbol)
+                        .is_none_or(|end| target + size > *end)
+                    {
+                        return Err(LinkError::ValuePastSection {
+                            object: name.clone(),
+                            line: r.line,
+                            symbol: r.symbol.clone(),
+                        });
+                    }
+                    data.copy_within(target..target + size, at);
+                }
+            }
+        }
+    }
+    strip_bss(&mut data, &placed, 0);
+
+    Ok(Linked {
+        data,
+        symbols,
+        sections: placed.into_iter().map(|(_, p)| p).collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::linker::{link, section_names, summary, LinkError, Script, SymbolError};
+    use crate::object::Object;
+    use crate::{Compiler, InMemorySourceProvider, SourceHeader};
+
+    fn object(source: &str) -> Object {
+        Compiler::new(
+            InMemorySourceProvider {
+                files: vec![(
+                    SourceHeader {
+                        filename: "main.z80".to_string(),
+                    },
+                    source.to_string(),
+                )],
+            },
+            1024,
+        )
+        .assemble_object()
+        .unwrap()
+    }
+
+    #[test]
+    fn link_two_objects() {
+        let objects = vec![
+            (
+                "main.o".to_string(),
+                object(".main: call &print\nld b, *count\njp &main\n"),
+            ),
+            ("print.o".to_string(), object(".print: ret\n.count: 12h\n")),
+        ];
+
+        let linked = link(&objects, &Script::default_for(section_names(&objects))).unwrap();
+        assert_eq!(
+            vec![0xCD, 0x08, 0x00, 0x06, 0x12, 0xC3, 0x00, 0x00, 0xC9, 0x12],
+            linked.data
+        );
+        assert_eq!(Some(&9), linked.symbols.get("count"));
+
+        let script = Script::parse("; code in ROM\nsection code at 0100h\n").unwrap();
+        let linked = link(&objects, &script).unwrap();
+        assert_eq!(0x10A, linked.data.len());
+        assert_eq!([0xCD, 0x08, 0x01], linked.data[0x100..0x103]);
+        assert!(linked.map().contains("0108h print\n"));
+    }
+
+    #[test]
+    fn memory_script() {
+        let objects = vec![
+            (
+                "main.o".to_string(),
+                object(".main: ld hl, tiles\nld de, buffer\n#section bss\n.buffer: 00h\n"),
+            ),
+            (
+                "tiles.o".to_string(),
+                object("#section tiles\n.tiles: 01h 02h\n"),
+            ),
+        ];
+        let script = Script::parse(
+            "memory ROM 0000h-7FFFh
+memory RAM 8000h-FFFFh
+memory VRAM 0000h-FFFFh bank 1  ; second bank
+section code in ROM
+section tiles in VRAM
+section bss at 8100h in RAM
+",
+        )
+        .unwrap();
+
+        let linked = link(&objects, &script).unwrap();
+        // bss is not output, tiles are at 0000h of bank 1
+        assert_eq!(0x10002, linked.data.len());
+        assert_eq!([0x21, 0x00, 0x00, 0x11, 0x00, 0x81], linked.data[..6]);
+        assert_eq!([0x01, 0x02], linked.data[0x10000..]);
+        assert_eq!(Some(&0x10000), linked.symbols.get("tiles"));
+        assert_eq!(
+            "code      0000h       6 bytes  ROM
+tiles     10000h       2 bytes  VRAM
+bss       8100h       1 bytes  RAM
+
+ROM             6 of 32768 bytes
+RAM             1 of 32768 bytes
+VRAM            2 of 65536 bytes
+",
+            summary(&script, &linked.sections)
+        );
+
+        let small = Script::parse(
+            "memory ROM 0000h-0003h\nsection code in ROM\nsection bss\nsection tiles",
+        )
+        .unwrap();
+        assert_eq!(
+            "section 'code' overflows ROM by 2 bytes",
+            link(&objects, &small).unwrap_err().to_string()
+        );
+        assert_eq!(
+            "script l2 - 8000h is outside ROM",
+            Script::parse("memory ROM 0000h-7FFFh\nsection code at 8000h in ROM")
+                .unwrap_err()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn symbol_errors() {
+        let objects = vec![
+            ("a.o".to_string(), object(".main: call &missing\n")),
+            ("b.o".to_string(), object(".main: ret\n")),
+        ];
+        assert_eq!(
+            Err(LinkError::Symbols(vec![
+                SymbolError::Duplicate {
+                    name: "main".to_string(),
+                    objects: vec!["a.o".to_string(), "b.o".to_string()],
+                },
+                SymbolError::Unresolved {
+                    name: "missing".to_string(),
+                    object: "a.o".to_string(),
+                    line: 1,
+                },
+            ])),
+            link(&objects, &Script::default_for(section_names(&objects))).map(|l| l.data)
+        );
+    }
+
+    #[test]
+    fn placement_errors() {
+        let objects = vec![("a.o".to_string(), object("JR NC, &far\n"))];
+        let far = Script::parse("section code at 0000h\nsection far at 1000h").unwrap();
+        let mut with_far = objects.clone();
+        with_far.push(("b.o".to_string(), {
+            let mut o = object(".far: ret\n");
+            o.sections[0].name = "far".to_string();
+            o.symbols[0].section = "far".to_string();
+            o
+        }));
+        assert_eq!(
+            "a.o: l1 - 'far' is too far for a jr",
+            link(&with_far, &far).unwrap_err().to_string()
+        );
+
+        // the value of a label at the end of its section isn't there
+        let values = [
+            ("a.o".to_string(), object(".main: ld b, *end\n.end:\n")),
+            (
+                "b.o".to_string(),
+                object("ld hl, *buf\n#section data\n.buf: 01h\n"),
+            ),
+        ];
+        assert_eq!(
+            "a.o: l1 - '*end' reads past the end of its section",
+            link(&values[..1], &Script::default_for(["code"]))
+                .unwrap_err()
+                .to_string()
+        );
+        assert_eq!(
+            "b.o: l1 - '*buf' reads past the end of its section",
+            link(&values[1..], &Script::default_for(["code", "data"]))
+                .unwrap_err()
+                .to_string()
+        );
+        let bss = [(
+            "c.o".to_string(),
+            object(".main: ld hl, *buf\n#section bss\n.buf: 00h 00h\n"),
+        )];
+        assert_eq!(
+            "c.o: l1 - '*buf' reads bss, which has no data",
+            link(&bss, &Script::default_for(section_names(&bss)))
+                .unwrap_err()
+                .to_string()
+        );
+
+        assert_eq!(
+            "a.o: section 'code' is not in the linker script",
+            link(&objects, &Script::parse("section data").unwrap())
+                .unwrap_err()
+                .to_string()
+        );
+        assert_eq!(
+            "section 'code' goes 1 bytes past the end of memory",
+            link(&objects, &Script::parse("section code at FFFFh").unwrap())
+                .unwrap_err()
+                .to_string()
+        );
+        assert_eq!(
+            "script l2 - expected 'memory <name> <start>-<end> [bank <n>]' or 'section <name>'",
+            Script::parse("section code\nplace data\n")
+                .unwrap_err()
+                .to_string()
+        );
+    }
+}
@@ -0,0 +1,323 @@
+use std::fmt::{Display, Formatter};
+
+/// Assembled but not yet placed code, written by `z80_assembler -c` and
+/// combined by `z80ld`. Symbols and relocations refer to sections by name.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Object {
+    pub sections: Vec<Section>,
+    pub symbols: Vec<Symbol>,
+    pub relocations: Vec<Relocation>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Section {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+/// Label defined at `offset` in `section`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub section: String,
+    pub offset: usize,
+}
+
+/// Bytes at `offset` in `section` that depend on where `symbol` ends up.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Relocation {
+    pub section: String,
+    pub offset: usize,
+    pub symbol: String,
+    pub kind: RelocationKind,
+    /// Source line of the reference, for error messages.
+    pub line: usize,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RelocationKind {
+    /// Address of the symbol, 2 bytes.
+    Absolute,
+    /// Distance from the byte after the relocation to the symbol, 1 byte.
+    Relative,
+    /// Byte stored at the symbol, like `ld b, *label`.
+    Byte,
+    /// 2 bytes stored at the symbol.
+    Word,
+}
+
+impl RelocationKind {
+    fn name(&self) -> &'static str {
+        match self {
+            RelocationKind::Absolute => "abs",
+            RelocationKind::Relative => "rel",
+            RelocationKind::Byte => "byte",
+            RelocationKind::Word => "word",
+        }
+    }
+
+    fn parse(name: &str) -> Option<RelocationKind> {
+        [
+            RelocationKind::Absolute,
+            RelocationKind::Relative,
+            RelocationKind::Byte,
+            RelocationKind::Word,
+        ]
+        .into_iter()
+        .find(|k| k.name() == name)
+    }
+
+    /// Bytes the relocation writes.
+    pub fn size(&self) -> usize {
+        match self {
+            RelocationKind::Relative | RelocationKind::Byte => 1,
+            RelocationKind::Absolute | RelocationKind::Word => 2,
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct ObjectError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for ObjectError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "l{} - {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ObjectError {}
+
+const OBJECT_HEADER: &str = "z80obj 1";
+const BYTES_PER_LINE: usize = 32;
+
+impl Object {
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|s| s.name == name)
+    }
+
+    /// Text form of the object:
+    ///
+    /// ```text
+    /// z80obj 1
+    /// section code 6
+    /// bytes CD0000C9CDC9
+    /// symbol main code 0000h
+    /// reloc code 0001h abs print 3
+    /// ```
+    ///
+    /// `bytes` lines follow their section, relocations end with the source
+    /// line of the reference.
+    pub fn to_text(&self) -> String {
+        let mut out = format!("{}\n", OBJECT_HEADER);
+        for s in self.sections.iter() {
+            out.push_str(&format!("section {} {}\n", s.name, s.data.len()));
+            for chunk in s.data.chunks(BYTES_PER_LINE) {
+                out.push_str("bytes ");
+                for b in chunk {
+                    out.push_str(&format!("{:02X}", b));
+                }
+                out.push('\n');
+            }
+        }
+        for s in self.symbols.iter() {
+            out.push_str(&format!(
+                "symbol {} {} {:04X}h\n",
+                s.name, s.section, s.offset
+            ));
+        }
+        for r in self.relocations.iter() {
+            out.push_str(&format!(
+                "reloc {} {:04X}h {} {} {}\n",
+                r.section,
+                r.offset,
+                r.kind.name(),
+                r.symbol,
+                r.line
+            ));
+        }
+        out
+    }
+
+    pub fn from_text(text: &str) -> Result<Object, ObjectError> {
+        let mut object = Object::default();
+        let mut lines = text.lines().enumerate();
+
+        match lines.next() {
+            Some((_, OBJECT_HEADER)) => {}
+            _ => {
+                return Err(ObjectError {
+                    line: 1,
+                    message: format!("expected '{}'", OBJECT_HEADER),
+                })
+            }
+        }
+
+        // declared size and line of the last section
+        let mut size = 0;
+        let mut section_line = 0;
+        for (i, line) in lines {
+            let err = |message: &str| ObjectError {
+                line: i + 1,
+                message: message.to_string(),
+            };
+            let fields = line.split(' ').collect::<Vec<_>>();
+            // length of a section that symbols and relocations refer to
+            let section_len = |name: &str| match object.section(name) {
+                Some(s) => Ok(s.data.len()),
+                None => Err(err(&format!("unknown section '{}'", name))),
+            };
+
+            match fields.as_slice() {
+                ["section", name, len] => {
+                    if object.section(name).is_some() {
+                        return Err(err(&format!("section '{}' is defined twice", name)));
+                    }
+                    check_size(object.sections.last(), size, section_line)?;
+                    size = len.parse().map_err(|_| err("invalid section size"))?;
+                    section_line = i + 1;
+                    object.sections.push(Section {
+                        name: name.to_string(),
+                        data: Vec::new(),
+                    });
+                }
+                ["bytes", hex] => {
+                    let section = object
+                        .sections
+                        .last_mut()
+                        .ok_or_else(|| err("bytes before the first section"))?;
+                    let bytes = parse_hex(hex).ok_or_else(|| err("invalid bytes"))?;
+                    if section.data.len() + bytes.len() > size {
+                        return Err(err("more bytes than the section size"));
+                    }
+                    section.data.extend(bytes);
+                }
+                ["symbol", name, section, offset] => {
+                    let offset = parse_offset(offset)
+                        .filter(|o| *o <= section_len(section).unwrap_or(0))
+                        .ok_or_else(|| err("invalid offset"))?;
+                    section_len(section)?;
+                    object.symbols.push(Symbol {
+                        name: name.to_string(),
+                        section: section.to_string(),
+                        offset,
+                    })
+                }
+                ["reloc", section, offset, kind, symbol, line] => {
+                    let kind = RelocationKind::parse(kind)
+                        .ok_or_else(|| err("invalid relocation kind"))?;
+                    let offset = parse_offset(offset)
+                        .filter(|o| o + kind.size() <= section_len(section).unwrap_or(0))
+                        .ok_or_else(|| err("invalid offset"))?;
+                    section_len(section)?;
+                    object.relocations.push(Relocation {
+                        section: section.to_string(),
+                        offset,
+                        symbol: symbol.to_string(),
+                        kind,
+                        line: line.parse().map_err(|_| err("invalid line"))?,
+                    })
+                }
+                [""] => {}
+                _ => return Err(err("invalid line")),
+            }
+        }
+        check_size(object.sections.last(), size, section_line)?;
+        Ok(object)
+    }
+}
+
+/// Fails if `section` holds fewer bytes than the `size` it was declared with.
+fn check_size(section: Option<&Section>, size: usize, line: usize) -> Result<(), ObjectError> {
+    match section {
+        Some(s) if s.data.len() != size => Err(ObjectError {
+            line,
+            message: format!(
+                "section '{}' has {} bytes, {} declared",
+                s.name,
+                s.data.len(),
+                size
+            ),
+        }),
+        _ => Ok(()),
+    }
+}
+
+fn parse_offset(s: &str) -> Option<usize> {
+    s.strip_suffix('h')
+        .and_then(|s| usize::from_str_radix(s, 16).ok())
+}
+
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::object::{Object, Relocation, RelocationKind, Section, Symbol};
+
+    #[test]
+    fn text_round_trip() {
+        let object = Object {
+            sections: vec![Section {
+                name: "code".to_string(),
+                data: (0..40).collect(),
+            }],
+            symbols: vec![Symbol {
+                name: "main".to_string(),
+                section: "code".to_string(),
+                offset: 0,
+            }],
+            relocations: vec![Relocation {
+                section: "code".to_string(),
+                offset: 0x21,
+                symbol: "print".to_string(),
+                kind: RelocationKind::Absolute,
+                line: 3,
+            }],
+        };
+
+        let text = object.to_text();
+        assert!(text.contains("\nreloc code 0021h abs print 3\n"));
+        assert_eq!(Ok(object), Object::from_text(&text));
+
+        assert_eq!(
+            "l3 - unknown section 'data'",
+            Object::from_text("z80obj 1\nsection code 0\nsymbol a data 0000h\n")
+                .unwrap_err()
+                .to_string()
+        );
+        assert_eq!(
+            "l4 - invalid offset",
+            Object::from_text("z80obj 1\nsection code 1\nbytes 00\nreloc code 0000h abs a 1\n")
+                .unwrap_err()
+                .to_string()
+        );
+        assert_eq!(
+            "l2 - section 'code' has 1 bytes, 3 declared",
+            Object::from_text("z80obj 1\nsection code 3\nbytes 00\nsection data 0\n")
+                .unwrap_err()
+                .to_string()
+        );
+        assert_eq!(
+            "l3 - section 'data' has 0 bytes, 2 declared",
+            Object::from_text("z80obj 1\nsection code 0\nsection data 2\n")
+                .unwrap_err()
+                .to_string()
+        );
+        assert_eq!(
+            "l2 - section 'code' has 0 bytes, 18446744073709551615 declared",
+            Object::from_text("z80obj 1\nsection code 18446744073709551615\n")
+                .unwrap_err()
+                .to_string()
+        );
+    }
+}
@@ -4,6 +4,8 @@ use crate::parser::token::TokenValue;
 pub enum ParseError {
     UnexpectedChar(char, usize),
     UnexpectedEOF(usize),
+    /// Missing closing character and line.
+    UnterminatedString(char, usize),
     UnexpectedToken(UnexpectedToken),
 }
 
@@ -51,6 +51,7 @@ impl Parser {
             token: TokenValue::Identifier(l),
             line,
             file_id,
+            ..
         } = tokenizer.next()?
         {
             tokenizer.expect(TokenValue::Colon)?;
@@ -72,6 +73,7 @@ impl Parser {
             token: TokenValue::Identifier(code),
             line,
             file_id,
+            ..
         } = tokenizer.next()?
         {
             let mut inst = Instruction {
@@ -131,7 +133,7 @@ impl Parser {
                 Ok(match parse_register(&i) {
                     ParsedRegister::ShortReg(sr) => Argument::ShortReg(sr),
                     ParsedRegister::WideReg(wr) => Argument::WideReg(wr),
-                    _ => unimplemented!(),
+                    ParsedRegister::Error(_) => Argument::Label(i),
                 })
             }
             TokenValue::Amp => {
@@ -187,15 +189,21 @@ impl Parser {
                         unimplemented!()
                     }
                 } else {
-                    if let ParsedRegister::WideReg(wr) = parse_register(&i) {
-                        tokenizer.expect(TokenValue::CloseParen)?;
-                        if wr == WideReg::IX || wr == WideReg::IY {
-                            Ok(Argument::RegOffsetAddress(wr, 0))
-                        } else {
-                            Ok(Argument::WideRegAddress(wr))
+                    match parse_register(&i) {
+                        ParsedRegister::WideReg(wr) => {
+                            tokenizer.expect(TokenValue::CloseParen)?;
+                            if wr == WideReg::IX || wr == WideReg::IY {
+                                Ok(Argument::RegOffsetAddress(wr, 0))
+                            } else {
+                                Ok(Argument::WideRegAddress(wr))
+                            }
                         }
-                    } else {
-                        unimplemented!()
+                        // the port of `in r, (c)` and `out (c), r`
+                        ParsedRegister::ShortReg(sr) => {
+                            tokenizer.expect(TokenValue::CloseParen)?;
+                            Ok(Argument::ShortRegAddress(sr))
+                        }
+                        _ => unimplemented!(),
                     }
                 }
             }
@@ -496,11 +504,13 @@ add a, @const1"#,
                     Token {
                         token: TokenValue::Identifier("dir".to_string()),
                         line: 2,
+                        column: 7,
                         file_id: 0,
                     },
                     Token {
                         token: TokenValue::Value(18, 1),
                         line: 2,
+                        column: 11,
                         file_id: 0,
                     },
                 ]
@@ -2,6 +2,7 @@
 pub struct Token {
     pub token: TokenValue,
     pub line: usize,
+    pub column: usize,
     pub file_id: usize,
 }
 
@@ -22,4 +23,8 @@ pub enum TokenValue {
     NewLine,
     EOF,
     Directive(String),
+    /// Text between double quotes, like the file of an include.
+    Str(String),
+    /// Name between angle brackets, like the library file of an include.
+    Library(String),
 }
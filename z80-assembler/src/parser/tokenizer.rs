@@ -17,6 +17,8 @@ pub struct SimpleTokenizer<'a> {
     file_id: usize,
     chars: Peekable<CharIndices<'a>>,
     curr_line: usize,
+    line_start: usize,
+    token_start: usize,
     head: Option<Token>,
 }
 
@@ -24,6 +26,9 @@ pub struct SimpleTokenizer<'a> {
 pub struct BufferedTokenizer<'a> {
     tokenizer: SimpleTokenizer<'a>,
     buffer: VecDeque<Token>,
+    /// Macro expansions the buffered tokens come from, innermost last, with
+    /// the number of their tokens still in the buffer.
+    expansions: Vec<(Token, usize)>,
 }
 
 impl<'a> BufferedTokenizer<'a> {
@@ -34,9 +39,12 @@ impl<'a> BufferedTokenizer<'a> {
                 file_id,
                 chars: source.char_indices().peekable(),
                 curr_line: 1,
+                line_start: 0,
+                token_start: 0,
                 head: None,
             },
             buffer: VecDeque::new(),
+            expansions: vec![],
         }
     }
 
@@ -58,6 +66,39 @@ impl<'a> BufferedTokenizer<'a> {
         for t in tokens.into_iter().rev() {
             self.buffer.push_front(t.clone())
         }
+        // pushed while expanding a macro, they are part of that expansion
+        if let Some((_, remaining)) = self.expansions.last_mut() {
+            *remaining += tokens.len();
+        }
+    }
+
+    /// Like `push_front` for the body of a macro, `call` is the token the
+    /// macro was invoked with.
+    pub fn push_expansion(&mut self, call: Token, tokens: &[Token]) {
+        if tokens.is_empty() {
+            return;
+        }
+        for t in tokens.iter().rev() {
+            self.buffer.push_front(t.clone())
+        }
+        self.expansions.push((call, tokens.len()));
+    }
+
+    /// Call sites of the macros the next token is expanded from, outermost
+    /// first.
+    pub fn expansion_stack(&self) -> Vec<Token> {
+        self.expansions.iter().map(|(t, _)| t.clone()).collect()
+    }
+
+    fn pop_buffer(&mut self) -> Option<Token> {
+        let t = self.buffer.pop_front()?;
+        if let Some((_, remaining)) = self.expansions.last_mut() {
+            *remaining -= 1;
+            if *remaining == 0 {
+                self.expansions.pop();
+            }
+        }
+        Some(t)
     }
 }
 
@@ -71,7 +112,7 @@ impl<'a> Tokenizer for BufferedTokenizer<'a> {
     }
 
     fn expect(&mut self, expected: TokenValue) -> Result<(), ParseError> {
-        if let Some(t) = self.buffer.pop_front() {
+        if let Some(t) = self.pop_buffer() {
             self.expect_token(t, expected)
         } else {
             self.tokenizer.expect(expected)
@@ -87,8 +128,8 @@ impl<'a> Tokenizer for BufferedTokenizer<'a> {
     }
 
     fn next(&mut self) -> Result<Token, ParseError> {
-        if let Some(t) = self.buffer.pop_front() {
-            Ok(t.clone())
+        if let Some(t) = self.pop_buffer() {
+            Ok(t)
         } else {
             self.tokenizer.next()
         }
@@ -102,6 +143,8 @@ impl<'a> SimpleTokenizer<'a> {
             file_id,
             chars: source.char_indices().peekable(),
             curr_line: 1,
+            line_start: 0,
+            token_start: 0,
             head: None,
         }
     }
@@ -193,14 +236,50 @@ impl<'a> SimpleTokenizer<'a> {
         }
     }
 
+    /// Text between double quotes, or a library name between angle brackets.
+    fn parse_string(&mut self) -> Result<Token, ParseError> {
+        let (start, open) = self.chars.next().unwrap();
+        let close = if open == '<' { '>' } else { '"' };
+        loop {
+            match self.chars.next() {
+                Some((end, c)) if c == close => {
+                    let text = self.source[start + 1..end].to_string();
+                    return Ok(self.create_token(match open {
+                        '<' => TokenValue::Library(text),
+                        _ => TokenValue::Str(text),
+                    }));
+                }
+                Some((_, '\n')) | None => {
+                    return Err(ParseError::UnterminatedString(close, self.curr_line))
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
     fn create_token(&self, token: TokenValue) -> Token {
+        let column = self
+            .source
+            .get(self.line_start..self.token_start)
+            .map(|s| s.chars().count())
+            .unwrap_or(0);
         Token {
             token,
             line: self.curr_line,
+            column: column + 1,
             file_id: self.file_id,
         }
     }
 
+    /// Moves to the next line, `pos` is the index of the `\n`.
+    fn new_line(&mut self, pos: usize) -> Token {
+        self.chars.next();
+        self.curr_line += 1;
+        self.line_start = pos + 1;
+        self.token_start = pos + 1;
+        self.create_token(TokenValue::NewLine)
+    }
+
     pub fn collect_all(&mut self) -> Result<Vec<Token>, ParseError> {
         let mut out = vec![];
 
@@ -246,36 +325,39 @@ impl<'a> Tokenizer for SimpleTokenizer<'a> {
         }
 
         loop {
-            if let Some((_, c)) = self.chars.peek() {
+            if let Some((p, c)) = self.chars.peek() {
+                let p = *p;
                 if *c == '\n' {
-                    self.curr_line += 1;
-                    self.chars.next();
-                    return Ok(self.create_token(TokenValue::NewLine));
+                    return Ok(self.new_line(p));
                 }
 
                 if *c == ';' {
                     // comment
                     loop {
                         match self.chars.peek() {
-                            Some((_, '\n')) => {
-                                self.curr_line += 1;
-                                self.chars.next();
-                                return Ok(self.create_token(TokenValue::NewLine));
+                            Some((p, '\n')) => {
+                                let p = *p;
+                                return Ok(self.new_line(p));
                             }
                             Some(_) => {
                                 self.chars.next();
                             }
-                            None => return Ok(self.create_token(TokenValue::EOF)),
+                            None => {
+                                self.token_start = self.source.len();
+                                return Ok(self.create_token(TokenValue::EOF));
+                            }
                         }
                     }
                 }
 
                 if !c.is_whitespace() {
+                    self.token_start = p;
                     break;
                 }
 
                 self.chars.next();
             } else {
+                self.token_start = self.source.len();
                 return Ok(self.create_token(TokenValue::EOF));
             }
         }
@@ -283,6 +365,7 @@ impl<'a> Tokenizer for SimpleTokenizer<'a> {
         if let Some((_, c)) = self.chars.peek() {
             match c {
                 '#' => self.parse_directive(),
+                '"' | '<' => self.parse_string(),
                 ',' | '(' | ')' | '+' | '-' | '.' | ':' | '&' | '*' | '@' => {
                     self.parse_single_char()
                 }
@@ -318,6 +401,7 @@ fn parse_identifier_or_value(s: &str) -> TokenValue {
 
 #[cfg(test)]
 mod tests {
+    use crate::parser::errors::ParseError;
     use crate::parser::token::TokenValue;
     use crate::parser::tokenizer::{SimpleTokenizer, Tokenizer};
 
@@ -441,6 +525,24 @@ call"#,
         assert_eq!(TokenValue::CloseParen, parser.next().unwrap().token);
     }
 
+    #[test]
+    fn test_include_names() {
+        let mut parser = SimpleTokenizer::new(r#"#include <std/math.z80> "a b.z80" <x"#, 0);
+        assert_eq!(
+            TokenValue::Directive("#include".to_string()),
+            parser.next().unwrap().token
+        );
+        assert_eq!(
+            TokenValue::Library("std/math.z80".to_string()),
+            parser.next().unwrap().token
+        );
+        assert_eq!(
+            TokenValue::Str("a b.z80".to_string()),
+            parser.next().unwrap().token
+        );
+        assert_eq!(Err(ParseError::UnterminatedString('>', 1)), parser.next());
+    }
+
     #[test]
     fn test_peek_next() {
         let mut parser = SimpleTokenizer::new(r"add a, 3Ah", 0);
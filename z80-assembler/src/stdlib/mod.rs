@@ -0,0 +1,20 @@
+/// Version of the standard library, bumped whenever a routine changes its
+/// registers or behavior.
+pub const VERSION: &str = "1.0";
+
+/// Files of the standard library, included with `#include <std/math.z80>`.
+pub const FILES: &[(&str, &str)] = &[
+    ("std/convert.z80", include_str!("../../std/convert.z80")),
+    ("std/math.z80", include_str!("../../std/math.z80")),
+    ("std/memory.z80", include_str!("../../std/memory.z80")),
+    ("std/serial.z80", include_str!("../../std/serial.z80")),
+    ("std/snes.z80", include_str!("../../std/snes.z80")),
+    ("std/string.z80", include_str!("../../std/string.z80")),
+    ("std/text.z80", include_str!("../../std/text.z80")),
+    ("std/vga.z80", include_str!("../../std/vga.z80")),
+];
+
+/// Source of the standard library file `name`, like `std/math.z80`.
+pub fn source(name: &str) -> Option<&'static str> {
+    FILES.iter().find(|(n, _)| *n == name).map(|(_, s)| *s)
+}
@@ -0,0 +1,51 @@
+use std::env;
+use std::io::{stdin, stdout};
+use std::process::ExitCode;
+use z80_emulator::debugger::Debugger;
+use z80_emulator::harness::assemble_file;
+
+fn help() {
+    println!("usage: z80dbg <source.z80> [entry]");
+    println!();
+    println!("Assembles the source and debugs it, starting from address 0 or calling");
+    println!("the entry label. Type 'help' at the prompt for the list of commands.");
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().collect();
+
+    if args.len() < 2 || args.len() > 3 {
+        help();
+        return ExitCode::from(2);
+    }
+
+    let source = &args[1];
+
+    let program = match assemble_file(source) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::from(2);
+        }
+    };
+
+    let entry = match args.get(2) {
+        Some(label) => match program.label(label) {
+            Some(addr) => Some(addr),
+            None => {
+                eprintln!("label '{}' not found", label);
+                return ExitCode::from(2);
+            }
+        },
+        None => None,
+    };
+
+    let mut debugger = Debugger::new(&program, entry);
+    match debugger.repl(stdin().lock(), stdout()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
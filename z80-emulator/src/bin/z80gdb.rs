@@ -0,0 +1,82 @@
+use std::env;
+use std::net::TcpListener;
+use std::process::ExitCode;
+use z80_emulator::debugger::Debugger;
+use z80_emulator::gdb::GdbServer;
+use z80_emulator::harness::assemble_file;
+
+const DEFAULT_PORT: u16 = 1234;
+
+fn help() {
+    println!("usage: z80gdb <source.z80> [entry] [--port <port>]");
+    println!();
+    println!("Assembles the source and waits for a GDB client on localhost, by default");
+    println!(
+        "on port {}: `target remote :{}`.",
+        DEFAULT_PORT, DEFAULT_PORT
+    );
+}
+
+fn main() -> ExitCode {
+    let mut args = env::args().skip(1);
+    let mut positional = vec![];
+    let mut port = DEFAULT_PORT;
+
+    while let Some(arg) = args.next() {
+        if arg == "--port" {
+            port = match args.next().and_then(|p| p.parse().ok()) {
+                Some(p) => p,
+                None => {
+                    help();
+                    return ExitCode::from(2);
+                }
+            };
+        } else {
+            positional.push(arg);
+        }
+    }
+
+    if positional.is_empty() || positional.len() > 2 {
+        help();
+        return ExitCode::from(2);
+    }
+
+    let source = &positional[0];
+
+    let program = match assemble_file(source) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::from(2);
+        }
+    };
+
+    let entry = match positional.get(1) {
+        Some(label) => match program.label(label) {
+            Some(addr) => Some(addr),
+            None => {
+                eprintln!("label '{}' not found", label);
+                return ExitCode::from(2);
+            }
+        },
+        None => None,
+    };
+
+    let listener = match TcpListener::bind(("127.0.0.1", port)) {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("unable to listen on port {}: {}", port, e);
+            return ExitCode::from(2);
+        }
+    };
+    println!("waiting for gdb on port {}", port);
+
+    let mut server = GdbServer::new(Debugger::new(&program, entry));
+    match server.listen(listener) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
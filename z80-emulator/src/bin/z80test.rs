@@ -0,0 +1,70 @@
+use std::env;
+use std::process::ExitCode;
+use z80_emulator::harness::{assemble_file, parse_test_blocks, run_test};
+
+fn help() {
+    println!("usage: z80test <source.z80> [filter]");
+    println!();
+    println!("Assembles the source and runs every #test block whose name contains filter.");
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().collect();
+
+    if args.len() < 2 || args.len() > 3 {
+        help();
+        return ExitCode::from(2);
+    }
+
+    let source = &args[1];
+    let filter = args.get(2).map(|f| f.as_str()).unwrap_or("");
+
+    let program = match assemble_file(source) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::from(2);
+        }
+    };
+
+    let cases = match parse_test_blocks(&program) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::from(2);
+        }
+    };
+
+    let cases = cases
+        .into_iter()
+        .filter(|c| c.name.contains(filter))
+        .collect::<Vec<_>>();
+
+    println!("running {} tests", cases.len());
+
+    let mut failed = 0;
+    for case in cases.iter() {
+        let res = run_test(&program, case);
+        println!(
+            "test {} ({} cycles) ... {}",
+            res.name, res.cycles, res.outcome
+        );
+        if !res.passed() {
+            failed += 1;
+        }
+    }
+
+    println!();
+    println!(
+        "test result: {}. {} passed; {} failed",
+        if failed == 0 { "ok" } else { "FAILED" },
+        cases.len() - failed,
+        failed
+    );
+
+    if failed == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
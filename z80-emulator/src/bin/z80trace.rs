@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+use std::env;
+use std::process::ExitCode;
+use z80_emulator::harness::{assemble_file, DEFAULT_MAX_CYCLES};
+use z80_emulator::trace::{
+    decode, diff, encode, format_record, label_range, record, replay, symbols, Interrupt, Options,
+    Trace,
+};
+
+fn help() {
+    println!("usage: z80trace record <source.z80> [entry] -o <out.trace> [options]");
+    println!("       z80trace show <trace> [source.z80]");
+    println!("       z80trace diff <a.trace> <b.trace> [source.z80]");
+    println!("       z80trace replay <source.z80> <trace>");
+    println!();
+    println!("record options:");
+    println!("  --filter <start-end|label>   only record instructions in the range, or in");
+    println!("                               the code from label to the next one");
+    println!("  --input <port>:<b>,<b>...    bytes returned by reads of the port");
+    println!("  --irq <cycles>[:data]        maskable interrupt, data defaults to FFh");
+    println!("  --irq-every <cycles>[:data]  periodic maskable interrupt");
+    println!("  --nmi <cycles>");
+    println!(
+        "  --max-cycles <n>             defaults to {}",
+        DEFAULT_MAX_CYCLES
+    );
+    println!();
+    println!("Numbers are decimal, or hex with a 'h' suffix. replay runs the program");
+    println!("again with the recorded inputs and interrupts and reports the first");
+    println!("record that differs.");
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let res = match args.first().map(|a| a.as_str()) {
+        Some("record") => cmd_record(&args[1..]),
+        Some("show") if args.len() == 2 || args.len() == 3 => cmd_show(&args[1..]),
+        Some("diff") if args.len() == 3 || args.len() == 4 => cmd_diff(&args[1..]),
+        Some("replay") if args.len() == 3 => cmd_replay(&args[1..]),
+        _ => {
+            help();
+            return ExitCode::from(2);
+        }
+    };
+
+    match res {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::from(2)
+        }
+    }
+}
+
+fn cmd_record(args: &[String]) -> Result<ExitCode, String> {
+    let mut positional = vec![];
+    let mut out = None;
+    let mut filters = vec![];
+    let mut inputs: Vec<(u8, Vec<u8>)> = vec![];
+    let mut interrupts = vec![];
+    let mut periodic = vec![];
+    let mut max_cycles = DEFAULT_MAX_CYCLES;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with('-') {
+            positional.push(arg.clone());
+            continue;
+        }
+        let val = args
+            .next()
+            .ok_or_else(|| format!("missing value for {}", arg))?;
+        let invalid = || format!("invalid value for {}: '{}'", arg, val);
+
+        match arg.as_str() {
+            "-o" => out = Some(val.clone()),
+            "--filter" => filters.push(val.clone()),
+            "--input" => {
+                let (port, bytes) = val.split_once(':').ok_or_else(invalid)?;
+                let port = parse_number(port).ok_or_else(invalid)? as u8;
+                let bytes = bytes
+                    .split(',')
+                    .map(|b| parse_number(b).map(|b| b as u8))
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(invalid)?;
+                match inputs.iter_mut().find(|(p, _)| *p == port) {
+                    Some((_, data)) => data.extend(bytes),
+                    None => inputs.push((port, bytes)),
+                }
+            }
+            "--irq" | "--irq-every" => {
+                let (cycles, data) = match val.split_once(':') {
+                    Some((c, d)) => (c, parse_number(d).ok_or_else(invalid)? as u8),
+                    None => (val.as_str(), 0xFF),
+                };
+                let cycles = parse_number(cycles)
+                    .filter(|c| *c > 0)
+                    .ok_or_else(invalid)?;
+                if arg == "--irq" {
+                    interrupts.push(Interrupt {
+                        cycles,
+                        data: Some(data),
+                    });
+                } else {
+                    periodic.push((cycles, data));
+                }
+            }
+            "--nmi" => interrupts.push(Interrupt {
+                cycles: parse_number(val).ok_or_else(invalid)?,
+                data: None,
+            }),
+            "--max-cycles" => max_cycles = parse_number(val).ok_or_else(invalid)?,
+            _ => return Err(format!("unknown option {}", arg)),
+        }
+    }
+
+    let out = out.ok_or("missing -o <out.trace>")?;
+    if positional.is_empty() || positional.len() > 2 {
+        return Err("expected <source.z80> [entry]".to_string());
+    }
+
+    let program = assemble_file(&positional[0])?;
+    let entry = match positional.get(1) {
+        Some(label) => Some(
+            program
+                .label(label)
+                .ok_or_else(|| format!("label '{}' not found", label))?,
+        ),
+        None => None,
+    };
+
+    let filter = filters
+        .iter()
+        .map(|f| match f.split_once('-') {
+            Some((s, e)) => match (parse_number(s), parse_number(e)) {
+                (Some(s), Some(e)) if s <= e && e <= 0xFFFF => Ok((s as u16, e as u16)),
+                _ => Err(format!("invalid range '{}'", f)),
+            },
+            None => label_range(&program, f).ok_or_else(|| format!("label '{}' not found", f)),
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    for (every, data) in periodic {
+        interrupts.extend((1..=max_cycles / every).map(|i| Interrupt {
+            cycles: i * every,
+            data: Some(data),
+        }));
+    }
+
+    let trace = record(
+        &program,
+        &Options {
+            entry,
+            max_cycles,
+            filter,
+            inputs,
+            interrupts,
+        },
+    );
+    let data = encode(&trace);
+    std::fs::write(&out, &data).map_err(|e| format!("unable to write {}: {}", out, e))?;
+    println!(
+        "{} records, {} bytes written to {}",
+        trace.records.len(),
+        data.len(),
+        out
+    );
+    Ok(ExitCode::SUCCESS)
+}
+
+fn cmd_show(args: &[String]) -> Result<ExitCode, String> {
+    let trace = read_trace(&args[0])?;
+    let labels = match args.get(1) {
+        Some(source) => symbols(&assemble_file(source)?),
+        None => HashMap::new(),
+    };
+    for r in trace.records.iter() {
+        println!("{}", format_record(r, &labels));
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn cmd_diff(args: &[String]) -> Result<ExitCode, String> {
+    let left = read_trace(&args[0])?;
+    let right = read_trace(&args[1])?;
+    let labels = match args.get(2) {
+        Some(source) => symbols(&assemble_file(source)?),
+        None => HashMap::new(),
+    };
+
+    if left.header != right.header {
+        println!("warning: the traces were recorded with different settings");
+    }
+    Ok(report(&left, &right, &labels))
+}
+
+fn cmd_replay(args: &[String]) -> Result<ExitCode, String> {
+    let program = assemble_file(&args[0])?;
+    let trace = read_trace(&args[1])?;
+    let replayed = replay(&program, &trace).map_err(|e| e.to_string())?;
+    Ok(report(&trace, &replayed, &symbols(&program)))
+}
+
+/// Prints the first difference with some context, exits with 1 if there is
+/// one.
+fn report(left: &Trace, right: &Trace, labels: &HashMap<u16, String>) -> ExitCode {
+    let d = match diff(left, right) {
+        Some(d) => d,
+        None => {
+            println!("traces match, {} records", left.records.len());
+            return ExitCode::SUCCESS;
+        }
+    };
+
+    println!("traces diverge at record {}:", d.index);
+    for r in left.records[d.index.saturating_sub(3)..d.index].iter() {
+        println!("  {}", format_record(r, labels));
+    }
+    match d.left {
+        Some(r) => println!("- {}", format_record(&r, labels)),
+        None => println!("- (end of trace)"),
+    }
+    match d.right {
+        Some(r) => println!("+ {}", format_record(&r, labels)),
+        None => println!("+ (end of trace)"),
+    }
+    ExitCode::FAILURE
+}
+
+fn read_trace(path: &str) -> Result<Trace, String> {
+    let data = std::fs::read(path).map_err(|e| format!("unable to read {}: {}", path, e))?;
+    decode(&data).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn parse_number(val: &str) -> Option<u64> {
+    let lower = val.to_lowercase();
+    if let Some(hex) = lower.strip_suffix('h') {
+        u64::from_str_radix(hex, 16).ok()
+    } else if let Some(hex) = lower.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16).ok()
+    } else {
+        lower.parse::<u64>().ok()
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::{HashMap, VecDeque};
+
+pub trait Bus {
+    fn read(&mut self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, val: u8);
+    fn input(&mut self, port: u16) -> u8;
+    fn output(&mut self, port: u16, val: u8);
+}
+
+/// Flat 64 KiB of RAM with queued input ports and a log of every output.
+pub struct Memory {
+    pub ram: Vec<u8>,
+    pub inputs: HashMap<u8, VecDeque<u8>>,
+    pub outputs: Vec<(u16, u8)>,
+}
+
+impl Memory {
+    pub fn new() -> Self {
+        Memory {
+            ram: vec![0u8; 64 * 1024],
+            inputs: HashMap::new(),
+            outputs: vec![],
+        }
+    }
+
+    pub fn load(&mut self, addr: u16, data: &[u8]) {
+        let len = self.ram.len();
+        for (i, b) in data.iter().enumerate() {
+            self.ram[(addr as usize + i) % len] = *b;
+        }
+    }
+
+    pub fn slice(&self, addr: u16, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|i| self.ram[(addr as usize + i) % self.ram.len()])
+            .collect()
+    }
+
+    pub fn queue_input(&mut self, port: u8, data: &[u8]) {
+        self.inputs.entry(port).or_default().extend(data);
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bus for Memory {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.ram[addr as usize]
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        self.ram[addr as usize] = val;
+    }
+
+    fn input(&mut self, port: u16) -> u8 {
+        self.inputs
+            .get_mut(&(port as u8))
+            .and_then(|q| q.pop_front())
+            .unwrap_or(0xFF)
+    }
+
+    fn output(&mut self, port: u16, val: u8) {
+        self.outputs.push((port, val));
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Access {
+    Read(u16, u8),
+    Write { addr: u16, val: u8, old: u8 },
+    In(u16, u8),
+    Out(u16, u8),
+}
+
+/// Wraps a bus and logs every access, writes keep the overwritten value so
+/// they can be undone.
+pub struct Recorder<'a, B: Bus> {
+    pub bus: &'a mut B,
+    pub accesses: Vec<Access>,
+}
+
+impl<'a, B: Bus> Recorder<'a, B> {
+    pub fn new(bus: &'a mut B) -> Self {
+        Recorder {
+            bus,
+            accesses: vec![],
+        }
+    }
+}
+
+impl<'a, B: Bus> Bus for Recorder<'a, B> {
+    fn read(&mut self, addr: u16) -> u8 {
+        let val = self.bus.read(addr);
+        self.accesses.push(Access::Read(addr, val));
+        val
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        let old = self.bus.read(addr);
+        self.bus.write(addr, val);
+        self.accesses.push(Access::Write { addr, val, old });
+    }
+
+    fn input(&mut self, port: u16) -> u8 {
+        let val = self.bus.input(port);
+        self.accesses.push(Access::In(port, val));
+        val
+    }
+
+    fn output(&mut self, port: u16, val: u8) {
+        self.bus.output(port, val);
+        self.accesses.push(Access::Out(port, val));
+    }
+}
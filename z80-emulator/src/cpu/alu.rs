@@ -0,0 +1,285 @@
+use crate::cpu::registers::*;
+
+fn sz_xy(val: u8) -> u8 {
+    let mut f = val & (FLAG_S | FLAG_X | FLAG_Y);
+    if val == 0 {
+        f |= FLAG_Z;
+    }
+    f
+}
+
+fn parity(val: u8) -> u8 {
+    if val.count_ones().is_multiple_of(2) {
+        FLAG_PV
+    } else {
+        0
+    }
+}
+
+pub fn add8(a: u8, b: u8, carry: bool) -> (u8, u8) {
+    let c = carry as u16;
+    let wide = a as u16 + b as u16 + c;
+    let res = wide as u8;
+    let mut f = sz_xy(res);
+    if (a & 0x0F) as u16 + (b & 0x0F) as u16 + c > 0x0F {
+        f |= FLAG_H;
+    }
+    if (a ^ !b) & (a ^ res) & 0x80 != 0 {
+        f |= FLAG_PV;
+    }
+    if wide > 0xFF {
+        f |= FLAG_C;
+    }
+    (res, f)
+}
+
+pub fn sub8(a: u8, b: u8, carry: bool) -> (u8, u8) {
+    let c = carry as i16;
+    let wide = a as i16 - b as i16 - c;
+    let res = wide as u8;
+    let mut f = sz_xy(res) | FLAG_N;
+    if ((a & 0x0F) as i16) - ((b & 0x0F) as i16) - c < 0 {
+        f |= FLAG_H;
+    }
+    if (a ^ b) & (a ^ res) & 0x80 != 0 {
+        f |= FLAG_PV;
+    }
+    if wide < 0 {
+        f |= FLAG_C;
+    }
+    (res, f)
+}
+
+pub fn cp8(a: u8, b: u8) -> u8 {
+    let (_, f) = sub8(a, b, false);
+    (f & !(FLAG_X | FLAG_Y)) | (b & (FLAG_X | FLAG_Y))
+}
+
+pub fn and8(a: u8, b: u8) -> (u8, u8) {
+    let res = a & b;
+    (res, sz_xy(res) | parity(res) | FLAG_H)
+}
+
+pub fn or8(a: u8, b: u8) -> (u8, u8) {
+    let res = a | b;
+    (res, sz_xy(res) | parity(res))
+}
+
+pub fn xor8(a: u8, b: u8) -> (u8, u8) {
+    let res = a ^ b;
+    (res, sz_xy(res) | parity(res))
+}
+
+/// Runs one of the eight accumulator operations in opcode order:
+/// ADD, ADC, SUB, SBC, AND, XOR, OR, CP.
+pub fn alu(op: u8, a: u8, b: u8, flags: u8) -> (u8, u8) {
+    let carry = flags & FLAG_C != 0;
+    match op {
+        0 => add8(a, b, false),
+        1 => add8(a, b, carry),
+        2 => sub8(a, b, false),
+        3 => sub8(a, b, carry),
+        4 => and8(a, b),
+        5 => xor8(a, b),
+        6 => or8(a, b),
+        _ => (a, cp8(a, b)),
+    }
+}
+
+pub fn inc8(val: u8, flags: u8) -> (u8, u8) {
+    let res = val.wrapping_add(1);
+    let mut f = sz_xy(res) | (flags & FLAG_C);
+    if val & 0x0F == 0x0F {
+        f |= FLAG_H;
+    }
+    if val == 0x7F {
+        f |= FLAG_PV;
+    }
+    (res, f)
+}
+
+pub fn dec8(val: u8, flags: u8) -> (u8, u8) {
+    let res = val.wrapping_sub(1);
+    let mut f = sz_xy(res) | (flags & FLAG_C) | FLAG_N;
+    if val & 0x0F == 0 {
+        f |= FLAG_H;
+    }
+    if val == 0x80 {
+        f |= FLAG_PV;
+    }
+    (res, f)
+}
+
+pub fn add16(a: u16, b: u16, flags: u8) -> (u16, u8) {
+    let wide = a as u32 + b as u32;
+    let res = wide as u16;
+    let mut f = flags & (FLAG_S | FLAG_Z | FLAG_PV);
+    f |= ((res >> 8) as u8) & (FLAG_X | FLAG_Y);
+    if (a & 0x0FFF) + (b & 0x0FFF) > 0x0FFF {
+        f |= FLAG_H;
+    }
+    if wide > 0xFFFF {
+        f |= FLAG_C;
+    }
+    (res, f)
+}
+
+pub fn adc16(a: u16, b: u16, flags: u8) -> (u16, u8) {
+    let c = (flags & FLAG_C) as u32;
+    let wide = a as u32 + b as u32 + c;
+    let res = wide as u16;
+    let mut f = ((res >> 8) as u8) & (FLAG_S | FLAG_X | FLAG_Y);
+    if res == 0 {
+        f |= FLAG_Z;
+    }
+    if (a & 0x0FFF) as u32 + (b & 0x0FFF) as u32 + c > 0x0FFF {
+        f |= FLAG_H;
+    }
+    if (a ^ !b) & (a ^ res) & 0x8000 != 0 {
+        f |= FLAG_PV;
+    }
+    if wide > 0xFFFF {
+        f |= FLAG_C;
+    }
+    (res, f)
+}
+
+pub fn sbc16(a: u16, b: u16, flags: u8) -> (u16, u8) {
+    let c = (flags & FLAG_C) as i32;
+    let wide = a as i32 - b as i32 - c;
+    let res = wide as u16;
+    let mut f = (((res >> 8) as u8) & (FLAG_S | FLAG_X | FLAG_Y)) | FLAG_N;
+    if res == 0 {
+        f |= FLAG_Z;
+    }
+    if ((a & 0x0FFF) as i32) - ((b & 0x0FFF) as i32) - c < 0 {
+        f |= FLAG_H;
+    }
+    if (a ^ b) & (a ^ res) & 0x8000 != 0 {
+        f |= FLAG_PV;
+    }
+    if wide < 0 {
+        f |= FLAG_C;
+    }
+    (res, f)
+}
+
+/// Runs one of the CB-prefixed rotate/shift operations in opcode order:
+/// RLC, RRC, RL, RR, SLA, SRA, SLL, SRL.
+pub fn rot(op: u8, val: u8, flags: u8) -> (u8, u8) {
+    let carry_in = flags & FLAG_C;
+    let (res, carry) = match op {
+        0 => (val.rotate_left(1), val >> 7),
+        1 => (val.rotate_right(1), val & 1),
+        2 => ((val << 1) | carry_in, val >> 7),
+        3 => ((val >> 1) | (carry_in << 7), val & 1),
+        4 => (val << 1, val >> 7),
+        5 => ((val >> 1) | (val & 0x80), val & 1),
+        6 => ((val << 1) | 1, val >> 7),
+        _ => (val >> 1, val & 1),
+    };
+    (res, sz_xy(res) | parity(res) | carry)
+}
+
+/// RLCA, RRCA, RLA and RRA only touch H, N and C.
+pub fn rot_a(op: u8, a: u8, flags: u8) -> (u8, u8) {
+    let (res, f) = rot(op, a, flags);
+    (
+        res,
+        (flags & (FLAG_S | FLAG_Z | FLAG_PV)) | (res & (FLAG_X | FLAG_Y)) | (f & FLAG_C),
+    )
+}
+
+pub fn bit(n: u8, val: u8, flags: u8) -> u8 {
+    let set = val & (1 << n);
+    let mut f = (flags & FLAG_C) | FLAG_H | (val & (FLAG_X | FLAG_Y));
+    if set == 0 {
+        f |= FLAG_Z | FLAG_PV;
+    }
+    if n == 7 && set != 0 {
+        f |= FLAG_S;
+    }
+    f
+}
+
+pub fn daa(a: u8, flags: u8) -> (u8, u8) {
+    let mut correction = 0u8;
+    let mut carry = flags & FLAG_C;
+    if flags & FLAG_H != 0 || a & 0x0F > 9 {
+        correction |= 0x06;
+    }
+    if carry != 0 || a > 0x99 {
+        correction |= 0x60;
+        carry = FLAG_C;
+    }
+    let res = if flags & FLAG_N != 0 {
+        a.wrapping_sub(correction)
+    } else {
+        a.wrapping_add(correction)
+    };
+    let half = if flags & FLAG_N != 0 {
+        flags & FLAG_H != 0 && a & 0x0F < 6
+    } else {
+        a & 0x0F > 9
+    };
+    let mut f = sz_xy(res) | parity(res) | carry | (flags & FLAG_N);
+    if half {
+        f |= FLAG_H;
+    }
+    (res, f)
+}
+
+/// Flags for IN r,(C), RLD, RRD and LD A,I / LD A,R.
+pub fn szp(val: u8, flags: u8) -> u8 {
+    sz_xy(val) | parity(val) | (flags & FLAG_C)
+}
+
+pub fn sz(val: u8, flags: u8) -> u8 {
+    sz_xy(val) | (flags & FLAG_C)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::alu::*;
+
+    #[test]
+    fn add_sub_flags() {
+        assert_eq!((0x00, FLAG_Z | FLAG_H | FLAG_C), add8(0xFF, 0x01, false));
+        assert_eq!((0x80, FLAG_S | FLAG_H | FLAG_PV), add8(0x7F, 0x01, false));
+        assert_eq!(
+            (0xFF, FLAG_S | FLAG_Y | FLAG_X | FLAG_H | FLAG_N | FLAG_C),
+            sub8(0x00, 0x01, false)
+        );
+        assert_eq!(
+            (0x7F, FLAG_Y | FLAG_X | FLAG_H | FLAG_PV | FLAG_N),
+            sub8(0x80, 0x01, false)
+        );
+        assert_eq!(FLAG_Z | FLAG_N, cp8(0x42, 0x42) & !(FLAG_X | FLAG_Y));
+    }
+
+    #[test]
+    fn daa_adjusts_bcd() {
+        let (sum, f) = add8(0x15, 0x27, false);
+        assert_eq!(0x42, daa(sum, f).0);
+        let (sum, f) = add8(0x99, 0x01, false);
+        let (res, f) = daa(sum, f);
+        assert_eq!(0x00, res);
+        assert_ne!(0, f & FLAG_C);
+        let (diff, f) = sub8(0x42, 0x15, false);
+        assert_eq!(0x27, daa(diff, f).0);
+    }
+
+    #[test]
+    fn wide_arithmetic() {
+        assert_eq!((0x0000, FLAG_H | FLAG_C), add16(0xFFFF, 0x0001, 0));
+        assert_eq!((0x0000, FLAG_Z | FLAG_N), sbc16(0x1234, 0x1234, 0));
+        assert_eq!(0x1236, adc16(0x1234, 0x0001, FLAG_C).0);
+    }
+
+    #[test]
+    fn rotates() {
+        assert_eq!((0x03, FLAG_PV | FLAG_C), rot(0, 0x81, 0));
+        assert_eq!((0x40, FLAG_C), rot(7, 0x81, 0));
+        assert_eq!((0xC0, FLAG_S | FLAG_PV | FLAG_C), rot(5, 0x81, 0));
+    }
+}
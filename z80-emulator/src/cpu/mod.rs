@@ -0,0 +1,929 @@
+use crate::bus::Bus;
+use crate::cpu::alu::*;
+use crate::cpu::registers::{pair, split};
+pub use crate::cpu::registers::{
+    Flag, Register, Registers, FLAG_C, FLAG_H, FLAG_N, FLAG_PV, FLAG_S, FLAG_X, FLAG_Y, FLAG_Z,
+};
+
+mod alu;
+mod registers;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Index {
+    HL,
+    IX,
+    IY,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Cpu {
+    pub regs: Registers,
+    pub halted: bool,
+    pub cycles: u64,
+    ei_delay: bool,
+}
+
+impl Cpu {
+    pub fn new() -> Self {
+        Cpu {
+            regs: Registers {
+                a: 0xFF,
+                f: 0xFF,
+                sp: 0xFFFF,
+                ..Registers::default()
+            },
+            ..Cpu::default()
+        }
+    }
+
+    /// Executes a single instruction (prefixes included) and returns the
+    /// number of T-states it took.
+    pub fn step(&mut self, bus: &mut impl Bus) -> u32 {
+        self.ei_delay = false;
+        let t = if self.halted {
+            self.inc_r();
+            4
+        } else {
+            let op = self.fetch_op(bus);
+            self.exec_main(op, bus, Index::HL)
+        };
+        self.cycles += t as u64;
+        t
+    }
+
+    /// Requests a maskable interrupt, `data` is the byte the interrupting
+    /// device puts on the bus. Returns `None` if interrupts are disabled.
+    pub fn interrupt(&mut self, bus: &mut impl Bus, data: u8) -> Option<u32> {
+        if !self.regs.iff1 || self.ei_delay {
+            return None;
+        }
+
+        self.halted = false;
+        self.regs.iff1 = false;
+        self.regs.iff2 = false;
+        self.inc_r();
+
+        let t = match self.regs.im {
+            0 => 2 + self.exec_main(data, bus, Index::HL),
+            1 => {
+                self.push(bus, self.regs.pc);
+                self.regs.pc = 0x0038;
+                13
+            }
+            _ => {
+                self.push(bus, self.regs.pc);
+                let vector = pair(self.regs.i, data);
+                self.regs.pc = self.read16(bus, vector);
+                19
+            }
+        };
+        self.cycles += t as u64;
+        Some(t)
+    }
+
+    pub fn nmi(&mut self, bus: &mut impl Bus) -> u32 {
+        self.halted = false;
+        self.regs.iff2 = self.regs.iff1;
+        self.regs.iff1 = false;
+        self.inc_r();
+        self.push(bus, self.regs.pc);
+        self.regs.pc = 0x0066;
+        self.cycles += 11;
+        11
+    }
+
+    fn inc_r(&mut self) {
+        self.regs.r = (self.regs.r & 0x80) | (self.regs.r.wrapping_add(1) & 0x7F);
+    }
+
+    fn fetch_op(&mut self, bus: &mut impl Bus) -> u8 {
+        self.inc_r();
+        self.fetch(bus)
+    }
+
+    fn fetch(&mut self, bus: &mut impl Bus) -> u8 {
+        let val = bus.read(self.regs.pc);
+        self.regs.pc = self.regs.pc.wrapping_add(1);
+        val
+    }
+
+    fn fetch16(&mut self, bus: &mut impl Bus) -> u16 {
+        let low = self.fetch(bus);
+        let high = self.fetch(bus);
+        pair(high, low)
+    }
+
+    fn read16(&mut self, bus: &mut impl Bus, addr: u16) -> u16 {
+        let low = bus.read(addr);
+        let high = bus.read(addr.wrapping_add(1));
+        pair(high, low)
+    }
+
+    fn write16(&mut self, bus: &mut impl Bus, addr: u16, val: u16) {
+        let (high, low) = split(val);
+        bus.write(addr, low);
+        bus.write(addr.wrapping_add(1), high);
+    }
+
+    fn push(&mut self, bus: &mut impl Bus, val: u16) {
+        let (high, low) = split(val);
+        self.regs.sp = self.regs.sp.wrapping_sub(1);
+        bus.write(self.regs.sp, high);
+        self.regs.sp = self.regs.sp.wrapping_sub(1);
+        bus.write(self.regs.sp, low);
+    }
+
+    fn pop(&mut self, bus: &mut impl Bus) -> u16 {
+        let low = bus.read(self.regs.sp);
+        self.regs.sp = self.regs.sp.wrapping_add(1);
+        let high = bus.read(self.regs.sp);
+        self.regs.sp = self.regs.sp.wrapping_add(1);
+        pair(high, low)
+    }
+
+    fn index_reg(&self, idx: Index) -> u16 {
+        match idx {
+            Index::HL => self.regs.hl(),
+            Index::IX => self.regs.ix,
+            Index::IY => self.regs.iy,
+        }
+    }
+
+    fn set_index_reg(&mut self, idx: Index, val: u16) {
+        match idx {
+            Index::HL => self.regs.set_hl(val),
+            Index::IX => self.regs.ix = val,
+            Index::IY => self.regs.iy = val,
+        }
+    }
+
+    /// Address of the `(HL)` operand, fetching the displacement for `(IX+d)`.
+    fn operand_addr(&mut self, bus: &mut impl Bus, idx: Index) -> u16 {
+        match idx {
+            Index::HL => self.regs.hl(),
+            _ => {
+                let d = self.fetch(bus) as i8;
+                self.index_reg(idx).wrapping_add(d as u16)
+            }
+        }
+    }
+
+    /// 8-bit register by its 3-bit opcode code, H and L are replaced by the
+    /// index halves when a DD/FD prefix is active. Code 6 is not handled here.
+    fn reg8(&self, code: u8, idx: Index) -> u8 {
+        match code {
+            0 => self.regs.b,
+            1 => self.regs.c,
+            2 => self.regs.d,
+            3 => self.regs.e,
+            4 => split(self.index_reg(idx)).0,
+            5 => split(self.index_reg(idx)).1,
+            7 => self.regs.a,
+            _ => unreachable!(),
+        }
+    }
+
+    fn set_reg8(&mut self, code: u8, idx: Index, val: u8) {
+        match code {
+            0 => self.regs.b = val,
+            1 => self.regs.c = val,
+            2 => self.regs.d = val,
+            3 => self.regs.e = val,
+            4 => {
+                let (_, low) = split(self.index_reg(idx));
+                self.set_index_reg(idx, pair(val, low))
+            }
+            5 => {
+                let (high, _) = split(self.index_reg(idx));
+                self.set_index_reg(idx, pair(high, val))
+            }
+            7 => self.regs.a = val,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Reads an `r` operand; returns the value and the extra T-states spent
+    /// on the memory operand.
+    fn read_operand(&mut self, bus: &mut impl Bus, code: u8, idx: Index) -> (u8, u32) {
+        if code == 6 {
+            let addr = self.operand_addr(bus, idx);
+            (bus.read(addr), if idx == Index::HL { 3 } else { 11 })
+        } else {
+            (self.reg8(code, idx), 0)
+        }
+    }
+
+    fn rp(&self, p: u8, idx: Index) -> u16 {
+        match p {
+            0 => self.regs.bc(),
+            1 => self.regs.de(),
+            2 => self.index_reg(idx),
+            _ => self.regs.sp,
+        }
+    }
+
+    fn set_rp(&mut self, p: u8, idx: Index, val: u16) {
+        match p {
+            0 => self.regs.set_bc(val),
+            1 => self.regs.set_de(val),
+            2 => self.set_index_reg(idx, val),
+            _ => self.regs.sp = val,
+        }
+    }
+
+    fn rp2(&self, p: u8, idx: Index) -> u16 {
+        match p {
+            3 => self.regs.af(),
+            _ => self.rp(p, idx),
+        }
+    }
+
+    fn set_rp2(&mut self, p: u8, idx: Index, val: u16) {
+        match p {
+            3 => self.regs.set_af(val),
+            _ => self.set_rp(p, idx, val),
+        }
+    }
+
+    fn condition(&self, cc: u8) -> bool {
+        let f = self.regs.f;
+        match cc {
+            0 => f & FLAG_Z == 0,
+            1 => f & FLAG_Z != 0,
+            2 => f & FLAG_C == 0,
+            3 => f & FLAG_C != 0,
+            4 => f & FLAG_PV == 0,
+            5 => f & FLAG_PV != 0,
+            6 => f & FLAG_S == 0,
+            _ => f & FLAG_S != 0,
+        }
+    }
+
+    fn jump_relative(&mut self, d: u8) {
+        self.regs.pc = self.regs.pc.wrapping_add(d as i8 as u16);
+    }
+
+    fn exec_main(&mut self, op: u8, bus: &mut impl Bus, idx: Index) -> u32 {
+        let x = op >> 6;
+        let y = (op >> 3) & 7;
+        let z = op & 7;
+        let p = y >> 1;
+        let q = y & 1;
+        let prefix = if idx == Index::HL { 0 } else { 4 };
+
+        match (x, z) {
+            (0, 0) => match y {
+                0 => 4,
+                1 => {
+                    let af = self.regs.af();
+                    self.regs.set_af(self.regs.af_alt);
+                    self.regs.af_alt = af;
+                    4 + prefix
+                }
+                2 => {
+                    let d = self.fetch(bus);
+                    self.regs.b = self.regs.b.wrapping_sub(1);
+                    if self.regs.b != 0 {
+                        self.jump_relative(d);
+                        13 + prefix
+                    } else {
+                        8 + prefix
+                    }
+                }
+                3 => {
+                    let d = self.fetch(bus);
+                    self.jump_relative(d);
+                    12 + prefix
+                }
+                _ => {
+                    let d = self.fetch(bus);
+                    if self.condition(y - 4) {
+                        self.jump_relative(d);
+                        12 + prefix
+                    } else {
+                        7 + prefix
+                    }
+                }
+            },
+            (0, 1) => {
+                if q == 0 {
+                    let nn = self.fetch16(bus);
+                    self.set_rp(p, idx, nn);
+                    10 + prefix
+                } else {
+                    let (res, f) = add16(self.index_reg(idx), self.rp(p, idx), self.regs.f);
+                    self.set_index_reg(idx, res);
+                    self.regs.f = f;
+                    11 + prefix
+                }
+            }
+            (0, 2) => match (q, p) {
+                (0, 0) => {
+                    bus.write(self.regs.bc(), self.regs.a);
+                    7 + prefix
+                }
+                (0, 1) => {
+                    bus.write(self.regs.de(), self.regs.a);
+                    7 + prefix
+                }
+                (0, 2) => {
+                    let nn = self.fetch16(bus);
+                    self.write16(bus, nn, self.index_reg(idx));
+                    16 + prefix
+                }
+                (0, _) => {
+                    let nn = self.fetch16(bus);
+                    bus.write(nn, self.regs.a);
+                    13 + prefix
+                }
+                (_, 0) => {
+                    self.regs.a = bus.read(self.regs.bc());
+                    7 + prefix
+                }
+                (_, 1) => {
+                    self.regs.a = bus.read(self.regs.de());
+                    7 + prefix
+                }
+                (_, 2) => {
+                    let nn = self.fetch16(bus);
+                    let val = self.read16(bus, nn);
+                    self.set_index_reg(idx, val);
+                    16 + prefix
+                }
+                (_, _) => {
+                    let nn = self.fetch16(bus);
+                    self.regs.a = bus.read(nn);
+                    13 + prefix
+                }
+            },
+            (0, 3) => {
+                let val = self.rp(p, idx);
+                let val = if q == 0 {
+                    val.wrapping_add(1)
+                } else {
+                    val.wrapping_sub(1)
+                };
+                self.set_rp(p, idx, val);
+                6 + prefix
+            }
+            (0, 4) | (0, 5) => {
+                let f = if z == 4 { inc8 } else { dec8 };
+                if y == 6 {
+                    let addr = self.operand_addr(bus, idx);
+                    let (res, flags) = f(bus.read(addr), self.regs.f);
+                    bus.write(addr, res);
+                    self.regs.f = flags;
+                    11 + prefix + if idx == Index::HL { 0 } else { 8 }
+                } else {
+                    let (res, flags) = f(self.reg8(y, idx), self.regs.f);
+                    self.set_reg8(y, idx, res);
+                    self.regs.f = flags;
+                    4 + prefix
+                }
+            }
+            (0, 6) => {
+                if y == 6 {
+                    let addr = self.operand_addr(bus, idx);
+                    let n = self.fetch(bus);
+                    bus.write(addr, n);
+                    10 + prefix + if idx == Index::HL { 0 } else { 5 }
+                } else {
+                    let n = self.fetch(bus);
+                    self.set_reg8(y, idx, n);
+                    7 + prefix
+                }
+            }
+            (0, _) => {
+                let a = self.regs.a;
+                let f = self.regs.f;
+                let (res, flags) = match y {
+                    0..=3 => rot_a(y, a, f),
+                    4 => daa(a, f),
+                    5 => (
+                        !a,
+                        (f & (FLAG_S | FLAG_Z | FLAG_PV | FLAG_C))
+                            | (!a & (FLAG_X | FLAG_Y))
+                            | FLAG_H
+                            | FLAG_N,
+                    ),
+                    6 => (
+                        a,
+                        (f & (FLAG_S | FLAG_Z | FLAG_PV)) | (a & (FLAG_X | FLAG_Y)) | FLAG_C,
+                    ),
+                    _ => (
+                        a,
+                        (f & (FLAG_S | FLAG_Z | FLAG_PV))
+                            | (a & (FLAG_X | FLAG_Y))
+                            | if f & FLAG_C != 0 { FLAG_H } else { FLAG_C },
+                    ),
+                };
+                self.regs.a = res;
+                self.regs.f = flags;
+                4 + prefix
+            }
+            (1, _) => {
+                if y == 6 && z == 6 {
+                    self.halted = true;
+                    4 + prefix
+                } else if z == 6 {
+                    let addr = self.operand_addr(bus, idx);
+                    let val = bus.read(addr);
+                    self.set_reg8(y, Index::HL, val);
+                    7 + prefix + if idx == Index::HL { 0 } else { 8 }
+                } else if y == 6 {
+                    let addr = self.operand_addr(bus, idx);
+                    bus.write(addr, self.reg8(z, Index::HL));
+                    7 + prefix + if idx == Index::HL { 0 } else { 8 }
+                } else {
+                    let val = self.reg8(z, idx);
+                    self.set_reg8(y, idx, val);
+                    4 + prefix
+                }
+            }
+            (2, _) => {
+                let (val, extra) = self.read_operand(bus, z, idx);
+                let (res, flags) = alu(y, self.regs.a, val, self.regs.f);
+                self.regs.a = res;
+                self.regs.f = flags;
+                4 + prefix + extra
+            }
+            (_, 0) => {
+                if self.condition(y) {
+                    self.regs.pc = self.pop(bus);
+                    11 + prefix
+                } else {
+                    5 + prefix
+                }
+            }
+            (_, 1) => match (q, p) {
+                (0, _) => {
+                    let val = self.pop(bus);
+                    self.set_rp2(p, idx, val);
+                    10 + prefix
+                }
+                (_, 0) => {
+                    self.regs.pc = self.pop(bus);
+                    10 + prefix
+                }
+                (_, 1) => {
+                    let r = &mut self.regs;
+                    let (bc, de, hl) = (r.bc(), r.de(), r.hl());
+                    r.set_bc(r.bc_alt);
+                    r.set_de(r.de_alt);
+                    r.set_hl(r.hl_alt);
+                    (r.bc_alt, r.de_alt, r.hl_alt) = (bc, de, hl);
+                    4 + prefix
+                }
+                (_, 2) => {
+                    self.regs.pc = self.index_reg(idx);
+                    4 + prefix
+                }
+                (_, _) => {
+                    self.regs.sp = self.index_reg(idx);
+                    6 + prefix
+                }
+            },
+            (_, 2) => {
+                let nn = self.fetch16(bus);
+                if self.condition(y) {
+                    self.regs.pc = nn;
+                }
+                10 + prefix
+            }
+            (_, 3) => match y {
+                0 => {
+                    self.regs.pc = self.fetch16(bus);
+                    10 + prefix
+                }
+                1 => self.exec_cb(bus, idx) + prefix,
+                2 => {
+                    let n = self.fetch(bus);
+                    bus.output(pair(self.regs.a, n), self.regs.a);
+                    11 + prefix
+                }
+                3 => {
+                    let n = self.fetch(bus);
+                    self.regs.a = bus.input(pair(self.regs.a, n));
+                    11 + prefix
+                }
+                4 => {
+                    let val = self.read16(bus, self.regs.sp);
+                    self.write16(bus, self.regs.sp, self.index_reg(idx));
+                    self.set_index_reg(idx, val);
+                    19 + prefix
+                }
+                5 => {
+                    let r = &mut self.regs;
+                    (r.d, r.e, r.h, r.l) = (r.h, r.l, r.d, r.e);
+                    4 + prefix
+                }
+                6 => {
+                    self.regs.iff1 = false;
+                    self.regs.iff2 = false;
+                    4 + prefix
+                }
+                _ => {
+                    self.regs.iff1 = true;
+                    self.regs.iff2 = true;
+                    self.ei_delay = true;
+                    4 + prefix
+                }
+            },
+            (_, 4) => {
+                let nn = self.fetch16(bus);
+                if self.condition(y) {
+                    self.push(bus, self.regs.pc);
+                    self.regs.pc = nn;
+                    17 + prefix
+                } else {
+                    10 + prefix
+                }
+            }
+            (_, 5) => match (q, p) {
+                (0, _) => {
+                    self.push(bus, self.rp2(p, idx));
+                    11 + prefix
+                }
+                (_, 0) => {
+                    let nn = self.fetch16(bus);
+                    self.push(bus, self.regs.pc);
+                    self.regs.pc = nn;
+                    17 + prefix
+                }
+                (_, 1) => {
+                    let op = self.fetch_op(bus);
+                    self.exec_main(op, bus, Index::IX) + prefix
+                }
+                (_, 2) => {
+                    let op = self.fetch_op(bus);
+                    self.exec_ed(op, bus) + prefix
+                }
+                (_, _) => {
+                    let op = self.fetch_op(bus);
+                    self.exec_main(op, bus, Index::IY) + prefix
+                }
+            },
+            (_, 6) => {
+                let n = self.fetch(bus);
+                let (res, flags) = alu(y, self.regs.a, n, self.regs.f);
+                self.regs.a = res;
+                self.regs.f = flags;
+                7 + prefix
+            }
+            (_, _) => {
+                self.push(bus, self.regs.pc);
+                self.regs.pc = (y as u16) * 8;
+                11 + prefix
+            }
+        }
+    }
+
+    fn exec_cb(&mut self, bus: &mut impl Bus, idx: Index) -> u32 {
+        // DD CB d op: the displacement comes before the opcode.
+        let addr = if idx == Index::HL {
+            None
+        } else {
+            Some(self.operand_addr(bus, idx))
+        };
+        let op = if addr.is_none() {
+            self.fetch_op(bus)
+        } else {
+            self.fetch(bus)
+        };
+
+        let x = op >> 6;
+        let y = (op >> 3) & 7;
+        let z = op & 7;
+
+        let addr = match (addr, z) {
+            (None, 6) => Some(self.regs.hl()),
+            (a, _) => a,
+        };
+        let val = match addr {
+            Some(a) => bus.read(a),
+            None => self.reg8(z, Index::HL),
+        };
+
+        let res = match x {
+            0 => {
+                let (res, flags) = rot(y, val, self.regs.f);
+                self.regs.f = flags;
+                res
+            }
+            1 => {
+                self.regs.f = bit(y, val, self.regs.f);
+                return match (idx, z) {
+                    (Index::HL, 6) => 12,
+                    (Index::HL, _) => 8,
+                    _ => 16,
+                };
+            }
+            2 => val & !(1 << y),
+            _ => val | (1 << y),
+        };
+
+        if let Some(a) = addr {
+            bus.write(a, res);
+        }
+        // undocumented: indexed results are also copied to the register
+        if z != 6 {
+            self.set_reg8(z, Index::HL, res);
+        }
+
+        match (idx, z) {
+            (Index::HL, 6) => 15,
+            (Index::HL, _) => 8,
+            _ => 19,
+        }
+    }
+
+    fn exec_ed(&mut self, op: u8, bus: &mut impl Bus) -> u32 {
+        let x = op >> 6;
+        let y = (op >> 3) & 7;
+        let z = op & 7;
+        let p = y >> 1;
+        let q = y & 1;
+
+        match (x, z) {
+            (1, 0) => {
+                let val = bus.input(self.regs.bc());
+                if y != 6 {
+                    self.set_reg8(y, Index::HL, val);
+                }
+                self.regs.f = szp(val, self.regs.f);
+                12
+            }
+            (1, 1) => {
+                let val = if y == 6 { 0 } else { self.reg8(y, Index::HL) };
+                bus.output(self.regs.bc(), val);
+                12
+            }
+            (1, 2) => {
+                let hl = self.regs.hl();
+                let rr = self.rp(p, Index::HL);
+                let (res, flags) = if q == 0 {
+                    sbc16(hl, rr, self.regs.f)
+                } else {
+                    adc16(hl, rr, self.regs.f)
+                };
+                self.regs.set_hl(res);
+                self.regs.f = flags;
+                15
+            }
+            (1, 3) => {
+                let nn = self.fetch16(bus);
+                if q == 0 {
+                    self.write16(bus, nn, self.rp(p, Index::HL));
+                } else {
+                    let val = self.read16(bus, nn);
+                    self.set_rp(p, Index::HL, val);
+                }
+                20
+            }
+            (1, 4) => {
+                let (res, flags) = sub8(0, self.regs.a, false);
+                self.regs.a = res;
+                self.regs.f = flags;
+                8
+            }
+            (1, 5) => {
+                self.regs.pc = self.pop(bus);
+                self.regs.iff1 = self.regs.iff2;
+                14
+            }
+            (1, 6) => {
+                self.regs.im = match y & 3 {
+                    0 | 1 => 0,
+                    2 => 1,
+                    _ => 2,
+                };
+                8
+            }
+            (1, _) => match y {
+                0 => {
+                    self.regs.i = self.regs.a;
+                    9
+                }
+                1 => {
+                    self.regs.r = self.regs.a;
+                    9
+                }
+                2 | 3 => {
+                    let val = if y == 2 { self.regs.i } else { self.regs.r };
+                    self.regs.a = val;
+                    let mut f = sz(val, self.regs.f);
+                    if self.regs.iff2 {
+                        f |= FLAG_PV;
+                    }
+                    self.regs.f = f;
+                    9
+                }
+                4 | 5 => {
+                    let hl = self.regs.hl();
+                    let mem = bus.read(hl);
+                    let a = self.regs.a;
+                    let (new_a, new_mem) = if y == 4 {
+                        ((a & 0xF0) | (mem & 0x0F), (a << 4) | (mem >> 4))
+                    } else {
+                        ((a & 0xF0) | (mem >> 4), (mem << 4) | (a & 0x0F))
+                    };
+                    bus.write(hl, new_mem);
+                    self.regs.a = new_a;
+                    self.regs.f = szp(new_a, self.regs.f);
+                    18
+                }
+                _ => 8,
+            },
+            (2, 0..=3) if y >= 4 => self.exec_block(bus, y, z),
+            _ => 8,
+        }
+    }
+
+    fn exec_block(&mut self, bus: &mut impl Bus, y: u8, z: u8) -> u32 {
+        let increment = y & 1 == 0;
+        let repeat = y >= 6;
+        let step = |v: u16| {
+            if increment {
+                v.wrapping_add(1)
+            } else {
+                v.wrapping_sub(1)
+            }
+        };
+
+        let hl = self.regs.hl();
+        let again = match z {
+            0 => {
+                let val = bus.read(hl);
+                bus.write(self.regs.de(), val);
+                self.regs.set_hl(step(hl));
+                self.regs.set_de(step(self.regs.de()));
+                let bc = self.regs.bc().wrapping_sub(1);
+                self.regs.set_bc(bc);
+                let n = val.wrapping_add(self.regs.a);
+                let mut f = self.regs.f & (FLAG_S | FLAG_Z | FLAG_C);
+                f |= n & FLAG_X;
+                f |= (n << 4) & FLAG_Y;
+                if bc != 0 {
+                    f |= FLAG_PV;
+                }
+                self.regs.f = f;
+                bc != 0
+            }
+            1 => {
+                let val = bus.read(hl);
+                let (res, flags) = sub8(self.regs.a, val, false);
+                self.regs.set_hl(step(hl));
+                let bc = self.regs.bc().wrapping_sub(1);
+                self.regs.set_bc(bc);
+                let mut f = (flags & (FLAG_S | FLAG_Z | FLAG_H | FLAG_N)) | (self.regs.f & FLAG_C);
+                if bc != 0 {
+                    f |= FLAG_PV;
+                }
+                self.regs.f = f;
+                bc != 0 && res != 0
+            }
+            2 => {
+                let val = bus.input(self.regs.bc());
+                bus.write(hl, val);
+                self.regs.set_hl(step(hl));
+                self.regs.b = self.regs.b.wrapping_sub(1);
+                self.regs.f = sz(self.regs.b, self.regs.f) | FLAG_N;
+                self.regs.b != 0
+            }
+            _ => {
+                let val = bus.read(hl);
+                self.regs.b = self.regs.b.wrapping_sub(1);
+                bus.output(self.regs.bc(), val);
+                self.regs.set_hl(step(hl));
+                self.regs.f = sz(self.regs.b, self.regs.f) | FLAG_N;
+                self.regs.b != 0
+            }
+        };
+
+        if repeat && again {
+            self.regs.pc = self.regs.pc.wrapping_sub(2);
+            21
+        } else {
+            16
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bus::{Bus, Memory};
+    use crate::cpu::{Cpu, Flag, Register};
+
+    fn run(code: &[u8], steps: usize) -> (Cpu, Memory) {
+        let mut mem = Memory::new();
+        mem.load(0, code);
+        let mut cpu = Cpu::new();
+        cpu.regs.pc = 0;
+        for _ in 0..steps {
+            cpu.step(&mut mem);
+        }
+        (cpu, mem)
+    }
+
+    #[test]
+    fn load_and_add() {
+        // LD A, 10h ; LD B, 22h ; ADD A, B
+        let (cpu, _) = run(&[0x3E, 0x10, 0x06, 0x22, 0x80], 3);
+        assert_eq!(0x32, cpu.regs.a);
+        assert_eq!(18, cpu.cycles);
+        assert!(!cpu.regs.flag(Flag::Z));
+        assert!(!cpu.regs.flag(Flag::C));
+    }
+
+    #[test]
+    fn indexed_load_store() {
+        // LD IX, 1000h ; LD (IX+5h), 42h ; LD A, (IX+5h) ; INC (IX+5h)
+        let (cpu, mut mem) = run(
+            &[
+                0xDD, 0x21, 0x00, 0x10, 0xDD, 0x36, 0x05, 0x42, 0xDD, 0x7E, 0x05, 0xDD, 0x34, 0x05,
+            ],
+            4,
+        );
+        assert_eq!(0x42, cpu.regs.a);
+        assert_eq!(0x43, mem.read(0x1005));
+        assert_eq!(14 + 19 + 19 + 23, cpu.cycles);
+    }
+
+    #[test]
+    fn call_and_return() {
+        // LD SP, 8000h ; CALL 0008h ; HALT ; NOP ; NOP ; LD A, 7h ; RET
+        let (cpu, _) = run(
+            &[
+                0x31, 0x00, 0x80, 0xCD, 0x08, 0x00, 0x76, 0x00, 0x3E, 0x07, 0xC9,
+            ],
+            5,
+        );
+        assert_eq!(0x07, cpu.regs.a);
+        assert!(cpu.halted);
+        assert_eq!(0x8000, cpu.regs.get(Register::SP));
+    }
+
+    #[test]
+    fn djnz_loop() {
+        // LD B, 5h ; XOR A ; loop: INC A ; DJNZ loop
+        let (cpu, _) = run(&[0x06, 0x05, 0xAF, 0x3C, 0x10, 0xFD], 2 + 10);
+        assert_eq!(5, cpu.regs.a);
+        assert_eq!(0, cpu.regs.b);
+        assert_eq!(6, cpu.regs.pc);
+    }
+
+    #[test]
+    fn block_copy() {
+        // LD HL, 0100h ; LD DE, 0200h ; LD BC, 3h ; LDIR
+        let mut mem = Memory::new();
+        mem.load(
+            0,
+            &[
+                0x21, 0x00, 0x01, 0x11, 0x00, 0x02, 0x01, 0x03, 0x00, 0xED, 0xB0,
+            ],
+        );
+        mem.load(0x100, &[1, 2, 3]);
+        let mut cpu = Cpu::new();
+        cpu.regs.pc = 0;
+        while cpu.regs.pc != 11 {
+            cpu.step(&mut mem);
+        }
+        assert_eq!(vec![1, 2, 3], mem.slice(0x200, 3));
+        assert_eq!(0, cpu.regs.bc());
+        assert!(!cpu.regs.flag(Flag::PV));
+    }
+
+    #[test]
+    fn interrupt_mode_2() {
+        // IM 2 ; EI ; HALT
+        let mut mem = Memory::new();
+        mem.load(0, &[0xED, 0x5E, 0xFB, 0x76]);
+        mem.load(0x3010, &[0x00, 0x20]);
+        let mut cpu = Cpu::new();
+        cpu.regs.pc = 0;
+        cpu.regs.i = 0x30;
+        for _ in 0..4 {
+            cpu.step(&mut mem);
+        }
+        assert!(cpu.halted);
+        assert_eq!(Some(19), cpu.interrupt(&mut mem, 0x10));
+        assert!(!cpu.halted);
+        assert_eq!(0x2000, cpu.regs.pc);
+        assert_eq!(None, cpu.interrupt(&mut mem, 0x10));
+    }
+
+    #[test]
+    fn io_ports() {
+        // LD A, 12h ; OUT (34h), A ; IN A, (35h)
+        let mut mem = Memory::new();
+        mem.load(0, &[0x3E, 0x12, 0xD3, 0x34, 0xDB, 0x35]);
+        mem.queue_input(0x35, &[0x99]);
+        let mut cpu = Cpu::new();
+        cpu.regs.pc = 0;
+        for _ in 0..3 {
+            cpu.step(&mut mem);
+        }
+        assert_eq!(vec![(0x1234, 0x12)], mem.outputs);
+        assert_eq!(0x99, cpu.regs.a);
+    }
+}
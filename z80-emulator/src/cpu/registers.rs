@@ -0,0 +1,288 @@
+pub const FLAG_C: u8 = 0b0000_0001;
+pub const FLAG_N: u8 = 0b0000_0010;
+pub const FLAG_PV: u8 = 0b0000_0100;
+pub const FLAG_X: u8 = 0b0000_1000;
+pub const FLAG_H: u8 = 0b0001_0000;
+pub const FLAG_Y: u8 = 0b0010_0000;
+pub const FLAG_Z: u8 = 0b0100_0000;
+pub const FLAG_S: u8 = 0b1000_0000;
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Registers {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub af_alt: u16,
+    pub bc_alt: u16,
+    pub de_alt: u16,
+    pub hl_alt: u16,
+    pub ix: u16,
+    pub iy: u16,
+    pub sp: u16,
+    pub pc: u16,
+    pub i: u8,
+    pub r: u8,
+    pub iff1: bool,
+    pub iff2: bool,
+    pub im: u8,
+}
+
+impl Registers {
+    pub fn af(&self) -> u16 {
+        pair(self.a, self.f)
+    }
+
+    pub fn bc(&self) -> u16 {
+        pair(self.b, self.c)
+    }
+
+    pub fn de(&self) -> u16 {
+        pair(self.d, self.e)
+    }
+
+    pub fn hl(&self) -> u16 {
+        pair(self.h, self.l)
+    }
+
+    pub fn set_af(&mut self, val: u16) {
+        (self.a, self.f) = split(val);
+    }
+
+    pub fn set_bc(&mut self, val: u16) {
+        (self.b, self.c) = split(val);
+    }
+
+    pub fn set_de(&mut self, val: u16) {
+        (self.d, self.e) = split(val);
+    }
+
+    pub fn set_hl(&mut self, val: u16) {
+        (self.h, self.l) = split(val);
+    }
+
+    pub fn flag(&self, flag: Flag) -> bool {
+        self.f & flag.mask() != 0
+    }
+
+    pub fn set_flag(&mut self, flag: Flag, val: bool) {
+        if val {
+            self.f |= flag.mask();
+        } else {
+            self.f &= !flag.mask();
+        }
+    }
+
+    pub fn get(&self, reg: Register) -> u16 {
+        match reg {
+            Register::A => self.a as u16,
+            Register::F => self.f as u16,
+            Register::B => self.b as u16,
+            Register::C => self.c as u16,
+            Register::D => self.d as u16,
+            Register::E => self.e as u16,
+            Register::H => self.h as u16,
+            Register::L => self.l as u16,
+            Register::I => self.i as u16,
+            Register::R => self.r as u16,
+            Register::AF => self.af(),
+            Register::BC => self.bc(),
+            Register::DE => self.de(),
+            Register::HL => self.hl(),
+            Register::AFp => self.af_alt,
+            Register::BCp => self.bc_alt,
+            Register::DEp => self.de_alt,
+            Register::HLp => self.hl_alt,
+            Register::IX => self.ix,
+            Register::IY => self.iy,
+            Register::SP => self.sp,
+            Register::PC => self.pc,
+        }
+    }
+
+    pub fn set(&mut self, reg: Register, val: u16) {
+        match reg {
+            Register::A => self.a = val as u8,
+            Register::F => self.f = val as u8,
+            Register::B => self.b = val as u8,
+            Register::C => self.c = val as u8,
+            Register::D => self.d = val as u8,
+            Register::E => self.e = val as u8,
+            Register::H => self.h = val as u8,
+            Register::L => self.l = val as u8,
+            Register::I => self.i = val as u8,
+            Register::R => self.r = val as u8,
+            Register::AF => self.set_af(val),
+            Register::BC => self.set_bc(val),
+            Register::DE => self.set_de(val),
+            Register::HL => self.set_hl(val),
+            Register::AFp => self.af_alt = val,
+            Register::BCp => self.bc_alt = val,
+            Register::DEp => self.de_alt = val,
+            Register::HLp => self.hl_alt = val,
+            Register::IX => self.ix = val,
+            Register::IY => self.iy = val,
+            Register::SP => self.sp = val,
+            Register::PC => self.pc = val,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Register {
+    A,
+    F,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    I,
+    R,
+    AF,
+    BC,
+    DE,
+    HL,
+    AFp,
+    BCp,
+    DEp,
+    HLp,
+    IX,
+    IY,
+    SP,
+    PC,
+}
+
+impl Register {
+    pub const ALL: [Register; 22] = [
+        Register::A,
+        Register::F,
+        Register::B,
+        Register::C,
+        Register::D,
+        Register::E,
+        Register::H,
+        Register::L,
+        Register::I,
+        Register::R,
+        Register::AF,
+        Register::BC,
+        Register::DE,
+        Register::HL,
+        Register::AFp,
+        Register::BCp,
+        Register::DEp,
+        Register::HLp,
+        Register::IX,
+        Register::IY,
+        Register::SP,
+        Register::PC,
+    ];
+
+    /// Uses the assembler spelling, `AFp` is the alternate `AF'`.
+    pub fn parse(name: &str) -> Option<Register> {
+        Register::ALL
+            .iter()
+            .find(|r| r.name().eq_ignore_ascii_case(name))
+            .copied()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Register::A => "A",
+            Register::F => "F",
+            Register::B => "B",
+            Register::C => "C",
+            Register::D => "D",
+            Register::E => "E",
+            Register::H => "H",
+            Register::L => "L",
+            Register::I => "I",
+            Register::R => "R",
+            Register::AF => "AF",
+            Register::BC => "BC",
+            Register::DE => "DE",
+            Register::HL => "HL",
+            Register::AFp => "AFp",
+            Register::BCp => "BCp",
+            Register::DEp => "DEp",
+            Register::HLp => "HLp",
+            Register::IX => "IX",
+            Register::IY => "IY",
+            Register::SP => "SP",
+            Register::PC => "PC",
+        }
+    }
+
+    pub fn is_wide(&self) -> bool {
+        !matches!(
+            self,
+            Register::A
+                | Register::F
+                | Register::B
+                | Register::C
+                | Register::D
+                | Register::E
+                | Register::H
+                | Register::L
+                | Register::I
+                | Register::R
+        )
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Flag {
+    S,
+    Z,
+    H,
+    PV,
+    N,
+    C,
+}
+
+impl Flag {
+    pub const ALL: [Flag; 6] = [Flag::S, Flag::Z, Flag::H, Flag::PV, Flag::N, Flag::C];
+
+    pub fn parse(name: &str) -> Option<Flag> {
+        Flag::ALL
+            .iter()
+            .find(|f| f.name().eq_ignore_ascii_case(name))
+            .copied()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Flag::S => "S",
+            Flag::Z => "Z",
+            Flag::H => "H",
+            Flag::PV => "PV",
+            Flag::N => "N",
+            Flag::C => "C",
+        }
+    }
+
+    pub fn mask(&self) -> u8 {
+        match self {
+            Flag::S => FLAG_S,
+            Flag::Z => FLAG_Z,
+            Flag::H => FLAG_H,
+            Flag::PV => FLAG_PV,
+            Flag::N => FLAG_N,
+            Flag::C => FLAG_C,
+        }
+    }
+}
+
+pub fn pair(high: u8, low: u8) -> u16 {
+    ((high as u16) << 8) | low as u16
+}
+
+pub fn split(val: u16) -> (u8, u8) {
+    ((val >> 8) as u8, val as u8)
+}
@@ -0,0 +1,403 @@
+use crate::bus::{Access, Bus};
+use crate::cpu::{Flag, Register};
+use crate::debugger::{Debugger, Stop, WatchMode, WatchTarget};
+use std::fmt::Write;
+
+const HELP: &str = "\
+s, step [n]               execute n instructions
+n, next                   step over calls
+fin, finish               run until the current subroutine returns
+c, continue               run until a breakpoint or watchpoint
+rs, rstep [n]             undo the last n instructions
+b, break <loc>            break at a label, address or file:line
+watch [io] <range> [r|w|rw]
+                          stop on memory (or port) accesses, range is
+                          <addr>, <addr>-<end> or <addr>+<len>
+bl                        list breakpoints and watchpoints
+d, delete [id]            delete one or all breakpoints and watchpoints
+r, regs                   show registers and flags
+set <reg> <value>         change a register
+set (<addr>) <bytes>...   change memory
+x <addr> [len]            hexdump memory
+dis [addr] [n]            disassemble n instructions
+where                     show the current instruction
+int [data], nmi           request an interrupt
+q, quit                   exit
+";
+
+type CommandResult = Result<String, String>;
+
+impl Debugger {
+    /// Runs a single debugger command and returns its output.
+    pub fn execute(&mut self, line: &str) -> String {
+        let args = line.split_whitespace().collect::<Vec<_>>();
+        let res = match args.as_slice() {
+            [] => Ok(String::new()),
+            [cmd, args @ ..] => self.command(cmd, args),
+        };
+        match res {
+            Ok(out) => out,
+            Err(e) => format!("error: {}\n", e),
+        }
+    }
+
+    fn command(&mut self, cmd: &str, args: &[&str]) -> CommandResult {
+        match (cmd, args) {
+            ("s" | "step", _) => {
+                let count = self.optional_value(args.first(), 1)? as u64;
+                let stop = self.step(count.max(1));
+                Ok(self.report(stop))
+            }
+            ("n" | "next", []) => {
+                let stop = self.step_over();
+                Ok(self.report(stop))
+            }
+            ("fin" | "finish", []) => {
+                let stop = self.finish();
+                Ok(self.report(stop))
+            }
+            ("c" | "continue", []) => {
+                let stop = self.cont();
+                Ok(self.report(stop))
+            }
+            ("rs" | "rstep", _) => {
+                let count = self.optional_value(args.first(), 1)? as usize;
+                let undone = self.reverse_step(count);
+                if undone == 0 {
+                    return Err("no history to go back to".to_string());
+                }
+                Ok(format!(
+                    "went back {} instructions\n{}",
+                    undone,
+                    self.where_()
+                ))
+            }
+            ("b" | "break", []) | ("bl", []) => Ok(self.list_breakpoints()),
+            ("b" | "break", [loc]) => {
+                let addr = self.resolve(loc)?;
+                let id = self.add_breakpoint(addr);
+                Ok(format!("breakpoint {} at {}\n", id, self.describe(addr)))
+            }
+            ("watch", _) => self.watch(args),
+            ("d" | "delete", []) => {
+                self.breakpoints.clear();
+                self.watchpoints.clear();
+                Ok(String::new())
+            }
+            ("d" | "delete", [id]) => {
+                let id = id.parse::<usize>().map_err(|_| "invalid id".to_string())?;
+                if self.delete(id) {
+                    Ok(String::new())
+                } else {
+                    Err(format!("no breakpoint or watchpoint {}", id))
+                }
+            }
+            ("r" | "regs", []) => Ok(self.registers()),
+            ("set", [target, values @ ..]) if !values.is_empty() => self.set(target, values),
+            ("x", [addr]) => Ok(self.hexdump(self.resolve(addr)?, 64)),
+            ("x", [addr, len]) => Ok(self.hexdump(self.resolve(addr)?, self.value(len)? as usize)),
+            ("dis", _) if args.len() <= 2 => {
+                let addr = match args.first() {
+                    Some(a) => self.resolve(a)?,
+                    None => self.cpu.regs.pc,
+                };
+                let count = self.optional_value(args.get(1), 10)?;
+                Ok(self.disassembly(addr, count as usize))
+            }
+            ("where", []) => Ok(self.where_()),
+            ("int", _) if args.len() <= 1 => {
+                let data = self.optional_value(args.first(), 0xFF)? as u8;
+                match self.cpu.interrupt(&mut self.mem, data) {
+                    Some(_) => Ok(self.where_()),
+                    None => Err("interrupts are disabled".to_string()),
+                }
+            }
+            ("nmi", []) => {
+                self.cpu.nmi(&mut self.mem);
+                Ok(self.where_())
+            }
+            ("h" | "help", _) => Ok(HELP.to_string()),
+            _ => Err(format!("unknown command '{}', try 'help'", cmd)),
+        }
+    }
+
+    fn report(&self, stop: Stop) -> String {
+        let reason = match stop {
+            Stop::Done => String::new(),
+            Stop::Breakpoint(id) => format!("breakpoint {}\n", id),
+            Stop::Watchpoint(id, access) => format!(
+                "watchpoint {}: {}\n",
+                id,
+                match access {
+                    Access::Read(addr, val) => format!("read {:02X}h from {:04X}h", val, addr),
+                    Access::Write { addr, val, old } =>
+                        format!("write {:02X}h to {:04X}h (was {:02X}h)", val, addr, old),
+                    Access::In(port, val) =>
+                        format!("in {:02X}h from port {:02X}h", val, port & 0xFF),
+                    Access::Out(port, val) =>
+                        format!("out {:02X}h to port {:02X}h", val, port & 0xFF),
+                }
+            ),
+            Stop::Halted => "halted with interrupts disabled\n".to_string(),
+            Stop::Returned => "entry routine returned\n".to_string(),
+            Stop::StepLimit => format!("stopped after {} instructions\n", self.step_limit),
+        };
+        format!("{}{}", reason, self.where_())
+    }
+
+    /// Current instruction with its label, source line and the macros it
+    /// was expanded from.
+    fn where_(&self) -> String {
+        let pc = self.cpu.regs.pc;
+        let d = self.decode(pc);
+        let mut out = format!("{:04X}h", pc);
+        if let Some(sym) = self.symbolize(pc) {
+            write!(out, " {}", sym).unwrap();
+        }
+        if let Some(loc) = self.source_location(pc) {
+            write!(out, " {}", loc).unwrap();
+        }
+        writeln!(out, ": {}", d.text).unwrap();
+        for m in self.macro_stack(pc) {
+            writeln!(out, "    in macro {}", m).unwrap();
+        }
+        out
+    }
+
+    fn describe(&self, addr: u16) -> String {
+        let mut out = format!("{:04X}h", addr);
+        let extra = [self.symbolize(addr), self.source_location(addr)]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+        if !extra.is_empty() {
+            write!(out, " ({})", extra.join(", ")).unwrap();
+        }
+        out
+    }
+
+    fn list_breakpoints(&self) -> String {
+        let mut out = String::new();
+        for b in self.breakpoints.iter() {
+            writeln!(out, "{}: break {}", b.id, self.describe(b.addr)).unwrap();
+        }
+        for w in self.watchpoints.iter() {
+            writeln!(
+                out,
+                "{}: watch {}{:04X}h-{:04X}h {}",
+                w.id,
+                if w.target == WatchTarget::Io {
+                    "io "
+                } else {
+                    ""
+                },
+                w.start,
+                w.end,
+                match w.mode {
+                    WatchMode::Read => "r",
+                    WatchMode::Write => "w",
+                    WatchMode::ReadWrite => "rw",
+                }
+            )
+            .unwrap();
+        }
+        if out.is_empty() {
+            out.push_str("no breakpoints or watchpoints\n");
+        }
+        out
+    }
+
+    fn watch(&mut self, args: &[&str]) -> CommandResult {
+        let (target, args) = match args {
+            ["io", rest @ ..] => (WatchTarget::Io, rest),
+            _ => (WatchTarget::Memory, args),
+        };
+        let (range, mode) = match args {
+            [range] => (range, WatchMode::Write),
+            [range, "r"] => (range, WatchMode::Read),
+            [range, "w"] => (range, WatchMode::Write),
+            [range, "rw"] => (range, WatchMode::ReadWrite),
+            _ => return Err("usage: watch [io] <range> [r|w|rw]".to_string()),
+        };
+
+        let (start, end) = if let Some((start, end)) = range.split_once('-') {
+            (self.resolve(start)?, self.resolve(end)?)
+        } else if let Some((start, len)) = range.split_once('+') {
+            let start = self.resolve(start)?;
+            let len = self.value(len)?.max(1);
+            (start, start.wrapping_add(len - 1))
+        } else {
+            let addr = self.resolve(range)?;
+            (addr, addr)
+        };
+        if end < start {
+            return Err("range end is before its start".to_string());
+        }
+
+        let id = self.add_watchpoint(target, start, end, mode);
+        Ok(format!(
+            "watchpoint {} on {:04X}h-{:04X}h\n",
+            id, start, end
+        ))
+    }
+
+    fn registers(&self) -> String {
+        let r = &self.cpu.regs;
+        let mut out = String::new();
+        for row in [
+            [Register::AF, Register::BC, Register::DE, Register::HL],
+            [Register::AFp, Register::BCp, Register::DEp, Register::HLp],
+            [Register::IX, Register::IY, Register::SP, Register::PC],
+        ] {
+            let line = row
+                .iter()
+                .map(|reg| format!("{:<3} {:04X}h", reg.name(), r.get(*reg)))
+                .collect::<Vec<_>>();
+            writeln!(out, "{}", line.join("  ")).unwrap();
+        }
+        writeln!(
+            out,
+            "I   {:02X}h    R   {:02X}h    IM  {}      IFF {}{}",
+            r.i, r.r, r.im, r.iff1 as u8, r.iff2 as u8
+        )
+        .unwrap();
+        let flags = Flag::ALL
+            .iter()
+            .map(|f| format!("{}={}", f.name(), r.flag(*f) as u8))
+            .collect::<Vec<_>>();
+        writeln!(out, "{}  cycles {}", flags.join(" "), self.cpu.cycles).unwrap();
+        out
+    }
+
+    fn set(&mut self, target: &str, values: &[&str]) -> CommandResult {
+        if let Some(addr) = target.strip_prefix('(').and_then(|t| t.strip_suffix(')')) {
+            let addr = self.resolve(addr)?;
+            for (i, v) in values.iter().enumerate() {
+                let v = self.value(v)?;
+                self.mem.write(addr.wrapping_add(i as u16), v as u8);
+            }
+            return Ok(String::new());
+        }
+
+        let reg = Register::parse(target).ok_or(format!("unknown register '{}'", target))?;
+        match values {
+            [v] => {
+                let v = self.value(v)?;
+                self.cpu.regs.set(reg, v);
+                Ok(String::new())
+            }
+            _ => Err("expected a single value".to_string()),
+        }
+    }
+
+    fn hexdump(&self, addr: u16, len: usize) -> String {
+        let mut out = String::new();
+        let data = self.mem.slice(addr, len);
+        for (i, row) in data.chunks(16).enumerate() {
+            let hex = row
+                .iter()
+                .map(|b| format!("{:02X}", b))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let ascii = row
+                .iter()
+                .map(|b| {
+                    if b.is_ascii_graphic() || *b == b' ' {
+                        *b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect::<String>();
+            writeln!(
+                out,
+                "{:04X}h  {:<47}  |{}|",
+                addr.wrapping_add(i as u16 * 16),
+                hex,
+                ascii
+            )
+            .unwrap();
+        }
+        out
+    }
+
+    fn disassembly(&self, addr: u16, count: usize) -> String {
+        let mut out = String::new();
+        let mut addr = addr;
+        for _ in 0..count {
+            if let Some(label) = self.symbols.get(&addr) {
+                writeln!(out, ".{}:", label).unwrap();
+            }
+            let d = self.decode(addr);
+            let marker = match (
+                addr == self.cpu.regs.pc,
+                self.breakpoints.iter().any(|b| b.addr == addr),
+            ) {
+                (true, _) => "=>",
+                (false, true) => " *",
+                _ => "  ",
+            };
+            let bytes = d
+                .bytes
+                .iter()
+                .map(|b| format!("{:02X}", b))
+                .collect::<Vec<_>>()
+                .join(" ");
+            write!(out, "{} {:04X}h  {:<12} {}", marker, addr, bytes, d.text).unwrap();
+            if let Some(loc) = self.source_location(addr) {
+                write!(out, "  ; {}", loc).unwrap();
+            }
+            out.push('\n');
+            addr = addr.wrapping_add(d.bytes.len() as u16);
+        }
+        out
+    }
+
+    /// Turns a breakpoint location into an address: `label`, `&label`,
+    /// `file:line` or a number.
+    fn resolve(&self, loc: &str) -> Result<u16, String> {
+        if let Some((file, line)) = loc.rsplit_once(':') {
+            let line = line
+                .parse::<usize>()
+                .map_err(|_| format!("invalid line '{}'", line))?;
+            return self
+                .debug_info
+                .addresses(file, line)
+                .first()
+                .map(|a| *a as u16)
+                .ok_or(format!("no code at {}:{}", file, line));
+        }
+        self.value(loc)
+    }
+
+    /// Labels win over numbers so `beefh` can still name a label.
+    fn value(&self, val: &str) -> Result<u16, String> {
+        let name = val.strip_prefix('&').unwrap_or(val);
+        if let Some(addr) = self.labels.get(name) {
+            return Ok(*addr);
+        }
+        if val.starts_with('&') {
+            return Err(format!("label '{}' not found", name));
+        }
+        parse_number(val).ok_or(format!("invalid value '{}'", val))
+    }
+
+    fn optional_value(&self, val: Option<&&str>, default: u16) -> Result<u16, String> {
+        match val {
+            Some(v) => self.value(v),
+            None => Ok(default),
+        }
+    }
+}
+
+/// `1234h` and `0x1234` are hex, anything else decimal.
+fn parse_number(val: &str) -> Option<u16> {
+    let lower = val.to_lowercase();
+    if let Some(hex) = lower.strip_suffix('h') {
+        u16::from_str_radix(hex, 16).ok()
+    } else if let Some(hex) = lower.strip_prefix("0x") {
+        u16::from_str_radix(hex, 16).ok()
+    } else {
+        lower.parse::<u16>().ok()
+    }
+}
@@ -0,0 +1,557 @@
+use crate::bus::{Access, Bus, Memory, Recorder};
+use crate::cpu::Cpu;
+use crate::disasm::{disassemble, is_call, is_return, Disassembly};
+use crate::harness::{load_program, RETURN_ADDRESS};
+use crate::trace::symbols;
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, Write};
+use z80_assembler::{DebugInfo, EntryKind, Program};
+
+mod commands;
+
+pub const DEFAULT_HISTORY: usize = 10_000;
+/// Instructions a single `continue`/`next`/`finish` may execute before
+/// control goes back to the prompt.
+pub const DEFAULT_STEP_LIMIT: u64 = 10_000_000;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Breakpoint {
+    pub id: usize,
+    pub addr: u16,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum WatchMode {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum WatchTarget {
+    Memory,
+    /// Matches on the low byte of the port address, like the hardware does.
+    Io,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Watchpoint {
+    pub id: usize,
+    pub target: WatchTarget,
+    pub start: u16,
+    pub end: u16,
+    pub mode: WatchMode,
+}
+
+impl Watchpoint {
+    fn matches(&self, access: &Access, pc: u16, len: u16) -> bool {
+        let (target, addr, write) = match *access {
+            Access::Read(addr, _) => {
+                // fetching the instruction itself is not a data read
+                if addr.wrapping_sub(pc) < len {
+                    return false;
+                }
+                (WatchTarget::Memory, addr, false)
+            }
+            Access::Write { addr, .. } => (WatchTarget::Memory, addr, true),
+            Access::In(port, _) => (WatchTarget::Io, port & 0xFF, false),
+            Access::Out(port, _) => (WatchTarget::Io, port & 0xFF, true),
+        };
+
+        target == self.target
+            && addr >= self.start
+            && addr <= self.end
+            && match self.mode {
+                WatchMode::Read => !write,
+                WatchMode::Write => write,
+                WatchMode::ReadWrite => true,
+            }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Stop {
+    /// The requested number of instructions was executed.
+    Done,
+    Breakpoint(usize),
+    Watchpoint(usize, Access),
+    /// `HALT` with interrupts disabled, nothing can wake the CPU up.
+    Halted,
+    /// The entry routine returned to `RETURN_ADDRESS`.
+    Returned,
+    StepLimit,
+}
+
+/// State before an instruction ran, with the old value of every byte it
+/// overwrote, enough to undo it.
+struct Snapshot {
+    cpu: Cpu,
+    writes: Vec<(u16, u8)>,
+}
+
+pub struct Debugger {
+    pub cpu: Cpu,
+    pub mem: Memory,
+    pub labels: HashMap<String, u16>,
+    pub debug_info: DebugInfo,
+    pub breakpoints: Vec<Breakpoint>,
+    pub watchpoints: Vec<Watchpoint>,
+    pub history_limit: usize,
+    pub step_limit: u64,
+    symbols: HashMap<u16, String>,
+    history: VecDeque<Snapshot>,
+    next_id: usize,
+    last_command: String,
+}
+
+impl Debugger {
+    /// See `load_program`.
+    pub fn new(program: &Program, entry: Option<u16>) -> Self {
+        let (cpu, mem) = load_program(program, entry);
+
+        let labels = program
+            .labels
+            .iter()
+            .map(|(name, addr)| (name.clone(), *addr as u16))
+            .collect::<HashMap<_, _>>();
+
+        Debugger {
+            cpu,
+            mem,
+            labels,
+            debug_info: program.debug_info.clone(),
+            breakpoints: vec![],
+            watchpoints: vec![],
+            history_limit: DEFAULT_HISTORY,
+            step_limit: DEFAULT_STEP_LIMIT,
+            symbols: symbols(program),
+            history: VecDeque::new(),
+            next_id: 1,
+            last_command: String::new(),
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) -> usize {
+        let id = self.new_id();
+        self.breakpoints.push(Breakpoint { id, addr });
+        id
+    }
+
+    pub fn add_watchpoint(
+        &mut self,
+        target: WatchTarget,
+        start: u16,
+        end: u16,
+        mode: WatchMode,
+    ) -> usize {
+        let id = self.new_id();
+        self.watchpoints.push(Watchpoint {
+            id,
+            target,
+            start,
+            end,
+            mode,
+        });
+        id
+    }
+
+    /// Removes a breakpoint or watchpoint, they share the id space.
+    pub fn delete(&mut self, id: usize) -> bool {
+        let count = self.breakpoints.len() + self.watchpoints.len();
+        self.breakpoints.retain(|b| b.id != id);
+        self.watchpoints.retain(|w| w.id != id);
+        count != self.breakpoints.len() + self.watchpoints.len()
+    }
+
+    fn new_id(&mut self) -> usize {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    /// Executes one instruction, returning the first watchpoint it hit.
+    fn step_one(&mut self) -> Option<Stop> {
+        let pc = self.cpu.regs.pc;
+        let len = disassemble(&self.mem.ram, pc, &self.symbols).bytes.len() as u16;
+        let before = self.cpu.clone();
+
+        let mut rec = Recorder::new(&mut self.mem);
+        self.cpu.step(&mut rec);
+        let accesses = rec.accesses;
+
+        self.history.push_back(Snapshot {
+            cpu: before,
+            writes: accesses
+                .iter()
+                .filter_map(|a| match a {
+                    Access::Write { addr, old, .. } => Some((*addr, *old)),
+                    _ => None,
+                })
+                .collect(),
+        });
+        while self.history.len() > self.history_limit {
+            self.history.pop_front();
+        }
+
+        for a in accesses.iter() {
+            if let Some(w) = self.watchpoints.iter().find(|w| w.matches(a, pc, len)) {
+                return Some(Stop::Watchpoint(w.id, *a));
+            }
+        }
+        None
+    }
+
+    /// Runs until `done` returns true after an instruction, a breakpoint is
+    /// reached or something else stops execution. `done` gets the PC the
+    /// instruction was fetched from.
+    fn run(&mut self, max: u64, mut done: impl FnMut(&Self, u16) -> bool) -> Stop {
+        for i in 0..max {
+            if self.cpu.regs.pc == RETURN_ADDRESS {
+                return Stop::Returned;
+            }
+            if self.cpu.halted && !self.cpu.regs.iff1 {
+                return Stop::Halted;
+            }
+            if i > 0 {
+                if let Some(b) = self.breakpoints.iter().find(|b| b.addr == self.cpu.regs.pc) {
+                    return Stop::Breakpoint(b.id);
+                }
+            }
+
+            let pc = self.cpu.regs.pc;
+            if let Some(stop) = self.step_one() {
+                return stop;
+            }
+            if done(self, pc) {
+                return Stop::Done;
+            }
+        }
+        Stop::StepLimit
+    }
+
+    pub fn step(&mut self, count: u64) -> Stop {
+        let mut n = 0;
+        self.run(count, |_, _| {
+            n += 1;
+            n == count
+        })
+    }
+
+    /// Like `step` but runs called subroutines (`CALL`, `RST`) to completion.
+    pub fn step_over(&mut self) -> Stop {
+        let pc = self.cpu.regs.pc;
+        if !is_call(&self.mem.ram, pc) {
+            return self.step(1);
+        }
+
+        let len = disassemble(&self.mem.ram, pc, &self.symbols).bytes.len() as u16;
+        let ret = pc.wrapping_add(len);
+        let sp = self.cpu.regs.sp;
+        // the SP check keeps recursive calls from stopping early
+        self.run(self.step_limit, |d, _| {
+            d.cpu.regs.pc == ret && d.cpu.regs.sp >= sp
+        })
+    }
+
+    /// Runs until the current subroutine returns.
+    pub fn finish(&mut self) -> Stop {
+        let sp = self.cpu.regs.sp;
+        // returns don't write memory, the opcode at `pc` is still the one
+        // that just ran
+        self.run(self.step_limit, |d, pc| {
+            d.cpu.regs.sp > sp && is_return(&d.mem.ram, pc)
+        })
+    }
+
+    pub fn cont(&mut self) -> Stop {
+        self.run(self.step_limit, |_, _| false)
+    }
+
+    /// Undoes up to `count` instructions, returns how many were undone.
+    /// Port writes cannot be taken back.
+    pub fn reverse_step(&mut self, count: usize) -> usize {
+        for i in 0..count {
+            match self.history.pop_back() {
+                Some(s) => {
+                    for (addr, old) in s.writes.iter().rev() {
+                        self.mem.write(*addr, *old);
+                    }
+                    self.cpu = s.cpu;
+                }
+                None => return i,
+            }
+        }
+        count
+    }
+
+    pub fn history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    /// Nearest label at or before `addr`, formatted as `&label+offset`.
+    pub fn symbolize(&self, addr: u16) -> Option<String> {
+        self.symbols
+            .iter()
+            .filter(|(a, _)| **a <= addr)
+            .max_by_key(|(a, name)| (**a, std::cmp::Reverse((*name).clone())))
+            .map(|(a, name)| {
+                if *a == addr {
+                    format!("&{}", name)
+                } else {
+                    format!("&{}+{:X}h", name, addr - a)
+                }
+            })
+    }
+
+    /// `file:line:column` of the code at `addr`.
+    pub fn source_location(&self, addr: u16) -> Option<String> {
+        let entry = self.debug_info.location(addr as usize)?;
+        Some(format!(
+            "{}:{}:{}",
+            self.file_name(entry.file_id)?,
+            entry.line,
+            entry.column
+        ))
+    }
+
+    /// Macro invocations the code at `addr` was expanded from, innermost
+    /// first like a backtrace.
+    pub fn macro_stack(&self, addr: u16) -> Vec<String> {
+        let entry = match self.debug_info.location(addr as usize) {
+            Some(e) => e,
+            None => return vec![],
+        };
+        entry
+            .macro_stack
+            .iter()
+            .rev()
+            .map(|m| {
+                format!(
+                    "{} from {}:{}:{}",
+                    m.name,
+                    self.file_name(m.file_id).unwrap_or_default(),
+                    m.line,
+                    m.column
+                )
+            })
+            .collect()
+    }
+
+    fn file_name(&self, file_id: usize) -> Option<String> {
+        let file = self.debug_info.filename(file_id)?;
+        Some(
+            std::path::Path::new(file)
+                .file_name()
+                .map(|f| f.to_string_lossy().to_string())
+                .unwrap_or_else(|| file.to_string()),
+        )
+    }
+
+    /// Disassembles `addr`, bytes the assembler emitted as data are shown
+    /// as such.
+    pub fn decode(&self, addr: u16) -> Disassembly {
+        match self.debug_info.location(addr as usize) {
+            Some(e) if e.kind == EntryKind::Data => {
+                let bytes = self.mem.slice(addr, e.addr + e.len - addr as usize);
+                Disassembly {
+                    addr,
+                    text: bytes
+                        .iter()
+                        .map(|b| format!("{:02X}h", b))
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                    bytes,
+                }
+            }
+            _ => disassemble(&self.mem.ram, addr, &self.symbols),
+        }
+    }
+
+    /// Reads commands until `quit` or the end of the input, an empty line
+    /// repeats the previous command.
+    pub fn repl(&mut self, input: impl BufRead, mut output: impl Write) -> std::io::Result<()> {
+        write!(output, "{}", self.execute("where"))?;
+        write!(output, "(z80dbg) ")?;
+        output.flush()?;
+
+        for line in input.lines() {
+            let line = line?;
+            let line = if line.trim().is_empty() {
+                self.last_command.clone()
+            } else {
+                self.last_command = line.trim().to_string();
+                line
+            };
+
+            if matches!(line.trim(), "q" | "quit") {
+                return Ok(());
+            }
+            write!(output, "{}", self.execute(&line))?;
+            write!(output, "(z80dbg) ")?;
+            output.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::Register;
+    use crate::debugger::{Debugger, Stop, WatchMode, WatchTarget};
+    use z80_assembler::{Compiler, InMemorySourceProvider, Program, SourceHeader};
+
+    const SOURCE: &str = r#"
+@buffer: 8000h
+.main:      LD   HL,  @buffer
+            LD   B,   3h
+.fill:      CALL *store
+            INC  HL
+            DJNZ &fill
+            LD   A,   42h
+            OUT  10h, A
+            RET
+
+.store:     LD   (HL), B
+            RET
+"#;
+
+    const BUFFER: u16 = 0x8000;
+
+    fn debugger() -> Debugger {
+        debugger_for(SOURCE)
+    }
+
+    fn debugger_for(source: &str) -> Debugger {
+        let program: Program = Compiler::new(
+            InMemorySourceProvider {
+                files: vec![(
+                    SourceHeader {
+                        filename: "src/main.z80".to_string(),
+                    },
+                    source.to_string(),
+                )],
+            },
+            64 * 1024,
+        )
+        .assemble()
+        .unwrap();
+        let entry = program.label("main");
+        Debugger::new(&program, entry)
+    }
+
+    #[test]
+    fn breakpoints_on_labels_and_lines() {
+        let mut d = debugger();
+        let store = d.labels["store"];
+
+        assert_eq!(
+            format!("breakpoint 1 at {:04X}h (&store, main.z80:12:13)\n", store),
+            d.execute("b store")
+        );
+        assert_eq!(Stop::Breakpoint(1), d.cont());
+        assert_eq!(store, d.cpu.regs.pc);
+        assert_eq!(3, d.cpu.regs.b);
+
+        d.execute("d 1");
+        d.execute("b main.z80:9");
+        assert_eq!(Stop::Breakpoint(2), d.cont());
+        assert_eq!(0x42, d.cpu.regs.a);
+        assert_eq!(Stop::Returned, d.cont());
+    }
+
+    #[test]
+    fn next_and_finish() {
+        let mut d = debugger();
+        d.step(2);
+        let after_call = d.labels["fill"] + 3;
+
+        // steps over the whole subroutine
+        assert_eq!(Stop::Done, d.step_over());
+        assert_eq!(after_call, d.cpu.regs.pc);
+        assert_eq!(3, d.mem.ram[BUFFER as usize]);
+
+        d.step(3);
+        assert_eq!(d.labels["store"], d.cpu.regs.pc);
+        assert_eq!(Stop::Done, d.finish());
+        assert_eq!(after_call, d.cpu.regs.pc);
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut d = debugger();
+        d.add_watchpoint(
+            WatchTarget::Memory,
+            BUFFER + 2,
+            BUFFER + 2,
+            WatchMode::Write,
+        );
+        d.add_watchpoint(WatchTarget::Io, 0x10, 0x10, WatchMode::ReadWrite);
+
+        assert!(matches!(d.cont(), Stop::Watchpoint(1, _)));
+        assert_eq!(1, d.mem.ram[BUFFER as usize + 2]);
+        assert!(matches!(d.cont(), Stop::Watchpoint(2, _)));
+        assert_eq!(vec![(0x4210, 0x42)], d.mem.outputs);
+
+        // reading the code doesn't count as a data read
+        d.execute("d");
+        d.execute("watch &main+3 rw");
+        assert_eq!(Stop::Returned, d.cont());
+    }
+
+    #[test]
+    fn reverse_step() {
+        let mut d = debugger();
+        let buffer = BUFFER as usize;
+        d.step(4);
+        assert_eq!(3, d.mem.ram[buffer]);
+        let regs = d.cpu.regs.clone();
+
+        d.step(3);
+        assert_eq!(3, d.reverse_step(3));
+        assert_eq!(regs, d.cpu.regs);
+
+        d.reverse_step(1);
+        assert_eq!(0, d.mem.ram[buffer]);
+        assert_eq!(3, d.reverse_step(10));
+        assert_eq!(d.labels["main"], d.cpu.regs.pc);
+    }
+
+    #[test]
+    fn inspect_and_modify() {
+        let mut d = debugger();
+        assert_eq!("", d.execute("set HL 1234h"));
+        assert_eq!("", d.execute("set (8000h) 1 2 41h"));
+        assert_eq!(0x1234, d.cpu.regs.get(Register::HL));
+        assert_eq!(
+            format!("8000h  01 02 41{}  |..A|\n", " ".repeat(39)),
+            d.execute("x 0x8000 3")
+        );
+        assert!(d
+            .execute("r")
+            .starts_with("AF  FFFFh  BC  0000h  DE  0000h  HL  1234h\n"));
+        assert!(d.execute("dis main 2").starts_with(".main:\n=> 0000h  21 "));
+        assert_eq!("error: label 'nope' not found\n", d.execute("b &nope"));
+    }
+
+    #[test]
+    fn macro_expansions_and_data() {
+        let mut d = debugger_for(
+            r#"
+#defm twice reg
+    INC reg
+    INC reg
+#endm
+.main:  #exec twice B
+        RET
+.table: 12h 34h
+"#,
+        );
+
+        assert_eq!(
+            "0000h &main main.z80:3:5: INC B\n    in macro twice from main.z80:6:15\n",
+            d.execute("where")
+        );
+        assert!(d
+            .execute("dis table 2")
+            .contains("0003h  12           12h  ; main.z80:8:9\n"));
+    }
+}
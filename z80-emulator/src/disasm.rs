@@ -0,0 +1,351 @@
+use std::collections::HashMap;
+
+const R: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const RP: [&str; 4] = ["BC", "DE", "HL", "SP"];
+const CC: [&str; 8] = ["NZ", "Z", "NC", "C", "PO", "PE", "P", "M"];
+const ALU: [&str; 8] = [
+    "ADD A, ", "ADC A, ", "SUB ", "SBC A, ", "AND ", "XOR ", "OR ", "CP ",
+];
+const ROT: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SLL", "SRL"];
+const BLOCK: [[&str; 4]; 4] = [
+    ["LDI", "CPI", "INI", "OUTI"],
+    ["LDD", "CPD", "IND", "OUTD"],
+    ["LDIR", "CPIR", "INIR", "OTIR"],
+    ["LDDR", "CPDR", "INDR", "OTDR"],
+];
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Disassembly {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    pub text: String,
+}
+
+/// Decodes the instruction at `addr` using the assembler's syntax, so jump
+/// and call targets that match a label are printed as `&label`/`*label`.
+pub fn disassemble(mem: &[u8], addr: u16, labels: &HashMap<u16, String>) -> Disassembly {
+    let mut d = Decoder {
+        mem,
+        pc: addr,
+        labels,
+    };
+    let text = d.main();
+    let len = d.pc.wrapping_sub(addr) as usize;
+    Disassembly {
+        addr,
+        bytes: (0..len)
+            .map(|i| mem[(addr as usize + i) % mem.len()])
+            .collect(),
+        text,
+    }
+}
+
+struct Decoder<'a> {
+    mem: &'a [u8],
+    pc: u16,
+    labels: &'a HashMap<u16, String>,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Index {
+    HL,
+    IX,
+    IY,
+}
+
+impl Index {
+    fn name(&self) -> &'static str {
+        match self {
+            Index::HL => "HL",
+            Index::IX => "IX",
+            Index::IY => "IY",
+        }
+    }
+}
+
+impl<'a> Decoder<'a> {
+    fn fetch(&mut self) -> u8 {
+        let val = self.mem[self.pc as usize % self.mem.len()];
+        self.pc = self.pc.wrapping_add(1);
+        val
+    }
+
+    fn fetch16(&mut self) -> u16 {
+        let low = self.fetch() as u16;
+        let high = self.fetch() as u16;
+        (high << 8) | low
+    }
+
+    fn imm8(&mut self) -> String {
+        format!("{:02X}h", self.fetch())
+    }
+
+    fn imm16(&mut self) -> String {
+        format!("{:04X}h", self.fetch16())
+    }
+
+    fn target(&self, addr: u16) -> String {
+        match self.labels.get(&addr) {
+            Some(l) => format!("&{}", l),
+            None => format!("{:04X}h", addr),
+        }
+    }
+
+    fn abs_target(&mut self) -> String {
+        let addr = self.fetch16();
+        self.target(addr)
+    }
+
+    /// `CALL` takes its target as a value, `*label` in the source.
+    fn call_target(&mut self) -> String {
+        let addr = self.fetch16();
+        match self.labels.get(&addr) {
+            Some(l) => format!("*{}", l),
+            None => format!("{:04X}h", addr),
+        }
+    }
+
+    fn rel_target(&mut self) -> String {
+        let d = self.fetch() as i8;
+        let addr = self.pc.wrapping_add(d as u16);
+        self.target(addr)
+    }
+
+    fn indexed(&mut self, idx: Index) -> String {
+        let d = self.fetch() as i8;
+        if d < 0 {
+            format!("({} - {:X}h)", idx.name(), -(d as i16))
+        } else {
+            format!("({} + {:X}h)", idx.name(), d)
+        }
+    }
+
+    fn reg(&mut self, code: u8, idx: Index) -> String {
+        match (code, idx) {
+            (6, Index::HL) => R[6].to_string(),
+            (6, _) => self.indexed(idx),
+            (4, Index::IX) => "IXH".to_string(),
+            (5, Index::IX) => "IXL".to_string(),
+            (4, Index::IY) => "IYH".to_string(),
+            (5, Index::IY) => "IYL".to_string(),
+            _ => R[code as usize].to_string(),
+        }
+    }
+
+    fn rp(&self, p: u8, idx: Index) -> &'static str {
+        if p == 2 {
+            idx.name()
+        } else {
+            RP[p as usize]
+        }
+    }
+
+    fn main(&mut self) -> String {
+        let op = self.fetch();
+        self.decode(op, Index::HL)
+    }
+
+    fn decode(&mut self, op: u8, idx: Index) -> String {
+        let x = op >> 6;
+        let y = (op >> 3) & 7;
+        let z = op & 7;
+        let p = y >> 1;
+        let q = y & 1;
+
+        match (x, z) {
+            (0, 0) => match y {
+                0 => "NOP".to_string(),
+                1 => "EX AF, AFp".to_string(),
+                2 => format!("DJNZ {}", self.rel_target()),
+                3 => format!("JR {}", self.rel_target()),
+                _ => format!("JR {}, {}", CC[y as usize - 4], self.rel_target()),
+            },
+            (0, 1) => {
+                if q == 0 {
+                    format!("LD {}, {}", self.rp(p, idx), self.imm16())
+                } else {
+                    format!("ADD {}, {}", idx.name(), self.rp(p, idx))
+                }
+            }
+            (0, 2) => match (q, p) {
+                (0, 0) => "LD (BC), A".to_string(),
+                (0, 1) => "LD (DE), A".to_string(),
+                (0, 2) => format!("LD ({}), {}", self.abs_target(), idx.name()),
+                (0, _) => format!("LD ({}), A", self.abs_target()),
+                (_, 0) => "LD A, (BC)".to_string(),
+                (_, 1) => "LD A, (DE)".to_string(),
+                (_, 2) => format!("LD {}, ({})", idx.name(), self.abs_target()),
+                (_, _) => format!("LD A, ({})", self.abs_target()),
+            },
+            (0, 3) => format!("{} {}", if q == 0 { "INC" } else { "DEC" }, self.rp(p, idx)),
+            (0, 4) => format!("INC {}", self.reg(y, idx)),
+            (0, 5) => format!("DEC {}", self.reg(y, idx)),
+            (0, 6) => {
+                let r = self.reg(y, idx);
+                format!("LD {}, {}", r, self.imm8())
+            }
+            (0, _) => {
+                ["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"][y as usize].to_string()
+            }
+            (1, _) => {
+                if y == 6 && z == 6 {
+                    "HALT".to_string()
+                } else if z == 6 {
+                    format!("LD {}, {}", R[y as usize], self.reg(6, idx))
+                } else if y == 6 {
+                    format!("LD {}, {}", self.reg(6, idx), R[z as usize])
+                } else {
+                    format!("LD {}, {}", self.reg(y, idx), self.reg(z, idx))
+                }
+            }
+            (2, _) => format!("{}{}", ALU[y as usize], self.reg(z, idx)),
+            (_, 0) => format!("RET {}", CC[y as usize]),
+            (_, 1) => match (q, p) {
+                (0, 3) => "POP AF".to_string(),
+                (0, _) => format!("POP {}", self.rp(p, idx)),
+                (_, 0) => "RET".to_string(),
+                (_, 1) => "EXX".to_string(),
+                (_, 2) => format!("JP ({})", idx.name()),
+                (_, _) => format!("LD SP, {}", idx.name()),
+            },
+            (_, 2) => format!("JP {}, {}", CC[y as usize], self.abs_target()),
+            (_, 3) => match y {
+                0 => format!("JP {}", self.abs_target()),
+                1 => self.decode_cb(idx),
+                2 => format!("OUT {}, A", self.imm8()),
+                3 => format!("IN A, {}", self.imm8()),
+                4 => format!("EX (SP), {}", idx.name()),
+                5 => "EX DE, HL".to_string(),
+                6 => "DI".to_string(),
+                _ => "EI".to_string(),
+            },
+            (_, 4) => format!("CALL {}, {}", CC[y as usize], self.call_target()),
+            (_, 5) => match (q, p) {
+                (0, 3) => "PUSH AF".to_string(),
+                (0, _) => format!("PUSH {}", self.rp(p, idx)),
+                (_, 0) => format!("CALL {}", self.call_target()),
+                (_, 1) => {
+                    let op = self.fetch();
+                    self.decode(op, Index::IX)
+                }
+                (_, 2) => self.decode_ed(),
+                (_, _) => {
+                    let op = self.fetch();
+                    self.decode(op, Index::IY)
+                }
+            },
+            (_, 6) => format!("{}{}", ALU[y as usize], self.imm8()),
+            (_, _) => format!("RST {:02X}h", y * 8),
+        }
+    }
+
+    fn decode_cb(&mut self, idx: Index) -> String {
+        let (operand, op) = if idx == Index::HL {
+            let op = self.fetch();
+            (R[(op & 7) as usize].to_string(), op)
+        } else {
+            let operand = self.indexed(idx);
+            (operand, self.fetch())
+        };
+        let x = op >> 6;
+        let y = (op >> 3) & 7;
+
+        match x {
+            0 => format!("{} {}", ROT[y as usize], operand),
+            1 => format!("BIT {}h, {}", y, operand),
+            2 => format!("RES {}h, {}", y, operand),
+            _ => format!("SET {}h, {}", y, operand),
+        }
+    }
+
+    fn decode_ed(&mut self) -> String {
+        let op = self.fetch();
+        let x = op >> 6;
+        let y = (op >> 3) & 7;
+        let z = op & 7;
+        let p = y >> 1;
+        let q = y & 1;
+
+        match (x, z) {
+            (1, 0) if y == 6 => "IN (C)".to_string(),
+            (1, 0) => format!("IN {}, (C)", R[y as usize]),
+            (1, 1) if y == 6 => "OUT (C), 0h".to_string(),
+            (1, 1) => format!("OUT (C), {}", R[y as usize]),
+            (1, 2) => format!(
+                "{} HL, {}",
+                if q == 0 { "SBC" } else { "ADC" },
+                RP[p as usize]
+            ),
+            (1, 3) if q == 0 => format!("LD ({}), {}", self.abs_target(), RP[p as usize]),
+            (1, 3) => format!("LD {}, ({})", RP[p as usize], self.abs_target()),
+            (1, 4) => "NEG".to_string(),
+            (1, 5) if y == 1 => "RETI".to_string(),
+            (1, 5) => "RETN".to_string(),
+            (1, 6) => format!("IM {}h", [0, 0, 1, 2][(y & 3) as usize]),
+            (1, _) => match y {
+                0 => "LD I, A".to_string(),
+                1 => "LD R, A".to_string(),
+                2 => "LD A, I".to_string(),
+                3 => "LD A, R".to_string(),
+                4 => "RRD".to_string(),
+                5 => "RLD".to_string(),
+                _ => "NOP".to_string(),
+            },
+            (2, 0..=3) if y >= 4 => BLOCK[(y - 4) as usize][z as usize].to_string(),
+            _ => format!("DB EDh, {:02X}h", op),
+        }
+    }
+}
+
+/// True for the instructions `next` should step over.
+pub fn is_call(mem: &[u8], addr: u16) -> bool {
+    let op = mem[addr as usize % mem.len()];
+    op == 0xCD || op & 0b11000111 == 0b11000100 || op & 0b11000111 == 0b11000111
+}
+
+pub fn is_return(mem: &[u8], addr: u16) -> bool {
+    let op = mem[addr as usize % mem.len()];
+    let next = mem[(addr as usize + 1) % mem.len()];
+    op == 0xC9 || op & 0b11000111 == 0b11000000 || (op == 0xED && (next == 0x4D || next == 0x45))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::disasm::disassemble;
+    use std::collections::HashMap;
+
+    fn dis(bytes: &[u8]) -> Vec<String> {
+        let mut mem = vec![0u8; 64 * 1024];
+        mem[..bytes.len()].copy_from_slice(bytes);
+        let labels = HashMap::from([(0x0003u16, "loop".to_string())]);
+        let mut out = vec![];
+        let mut addr = 0u16;
+        while (addr as usize) < bytes.len() {
+            let d = disassemble(&mem, addr, &labels);
+            addr += d.bytes.len() as u16;
+            out.push(d.text);
+        }
+        out
+    }
+
+    #[test]
+    fn disassembles_common_instructions() {
+        assert_eq!(
+            vec![
+                "LD BC, 1234h",
+                "LD A, (HL)",
+                "JR NZ, &loop",
+                "LD E, (IX + 5h)",
+                "BIT 7h, (IY - 2h)",
+                "SBC HL, DE",
+                "CALL 4000h",
+                "LDIR",
+                "RST 38h",
+            ],
+            dis(&[
+                0x01, 0x34, 0x12, 0x7E, 0x20, 0xFD, 0xDD, 0x5E, 0x05, 0xFD, 0xCB, 0xFE, 0x7E, 0xED,
+                0x52, 0xCD, 0x00, 0x40, 0xED, 0xB0, 0xFF,
+            ])
+        );
+    }
+}
@@ -0,0 +1,492 @@
+use crate::bus::{Access, Bus};
+use crate::cpu::Register;
+use crate::debugger::{Debugger, Stop, WatchMode, WatchTarget};
+use crate::gdb::packet::{hex, unhex, Connection, Incoming};
+use std::io::Result;
+use std::net::TcpListener;
+
+pub use crate::gdb::packet::Transport;
+
+mod packet;
+
+/// Register order of GDB's z80 target, `ir` packs `I` and `R`.
+const REGISTERS: [&str; 13] = [
+    "af", "bc", "de", "hl", "sp", "pc", "ix", "iy", "af'", "bc'", "de'", "hl'", "ir",
+];
+
+/// Largest packet the client may send, advertised in `qSupported`.
+const PACKET_SIZE: usize = 0x4000;
+
+/// Instructions executed between checks for a break from the client.
+const RUN_CHUNK: u64 = 100_000;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// Serves one GDB client at a time over the remote serial protocol, using a
+/// `Debugger` for breakpoints, watchpoints and stepping.
+pub struct GdbServer {
+    pub debugger: Debugger,
+}
+
+impl GdbServer {
+    pub fn new(mut debugger: Debugger) -> Self {
+        debugger.step_limit = RUN_CHUNK;
+        GdbServer { debugger }
+    }
+
+    /// Accepts clients until one of them sends `k`ill.
+    pub fn listen(&mut self, listener: TcpListener) -> Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            stream.set_nodelay(true)?;
+            if self.serve(stream)? {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles a single session, returns true if the client asked to kill
+    /// the target rather than detach.
+    pub fn serve<T: Transport>(&mut self, transport: T) -> Result<bool> {
+        let mut conn = Connection::new(transport);
+
+        loop {
+            let packet = match conn.receive() {
+                Ok(Incoming::Packet(p)) => p,
+                // a break while stopped, report where we are
+                Ok(Incoming::Break) => {
+                    conn.send(&signal(SIGINT))?;
+                    continue;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(false),
+                Err(e) => return Err(e),
+            };
+
+            match packet.as_bytes().first() {
+                Some(b'k') => return Ok(true),
+                Some(b'D') => {
+                    conn.send("OK")?;
+                    return Ok(false);
+                }
+                Some(b'c') => {
+                    if let Some(addr) = parse_hex(&packet[1..]) {
+                        self.debugger.cpu.regs.pc = addr;
+                    }
+                    let reply = self.cont(&mut conn);
+                    conn.send(&reply)?;
+                }
+                Some(b's') => {
+                    if let Some(addr) = parse_hex(&packet[1..]) {
+                        self.debugger.cpu.regs.pc = addr;
+                    }
+                    let stop = self.debugger.step(1);
+                    conn.send(&self.stop_reply(stop))?;
+                }
+                _ => {
+                    let reply = self.handle(&packet, &mut conn.ack);
+                    conn.send(&reply)?;
+                }
+            }
+        }
+    }
+
+    /// Runs in chunks so a break from the client can interrupt the target.
+    fn cont<T: Transport>(&mut self, conn: &mut Connection<T>) -> String {
+        loop {
+            match self.debugger.cont() {
+                Stop::StepLimit => {
+                    if conn.transport.poll_break() {
+                        return signal(SIGINT);
+                    }
+                }
+                stop => return self.stop_reply(stop),
+            }
+        }
+    }
+
+    /// Packets that don't resume the target, an empty reply means the packet
+    /// is not supported.
+    fn handle(&mut self, packet: &str, ack: &mut bool) -> String {
+        if packet.is_empty() || !packet.is_char_boundary(1) {
+            return String::new();
+        }
+        let (cmd, args) = packet.split_at(1);
+        match cmd {
+            "?" => signal(SIGTRAP),
+            "g" => REGISTERS
+                .iter()
+                .enumerate()
+                .map(|(i, _)| hex(&self.register(i).to_le_bytes()))
+                .collect(),
+            "G" => match unhex(args) {
+                Some(data) if data.len() == REGISTERS.len() * 2 => {
+                    for (i, v) in data.chunks(2).enumerate() {
+                        self.set_register(i, u16::from_le_bytes([v[0], v[1]]));
+                    }
+                    "OK".to_string()
+                }
+                _ => error(1),
+            },
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(i) if i < REGISTERS.len() => hex(&self.register(i).to_le_bytes()),
+                _ => error(1),
+            },
+            "P" => {
+                let parsed = args.split_once('=').and_then(|(i, v)| {
+                    let i = usize::from_str_radix(i, 16).ok()?;
+                    let v = unhex(v)?;
+                    (i < REGISTERS.len() && v.len() == 2).then_some((i, v))
+                });
+                match parsed {
+                    Some((i, v)) => {
+                        self.set_register(i, u16::from_le_bytes([v[0], v[1]]));
+                        "OK".to_string()
+                    }
+                    None => error(1),
+                }
+            }
+            // the reply is hex, twice as long as the bytes
+            "m" => match parse_range(args) {
+                Some((addr, len)) => hex(&self.debugger.mem.slice(addr, len.min(PACKET_SIZE / 2))),
+                None => error(1),
+            },
+            "M" => {
+                let parsed = args
+                    .split_once(':')
+                    .and_then(|(range, data)| Some((parse_range(range)?, unhex(data)?)));
+                match parsed {
+                    Some(((addr, len), data)) if data.len() == len => {
+                        for (i, b) in data.iter().enumerate() {
+                            self.debugger.mem.write(addr.wrapping_add(i as u16), *b);
+                        }
+                        "OK".to_string()
+                    }
+                    _ => error(1),
+                }
+            }
+            "Z" | "z" => self.breakpoint(cmd == "Z", args),
+            "H" => "OK".to_string(),
+            "T" => "OK".to_string(),
+            "q" | "Q" => self.query(packet, ack),
+            _ => String::new(),
+        }
+    }
+
+    fn query(&mut self, packet: &str, ack: &mut bool) -> String {
+        if packet.starts_with("qSupported") {
+            return format!(
+                "PacketSize={:X};qXfer:features:read+;swbreak+;QStartNoAckMode+",
+                PACKET_SIZE
+            );
+        }
+        if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return match parse_range(args) {
+                Some((offset, len)) => {
+                    let xml = target_xml();
+                    let offset = (offset as usize).min(xml.len());
+                    let end = (offset + len).min(xml.len());
+                    let more = if end < xml.len() { "m" } else { "l" };
+                    format!("{}{}", more, &xml[offset..end])
+                }
+                None => error(1),
+            };
+        }
+        match packet {
+            "QStartNoAckMode" => {
+                *ack = false;
+                "OK".to_string()
+            }
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    /// `Z0`/`Z1` are breakpoints, `Z2`..`Z4` write, read and access
+    /// watchpoints.
+    fn breakpoint(&mut self, insert: bool, args: &str) -> String {
+        let mut parts = args.split(',');
+        let kind = parts.next().and_then(|k| k.parse::<u8>().ok());
+        let addr = parts.next().and_then(parse_hex);
+        let len = parts
+            .next()
+            .and_then(|l| u16::from_str_radix(l, 16).ok())
+            .unwrap_or(1)
+            .max(1);
+        let (kind, addr) = match (kind, addr) {
+            (Some(k), Some(a)) => (k, a),
+            _ => return error(1),
+        };
+
+        let d = &mut self.debugger;
+        match (kind, insert) {
+            (0 | 1, true) => {
+                if !d.breakpoints.iter().any(|b| b.addr == addr) {
+                    d.add_breakpoint(addr);
+                }
+            }
+            (0 | 1, false) => d.breakpoints.retain(|b| b.addr != addr),
+            (2..=4, _) => {
+                let mode = match kind {
+                    2 => WatchMode::Write,
+                    3 => WatchMode::Read,
+                    _ => WatchMode::ReadWrite,
+                };
+                let end = addr.wrapping_add(len - 1);
+                if insert {
+                    d.add_watchpoint(WatchTarget::Memory, addr, end, mode);
+                } else {
+                    d.watchpoints.retain(|w| {
+                        !(w.target == WatchTarget::Memory
+                            && w.start == addr
+                            && w.end == end
+                            && w.mode == mode)
+                    });
+                }
+            }
+            _ => return String::new(),
+        }
+        "OK".to_string()
+    }
+
+    fn register(&self, i: usize) -> u16 {
+        let r = &self.debugger.cpu.regs;
+        match i {
+            12 => ((r.i as u16) << 8) | r.r as u16,
+            _ => r.get(register_at(i)),
+        }
+    }
+
+    fn set_register(&mut self, i: usize, val: u16) {
+        let r = &mut self.debugger.cpu.regs;
+        match i {
+            12 => {
+                r.i = (val >> 8) as u8;
+                r.r = val as u8;
+            }
+            _ => r.set(register_at(i), val),
+        }
+    }
+
+    fn stop_reply(&self, stop: Stop) -> String {
+        match stop {
+            Stop::Breakpoint(_) => format!("T{:02x}swbreak:;", SIGTRAP),
+            Stop::Watchpoint(id, access) => {
+                let mode = self
+                    .debugger
+                    .watchpoints
+                    .iter()
+                    .find(|w| w.id == id)
+                    .map(|w| w.mode);
+                let (addr, write) = match access {
+                    Access::Read(addr, _) | Access::In(addr, _) => (addr, false),
+                    Access::Write { addr, .. } | Access::Out(addr, _) => (addr, true),
+                };
+                let kind = match (mode, write) {
+                    (Some(WatchMode::ReadWrite), _) => "awatch",
+                    (_, true) => "watch",
+                    (_, false) => "rwatch",
+                };
+                format!("T{:02x}{}:{:x};", SIGTRAP, kind, addr)
+            }
+            Stop::Returned => "W00".to_string(),
+            Stop::Done | Stop::Halted | Stop::StepLimit => signal(SIGTRAP),
+        }
+    }
+}
+
+fn register_at(i: usize) -> Register {
+    [
+        Register::AF,
+        Register::BC,
+        Register::DE,
+        Register::HL,
+        Register::SP,
+        Register::PC,
+        Register::IX,
+        Register::IY,
+        Register::AFp,
+        Register::BCp,
+        Register::DEp,
+        Register::HLp,
+    ][i]
+}
+
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n\
+         <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+         <target version=\"1.0\">\n\
+         <architecture>z80</architecture>\n\
+         <feature name=\"org.gnu.gdb.z80.cpu\">\n",
+    );
+    for (i, name) in REGISTERS.iter().enumerate() {
+        let ty = match *name {
+            "sp" => "data_ptr",
+            "pc" => "code_ptr",
+            _ => "int",
+        };
+        xml.push_str(&format!(
+            "<reg name=\"{}\" bitsize=\"16\" type=\"{}\" regnum=\"{}\"/>\n",
+            name, ty, i
+        ));
+    }
+    xml.push_str("</feature>\n</target>\n");
+    xml
+}
+
+fn signal(sig: u8) -> String {
+    format!("S{:02x}", sig)
+}
+
+fn error(code: u8) -> String {
+    format!("E{:02x}", code)
+}
+
+fn parse_hex(s: &str) -> Option<u16> {
+    u16::from_str_radix(s, 16).ok()
+}
+
+fn parse_range(s: &str) -> Option<(u16, usize)> {
+    let (addr, len) = s.split_once(',')?;
+    Some((parse_hex(addr)?, usize::from_str_radix(len, 16).ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::debugger::Debugger;
+    use crate::gdb::packet::checksum;
+    use crate::gdb::GdbServer;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use z80_assembler::{Compiler, InMemorySourceProvider, SourceHeader};
+
+    const SOURCE: &str = r#"
+@buffer: 8000h
+.main:      LD   HL,  @buffer
+            LD   B,   3h
+.fill:      LD   (HL), B
+            INC  HL
+            DJNZ &fill
+            RET
+"#;
+
+    /// Minimal scripted client speaking the same framing as GDB.
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn byte(&mut self) -> u8 {
+            let mut b = [0u8];
+            self.stream.read_exact(&mut b).unwrap();
+            b[0]
+        }
+
+        fn request(&mut self, packet: &str) -> String {
+            write!(
+                self.stream,
+                "${}#{:02x}",
+                packet,
+                checksum(packet.as_bytes())
+            )
+            .unwrap();
+            assert_eq!(b'+', self.byte());
+
+            while self.byte() != b'$' {}
+            let mut data = vec![];
+            loop {
+                match self.byte() {
+                    b'#' => break,
+                    b => data.push(b),
+                }
+            }
+            let cs = [self.byte(), self.byte()];
+            assert_eq!(
+                format!("{:02x}", checksum(&data)),
+                String::from_utf8_lossy(&cs)
+            );
+            self.stream.write_all(b"+").unwrap();
+            String::from_utf8(data).unwrap()
+        }
+    }
+
+    fn start() -> (Client, thread::JoinHandle<()>) {
+        let program = Compiler::new(
+            InMemorySourceProvider {
+                files: vec![(
+                    SourceHeader {
+                        filename: "main.z80".to_string(),
+                    },
+                    SOURCE.to_string(),
+                )],
+            },
+            64 * 1024,
+        )
+        .assemble()
+        .unwrap();
+        let entry = program.label("main");
+        let mut server = GdbServer::new(Debugger::new(&program, entry));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || server.listen(listener).unwrap());
+
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_nodelay(true).unwrap();
+        (Client { stream }, handle)
+    }
+
+    #[test]
+    fn scripted_session() {
+        let (mut c, handle) = start();
+
+        assert!(c
+            .request("qSupported:swbreak+")
+            .contains("qXfer:features:read+"));
+        let xml = c.request("qXfer:features:read:target.xml:0,fff");
+        assert!(xml.starts_with("l<?xml"));
+        assert!(xml.contains("<architecture>z80</architecture>"));
+        assert_eq!("S05", c.request("?"));
+
+        // af bc de hl sp pc ...
+        let regs = c.request("g");
+        assert_eq!(13 * 4, regs.len());
+        assert_eq!("feff", &regs[16..20]);
+
+        assert_eq!("OK", c.request("Z0,5,1"));
+        assert_eq!("T05swbreak:;", c.request("c"));
+        assert_eq!("0500", c.request("p5"));
+        assert_eq!("0080", c.request("p3"));
+
+        assert_eq!("T05swbreak:;", c.request("c"));
+        assert_eq!("OK", c.request("z0,5,1"));
+        assert_eq!("0300", c.request("m8000,2"));
+
+        assert_eq!("OK", c.request("Z2,8002,1"));
+        assert_eq!("T05watch:8002;", c.request("c"));
+        assert_eq!("030201", c.request("m8000,3"));
+        assert_eq!("OK", c.request("z2,8002,1"));
+
+        assert_eq!("OK", c.request("M8000,2:aabb"));
+        assert_eq!("aabb", c.request("m8000,2"));
+        assert_eq!(0x4000, c.request("m0,ffffffff").len());
+        assert_eq!("OK", c.request("P2=3412"));
+        assert_eq!("3412", &c.request("g")[8..12]);
+
+        let pc = c.request("p5");
+        assert_eq!("S05", c.request("s"));
+        assert_ne!(pc, c.request("p5"));
+
+        assert_eq!("W00", c.request("c"));
+
+        write!(c.stream, "$k#6b").unwrap();
+        assert_eq!(b'+', c.byte());
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,162 @@
+use std::io::{ErrorKind, Read, Result, Write};
+use std::net::TcpStream;
+
+/// Byte stream a GDB client talks over.
+pub trait Transport: Read + Write {
+    /// True if the client sent a break (`0x03`) while the target was running,
+    /// must not block.
+    fn poll_break(&mut self) -> bool {
+        false
+    }
+}
+
+impl Transport for TcpStream {
+    fn poll_break(&mut self) -> bool {
+        let mut b = [0u8];
+        if self.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let res = self.peek(&mut b);
+        let _ = self.set_nonblocking(false);
+        match res {
+            Ok(1) if b[0] == 0x03 => self.read_exact(&mut b).is_ok(),
+            _ => false,
+        }
+    }
+}
+
+pub enum Incoming {
+    Packet(String),
+    Break,
+}
+
+/// `$data#checksum` framing with `+`/`-` acknowledgements.
+pub struct Connection<T: Transport> {
+    pub transport: T,
+    pub ack: bool,
+}
+
+impl<T: Transport> Connection<T> {
+    pub fn new(transport: T) -> Self {
+        Connection {
+            transport,
+            ack: true,
+        }
+    }
+
+    fn read_byte(&mut self) -> Result<u8> {
+        let mut b = [0u8];
+        self.transport.read_exact(&mut b)?;
+        Ok(b[0])
+    }
+
+    /// Waits for the next packet, acks are skipped and packets with a bad
+    /// checksum are asked again.
+    pub fn receive(&mut self) -> Result<Incoming> {
+        loop {
+            match self.read_byte()? {
+                b'$' => {}
+                0x03 => return Ok(Incoming::Break),
+                _ => continue,
+            }
+
+            let mut data = vec![];
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    b => data.push(b),
+                }
+            }
+            let hi = self.read_byte()?;
+            let lo = self.read_byte()?;
+            let expected = std::str::from_utf8(&[hi, lo])
+                .ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok());
+
+            if expected != Some(checksum(&data)) {
+                if self.ack {
+                    self.transport.write_all(b"-")?;
+                }
+                continue;
+            }
+            if self.ack {
+                self.transport.write_all(b"+")?;
+            }
+            return Ok(Incoming::Packet(
+                String::from_utf8_lossy(&unescape(&data)).to_string(),
+            ));
+        }
+    }
+
+    pub fn send(&mut self, data: &str) -> Result<()> {
+        let escaped = escape(data.as_bytes());
+        loop {
+            self.transport.write_all(b"$")?;
+            self.transport.write_all(&escaped)?;
+            write!(self.transport, "#{:02x}", checksum(&escaped))?;
+            self.transport.flush()?;
+
+            if !self.ack {
+                return Ok(());
+            }
+            match self.read_byte()? {
+                b'+' => return Ok(()),
+                b'-' => continue,
+                b => {
+                    return Err(std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("expected ack, got {:02X}h", b),
+                    ))
+                }
+            }
+        }
+    }
+}
+
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b))
+}
+
+fn escape(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    for b in data {
+        match b {
+            b'$' | b'#' | b'}' | b'*' => {
+                out.push(b'}');
+                out.push(b ^ 0x20);
+            }
+            _ => out.push(*b),
+        }
+    }
+    out
+}
+
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut iter = data.iter();
+    while let Some(b) = iter.next() {
+        match b {
+            b'}' => {
+                if let Some(b) = iter.next() {
+                    out.push(b ^ 0x20);
+                }
+            }
+            _ => out.push(*b),
+        }
+    }
+    out
+}
+
+pub fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn unhex(data: &str) -> Option<Vec<u8>> {
+    if !data.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..data.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(data.get(i..i + 2)?, 16).ok())
+        .collect()
+}
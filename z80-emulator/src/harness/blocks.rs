@@ -0,0 +1,228 @@
+use crate::cpu::{Flag, Register};
+use crate::harness::TestCase;
+use std::fmt::{Display, Formatter};
+use z80_assembler::parser::{Token, TokenValue};
+use z80_assembler::{Program, TestBlock};
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct HarnessError {
+    pub test: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for HarnessError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "l{} - test '{}': {}", self.line, self.test, self.message)
+    }
+}
+
+impl std::error::Error for HarnessError {}
+
+/// Turns the `#test` blocks collected by the assembler into test cases.
+///
+/// ```text
+/// #test mult_3_by_4
+/// set DE, 3h              ; registers, `set (addr), bytes...` for memory
+/// set C                   ; flags use condition names: Z NZ C NC PE PO M P
+/// call Mult16
+/// expect HL, Ch
+/// expect (&buffer), 12h, 34h
+/// expect NC
+/// input 10h, 01h, 00h     ; bytes the next reads of port 10h return
+/// output 10h, 41h         ; every byte written to port 10h
+/// cycles 2000h
+/// #endt
+/// ```
+pub fn parse_test_blocks(program: &Program) -> Result<Vec<TestCase>, HarnessError> {
+    program
+        .test_blocks
+        .iter()
+        .map(|b| parse_test_block(program, b))
+        .collect()
+}
+
+fn parse_test_block(program: &Program, block: &TestBlock) -> Result<TestCase, HarnessError> {
+    let mut case = TestCase::new(&block.name, "");
+
+    for line in block.tokens.split(|t| t.token == TokenValue::NewLine) {
+        if line.is_empty() {
+            continue;
+        }
+
+        let err = |message: &str| HarnessError {
+            test: block.name.clone(),
+            line: line[0].line,
+            message: message.to_string(),
+        };
+
+        let cmd = match &line[0].token {
+            TokenValue::Identifier(i) => i.to_lowercase(),
+            t => return Err(err(&format!("unexpected token {:?}", t))),
+        };
+        let args = split_args(&line[1..]);
+
+        match (cmd.as_str(), args.as_slice()) {
+            ("call", [target]) => {
+                case.entry = match target {
+                    [Token {
+                        token: TokenValue::Identifier(l),
+                        ..
+                    }] => l.clone(),
+                    [Token {
+                        token: TokenValue::Amp,
+                        ..
+                    }, Token {
+                        token: TokenValue::Identifier(l),
+                        ..
+                    }] => l.clone(),
+                    _ => return Err(err("expected label after 'call'")),
+                }
+            }
+            ("cycles", [val]) => {
+                case.max_cycles = parse_value(program, val).map_err(|m| err(&m))? as u64
+            }
+            ("input", [port, values @ ..]) | ("output", [port, values @ ..]) => {
+                let port = parse_value(program, port).map_err(|m| err(&m))?;
+                let port = u8::try_from(port).map_err(|_| err("ports go up to FFh"))?;
+                let mut data = vec![];
+                for v in values {
+                    data.extend(parse_bytes(program, v).map_err(|m| err(&m))?);
+                }
+                case = if cmd == "input" {
+                    case.input(port, &data)
+                } else {
+                    case.expect_output(port, &data)
+                }
+            }
+            ("set", [cond]) => {
+                let (flag, val) = parse_condition(cond).ok_or_else(|| err("expected flag"))?;
+                case.flags.push((flag, val));
+            }
+            ("expect", [cond]) => {
+                let (flag, val) = parse_condition(cond).ok_or_else(|| err("expected flag"))?;
+                case = case.expect_flag(flag, val);
+            }
+            ("set", [target, values @ ..]) | ("expect", [target, values @ ..]) => {
+                let expect = cmd == "expect";
+                match parse_target(program, target).map_err(|m| err(&m))? {
+                    Target::Register(reg) => {
+                        if values.len() != 1 {
+                            return Err(err("expected a single register value"));
+                        }
+                        let val = parse_value(program, values[0]).map_err(|m| err(&m))?;
+                        case = if expect {
+                            case.expect_reg(reg, val)
+                        } else {
+                            case.reg(reg, val)
+                        }
+                    }
+                    Target::Memory(addr) => {
+                        let mut data = vec![];
+                        for v in values {
+                            data.extend(parse_bytes(program, v).map_err(|m| err(&m))?);
+                        }
+                        case = if expect {
+                            case.expect_mem(addr, &data)
+                        } else {
+                            case.mem(addr, &data)
+                        }
+                    }
+                }
+            }
+            _ => return Err(err(&format!("unknown test command '{}'", cmd))),
+        }
+    }
+
+    if case.entry.is_empty() {
+        return Err(HarnessError {
+            test: block.name.clone(),
+            line: block.line,
+            message: "missing 'call <label>'".to_string(),
+        });
+    }
+
+    Ok(case)
+}
+
+enum Target {
+    Register(Register),
+    Memory(u16),
+}
+
+fn split_args(tokens: &[Token]) -> Vec<&[Token]> {
+    if tokens.is_empty() {
+        return vec![];
+    }
+    tokens.split(|t| t.token == TokenValue::Comma).collect()
+}
+
+fn parse_condition(tokens: &[Token]) -> Option<(Flag, bool)> {
+    if let [Token {
+        token: TokenValue::Identifier(i),
+        ..
+    }] = tokens
+    {
+        match i.to_lowercase().as_str() {
+            "z" => Some((Flag::Z, true)),
+            "nz" => Some((Flag::Z, false)),
+            "c" => Some((Flag::C, true)),
+            "nc" => Some((Flag::C, false)),
+            "pe" => Some((Flag::PV, true)),
+            "po" => Some((Flag::PV, false)),
+            "m" => Some((Flag::S, true)),
+            "p" => Some((Flag::S, false)),
+            _ => None,
+        }
+    } else {
+        None
+    }
+}
+
+fn parse_target(program: &Program, tokens: &[Token]) -> Result<Target, String> {
+    match tokens {
+        [Token {
+            token: TokenValue::Identifier(i),
+            ..
+        }] => Register::parse(i)
+            .map(Target::Register)
+            .ok_or(format!("unknown register '{}'", i)),
+        [Token {
+            token: TokenValue::OpenParen,
+            ..
+        }, addr @ .., Token {
+            token: TokenValue::CloseParen,
+            ..
+        }] => Ok(Target::Memory(parse_value(program, addr)?)),
+        _ => Err("expected register or (address)".to_string()),
+    }
+}
+
+fn parse_value(program: &Program, tokens: &[Token]) -> Result<u16, String> {
+    match tokens {
+        [Token {
+            token: TokenValue::Value(v, _),
+            ..
+        }] => Ok(*v),
+        [Token {
+            token: TokenValue::Amp,
+            ..
+        }, Token {
+            token: TokenValue::Identifier(l),
+            ..
+        }] => program.label(l).ok_or(format!("label '{}' not found", l)),
+        _ => Err("expected value or &label".to_string()),
+    }
+}
+
+/// Values are stored like data lines in the source: wide values take two
+/// bytes, little-endian.
+fn parse_bytes(program: &Program, tokens: &[Token]) -> Result<Vec<u8>, String> {
+    match tokens {
+        [Token {
+            token: TokenValue::Value(v, 1),
+            ..
+        }] => Ok(vec![*v as u8]),
+        _ => Ok(parse_value(program, tokens)?.to_le_bytes().to_vec()),
+    }
+}
@@ -0,0 +1,577 @@
+use crate::bus::Memory;
+use crate::cpu::{Cpu, Flag, Register};
+use std::fmt::{Display, Formatter};
+use z80_assembler::{Compiler, InMemorySourceProvider, Program, SourceHeader};
+
+pub use crate::harness::blocks::{parse_test_blocks, HarnessError};
+
+mod blocks;
+
+/// Return address pushed before calling the routine under test, reaching it
+/// means the routine executed its final `RET`.
+pub const RETURN_ADDRESS: u16 = 0xFFFF;
+pub const DEFAULT_MAX_CYCLES: u64 = 1_000_000;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TestCase {
+    pub name: String,
+    pub entry: String,
+    pub registers: Vec<(Register, u16)>,
+    pub flags: Vec<(Flag, bool)>,
+    pub memory: Vec<(u16, Vec<u8>)>,
+    /// Bytes returned by reads of a port, in order.
+    pub inputs: Vec<(u8, Vec<u8>)>,
+    pub expectations: Vec<Expectation>,
+    pub max_cycles: u64,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Expectation {
+    Register(Register, u16),
+    Flag(Flag, bool),
+    Memory(u16, Vec<u8>),
+    /// Every byte written to the port, in order.
+    Output(u8, Vec<u8>),
+}
+
+impl TestCase {
+    pub fn new(name: &str, entry: &str) -> Self {
+        TestCase {
+            name: name.to_string(),
+            entry: entry.to_string(),
+            registers: vec![],
+            flags: vec![],
+            memory: vec![],
+            inputs: vec![],
+            expectations: vec![],
+            max_cycles: DEFAULT_MAX_CYCLES,
+        }
+    }
+
+    pub fn reg(mut self, reg: Register, val: u16) -> Self {
+        self.registers.push((reg, val));
+        self
+    }
+
+    pub fn flag(mut self, flag: Flag, val: bool) -> Self {
+        self.flags.push((flag, val));
+        self
+    }
+
+    pub fn mem(mut self, addr: u16, data: &[u8]) -> Self {
+        self.memory.push((addr, data.to_vec()));
+        self
+    }
+
+    pub fn input(mut self, port: u8, data: &[u8]) -> Self {
+        self.inputs.push((port, data.to_vec()));
+        self
+    }
+
+    pub fn expect_reg(mut self, reg: Register, val: u16) -> Self {
+        self.expectations.push(Expectation::Register(reg, val));
+        self
+    }
+
+    pub fn expect_flag(mut self, flag: Flag, val: bool) -> Self {
+        self.expectations.push(Expectation::Flag(flag, val));
+        self
+    }
+
+    pub fn expect_mem(mut self, addr: u16, data: &[u8]) -> Self {
+        self.expectations
+            .push(Expectation::Memory(addr, data.to_vec()));
+        self
+    }
+
+    pub fn expect_output(mut self, port: u8, data: &[u8]) -> Self {
+        self.expectations
+            .push(Expectation::Output(port, data.to_vec()));
+        self
+    }
+
+    pub fn max_cycles(mut self, cycles: u64) -> Self {
+        self.max_cycles = cycles;
+        self
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct TestResult {
+    pub name: String,
+    pub cycles: u64,
+    pub outcome: Outcome,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.outcome == Outcome::Passed
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum Outcome {
+    Passed,
+    Failed(Vec<Failure>),
+    Timeout,
+    Halted(u16),
+    EntryNotFound(String),
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum Failure {
+    Register {
+        reg: Register,
+        expected: u16,
+        actual: u16,
+    },
+    Flag {
+        flag: Flag,
+        expected: bool,
+        actual: bool,
+    },
+    Memory {
+        addr: u16,
+        expected: u8,
+        actual: u8,
+    },
+    Output {
+        port: u8,
+        expected: Vec<u8>,
+        actual: Vec<u8>,
+    },
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Failure::Register {
+                reg,
+                expected,
+                actual,
+            } => {
+                let width = if reg.is_wide() { 4 } else { 2 };
+                write!(
+                    f,
+                    "{}: expected {:0w$X}h, got {:0w$X}h",
+                    reg.name(),
+                    expected,
+                    actual,
+                    w = width
+                )
+            }
+            Failure::Flag {
+                flag,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "flag {}: expected {}, got {}",
+                flag.name(),
+                *expected as u8,
+                *actual as u8
+            ),
+            Failure::Memory {
+                addr,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "({:04X}h): expected {:02X}h, got {:02X}h",
+                addr, expected, actual
+            ),
+            Failure::Output {
+                port,
+                expected,
+                actual,
+            } => {
+                let bytes = |data: &[u8]| match data {
+                    [] => "nothing".to_string(),
+                    _ => data
+                        .iter()
+                        .map(|b| format!("{:02X}h", b))
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                };
+                write!(
+                    f,
+                    "port {:02X}h: expected {}, got {}",
+                    port,
+                    bytes(expected),
+                    bytes(actual)
+                )
+            }
+        }
+    }
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Passed => write!(f, "ok"),
+            Outcome::Failed(failures) => {
+                write!(f, "FAILED")?;
+                for failure in failures {
+                    write!(f, "\n    {}", failure)?;
+                }
+                Ok(())
+            }
+            Outcome::Timeout => write!(f, "FAILED (cycle budget exceeded)"),
+            Outcome::Halted(pc) => write!(f, "FAILED (halted at {:04X}h)", pc),
+            Outcome::EntryNotFound(l) => write!(f, "FAILED (label '{}' not found)", l),
+        }
+    }
+}
+
+/// Reads and assembles the source file `source`, the error is ready to
+/// print and names the file.
+pub fn assemble_file(source: &str) -> Result<Program, String> {
+    let s =
+        std::fs::read_to_string(source).map_err(|e| format!("unable to read {}: {}", source, e))?;
+
+    Compiler::new(
+        InMemorySourceProvider {
+            files: vec![(
+                SourceHeader {
+                    filename: source.to_string(),
+                },
+                s,
+            )],
+        },
+        64 * 1024,
+    )
+    .assemble()
+    .map_err(|e| format!("{}: {}", source, e))
+}
+
+/// Loads the program at address 0 with SP at 0. With an `entry` the CPU
+/// starts there with `RETURN_ADDRESS` on the stack, like a `#test` block call.
+pub fn load_program(program: &Program, entry: Option<u16>) -> (Cpu, Memory) {
+    let mut mem = Memory::new();
+    let len = program.data.len().min(mem.ram.len());
+    mem.load(0, &program.data[..len]);
+
+    let mut cpu = Cpu::new();
+    cpu.regs.sp = 0;
+    if let Some(entry) = entry {
+        call(&mut cpu, &mut mem, entry);
+    }
+    (cpu, mem)
+}
+
+/// Calls `entry` with `RETURN_ADDRESS` on the stack.
+fn call(cpu: &mut Cpu, mem: &mut Memory, entry: u16) {
+    cpu.regs.sp = cpu.regs.sp.wrapping_sub(2);
+    mem.load(cpu.regs.sp, &RETURN_ADDRESS.to_le_bytes());
+    cpu.regs.pc = entry;
+}
+
+/// Loads the program at address 0, applies the preconditions and calls the
+/// entry label until it returns or the cycle budget runs out.
+pub fn run_test(program: &Program, case: &TestCase) -> TestResult {
+    let (mut cpu, mut mem) = load_program(program, None);
+    for (reg, val) in case.registers.iter() {
+        cpu.regs.set(*reg, *val);
+    }
+    for (flag, val) in case.flags.iter() {
+        cpu.regs.set_flag(*flag, *val);
+    }
+    for (addr, data) in case.memory.iter() {
+        mem.load(*addr, data);
+    }
+    for (port, data) in case.inputs.iter() {
+        mem.queue_input(*port, data);
+    }
+
+    let result = |outcome, cpu: &Cpu| TestResult {
+        name: case.name.clone(),
+        cycles: cpu.cycles,
+        outcome,
+    };
+
+    match program.label(&case.entry) {
+        Some(entry) => call(&mut cpu, &mut mem, entry),
+        None => return result(Outcome::EntryNotFound(case.entry.clone()), &cpu),
+    }
+
+    while cpu.regs.pc != RETURN_ADDRESS {
+        if cpu.cycles >= case.max_cycles {
+            return result(Outcome::Timeout, &cpu);
+        }
+        if cpu.halted && !cpu.regs.iff1 {
+            return result(Outcome::Halted(cpu.regs.pc.wrapping_sub(1)), &cpu);
+        }
+        cpu.step(&mut mem);
+    }
+
+    let failures = check_expectations(case, &cpu, &mem);
+    if failures.is_empty() {
+        result(Outcome::Passed, &cpu)
+    } else {
+        result(Outcome::Failed(failures), &cpu)
+    }
+}
+
+fn check_expectations(case: &TestCase, cpu: &Cpu, mem: &Memory) -> Vec<Failure> {
+    let mut failures = vec![];
+
+    for e in case.expectations.iter() {
+        match e {
+            Expectation::Register(reg, expected) => {
+                let actual = cpu.regs.get(*reg);
+                if actual != *expected {
+                    failures.push(Failure::Register {
+                        reg: *reg,
+                        expected: *expected,
+                        actual,
+                    });
+                }
+            }
+            Expectation::Flag(flag, expected) => {
+                let actual = cpu.regs.flag(*flag);
+                if actual != *expected {
+                    failures.push(Failure::Flag {
+                        flag: *flag,
+                        expected: *expected,
+                        actual,
+                    });
+                }
+            }
+            Expectation::Memory(addr, expected) => {
+                let actual = mem.slice(*addr, expected.len());
+                for (i, (e, a)) in expected.iter().zip(actual.iter()).enumerate() {
+                    if e != a {
+                        failures.push(Failure::Memory {
+                            addr: addr.wrapping_add(i as u16),
+                            expected: *e,
+                            actual: *a,
+                        });
+                    }
+                }
+            }
+            Expectation::Output(port, expected) => {
+                let actual = mem
+                    .outputs
+                    .iter()
+                    .filter(|(p, _)| *p as u8 == *port)
+                    .map(|(_, v)| *v)
+                    .collect::<Vec<_>>();
+                if actual != *expected {
+                    failures.push(Failure::Output {
+                        port: *port,
+                        expected: expected.clone(),
+                        actual,
+                    });
+                }
+            }
+        }
+    }
+
+    failures
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::{Flag, Register};
+    use crate::harness::{assemble_file, parse_test_blocks, run_test, Failure, Outcome, TestCase};
+    use z80_assembler::{Compiler, InMemorySourceProvider, Program, SourceHeader};
+
+    const MULT16: &str = r#"
+.Mult16:
+            LD   B,   10h           ; number of bits init
+            LD   C,   D             ; move multiplier
+            LD   A,   E             ;
+            EX   DE,  HL            ; move multiplicand
+            LD   HL,  0h            ; clear partial result
+.mloop:     SRL  C                  ; shift multiplier right
+            RRA                     ; least-significat bit is in carry
+            JR   NC,  &noadd        ; skip add if no carry
+            ADD  HL,  DE            ; else add multiplicand to partialresult
+.noadd:     EX   DE,  HL            ; shift multiplicand left
+            ADD  HL,  HL            ; by multiplying it by two
+            EX   DE,  HL            ;
+            DJNZ &mloop             ; repeat until no more bits
+            RET                     ;
+"#;
+
+    const BSORT: &str = r#"
+.BSort:
+@flag:  0h
+            LD   &data, HL          ; save data address
+.loop:      RES  @flag, H           ; initialize exchange flag
+            LD   B,     C           ; initialize length counter
+            DEC  B                  ; adjust for testing
+            LD   IX,    &data       ; initialize array pointer
+.next:      LD   A,     (IX)        ; first element in comparison
+            LD   D,     A           ; temporary storage for element
+            LD   E,     (IX+1h)     ; second element in comparison
+            SUB  E                  ; comparison first to second
+            JR   NC,    &noex       ; if first > second, no jump
+            LD   (IX),  E           ; exchange array elements
+            LD   (IX+1h), D
+            SET  @flag, H           ; record exchange occurred
+.noex:      INC  IX                 ; point to next data element
+            DJNZ &next              ; count number of comparisons, repeat if more data pairs
+            BIT  @flag, H           ; etermine if exchange occurred
+            JR   NZ, &loop          ; continue if data unsorted
+            RET
+
+.data:      0000h
+"#;
+
+    fn assemble(source: &str) -> Program {
+        Compiler::new(
+            InMemorySourceProvider {
+                files: vec![(
+                    SourceHeader {
+                        filename: "main.z80".to_string(),
+                    },
+                    source.to_string(),
+                )],
+            },
+            64 * 1024,
+        )
+        .assemble()
+        .unwrap()
+    }
+
+    #[test]
+    fn assemble_errors() {
+        let path = std::env::temp_dir().join(format!("harness-{}.z80", std::process::id()));
+        let source = path.to_str().unwrap();
+        std::fs::write(&path, "NOP\nJP &missing\n").unwrap();
+        assert_eq!(
+            format!("{}: l2 - label 'missing' not found", source),
+            assemble_file(source).unwrap_err()
+        );
+        std::fs::remove_file(&path).unwrap();
+        assert!(assemble_file(source)
+            .unwrap_err()
+            .starts_with(&format!("unable to read {}: ", source)));
+    }
+
+    #[test]
+    fn mult16() {
+        let program = assemble(MULT16);
+        let case = TestCase::new("mult", "Mult16")
+            .reg(Register::DE, 123)
+            .reg(Register::HL, 45)
+            .expect_reg(Register::HL, 123 * 45)
+            .expect_reg(Register::B, 0);
+
+        let res = run_test(&program, &case);
+        assert_eq!(Outcome::Passed, res.outcome);
+        assert!(res.cycles > 0);
+    }
+
+    #[test]
+    fn bubble_sort() {
+        let program = assemble(BSORT);
+        let case = TestCase::new("sort", "BSort")
+            .reg(Register::HL, 0x8000)
+            .reg(Register::C, 5)
+            .mem(0x8000, &[5, 3, 9, 1, 4])
+            .expect_mem(0x8000, &[9, 5, 4, 3, 1]);
+
+        assert_eq!(Outcome::Passed, run_test(&program, &case).outcome);
+    }
+
+    #[test]
+    fn reports_failures() {
+        let program = assemble(MULT16);
+        let case = TestCase::new("mult", "Mult16")
+            .reg(Register::DE, 2)
+            .reg(Register::HL, 3)
+            .expect_reg(Register::HL, 7)
+            .expect_flag(Flag::Z, false)
+            .expect_mem(0x9000, &[0, 1]);
+
+        assert_eq!(
+            Outcome::Failed(vec![
+                Failure::Register {
+                    reg: Register::HL,
+                    expected: 7,
+                    actual: 6
+                },
+                Failure::Flag {
+                    flag: Flag::Z,
+                    expected: false,
+                    actual: true
+                },
+                Failure::Memory {
+                    addr: 0x9001,
+                    expected: 1,
+                    actual: 0
+                },
+            ]),
+            run_test(&program, &case).outcome
+        );
+    }
+
+    #[test]
+    fn cycle_budget_and_missing_label() {
+        let program = assemble(".spin: JP &spin\n");
+        let case = TestCase::new("spin", "spin").max_cycles(1000);
+        assert_eq!(Outcome::Timeout, run_test(&program, &case).outcome);
+
+        let case = TestCase::new("missing", "nope");
+        assert_eq!(
+            Outcome::EntryNotFound("nope".to_string()),
+            run_test(&program, &case).outcome
+        );
+    }
+
+    #[test]
+    fn test_blocks_in_source() {
+        let source = format!(
+            "{}{}",
+            MULT16,
+            r#"
+#test mult_3_by_4
+set DE, 3h
+set HL, 4h
+call Mult16
+expect HL, Ch
+expect NC
+#endt
+
+#test mult_wrong
+set DE, 3h
+set HL, 4h
+set (&buffer), 1234h
+call Mult16
+expect HL, Dh
+expect (&buffer), 34h, 13h
+#endt
+
+.buffer: 0000h
+"#
+        );
+        let program = assemble(&source);
+        let cases = parse_test_blocks(&program).unwrap();
+
+        assert_eq!(2, cases.len());
+        assert!(run_test(&program, &cases[0]).passed());
+
+        let res = run_test(&program, &cases[1]);
+        if let Outcome::Failed(failures) = res.outcome {
+            assert_eq!(2, failures.len());
+        } else {
+            panic!("expected failure, got {:?}", res.outcome)
+        }
+    }
+
+    #[test]
+    fn std_library() {
+        for (name, source) in z80_assembler::stdlib::FILES {
+            let program = assemble(source);
+            let cases = parse_test_blocks(&program).unwrap();
+
+            assert!(!cases.is_empty(), "{} has no tests", name);
+            for case in cases {
+                let res = run_test(&program, &case);
+                assert!(res.passed(), "{} {}: {}", name, case.name, res.outcome);
+            }
+        }
+    }
+}
@@ -0,0 +1,10 @@
+pub mod bus;
+pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod gdb;
+pub mod harness;
+pub mod trace;
+
+pub use bus::{Bus, Memory};
+pub use cpu::{Cpu, Flag, Register};
@@ -0,0 +1,250 @@
+use crate::trace::{EndReason, Header, Record, Step, Trace, TraceError, TRACED};
+
+/// Binary layout, multi-byte fields are little endian and `varint`s are
+/// LEB128:
+///
+/// ```text
+/// "Z80T" version:u8
+/// entry:u8 (0 none, 1 followed by addr:u16) checksum:u32 max_cycles:varint
+/// filter_count:varint (start:u16 end:u16)...
+/// records...
+/// ```
+///
+/// every record is `tag:u8 cycles:varint` followed by its fields, cycles are
+/// the difference with the previous record so a step usually costs a byte.
+/// A step stores `pc:u16 len:u8 opcode... mask:u16 values:u16...
+/// write_count:varint (addr:u16 val:u8)... out_count:varint (port:u16 val:u8)...`
+/// with bit `n` of the mask set if `TRACED[n]` changed.
+const MAGIC: &[u8] = b"Z80T";
+const VERSION: u8 = 1;
+
+const TAG_STEP: u8 = 1;
+const TAG_INPUT: u8 = 2;
+const TAG_INTERRUPT: u8 = 3;
+const TAG_NMI: u8 = 4;
+const TAG_END: u8 = 5;
+
+pub fn encode(trace: &Trace) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+
+    let h = &trace.header;
+    match h.entry {
+        Some(entry) => {
+            out.push(1);
+            out.extend(entry.to_le_bytes());
+        }
+        None => out.push(0),
+    }
+    out.extend(h.checksum.to_le_bytes());
+    varint(&mut out, h.max_cycles);
+    varint(&mut out, h.filter.len() as u64);
+    for (start, end) in h.filter.iter() {
+        out.extend(start.to_le_bytes());
+        out.extend(end.to_le_bytes());
+    }
+
+    let mut last = 0;
+    for r in trace.records.iter() {
+        let (tag, cycles) = match r {
+            Record::Step(s) => (TAG_STEP, s.cycles),
+            Record::Input { cycles, .. } => (TAG_INPUT, *cycles),
+            Record::Interrupt {
+                cycles,
+                data: Some(_),
+            } => (TAG_INTERRUPT, *cycles),
+            Record::Interrupt { cycles, data: None } => (TAG_NMI, *cycles),
+            Record::End { cycles, .. } => (TAG_END, *cycles),
+        };
+        out.push(tag);
+        varint(&mut out, cycles.wrapping_sub(last));
+        last = cycles;
+
+        match r {
+            Record::Step(s) => {
+                out.extend(s.pc.to_le_bytes());
+                out.push(s.opcode.len() as u8);
+                out.extend(s.opcode.iter());
+
+                let mut mask = 0u16;
+                let mut values = vec![];
+                for (n, reg) in TRACED.iter().enumerate() {
+                    if let Some((_, v)) = s.regs.iter().find(|(r, _)| r == reg) {
+                        mask |= 1 << n;
+                        values.extend(v.to_le_bytes());
+                    }
+                }
+                out.extend(mask.to_le_bytes());
+                out.extend(values);
+
+                for list in [&s.writes, &s.outputs] {
+                    varint(&mut out, list.len() as u64);
+                    for (addr, val) in list.iter() {
+                        out.extend(addr.to_le_bytes());
+                        out.push(*val);
+                    }
+                }
+            }
+            Record::Input { port, val, .. } => {
+                out.extend(port.to_le_bytes());
+                out.push(*val);
+            }
+            Record::Interrupt { data: Some(d), .. } => out.push(*d),
+            Record::Interrupt { data: None, .. } => {}
+            Record::End { reason, .. } => out.push(match reason {
+                EndReason::Returned => 0,
+                EndReason::Halted => 1,
+                EndReason::Timeout => 2,
+            }),
+        }
+    }
+    out
+}
+
+pub fn decode(data: &[u8]) -> Result<Trace, TraceError> {
+    let mut r = Reader { data, pos: 0 };
+
+    if r.bytes(MAGIC.len())? != MAGIC {
+        return Err(r.error("not a trace file"));
+    }
+    let version = r.u8()?;
+    if version != VERSION {
+        return Err(r.error(&format!("unsupported version {}", version)));
+    }
+
+    let entry = match r.u8()? {
+        0 => None,
+        1 => Some(r.u16()?),
+        _ => return Err(r.error("invalid entry")),
+    };
+    let checksum = u32::from_le_bytes(r.bytes(4)?.try_into().unwrap());
+    let max_cycles = r.varint()?;
+    let filter = (0..r.varint()?)
+        .map(|_| Ok((r.u16()?, r.u16()?)))
+        .collect::<Result<Vec<_>, TraceError>>()?;
+
+    let mut records = vec![];
+    let mut cycles = 0u64;
+    while r.pos < data.len() {
+        let tag = r.u8()?;
+        cycles = cycles.wrapping_add(r.varint()?);
+
+        records.push(match tag {
+            TAG_STEP => {
+                let pc = r.u16()?;
+                let len = r.u8()? as usize;
+                let opcode = r.bytes(len)?.to_vec();
+
+                let mask = r.u16()?;
+                let mut regs = vec![];
+                for (n, reg) in TRACED.iter().enumerate() {
+                    if mask & (1 << n) != 0 {
+                        regs.push((*reg, r.u16()?));
+                    }
+                }
+
+                let mut lists = [vec![], vec![]];
+                for list in lists.iter_mut() {
+                    for _ in 0..r.varint()? {
+                        list.push((r.u16()?, r.u8()?));
+                    }
+                }
+                let [writes, outputs] = lists;
+
+                Record::Step(Step {
+                    cycles,
+                    pc,
+                    opcode,
+                    regs,
+                    writes,
+                    outputs,
+                })
+            }
+            TAG_INPUT => Record::Input {
+                cycles,
+                port: r.u16()?,
+                val: r.u8()?,
+            },
+            TAG_INTERRUPT => Record::Interrupt {
+                cycles,
+                data: Some(r.u8()?),
+            },
+            TAG_NMI => Record::Interrupt { cycles, data: None },
+            TAG_END => Record::End {
+                cycles,
+                reason: match r.u8()? {
+                    0 => EndReason::Returned,
+                    1 => EndReason::Halted,
+                    2 => EndReason::Timeout,
+                    _ => return Err(r.error("invalid end reason")),
+                },
+            },
+            _ => return Err(r.error(&format!("unknown record {:02X}h", tag))),
+        });
+    }
+
+    Ok(Trace {
+        header: Header {
+            entry,
+            max_cycles,
+            filter,
+            checksum,
+        },
+        records,
+    })
+}
+
+fn varint(out: &mut Vec<u8>, mut val: u64) {
+    loop {
+        let b = (val & 0x7F) as u8;
+        val >>= 7;
+        if val == 0 {
+            out.push(b);
+            return;
+        }
+        out.push(b | 0x80);
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn error(&self, message: &str) -> TraceError {
+        TraceError {
+            message: format!("offset {}: {}", self.pos, message),
+        }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], TraceError> {
+        match self.data.get(self.pos..self.pos + len) {
+            Some(b) => {
+                self.pos += len;
+                Ok(b)
+            }
+            None => Err(self.error("unexpected end of trace")),
+        }
+    }
+
+    fn u8(&mut self) -> Result<u8, TraceError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, TraceError> {
+        Ok(u16::from_le_bytes([self.u8()?, self.u8()?]))
+    }
+
+    fn varint(&mut self) -> Result<u64, TraceError> {
+        let mut val = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.u8()?;
+            val |= ((b & 0x7F) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Ok(val);
+            }
+        }
+        Err(self.error("invalid varint"))
+    }
+}
@@ -0,0 +1,547 @@
+use crate::bus::{Access, Recorder};
+use crate::cpu::{Cpu, Register};
+use crate::disasm::disassemble;
+use crate::harness::{load_program, RETURN_ADDRESS};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use z80_assembler::Program;
+
+pub use crate::trace::format::{decode, encode};
+
+mod format;
+
+/// Registers whose changes are recorded. `R` is left out, it changes on
+/// every instruction and replaying the run recomputes it anyway.
+pub const TRACED: [Register; 12] = [
+    Register::AF,
+    Register::BC,
+    Register::DE,
+    Register::HL,
+    Register::AFp,
+    Register::BCp,
+    Register::DEp,
+    Register::HLp,
+    Register::IX,
+    Register::IY,
+    Register::SP,
+    Register::I,
+];
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct TraceError {
+    pub message: String,
+}
+
+impl Display for TraceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for TraceError {}
+
+/// An interrupt requested once the CPU reaches `cycles`, `data` is the byte
+/// put on the bus, `None` for an NMI.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Interrupt {
+    pub cycles: u64,
+    pub data: Option<u8>,
+}
+
+/// Everything needed to start the same run again.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Header {
+    pub entry: Option<u16>,
+    pub max_cycles: u64,
+    /// Only instructions fetched from these inclusive ranges are recorded,
+    /// everything when empty.
+    pub filter: Vec<(u16, u16)>,
+    /// Of the program image, replaying a different program is an error.
+    pub checksum: u32,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Step {
+    /// Cycle count before the instruction ran.
+    pub cycles: u64,
+    pub pc: u16,
+    pub opcode: Vec<u8>,
+    /// New values of the `TRACED` registers the instruction changed.
+    pub regs: Vec<(Register, u16)>,
+    pub writes: Vec<(u16, u8)>,
+    pub outputs: Vec<(u16, u8)>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Record {
+    Step(Step),
+    /// Port reads are recorded even when the instruction is filtered out,
+    /// they are what makes the run reproducible.
+    Input {
+        cycles: u64,
+        port: u16,
+        val: u8,
+    },
+    /// An accepted interrupt, `data` is `None` for an NMI.
+    Interrupt {
+        cycles: u64,
+        data: Option<u8>,
+    },
+    End {
+        cycles: u64,
+        reason: EndReason,
+    },
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EndReason {
+    /// The entry routine returned to `RETURN_ADDRESS`.
+    Returned,
+    /// `HALT` with interrupts disabled.
+    Halted,
+    Timeout,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Trace {
+    pub header: Header,
+    pub records: Vec<Record>,
+}
+
+pub struct Options {
+    pub entry: Option<u16>,
+    pub max_cycles: u64,
+    pub filter: Vec<(u16, u16)>,
+    pub inputs: Vec<(u8, Vec<u8>)>,
+    pub interrupts: Vec<Interrupt>,
+}
+
+/// FNV-1a of the program image.
+pub fn checksum(data: &[u8]) -> u32 {
+    data.iter().fold(0x811C9DC5u32, |h, b| {
+        (h ^ *b as u32).wrapping_mul(0x01000193)
+    })
+}
+
+/// Addresses from `label` up to the next label, or to the end of the
+/// program, as a filter range.
+pub fn label_range(program: &Program, label: &str) -> Option<(u16, u16)> {
+    let start = *program.labels.get(label)?;
+    let end = program
+        .labels
+        .values()
+        .filter(|a| **a > start)
+        .min()
+        .copied()
+        .unwrap_or_else(|| program.data.len().max(start + 1));
+    Some((start as u16, (end - 1).min(0xFFFF) as u16))
+}
+
+/// Runs the program from a fresh machine, interrupts are raised as soon as
+/// the CPU has run for their cycle count and interrupts are enabled.
+pub fn record(program: &Program, options: &Options) -> Trace {
+    let (mut cpu, mut mem) = load_program(program, options.entry);
+    for (port, data) in options.inputs.iter() {
+        mem.queue_input(*port, data);
+    }
+
+    let mut pending = options.interrupts.clone();
+    pending.sort_by_key(|i| i.cycles);
+    let mut pending = VecDeque::from(pending);
+
+    let in_filter = |pc: u16| {
+        options.filter.is_empty() || options.filter.iter().any(|(s, e)| pc >= *s && pc <= *e)
+    };
+
+    let mut records = vec![];
+    let mut regs = values(&cpu);
+
+    let reason = loop {
+        if cpu.regs.pc == RETURN_ADDRESS {
+            break EndReason::Returned;
+        }
+        if cpu.cycles >= options.max_cycles {
+            break EndReason::Timeout;
+        }
+        // only an NMI can wake it up
+        if cpu.halted && !cpu.regs.iff1 && !pending.iter().any(|i| i.data.is_none()) {
+            break EndReason::Halted;
+        }
+
+        // a maskable interrupt stays pending until it is accepted, like a
+        // device holding the line
+        let cycles = cpu.cycles;
+        let accepted = pending
+            .iter()
+            .take_while(|i| i.cycles <= cycles)
+            .position(|i| match i.data {
+                Some(data) => cpu.interrupt(&mut mem, data).is_some(),
+                None => {
+                    cpu.nmi(&mut mem);
+                    true
+                }
+            });
+        if let Some(i) = accepted {
+            let irq = pending.remove(i).unwrap();
+            records.push(Record::Interrupt {
+                cycles,
+                data: irq.data,
+            });
+            continue;
+        }
+
+        let pc = cpu.regs.pc;
+        let halted = cpu.halted;
+        let opcode = disassemble(&mem.ram, pc, &HashMap::new()).bytes;
+
+        let mut rec = Recorder::new(&mut mem);
+        cpu.step(&mut rec);
+        let accesses = rec.accesses;
+
+        for a in accesses.iter() {
+            if let Access::In(port, val) = a {
+                records.push(Record::Input {
+                    cycles,
+                    port: *port,
+                    val: *val,
+                });
+            }
+        }
+
+        // a halted CPU only burns cycles, there is nothing to record
+        if halted || !in_filter(pc) {
+            continue;
+        }
+
+        let new = values(&cpu);
+        records.push(Record::Step(Step {
+            cycles,
+            pc,
+            opcode,
+            regs: TRACED
+                .iter()
+                .zip(regs.iter().zip(new.iter()))
+                .filter(|(_, (old, new))| old != new)
+                .map(|(r, (_, new))| (*r, *new))
+                .collect(),
+            writes: accesses
+                .iter()
+                .filter_map(|a| match a {
+                    Access::Write { addr, val, .. } => Some((*addr, *val)),
+                    _ => None,
+                })
+                .collect(),
+            outputs: accesses
+                .iter()
+                .filter_map(|a| match a {
+                    Access::Out(port, val) => Some((*port, *val)),
+                    _ => None,
+                })
+                .collect(),
+        }));
+        regs = new;
+    };
+
+    records.push(Record::End {
+        cycles: cpu.cycles,
+        reason,
+    });
+
+    Trace {
+        header: Header {
+            entry: options.entry,
+            max_cycles: options.max_cycles,
+            filter: options.filter.clone(),
+            checksum: checksum(&program.data),
+        },
+        records,
+    }
+}
+
+fn values(cpu: &Cpu) -> [u16; TRACED.len()] {
+    TRACED.map(|r| cpu.regs.get(r))
+}
+
+/// Runs the program again feeding it the recorded port reads and
+/// interrupts. The result matches `trace` unless the emulator changed.
+pub fn replay(program: &Program, trace: &Trace) -> Result<Trace, TraceError> {
+    if checksum(&program.data) != trace.header.checksum {
+        return Err(TraceError {
+            message: "the trace was recorded from a different program".to_string(),
+        });
+    }
+
+    let mut inputs: Vec<(u8, Vec<u8>)> = vec![];
+    let mut interrupts = vec![];
+    for r in trace.records.iter() {
+        match r {
+            Record::Input { port, val, .. } => {
+                match inputs.iter_mut().find(|(p, _)| *p == *port as u8) {
+                    Some((_, data)) => data.push(*val),
+                    None => inputs.push((*port as u8, vec![*val])),
+                }
+            }
+            Record::Interrupt { cycles, data } => interrupts.push(Interrupt {
+                cycles: *cycles,
+                data: *data,
+            }),
+            _ => {}
+        }
+    }
+
+    Ok(record(
+        program,
+        &Options {
+            entry: trace.header.entry,
+            max_cycles: trace.header.max_cycles,
+            filter: trace.header.filter.clone(),
+            inputs,
+            interrupts,
+        },
+    ))
+}
+
+/// First record where two traces differ.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Divergence {
+    pub index: usize,
+    pub left: Option<Record>,
+    pub right: Option<Record>,
+}
+
+pub fn diff(left: &Trace, right: &Trace) -> Option<Divergence> {
+    let len = left.records.len().max(right.records.len());
+    (0..len)
+        .find(|i| left.records.get(*i) != right.records.get(*i))
+        .map(|index| Divergence {
+            index,
+            left: left.records.get(index).cloned(),
+            right: right.records.get(index).cloned(),
+        })
+}
+
+/// One line per record, `labels` are used for the instruction addresses.
+pub fn format_record(record: &Record, labels: &HashMap<u16, String>) -> String {
+    match record {
+        Record::Step(s) => {
+            // placed at its address so relative jumps decode to the right target
+            let mut mem = vec![0u8; 64 * 1024];
+            for (i, b) in s.opcode.iter().enumerate() {
+                mem[s.pc.wrapping_add(i as u16) as usize] = *b;
+            }
+            let text = disassemble(&mem, s.pc, labels).text;
+
+            let mut out = format!(
+                "{:>10} {:04X}h {:<12} {:<20}",
+                s.cycles,
+                s.pc,
+                labels
+                    .get(&s.pc)
+                    .map(|l| format!("&{}", l))
+                    .unwrap_or_default(),
+                text
+            );
+            for (r, v) in s.regs.iter() {
+                out.push_str(&format!(" {}={:04X}h", r.name(), v));
+            }
+            for (addr, v) in s.writes.iter() {
+                out.push_str(&format!(" ({:04X}h)={:02X}h", addr, v));
+            }
+            for (port, v) in s.outputs.iter() {
+                out.push_str(&format!(" out {:02X}h={:02X}h", port & 0xFF, v));
+            }
+            out.trim_end().to_string()
+        }
+        Record::Input { cycles, port, val } => {
+            format!("{:>10} in {:02X}h={:02X}h", cycles, port & 0xFF, val)
+        }
+        Record::Interrupt { cycles, data } => match data {
+            Some(d) => format!("{:>10} interrupt {:02X}h", cycles, d),
+            None => format!("{:>10} nmi", cycles),
+        },
+        Record::End { cycles, reason } => format!(
+            "{:>10} end: {}",
+            cycles,
+            match reason {
+                EndReason::Returned => "returned",
+                EndReason::Halted => "halted",
+                EndReason::Timeout => "timeout",
+            }
+        ),
+    }
+}
+
+/// Address to name map for `format_record`, keeping the alphabetically
+/// first name when labels share an address.
+pub fn symbols(program: &Program) -> HashMap<u16, String> {
+    let mut symbols = HashMap::new();
+    for (name, addr) in program.labels.iter() {
+        let e = symbols.entry(*addr as u16).or_insert_with(|| name.clone());
+        if name < e {
+            *e = name.clone();
+        }
+    }
+    symbols
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::Register;
+    use crate::trace::{
+        decode, diff, encode, format_record, label_range, record, replay, symbols, EndReason,
+        Interrupt, Options, Record,
+    };
+    use z80_assembler::{Compiler, InMemorySourceProvider, Program, SourceHeader};
+
+    // the main loop adds the bytes read from port 10h into C, the interrupt
+    // handler (RST 00h in IM 0) counts into HL, which leaves the flags alone
+    const SOURCE: &str = r#"
+.isr:
+INC HL
+EI
+RET
+.main:
+IM 0h
+EI
+LD C, 0h
+LD B, 4h
+.loop:
+IN A, 10h
+ADD A, C
+LD C, A
+DJNZ &loop
+OUT 11h, A
+RET
+"#;
+
+    fn program() -> Program {
+        Compiler::new(
+            InMemorySourceProvider {
+                files: vec![(
+                    SourceHeader {
+                        filename: "src/main.z80".to_string(),
+                    },
+                    SOURCE.to_string(),
+                )],
+            },
+            64 * 1024,
+        )
+        .assemble()
+        .unwrap()
+    }
+
+    fn options(program: &Program) -> Options {
+        Options {
+            entry: program.label("main"),
+            max_cycles: 10_000,
+            filter: vec![],
+            inputs: vec![(0x10, vec![1, 2, 3, 4])],
+            interrupts: vec![Interrupt {
+                cycles: 40,
+                data: Some(0xC7),
+            }],
+        }
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let program = program();
+        let trace = record(&program, &options(&program));
+
+        let inputs = trace
+            .records
+            .iter()
+            .filter_map(|r| match r {
+                Record::Input { val, .. } => Some(*val),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(vec![1, 2, 3, 4], inputs);
+        assert!(trace.records.iter().any(|r| matches!(
+            r,
+            Record::Interrupt {
+                data: Some(0xC7),
+                ..
+            }
+        )));
+        assert!(trace.records.iter().any(|r| match r {
+            Record::Step(s) => s.outputs == vec![(0x0A11, 10)],
+            _ => false,
+        }));
+        match trace.records.last() {
+            Some(Record::End { reason, .. }) => assert_eq!(EndReason::Returned, *reason),
+            r => panic!("{:?}", r),
+        }
+
+        assert_eq!(trace, replay(&program, &trace).unwrap());
+        assert_eq!(trace, decode(&encode(&trace)).unwrap());
+    }
+
+    #[test]
+    fn filters() {
+        let program = program();
+        assert_eq!(Some((0x0, 0x2)), label_range(&program, "isr"));
+
+        let mut options = options(&program);
+        options.filter = vec![label_range(&program, "isr").unwrap()];
+        let trace = record(&program, &options);
+
+        let pcs = trace
+            .records
+            .iter()
+            .filter_map(|r| match r {
+                Record::Step(s) => Some(s.pc),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(vec![0x0, 0x1, 0x2], pcs);
+        // port reads outside the filter are still there for replaying
+        assert_eq!(
+            4,
+            trace
+                .records
+                .iter()
+                .filter(|r| matches!(r, Record::Input { .. }))
+                .count()
+        );
+        assert_eq!(trace, replay(&program, &trace).unwrap());
+
+        let steps = trace
+            .records
+            .iter()
+            .filter_map(|r| match r {
+                Record::Step(s) => Some(s),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        // the first recorded step holds every change since the start
+        assert!(steps[0].regs.contains(&(Register::HL, 1)));
+        assert_eq!(Vec::<(Register, u16)>::new(), steps[1].regs);
+
+        let labels = symbols(&program);
+        assert!(format_record(&trace.records[0], &labels).contains("in 10h=01h"));
+        assert!(format_record(&Record::Step(steps[0].clone()), &labels)
+            .contains("0000h &isr         INC HL"));
+    }
+
+    #[test]
+    fn divergence() {
+        let program = program();
+        let trace = record(&program, &options(&program));
+
+        let mut other = options(&program);
+        other.inputs = vec![(0x10, vec![1, 2, 7, 4])];
+        let other = record(&program, &other);
+
+        let d = diff(&trace, &other).unwrap();
+        match (d.left, d.right) {
+            (Some(Record::Input { val: 3, .. }), Some(Record::Input { val: 7, .. })) => {}
+            r => panic!("{:?}", r),
+        }
+        assert_eq!(None, diff(&trace, &trace));
+
+        assert!(decode(b"Z80X").is_err());
+        let data = encode(&trace);
+        assert!(decode(&data[..data.len() - 1]).is_err());
+    }
+}